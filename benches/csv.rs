@@ -0,0 +1,43 @@
+//! Compare `write_csv` throughput with and without the `rayon` feature:
+//!
+//! ```shell
+//! $ cargo bench --bench csv
+//! $ cargo bench --bench csv --features rayon
+//! ```
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use dose2gmsh::DoseBlock;
+
+// 100 x 100 x 100 voxels of unit size with varying doses
+fn block() -> DoseBlock {
+    let n = 100;
+    let nodes: Vec<f64> = (0..=n).map(|i| i as f64).collect();
+    let num_voxels = n * n * n;
+    DoseBlock {
+        xs: nodes.clone(),
+        ys: nodes.clone(),
+        zs: nodes,
+        doses: (0..num_voxels).map(|i| (i as f64).sqrt() * 1.0e-12).collect(),
+        uncerts: (0..num_voxels).map(|i| 1.0 / (1.0 + i as f64)).collect(),
+        variant: None,
+    }
+}
+
+fn write_csv(c: &mut Criterion) {
+    let data = block();
+    let mut group = c.benchmark_group("write_csv");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(data.num_voxels() as u64));
+    // write to memory: `io::sink()` can skip the formatting we want to measure
+    let mut out = Vec::new();
+    group.bench_function("100^3", |b| {
+        b.iter(|| {
+            out.clear();
+            data.write_csv_to(&mut out).unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, write_csv);
+criterion_main!(benches);
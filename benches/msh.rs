@@ -0,0 +1,45 @@
+//! Measure `write_msh2` throughput in elements (voxels) and nodes per second:
+//!
+//! ```shell
+//! $ cargo bench --bench msh
+//! ```
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use dose2gmsh::DoseBlock;
+
+// 60 x 60 x 60 voxels of 1 mm with varying doses
+fn block() -> DoseBlock {
+    let n = 60;
+    let nodes: Vec<f64> = (0..=n).map(|i| i as f64 * 0.1).collect();
+    let num_voxels = n * n * n;
+    DoseBlock {
+        xs: nodes.clone(),
+        ys: nodes.clone(),
+        zs: nodes,
+        doses: (0..num_voxels).map(|i| (i as f64).sqrt() * 1.0e-12).collect(),
+        uncerts: (0..num_voxels).map(|i| 1.0 / (1.0 + i as f64)).collect(),
+        variant: None,
+    }
+}
+
+fn write_msh2(c: &mut Criterion) {
+    let data = block();
+    let mut group = c.benchmark_group("write_msh2");
+    group.sample_size(10);
+    // write to memory: `io::sink()` can skip the formatting we want to measure
+    let mut out = Vec::new();
+    let counts = [("60^3 elements", data.num_voxels()), ("60^3 nodes", data.num_nodes())];
+    for &(name, count) in &counts {
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                out.clear();
+                data.write_msh2_to(&mut out).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, write_msh2);
+criterion_main!(benches);
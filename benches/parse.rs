@@ -0,0 +1,64 @@
+//! Measure `from_3d_dose` throughput in voxels and bytes per second:
+//!
+//! ```shell
+//! $ cargo bench --bench parse
+//! ```
+//!
+//! and again with `--features rayon` to see what parsing doses in parallel gains.
+
+use std::io::Write;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use dose2gmsh::DoseBlock;
+
+// 100 x 100 x 100 voxels of 1 mm, formatted like dosxyznrc output
+fn write_3ddose(path: &std::path::Path, n: usize) {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+    let num_voxels = n * n * n;
+    writeln!(file, "{:>12}{:>12}{:>12}", n, n, n).unwrap();
+    for _ in 0..3 {
+        for i in 0..=n {
+            write!(file, " {:.4}", -5.0 + i as f64 * 0.1).unwrap();
+        }
+        writeln!(file).unwrap();
+    }
+    for i in 0..num_voxels {
+        write!(file, " {:.4E}", (i as f64).sqrt() * 1.0e-12).unwrap();
+    }
+    writeln!(file).unwrap();
+    for i in 0..num_voxels {
+        write!(file, " {:.4}", 1.0 / (1.0 + (i % 1000) as f64)).unwrap();
+    }
+    writeln!(file).unwrap();
+    file.flush().unwrap();
+}
+
+fn from_3d_dose(c: &mut Criterion) {
+    let n = 100;
+    let path = std::env::temp_dir().join("dose2gmsh_bench_parse.3ddose");
+    write_3ddose(&path, n);
+
+    let mut group = c.benchmark_group("from_3d_dose");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements((n * n * n) as u64));
+    group.bench_function("100^3 voxels", |b| b.iter(|| DoseBlock::from_3d_dose(&path).unwrap()));
+    group.throughput(Throughput::Bytes(std::fs::metadata(&path).unwrap().len()));
+    group.bench_function("100^3 bytes", |b| b.iter(|| DoseBlock::from_3d_dose(&path).unwrap()));
+    group.finish();
+
+    // larger buffers matter most on network filesystems, where each read is a round trip
+    let mut group = c.benchmark_group("buffer size");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(std::fs::metadata(&path).unwrap().len()));
+    for &(name, size) in &[("8 KiB", 8 << 10), ("64 KiB", 64 << 10), ("1 MiB", 1 << 20)] {
+        group.bench_function(name, |b| {
+            b.iter(|| DoseBlock::from_3d_dose_buffered(&path, size).unwrap())
+        });
+    }
+    group.finish();
+
+    std::fs::remove_file(path).unwrap();
+}
+
+criterion_group!(benches, from_3d_dose);
+criterion_main!(benches);
@@ -0,0 +1,206 @@
+//! Sparse meshes merging boxes of voxels with equal dose into single hexahedra.
+
+use std::collections::BTreeSet;
+use std::io::Write;
+
+use crate::{write_msh2_element_data, DoseBlock, Fmt, Quantity};
+
+/// A mesh of axis-aligned hexahedra, each covering a box of voxels of one dose block.
+///
+/// Neighbouring elements can differ in size, so nodes of one element may lie on the face of
+/// another. That's fine for viewing, but not for finite element tools needing a conforming
+/// mesh.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoalescedMesh {
+    /// Node coordinates of the original grid along *x*, *y* and *z*.
+    pub xs: Vec<f64>,
+    pub ys: Vec<f64>,
+    pub zs: Vec<f64>,
+    /// Elements in order of their first voxel in `doses` order.
+    pub elements: Vec<CoalescedElement>,
+}
+
+/// A box of voxels merged into one element of a [`CoalescedMesh`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoalescedElement {
+    /// Voxel indices along *x*, *y* and *z* the box covers.
+    pub voxels: [std::ops::Range<usize>; 3],
+    /// Volume-weighted mean dose of the voxels.
+    pub dose: f64,
+    /// Fractional uncertainty of the mean dose.
+    pub uncert: f64,
+}
+
+impl DoseBlock {
+    /// Merge boxes of voxels whose doses are within `tol` of each other into single
+    /// hexahedra, shrinking meshes with large uniform regions such as zero dose in air.
+    ///
+    /// Boxes are grown greedily in `doses` order: each starts from the first voxel not yet
+    /// merged and grows along *x*, then *y*, then *z*, while every voxel added is within `tol`
+    /// of the first. Each element gets the volume-weighted mean of its doses, with the
+    /// uncertainty of that mean for independent voxels. A `tol` of zero merges only equal
+    /// doses; voxels with non-finite doses are never merged.
+    pub fn coalesce_equal(&self, tol: f64) -> CoalescedMesh {
+        let (nx, ny, nz) = (self.num_x(), self.num_y(), self.num_z());
+        let widths = |pts: &[f64]| -> Vec<f64> {
+            pts.windows(2).map(|pair| (pair[1] - pair[0]).abs()).collect()
+        };
+        let (dx, dy, dz) = (widths(&self.xs), widths(&self.ys), widths(&self.zs));
+        let variances = self.abs_variances();
+
+        let mut merged = vec![false; self.num_voxels()];
+        let mut elements = Vec::new();
+        for index in 0..self.num_voxels() {
+            if merged[index] {
+                continue;
+            }
+            let (i, j, k) = self.voxel_ijk(index);
+            let seed = self.doses[index];
+            let joins = |i, j, k| {
+                let index = self.voxel_index(i, j, k);
+                !merged[index] && (self.doses[index] - seed).abs() <= tol
+            };
+
+            let mut ie = i + 1;
+            while ie < nx && joins(ie, j, k) {
+                ie += 1;
+            }
+            let mut je = j + 1;
+            while je < ny && (i..ie).all(|i| joins(i, je, k)) {
+                je += 1;
+            }
+            let mut ke = k + 1;
+            while ke < nz && (j..je).all(|j| (i..ie).all(|i| joins(i, j, ke))) {
+                ke += 1;
+            }
+
+            let (mut volume, mut dose, mut variance) = (0.0, 0.0, 0.0);
+            for (k, wz) in (k..ke).zip(&dz[k..ke]) {
+                for (j, wy) in (j..je).zip(&dy[j..je]) {
+                    for (i, wx) in (i..ie).zip(&dx[i..ie]) {
+                        let index = self.voxel_index(i, j, k);
+                        merged[index] = true;
+                        let weight = wx * wy * wz;
+                        volume += weight;
+                        dose += weight * self.doses[index];
+                        variance += weight * weight * variances[index];
+                    }
+                }
+            }
+            let dose = if volume > 0.0 { dose / volume } else { seed };
+            let uncert = if dose != 0.0 { variance.sqrt() / volume / dose.abs() } else { 0.0 };
+            elements.push(CoalescedElement { voxels: [i..ie, j..je, k..ke], dose, uncert });
+        }
+
+        CoalescedMesh { xs: self.xs.clone(), ys: self.ys.clone(), zs: self.zs.clone(), elements }
+    }
+}
+
+impl CoalescedMesh {
+    /// Grid node ids, from 1 as in [`DoseBlock::write_msh2`], of an element's corners in
+    /// Gmsh order.
+    fn corners(&self, element: &CoalescedElement) -> [usize; 8] {
+        let [x, y, z] = &element.voxels;
+        let id = |i: usize, j: usize, k: usize| i + self.xs.len() * (j + self.ys.len() * k) + 1;
+        [
+            id(x.start, y.start, z.start),
+            id(x.end, y.start, z.start),
+            id(x.end, y.end, z.start),
+            id(x.start, y.end, z.start),
+            id(x.start, y.start, z.end),
+            id(x.end, y.start, z.end),
+            id(x.end, y.end, z.end),
+            id(x.start, y.end, z.end),
+        ]
+    }
+
+    /// Write the mesh with dose and uncertainty fields in Gmsh `.msh` format (version 2.2).
+    ///
+    /// Only nodes at element corners are written, keeping their ids in the full grid, so ids
+    /// have gaps. Fields are labelled for `quantity`.
+    pub fn write_msh2_to<W: Write>(
+        &self,
+        file: &mut W,
+        quantity: Quantity,
+    ) -> Result<(), std::io::Error> {
+        writeln!(file, "$MeshFormat\n2.2 0 8\n$EndMeshFormat")?;
+
+        let nodes: BTreeSet<usize> = self.elements.iter().flat_map(|e| self.corners(e)).collect();
+        writeln!(file, "$Nodes\n{}", nodes.len())?;
+        let (nx, nxy) = (self.xs.len(), self.xs.len() * self.ys.len());
+        for &id in &nodes {
+            let n = id - 1;
+            let (x, y, z) = (self.xs[n % nx], self.ys[n % nxy / nx], self.zs[n / nxy]);
+            writeln!(file, "{} {} {} {}", id, x, y, z)?;
+        }
+        writeln!(file, "$EndNodes")?;
+
+        writeln!(file, "$Elements\n{}", self.elements.len())?;
+        for (index, element) in self.elements.iter().enumerate() {
+            let corners: Vec<String> =
+                self.corners(element).iter().map(|id| id.to_string()).collect();
+            // see `DoseBlock::write_msh2_hex8` for the element line
+            writeln!(file, "{} 5 2 0 0 {}", index + 1, corners.join(" "))?;
+        }
+        writeln!(file, "$EndElements")?;
+
+        let doses: Vec<f64> = self.elements.iter().map(|element| element.dose).collect();
+        let uncerts: Vec<f64> = self.elements.iter().map(|element| element.uncert).collect();
+        let names = DoseBlock::field_names(Fmt::Msh2, quantity);
+        write_msh2_element_data(file, names[0], &doses)?;
+        write_msh2_element_data(file, names[1], &uncerts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesce_equal() {
+        // 3 x 2 x 2 voxels: zero everywhere but a hot column at i = 2, j = 0
+        let mut doses = vec![0.0; 12];
+        doses[2] = 5.0;
+        doses[2 + 6] = 5.0;
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 2.0, 4.0],
+            ys: vec![0.0, 1.0, 2.0],
+            zs: vec![0.0, 1.0, 2.0],
+            doses,
+            uncerts: vec![0.1; 12],
+            variant: None,
+        };
+        let mesh = data.coalesce_equal(0.0);
+        let boxes: Vec<_> = mesh.elements.iter().map(|e| (e.voxels.clone(), e.dose)).collect();
+        assert_eq!(
+            boxes,
+            vec![
+                ([0..2, 0..2, 0..2], 0.0),
+                ([2..3, 0..1, 0..2], 5.0),
+                ([2..3, 1..2, 0..2], 0.0),
+            ]
+        );
+        // two voxels of equal volume and uncertainty
+        approx::assert_relative_eq!(mesh.elements[1].uncert, 0.1 / 2.0_f64.sqrt());
+        assert_eq!(mesh.elements[0].uncert, 0.0);
+
+        // within tolerance, the first dose sets the bar
+        let mut data = data;
+        data.doses[2] = 0.4;
+        data.doses[2 + 6] = 0.5;
+        assert_eq!(data.coalesce_equal(0.5).elements.len(), 1);
+        assert_eq!(data.coalesce_equal(0.45).elements.len(), 4);
+        approx::assert_relative_eq!(data.coalesce_equal(0.5).elements[0].dose, 0.9 * 2.0 / 16.0);
+
+        let mut msh = Vec::new();
+        data.coalesce_equal(0.5).write_msh2_to(&mut msh, Quantity::Dose).unwrap();
+        let msh = String::from_utf8(msh).unwrap();
+        assert!(msh.contains("$Nodes\n8\n1 0 0 0\n4 4 0 0\n"));
+        assert!(msh.contains("$Elements\n1\n1 5 2 0 0 1 4 12 9 25 28 36 33\n$EndElements\n"));
+        assert!(msh.contains("\"Dose [Gy·cm2]\"\n1\n0.0\n3\n0\n1\n1\n1 0.1125\n"));
+
+        // every voxel of a noisy block is its own element
+        let noisy = DoseBlock { doses: (0..12).map(|i| i as f64).collect(), ..data.clone() };
+        assert_eq!(noisy.coalesce_equal(0.0).elements.len(), 12);
+    }
+}
@@ -0,0 +1,410 @@
+//! Combining dose blocks that share a voxel grid.
+
+use crate::{DoseBlock, DoseError, DEFAULT_BUFFER_SIZE};
+
+/// Decades apart two total doses can be before they're flagged as differently normalized, see
+/// [`DoseBlock::magnitude_gap`].
+pub const MAGNITUDE_WARNING_DECADES: f64 = 3.0;
+
+impl DoseBlock {
+    /// Sum of all voxel doses in `[Gy · cm2]`.
+    ///
+    /// The sum is compensated, so it stays accurate over hundreds of millions of voxels, and
+    /// runs in parallel under the `rayon` feature.
+    pub fn total_dap(&self) -> f64 {
+        crate::stats::stable_sum(&self.doses, crate::stats::PARALLEL)
+    }
+
+    /// Sum the doses of several blocks on the same grid, e.g. partial runs of one simulation.
+    ///
+    /// Uncertainties are combined as variances, not as fractions: each voxel's fractional
+    /// uncertainty is turned into an absolute variance `(uncert * dose)^2`, the doses and the
+    /// variances are summed over the blocks, and the summed variance is turned back into a
+    /// fraction of the summed dose, `sqrt(sum variance) / |sum dose|`. That's exact for
+    /// statistically independent runs, e.g. Monte Carlo runs with different random seeds, and
+    /// underestimates the uncertainty of correlated ones.
+    ///
+    /// A zero-dose voxel has zero variance whatever its fractional uncertainty, which `3ddose`
+    /// files often give as 1 or leave undefined, so it never turns the sum into `NaN`. Voxels
+    /// with zero total dose get zero uncertainty, unless doses of opposite sign cancel there,
+    /// see [`DoseBlock::difference`].
+    ///
+    /// Nothing checks that the blocks are normalized alike, see [`DoseBlock::magnitude_gap`].
+    pub fn sum_doses(blocks: &[DoseBlock]) -> Result<DoseBlock, DoseError> {
+        let first = match blocks.first() {
+            Some(first) => first,
+            None => return Err(DoseError::LengthMismatch { field: "blocks", expected: 1, found: 0 }),
+        };
+        let mut sum = DoseSum::new(first, 1.0);
+        for block in &blocks[1..] {
+            sum.add(block, 1.0)?;
+        }
+        Ok(sum.finish())
+    }
+
+    /// Accumulate the partial dose blocks of one simulation split across several jobs into
+    /// the dose of the whole run, the same as [`DoseBlock::sum_doses`].
+    ///
+    /// The grids must match, fractional uncertainties are combined in quadrature as absolute
+    /// variances, and the result holds fractions of the summed dose again.
+    pub fn accumulate(blocks: &[DoseBlock]) -> Result<DoseBlock, DoseError> {
+        DoseBlock::sum_doses(blocks)
+    }
+
+    /// Sum `(weight, block)` pairs on the same grid, scaling each block's doses by its weight,
+    /// e.g. the beams of a treatment plan with their beam weights.
+    ///
+    /// Uncertainties are combined as in [`DoseBlock::sum_doses`], with each block's absolute
+    /// variances scaled by its weight squared. Weights must be finite, otherwise this fails
+    /// with [`DoseError::InvalidParameter`].
+    pub fn sum_weighted(blocks: &[(f64, DoseBlock)]) -> Result<DoseBlock, DoseError> {
+        if let Some(&(weight, _)) = blocks.iter().find(|(weight, _)| !weight.is_finite()) {
+            return Err(DoseError::InvalidParameter { name: "weight", value: weight });
+        }
+        let ((weight, first), rest) = match blocks.split_first() {
+            Some(split) => split,
+            None => {
+                return Err(DoseError::LengthMismatch { field: "blocks", expected: 1, found: 0 });
+            }
+        };
+        let mut sum = DoseSum::new(first, *weight);
+        for (weight, block) in rest {
+            sum.add(block, *weight)?;
+        }
+        Ok(sum.finish())
+    }
+
+    /// Sum the doses of several `3ddose` files on the same grid, reading one file at a time.
+    ///
+    /// The same as reading every file and calling [`DoseBlock::sum_doses`], but holding only
+    /// the running sum and one file in memory, for combining many runs.
+    pub fn sum_dose_files<P: AsRef<std::path::Path>>(
+        input_files: &[P],
+    ) -> Result<DoseBlock, DoseError> {
+        DoseBlock::sum_dose_files_buffered(input_files, DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Sum the doses of several `3ddose` files like [`DoseBlock::sum_dose_files`], reading each
+    /// through a buffer of `buffer_size` bytes.
+    pub fn sum_dose_files_buffered<P: AsRef<std::path::Path>>(
+        input_files: &[P],
+        buffer_size: usize,
+    ) -> Result<DoseBlock, DoseError> {
+        let (first, rest) = match input_files.split_first() {
+            Some(split) => split,
+            None => return Err(DoseError::LengthMismatch { field: "files", expected: 1, found: 0 }),
+        };
+        let mut sum = DoseSum::new(&DoseBlock::from_3d_dose_buffered(first, buffer_size)?, 1.0);
+        for input_file in rest {
+            sum.add(&DoseBlock::from_3d_dose_buffered(input_file, buffer_size)?, 1.0)?;
+        }
+        Ok(sum.finish())
+    }
+
+    /// Average the doses of several `3ddose` files on the same grid, e.g. runs of one
+    /// simulation each normalized per history, reading one file at a time.
+    ///
+    /// The same as [`DoseBlock::sum_dose_files`] with the doses divided by the number of files,
+    /// which leaves the fractional uncertainties of the sum unchanged.
+    pub fn mean_dose_files<P: AsRef<std::path::Path>>(
+        input_files: &[P],
+    ) -> Result<DoseBlock, DoseError> {
+        DoseBlock::mean_dose_files_buffered(input_files, DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Average the doses of several `3ddose` files like [`DoseBlock::mean_dose_files`], reading
+    /// each through a buffer of `buffer_size` bytes.
+    pub fn mean_dose_files_buffered<P: AsRef<std::path::Path>>(
+        input_files: &[P],
+        buffer_size: usize,
+    ) -> Result<DoseBlock, DoseError> {
+        let mut mean = DoseBlock::sum_dose_files_buffered(input_files, buffer_size)?;
+        let count = input_files.len() as f64;
+        mean.doses.iter_mut().for_each(|dose| *dose /= count);
+        Ok(mean)
+    }
+
+    /// Subtract `other` from this block voxel by voxel, if they share the same grid, e.g. to
+    /// see where changing a simulation parameter moved the dose.
+    ///
+    /// Uncertainties are combined as variances, as in [`DoseBlock::sum_doses`], so the
+    /// fractional uncertainty of the difference is `sqrt((ua * da)^2 + (ub * db)^2) / |da - db|`
+    /// and grows without bound as the doses cancel. Propagating the fractions in quadrature,
+    /// `sqrt(ua^2 + ub^2)`, would hide that: a 1 % uncertainty on each of two nearly equal
+    /// doses is a far larger fraction of their small difference. Voxels where the doses cancel
+    /// exactly get an infinite uncertainty unless both are certain, so a diff map never shows
+    /// agreement with no uncertainty where the result is least certain.
+    ///
+    /// Fails with [`DoseError::MismatchedGrid`] if the grids differ by more than
+    /// [`crate::GRID_TOLERANCE`].
+    pub fn difference(&self, other: &DoseBlock) -> Result<DoseBlock, DoseError> {
+        let mut diff = DoseSum::new(self, 1.0);
+        diff.add(other, -1.0)?;
+        Ok(diff.finish())
+    }
+
+    /// Orders of magnitude between the total doses of this block and `other`, if they're more
+    /// than [`MAGNITUDE_WARNING_DECADES`] apart, which usually means one is normalized per
+    /// history and the other isn't. `None` if either total is zero.
+    ///
+    /// Summing and subtracting don't check this themselves, so callers can decide whether to
+    /// warn, fail or carry on.
+    pub fn magnitude_gap(&self, other: &DoseBlock) -> Option<f64> {
+        magnitude_gap(self.total_dap(), other.total_dap())
+            .filter(|&gap| gap > MAGNITUDE_WARNING_DECADES)
+    }
+
+    /// Subtract `other` from this block, the same as [`DoseBlock::difference`].
+    pub fn try_sub(&self, other: &DoseBlock) -> Result<DoseBlock, DoseError> {
+        self.difference(other)
+    }
+
+    /// Absolute dose variance of each voxel in `[(Gy · cm2)²]`, zero for zero-dose voxels.
+    pub(crate) fn abs_variances(&self) -> Vec<f64> {
+        self.doses
+            .iter()
+            .zip(&self.uncerts)
+            .map(|(&dose, uncert)| if dose == 0.0 { 0.0 } else { (dose * uncert).powi(2) })
+            .collect()
+    }
+}
+
+/// Running sum of weighted dose blocks on one grid, combining uncertainties as
+/// [`DoseBlock::sum_doses`] does, for summing blocks read one at a time.
+///
+/// Absolute variances are held in `uncerts` until [`DoseSum::finish`].
+pub struct DoseSum {
+    sum: DoseBlock,
+    /// Total dose of the first block, unweighted, which added blocks are compared to.
+    first_dap: f64,
+}
+
+impl DoseSum {
+    /// Start a sum with `first` scaled by `weight`.
+    pub fn new(first: &DoseBlock, weight: f64) -> Self {
+        let mut sum = first.clone();
+        sum.doses.iter_mut().for_each(|dose| *dose *= weight);
+        let variances = first.abs_variances();
+        sum.uncerts = variances.iter().map(|variance| weight * weight * variance).collect();
+        DoseSum { sum, first_dap: first.total_dap() }
+    }
+
+    /// Orders of magnitude between the total dose of `block` and that of the first block, as
+    /// [`DoseBlock::magnitude_gap`] gives them, to check before adding it.
+    pub fn magnitude_gap(&self, block: &DoseBlock) -> Option<f64> {
+        magnitude_gap(self.first_dap, block.total_dap())
+            .filter(|&gap| gap > MAGNITUDE_WARNING_DECADES)
+    }
+
+    /// Add `block` scaled by `weight`, failing with [`DoseError::MismatchedGrid`] if it isn't
+    /// on the grid of the first block.
+    pub fn add(&mut self, block: &DoseBlock, weight: f64) -> Result<(), DoseError> {
+        self.sum.check_same_grid(block)?;
+        for (index, variance) in block.abs_variances().into_iter().enumerate() {
+            self.sum.doses[index] += weight * block.doses[index];
+            self.sum.uncerts[index] += weight * weight * variance;
+        }
+        Ok(())
+    }
+
+    /// The summed block, with variances converted back to fractions of the summed dose.
+    ///
+    /// A zero dose is an infinite fraction of any nonzero variance, and zero uncertainty when
+    /// the variance is zero too.
+    pub fn finish(mut self) -> DoseBlock {
+        for (dose, uncert) in self.sum.doses.iter().zip(self.sum.uncerts.iter_mut()) {
+            *uncert = match (*dose == 0.0, *uncert == 0.0) {
+                (_, true) => 0.0,
+                (true, false) => f64::INFINITY,
+                (false, false) => uncert.sqrt() / dose.abs(),
+            };
+        }
+        self.sum
+    }
+}
+
+/// A `3ddose` file and the weight its dose is added with, parsed from `<file>:<weight>` on the
+/// command line.
+///
+/// The weight follows the last colon, so file names can contain colons.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WeightedFile {
+    pub path: std::path::PathBuf,
+    pub weight: f64,
+}
+
+impl std::str::FromStr for WeightedFile {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            format!("Could not parse {}, expected a file and weight like beam.3ddose:0.8", value)
+        };
+        let (path, weight) = value.rsplit_once(':').ok_or_else(err)?;
+        let weight: f64 = weight.trim().parse().map_err(|_| err())?;
+        if path.is_empty() || !weight.is_finite() {
+            return Err(err());
+        }
+        Ok(WeightedFile { path: path.into(), weight })
+    }
+}
+
+/// Number of decades between two total doses, if they're both nonzero.
+fn magnitude_gap(dap_a: f64, dap_b: f64) -> Option<f64> {
+    let (dap_a, dap_b) = (dap_a.abs(), dap_b.abs());
+    if dap_a == 0.0 || dap_b == 0.0 {
+        return None;
+    }
+    Some((dap_a.log10() - dap_b.log10()).abs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(doses: Vec<f64>, uncerts: Vec<f64>) -> DoseBlock {
+        DoseBlock {
+            xs: vec![0.0, 1.0, 2.0],
+            ys: vec![0.0, 1.0],
+            zs: vec![0.0, 1.0],
+            doses,
+            uncerts,
+            variant: None,
+        }
+    }
+
+    #[test]
+    fn sum_doses() {
+        let a = block(vec![3.0, 0.0], vec![0.1, 0.0]);
+        let b = block(vec![4.0, 0.0], vec![0.1, 0.5]);
+        let sum = DoseBlock::sum_doses(&[a.clone(), b]).unwrap();
+        assert_eq!(sum.doses, vec![7.0, 0.0]);
+        // sqrt(0.3² + 0.4²) / 7
+        approx::assert_relative_eq!(sum.uncerts[0], 0.5 / 7.0);
+        assert_eq!(sum.uncerts[1], 0.0);
+
+        // undefined uncertainties of zero-dose voxels don't leak into the sum
+        let undefined = block(vec![0.0, 2.0], vec![f64::NAN, 0.25]);
+        let sum = DoseBlock::sum_doses(&[a.clone(), undefined]).unwrap();
+        assert_eq!(sum.doses, vec![3.0, 2.0]);
+        approx::assert_relative_eq!(sum.uncerts[0], 0.1);
+        approx::assert_relative_eq!(sum.uncerts[1], 0.25);
+
+        let mut moved = a.clone();
+        moved.xs[2] = 3.0;
+        assert!(matches!(DoseBlock::sum_doses(&[a, moved]), Err(DoseError::MismatchedGrid { .. })));
+        assert!(DoseBlock::sum_doses(&[]).is_err());
+    }
+
+    #[test]
+    fn accumulate() {
+        let a = block(vec![3.0, 2.0], vec![0.1, 0.5]);
+        let b = block(vec![1.0, 2.0], vec![0.3, 0.5]);
+        let c = block(vec![4.0, 0.0], vec![0.05, 1.0]);
+        let total = DoseBlock::accumulate(&[a.clone(), b.clone(), c.clone()]).unwrap();
+        assert_eq!(total.doses, vec![8.0, 4.0]);
+        // sqrt(0.3² + 0.3² + 0.2²) / 8 and sqrt(1² + 1²) / 4
+        approx::assert_relative_eq!(total.uncerts[0], 0.22_f64.sqrt() / 8.0);
+        approx::assert_relative_eq!(total.uncerts[1], 2.0_f64.sqrt() / 4.0);
+        assert_eq!(total, DoseBlock::sum_doses(&[a, b, c]).unwrap());
+        assert!(DoseBlock::accumulate(&[]).is_err());
+    }
+
+    #[test]
+    fn sum_dose_files() {
+        let mut input = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        input.push("props");
+        input.push("asym_2x3x4.3ddose");
+        let single = DoseBlock::from_3d_dose(&input).unwrap();
+
+        let sum = DoseBlock::sum_dose_files(&[&input, &input, &input]).unwrap();
+        let in_memory = DoseBlock::sum_doses(&[single.clone(), single.clone(), single.clone()]);
+        assert_eq!(sum, in_memory.unwrap());
+        // three identical independent runs: 3x the dose, 1/sqrt(3) the relative uncertainty
+        approx::assert_relative_eq!(sum.doses[5], 3.0 * single.doses[5]);
+        approx::assert_relative_eq!(sum.uncerts[5], single.uncerts[5] / 3.0_f64.sqrt());
+        assert!(DoseBlock::sum_dose_files::<&str>(&[]).is_err());
+
+        let mean = DoseBlock::mean_dose_files(&[&input, &input, &input]).unwrap();
+        approx::assert_relative_eq!(mean.doses[5], single.doses[5]);
+        assert_eq!(mean.uncerts, sum.uncerts);
+        assert!(DoseBlock::mean_dose_files::<&str>(&[]).is_err());
+
+        // a buffer smaller than a line reads the same values
+        let inputs = [&input, &input, &input];
+        assert_eq!(DoseBlock::sum_dose_files_buffered(&inputs, 16).unwrap(), sum);
+        assert_eq!(DoseBlock::mean_dose_files_buffered(&inputs, 16).unwrap(), mean);
+    }
+
+    #[test]
+    fn sum_weighted() {
+        let a = block(vec![3.0, 0.0], vec![0.1, 0.0]);
+        let b = block(vec![4.0, 1.0], vec![0.1, 0.5]);
+        let sum = DoseBlock::sum_weighted(&[(2.0, a.clone()), (0.5, b.clone())]).unwrap();
+        assert_eq!(sum.doses, vec![8.0, 0.5]);
+        // sqrt((2 * 0.3)² + (0.5 * 0.4)²) / 8
+        approx::assert_relative_eq!(sum.uncerts[0], 0.4_f64.sqrt() / 8.0);
+        approx::assert_relative_eq!(sum.uncerts[1], 0.5);
+
+        // unit weights are a plain sum
+        let plain = DoseBlock::sum_weighted(&[(1.0, a.clone()), (1.0, b.clone())]).unwrap();
+        assert_eq!(plain, DoseBlock::sum_doses(&[a.clone(), b.clone()]).unwrap());
+
+        assert!(matches!(
+            DoseBlock::sum_weighted(&[(1.0, a.clone()), (f64::NAN, b)]),
+            Err(DoseError::InvalidParameter { name: "weight", .. })
+        ));
+        let mut moved = a.clone();
+        moved.xs[2] = 3.0;
+        assert!(DoseBlock::sum_weighted(&[(1.0, a), (1.0, moved)]).is_err());
+        assert!(DoseBlock::sum_weighted(&[]).is_err());
+
+        let added: WeightedFile = "C:\\beams\\b:1.5:0.8".parse().unwrap();
+        assert_eq!(added, WeightedFile { path: "C:\\beams\\b:1.5".into(), weight: 0.8 });
+        assert!("beam.3ddose".parse::<WeightedFile>().is_err());
+        assert!("beam.3ddose:x".parse::<WeightedFile>().is_err());
+    }
+
+    #[test]
+    fn difference() {
+        let a = block(vec![3.0, 1.0], vec![0.3, 0.0]);
+        let b = block(vec![1.0, 1.0], vec![0.4, 0.1]);
+        let diff = a.difference(&b).unwrap();
+        assert_eq!(diff.doses, vec![2.0, 0.0]);
+        // sqrt((0.3 * 3)² + (0.4 * 1)²) / 2, not the fractions in quadrature
+        approx::assert_relative_eq!(diff.uncerts[0], 0.97_f64.sqrt() / 2.0);
+        // cancelled, but one of the doses is uncertain
+        assert_eq!(diff.uncerts[1], f64::INFINITY);
+        // cancelled and certain
+        let certain = block(vec![1.0, 1.0], vec![0.4, 0.0]);
+        assert_eq!(a.difference(&certain).unwrap().uncerts[1], 0.0);
+        assert_eq!(a.try_sub(&b).unwrap(), diff);
+        // the same absolute uncertainty either way round
+        let reversed = b.difference(&a).unwrap();
+        assert_eq!(reversed.doses, vec![-2.0, 0.0]);
+        approx::assert_relative_eq!(reversed.uncerts[0], diff.uncerts[0]);
+
+        // single-precision rounding of the nodes is the same grid, a shifted node isn't
+        let mut c = b.clone();
+        c.xs[1] += 1.0e-7;
+        assert_eq!(a.difference(&c).unwrap().doses, diff.doses);
+        c.xs[1] = 1.5;
+        assert!(matches!(a.difference(&c), Err(DoseError::MismatchedGrid { .. })));
+    }
+
+    #[test]
+    fn magnitude_gap() {
+        let per_history = block(vec![1.0e-12, 3.0e-12], vec![0.1, 0.1]);
+        let absolute = block(vec![1.0e-3, 3.0e-3], vec![0.1, 0.1]);
+        let gap = super::magnitude_gap(per_history.total_dap(), absolute.total_dap());
+        approx::assert_relative_eq!(gap.unwrap(), 9.0);
+        assert!(super::magnitude_gap(per_history.total_dap(), 0.0).is_none());
+        assert_eq!(absolute.total_dap(), 4.0e-3);
+
+        approx::assert_relative_eq!(per_history.magnitude_gap(&absolute).unwrap(), 9.0);
+        let close = block(vec![1.0e-1, 3.0e-1], vec![0.1, 0.1]);
+        assert_eq!(absolute.magnitude_gap(&close), None);
+        let sum = DoseSum::new(&absolute, 1.0);
+        assert_eq!(sum.magnitude_gap(&close), None);
+        approx::assert_relative_eq!(sum.magnitude_gap(&per_history).unwrap(), 9.0);
+    }
+}
@@ -0,0 +1,77 @@
+//! Gzip compression of text output.
+
+use std::io::Write;
+use std::path::Path;
+
+/// Compression applied to msh, csv and VTK output, which shrink many times over.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Compression {
+    /// gzip, which Gmsh and ParaView can often read directly.
+    Gzip,
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+    fn from_str(compression: &str) -> Result<Self, Self::Err> {
+        match compression {
+            "gzip" => Ok(Compression::Gzip),
+            _ => Err(format!("Unknown compression {}, expected gzip", compression)),
+        }
+    }
+}
+
+impl Compression {
+    /// The compression an output file name asks for by its extension, e.g. `out.msh.gz`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Compression> {
+        match path.as_ref().extension() {
+            Some(ext) if ext == "gz" => Some(Compression::Gzip),
+            _ => None,
+        }
+    }
+
+    /// Wrap `inner` so everything written is compressed on its way through, e.g. to pass to
+    /// [`DoseBlock::write_msh2_fields_to`]. Call `finish` on the result to write the end of
+    /// the stream and get `inner` back.
+    ///
+    /// [`DoseBlock::write_msh2_fields_to`]: crate::DoseBlock::write_msh2_fields_to
+    pub fn writer<W: Write>(self, inner: W) -> flate2::write::GzEncoder<W> {
+        match self {
+            Compression::Gzip => flate2::write::GzEncoder::new(inner, flate2::Compression::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DoseBlock;
+    use std::io::Read;
+
+    #[test]
+    fn gzip_round_trip() {
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 2.0],
+            ys: vec![0.0, 1.0],
+            zs: vec![0.0, 1.0],
+            doses: vec![1.0, 2.0],
+            uncerts: vec![0.1, 0.2],
+            variant: None,
+        };
+        let mut expected = Vec::new();
+        data.write_msh2_to(&mut expected).unwrap();
+        let mut encoder = Compression::Gzip.writer(Vec::new());
+        data.write_msh2_to(&mut encoder).unwrap();
+        let compressed = encoder.finish().unwrap();
+        // gzip magic number
+        assert_eq!(compressed[..2], [0x1f, 0x8b]);
+
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(&compressed[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, expected);
+
+        assert_eq!(Compression::from_path("out.msh.gz"), Some(Compression::Gzip));
+        assert_eq!(Compression::from_path("out.msh"), None);
+        assert_eq!("gzip".parse(), Ok(Compression::Gzip));
+        assert!("zstd".parse::<Compression>().is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! Command line options read from a TOML file with `--config`.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+
+use structopt::clap::AppSettings;
+use structopt::StructOpt;
+
+use crate::{Cli, DoseError};
+
+/// Command line options from a TOML config file, keyed by their long names.
+///
+/// Keys are long option names without the dashes, in kebab or snake case, e.g.
+/// `format = "vtk"`, `mu = 200`, `trim_air = true` or `scale-coords = [10, 10, 10]`. Strings
+/// and numbers are the option's value, `true` sets a flag and arrays are joined with commas.
+///
+/// Options given on the command line take precedence over the file, which takes precedence
+/// over the defaults.
+#[derive(Debug, Clone, PartialEq, Default, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Config {
+    pub options: BTreeMap<String, toml::Value>,
+}
+
+impl Config {
+    /// Read a config from a TOML file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, DoseError> {
+        let text = std::fs::read_to_string(&path)?;
+        text.parse().map_err(|err| invalid(&path, err))
+    }
+
+    /// Command line arguments for the options in this config, skipping those `given`.
+    fn args(&self, given: impl Fn(&str) -> bool) -> Result<Vec<String>, String> {
+        let mut args = Vec::new();
+        for (key, value) in &self.options {
+            let name = key.replace('_', "-");
+            if name == "config" || given(&name) {
+                continue;
+            }
+            match value {
+                toml::Value::Boolean(true) => args.push(format!("--{}", name)),
+                toml::Value::Boolean(false) => {}
+                toml::Value::Array(values) => {
+                    let values: Result<Vec<_>, _> = values.iter().map(|v| scalar(key, v)).collect();
+                    args.push(format!("--{}={}", name, values?.join(",")));
+                }
+                value => args.push(format!("--{}={}", name, scalar(key, value)?)),
+            }
+        }
+        Ok(args)
+    }
+
+    /// Parse the command line `args`, starting with the program name, taking options missing
+    /// from them from this config.
+    ///
+    /// Exits with a usage message, as [`StructOpt::from_iter`] does, if `args` themselves are
+    /// invalid. Invalid config options fail with [`DoseError::Io`] naming the `path` they came
+    /// from.
+    pub fn apply<I, P>(&self, args: I, path: P) -> Result<Cli, DoseError>
+    where
+        I: IntoIterator,
+        I::Item: Into<OsString> + Clone,
+        P: AsRef<Path>,
+    {
+        let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        let matches = Cli::clap().get_matches_from(&args);
+        let options = self.args(|name| matches.occurrences_of(name) > 0);
+        let options = options.map_err(|err| invalid(&path, err))?;
+
+        // the config's options go first, so those after them keep their meaning
+        let mut merged = args.clone();
+        let split = merged.len().min(1);
+        merged.splice(split..split, options.into_iter().map(OsString::from));
+        let matches = Cli::clap().setting(AppSettings::ColorNever).get_matches_from_safe(merged);
+        match matches {
+            Ok(matches) => Ok(Cli::from_clap(&matches)),
+            // only the first line, without the usage of the command line
+            Err(err) => {
+                let message = err.message.lines().next().unwrap_or_default();
+                Err(invalid(&path, message.trim_start_matches("error: ")))
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = toml::de::Error;
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        toml::from_str(text)
+    }
+}
+
+/// A config option's value as it would be written on the command line.
+fn scalar(key: &str, value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        _ => Err(format!("unsupported value for {}: {}", key, value)),
+    }
+}
+
+/// An error for an invalid config file at `path`.
+fn invalid<P: AsRef<Path>>(path: P, err: impl std::fmt::Display) -> DoseError {
+    let message = format!("{}: {}", path.as_ref().display(), err);
+    DoseError::Io(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Fmt;
+
+    #[test]
+    fn apply() {
+        let config: Config = r#"
+            format = "csv"
+            mu = 200
+            cal_factor = 0.5
+            trim-air = true
+            flip-x = false
+            scale-coords = [10, 10, 10]
+            fields = ["dose", "gradient"]
+        "#
+        .parse()
+        .unwrap();
+
+        let args = config.apply(&["dose2gmsh", "in.3ddose"], "run.toml").unwrap();
+        assert!(matches!(args.format, Some(Fmt::Csv)));
+        assert_eq!((args.mu, args.cal_factor), (Some(200.0), Some(0.5)));
+        assert!(args.trim_air && !args.flip_x);
+        assert_eq!(args.scale_coords, Some([10.0, 10.0, 10.0]));
+        assert_eq!(args.select_fields.len(), 2);
+
+        // the command line wins, short or long
+        let args = config.apply(&["dose2gmsh", "in.3ddose", "-f", "vtk", "--mu=50"], "run.toml");
+        let args = args.unwrap();
+        assert!(matches!(args.format, Some(Fmt::Vtk)));
+        assert_eq!((args.mu, args.cal_factor), (Some(50.0), Some(0.5)));
+        assert_eq!(args.input_file, Some("in.3ddose".into()));
+
+        let typo: Config = "formt = \"csv\"".parse().unwrap();
+        let err = typo.apply(&["dose2gmsh", "in.3ddose"], "run.toml").unwrap_err();
+        assert!(matches!(&err, DoseError::Io(err) if err.to_string().starts_with("run.toml: Found")));
+        let table: Config = "[format]\nname = \"csv\"".parse().unwrap();
+        assert!(table.apply(&["dose2gmsh"], "run.toml").is_err());
+        assert!("format = ".parse::<Config>().is_err());
+    }
+}
@@ -0,0 +1,110 @@
+//! Consistency checks on the element connectivity written to msh files.
+
+use std::collections::HashMap;
+
+use crate::DoseBlock;
+
+/// Corners of each face of a linear hexahedron, in gmsh node order.
+const HEX_FACES: [[usize; 4]; 6] =
+    [[0, 1, 2, 3], [4, 5, 6, 7], [0, 1, 5, 4], [3, 2, 6, 7], [0, 3, 7, 4], [1, 2, 6, 5]];
+
+impl DoseBlock {
+    /// Check the linear hexahedra written to msh files form a conforming mesh, describing the
+    /// first problem found otherwise.
+    ///
+    /// Every element must have eight distinct nodes with valid ids, every face inside the
+    /// grid must be shared by exactly two elements and every face on its boundary must belong
+    /// to one. Duplicated or unshared nodes show up as faces with the wrong count. This
+    /// is a check on the node numbering, run on every msh write in debug builds.
+    pub fn verify_connectivity(&self) -> Result<(), String> {
+        let dims = [self.num_x(), self.num_y(), self.num_z()];
+        check_hexahedra(self.hex_elements(), dims, self.num_nodes())
+    }
+}
+
+/// Check `elements` form a conforming mesh of `dims` voxels with `num_nodes` nodes.
+fn check_hexahedra<I>(elements: I, dims: [usize; 3], num_nodes: usize) -> Result<(), String>
+where
+    I: Iterator<Item = [usize; 8]>,
+{
+    let [nx, ny, nz] = dims;
+    let mut faces: HashMap<[usize; 4], usize> = HashMap::with_capacity(3 * nx * ny * nz);
+    for (index, nodes) in elements.enumerate() {
+        let mut sorted = nodes;
+        sorted.sort_unstable();
+        if sorted.windows(2).any(|pair| pair[0] == pair[1]) {
+            return Err(format!("element {} repeats a node: {:?}", index + 1, nodes));
+        }
+        if let Some(&node) = nodes.iter().find(|&&node| node == 0 || node > num_nodes) {
+            return Err(format!("element {} has node {} outside 1 to {}", index + 1, node, num_nodes));
+        }
+        for corners in &HEX_FACES {
+            let mut face = [0; 4];
+            for (node, &corner) in face.iter_mut().zip(corners) {
+                *node = nodes[corner];
+            }
+            face.sort_unstable();
+            *faces.entry(face).or_insert(0) += 1;
+        }
+    }
+
+    if let Some((face, count)) = faces.iter().find(|&(_, &count)| count > 2) {
+        return Err(format!("face {:?} is shared by {} elements", face, count));
+    }
+    let boundary = faces.values().filter(|&&count| count == 1).count();
+    let expected = if nx * ny * nz == 0 { 0 } else { 2 * (nx * ny + ny * nz + nx * nz) };
+    if boundary != expected {
+        return Err(format!("{} boundary faces, expected {}", boundary, expected));
+    }
+    let internal = faces.len() - boundary;
+    let inner = |n: usize| n.saturating_sub(1);
+    let expected = inner(nx) * ny * nz + nx * inner(ny) * nz + nx * ny * inner(nz);
+    if internal != expected {
+        return Err(format!("{} internal faces, expected {}", internal, expected));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_connectivity() {
+        for &(nx, ny, nz) in &[(1, 1, 1), (2, 3, 4), (5, 1, 2), (1, 4, 1)] {
+            let num_voxels = nx * ny * nz;
+            let nodes = |n: usize| (0..=n).map(|i| i as f64).collect();
+            let data = DoseBlock {
+                xs: nodes(nx),
+                ys: nodes(ny),
+                zs: nodes(nz),
+                doses: vec![0.0; num_voxels],
+                uncerts: vec![0.0; num_voxels],
+                variant: None,
+            };
+            assert_eq!(data.verify_connectivity(), Ok(()), "{} x {} x {}", nx, ny, nz);
+            let elements: Vec<_> = data.hex_elements().collect();
+            assert_eq!(elements.len(), num_voxels);
+            // corners 4-7 are the corners 0-3 one x-y layer of nodes up
+            let layer = (nx + 1) * (ny + 1);
+            let bottom = [1, 2, nx + 3, nx + 2];
+            assert_eq!(elements[0][..4], bottom);
+            let top: Vec<usize> = bottom.iter().map(|node| node + layer).collect();
+            assert_eq!(elements[0][4..], top[..]);
+        }
+
+        // 2 x 1 x 1 voxels with 12 nodes
+        let check = |elements: Vec<[usize; 8]>, num_nodes| {
+            check_hexahedra(elements.into_iter(), [2, 1, 1], num_nodes)
+        };
+        let left = [1, 2, 5, 4, 7, 8, 11, 10];
+        let right = [2, 3, 6, 5, 8, 9, 12, 11];
+        assert_eq!(check(vec![left, right], 12), Ok(()));
+        assert_eq!(check(vec![left, left], 12), Err("0 boundary faces, expected 10".to_string()));
+        // the second element on nodes of its own, not sharing a face with the first
+        let apart = [13, 3, 6, 14, 15, 9, 12, 16];
+        assert_eq!(check(vec![left, apart], 16), Err("12 boundary faces, expected 10".to_string()));
+        assert!(check(vec![left, [2, 2, 6, 5, 8, 9, 12, 11]], 12).unwrap_err().contains("repeats"));
+        assert!(check(vec![left, right], 11).unwrap_err().contains("outside 1 to 11"));
+    }
+}
@@ -0,0 +1,142 @@
+//! Cutting a dose block down to a sub-grid of its voxels.
+
+use std::ops::Range;
+
+use crate::{centroids, DoseBlock, DoseError};
+
+impl DoseBlock {
+    /// The voxels with indices in `ranges` along *x*, *y* and *z*, keeping their nodes, doses
+    /// and uncertainties.
+    ///
+    /// Panics if a range is empty or goes past the last voxel.
+    pub(crate) fn crop_voxels(&self, ranges: [Range<usize>; 3]) -> DoseBlock {
+        let sizes = [self.num_x(), self.num_y(), self.num_z()];
+        for (range, &size) in ranges.iter().zip(&sizes) {
+            assert!(range.start < range.end && range.end <= size, "bad voxel range {:?}", range);
+        }
+        let [xr, yr, zr] = ranges;
+        let mut cropped = DoseBlock {
+            xs: self.xs[xr.start..=xr.end].to_vec(),
+            ys: self.ys[yr.start..=yr.end].to_vec(),
+            zs: self.zs[zr.start..=zr.end].to_vec(),
+            doses: Vec::with_capacity(xr.len() * yr.len() * zr.len()),
+            uncerts: Vec::with_capacity(xr.len() * yr.len() * zr.len()),
+            variant: None,
+        };
+        for k in zr {
+            for j in yr.clone() {
+                let row = self.voxel_index(xr.start, j, k)..=self.voxel_index(xr.end - 1, j, k);
+                cropped.doses.extend_from_slice(&self.doses[row.clone()]);
+                cropped.uncerts.extend_from_slice(&self.uncerts[row]);
+            }
+        }
+        cropped
+    }
+
+    /// Crop to the smallest box of voxels holding every voxel with a dose above `dose_frac` of
+    /// the maximum dose, e.g. `0.01` to drop the near-zero air around a phantom.
+    ///
+    /// Voxels inside the box keep their dose even if it is below the threshold. If no voxel is
+    /// above the threshold, as for an all-zero block or `dose_frac >= 1`, there is nothing to
+    /// crop to and the block is returned unchanged.
+    pub fn auto_crop(&self, dose_frac: f64) -> DoseBlock {
+        let max_dose = self.doses.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let threshold = dose_frac * max_dose;
+
+        // inclusive first and last voxel index above the threshold along each axis
+        let mut bounds: Option<[(usize, usize); 3]> = None;
+        for (index, _) in self.doses.iter().enumerate().filter(|&(_, &dose)| dose > threshold) {
+            let (i, j, k) = self.voxel_ijk(index);
+            let ijk = [i, j, k];
+            let bounds = bounds.get_or_insert([(i, i), (j, j), (k, k)]);
+            for (bound, &n) in bounds.iter_mut().zip(&ijk) {
+                *bound = (bound.0.min(n), bound.1.max(n));
+            }
+        }
+        match bounds {
+            Some([x, y, z]) => self.crop_voxels([x.0..x.1 + 1, y.0..y.1 + 1, z.0..z.1 + 1]),
+            None => self.clone(),
+        }
+    }
+
+    /// Crop to the voxels whose centroids lie inside the box spanning `x`, `y` and `z` `[cm]`,
+    /// each a `(min, max)` range including its ends.
+    ///
+    /// A box holding no voxel centroid along some axis would leave an empty grid, and fails
+    /// with [`DoseError::EmptyGrid`] instead.
+    pub fn crop(
+        &self,
+        x: (f64, f64),
+        y: (f64, f64),
+        z: (f64, f64),
+    ) -> Result<DoseBlock, DoseError> {
+        // centroids are monotonic along each axis, so those inside a range are contiguous
+        let inside = |pts: &[f64], (lo, hi): (f64, f64)| {
+            let cs = centroids(pts);
+            let first = cs.iter().position(|&c| lo <= c && c <= hi)?;
+            let last = cs.iter().rposition(|&c| lo <= c && c <= hi)?;
+            Some(first..last + 1)
+        };
+        match (inside(&self.xs, x), inside(&self.ys, y), inside(&self.zs, z)) {
+            (Some(xr), Some(yr), Some(zr)) => Ok(self.crop_voxels([xr, yr, zr])),
+            _ => Err(DoseError::EmptyGrid),
+        }
+    }
+
+    /// Total volume of the grid in `[cm3]`.
+    pub fn volume(&self) -> f64 {
+        self.bounds().iter().map(|(lo, hi)| (hi - lo).abs()).product()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_crop() {
+        // 4 x 3 x 2 voxels with dose in voxels (1, 1, 0) and (2, 1, 1) only
+        let num_voxels = 24;
+        let mut doses = vec![0.0; num_voxels];
+        doses[1 + 4] = 100.0;
+        doses[2 + 4 + 12] = 50.0;
+        // below 1 % of the maximum
+        doses[3 + 8 + 12] = 0.5;
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 2.0, 3.0, 4.0],
+            ys: vec![0.0, 1.0, 2.0, 3.0],
+            zs: vec![0.0, 2.0, 4.0],
+            doses,
+            uncerts: (0..num_voxels).map(|i| i as f64 / 100.0).collect(),
+            variant: None,
+        };
+        assert_eq!(data.volume(), 48.0);
+
+        let cropped = data.auto_crop(0.01);
+        assert_eq!(cropped.xs, vec![1.0, 2.0, 3.0]);
+        assert_eq!(cropped.ys, vec![1.0, 2.0]);
+        assert_eq!(cropped.zs, data.zs);
+        assert_eq!(cropped.doses, vec![100.0, 0.0, 0.0, 50.0]);
+        assert_eq!(cropped.uncerts, vec![0.05, 0.06, 0.17, 0.18]);
+        assert_eq!(cropped.volume(), 8.0);
+
+        // the low voxel is kept with a lower threshold
+        assert_eq!(data.auto_crop(0.001).xs, vec![1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(data.auto_crop(0.0).num_voxels(), 2 * 3 * 2);
+
+        let air = DoseBlock { doses: vec![0.0; num_voxels], ..data.clone() };
+        assert_eq!(air.auto_crop(0.01), air);
+        assert_eq!(data.auto_crop(1.0), data);
+
+        // centroids at 0.5, 1.5, ... along x and y and 1, 3 along z
+        let cropped = data.crop((1.5, 3.0), (0.0, 1.0), (-10.0, 10.0)).unwrap();
+        assert_eq!((cropped.xs, cropped.ys), (vec![1.0, 2.0, 3.0], vec![0.0, 1.0]));
+        assert_eq!(cropped.zs, data.zs);
+        assert_eq!(cropped.doses, vec![0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(cropped.uncerts, vec![0.01, 0.02, 0.13, 0.14]);
+        assert_eq!(data.crop((-1.0, 5.0), (-1.0, 5.0), (-1.0, 5.0)).unwrap(), data);
+        // between centroids, and reversed
+        assert!(matches!(data.crop((0.6, 1.4), (0.0, 3.0), (0.0, 4.0)), Err(DoseError::EmptyGrid)));
+        assert!(matches!(data.crop((3.0, 0.0), (0.0, 3.0), (0.0, 4.0)), Err(DoseError::EmptyGrid)));
+    }
+}
@@ -0,0 +1,281 @@
+//! DICOM RT Dose output, for overlaying dose on images in a treatment planning system.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::stats::{uniform_along, SPACING_TOLERANCE};
+use crate::{centroids, DoseBlock, DoseError};
+
+/// RT Dose Storage, the SOP class of the objects written.
+const RT_DOSE_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.481.2";
+/// Explicit VR little endian, the transfer syntax of the data set.
+const EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1";
+/// Identifies dose2gmsh as the writer, a UUID-derived UID under the `2.25` root.
+const IMPLEMENTATION_CLASS_UID: &str = "2.25.163418549238541785216432787398113097012";
+
+impl DoseBlock {
+    /// Write the dose as a DICOM RT Dose file, e.g. `run.dcm`, for a treatment planning system.
+    ///
+    /// See [`DoseBlock::write_dicom_rtdose_to`]. The grid is checked before the file is created.
+    pub fn write_dicom_rtdose<P: AsRef<Path>>(&self, output: P) -> Result<(), DoseError> {
+        self.pixel_spacing()?;
+        let mut file = BufWriter::new(File::create(output)?);
+        self.write_dicom_rtdose_to(&mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Write the dose as a DICOM RT Dose object, in a Part 10 file, to any writer.
+    ///
+    /// The object holds the tags RT Dose requires, empty where they describe a patient, study
+    /// or plan this tool knows nothing about, and fresh UIDs under the `2.25` root. Each *z*
+    /// slice is a frame of `num_y` rows and `num_x` columns, positioned by
+    /// `GridFrameOffsetVector` relative to the first, so slices may be unevenly spaced. Lengths
+    /// are in `[mm]`, and `ImagePositionPatient` is the centre of the first voxel.
+    ///
+    /// Doses are stored as unsigned 32-bit integers that `DoseGridScaling` multiplies back to
+    /// `doses`, with negative and non-finite doses stored as 0. `DoseUnits` is `GY`, so
+    /// normalize the dose, e.g. with [`DoseBlock::normalize_per_mu`], first.
+    ///
+    /// DICOM needs the same pixel spacing throughout a frame, so grids unevenly spaced in *x*
+    /// or *y* fail with [`DoseError::NonUniformGrid`].
+    pub fn write_dicom_rtdose_to<W: Write>(&self, file: &mut W) -> Result<(), DoseError> {
+        let [dx, dy] = self.pixel_spacing()?;
+        let sop_instance = new_uid();
+
+        let mut meta = Vec::new();
+        element(&mut meta, (0x0002, 0x0001), b"OB", &[0, 1])?;
+        element(&mut meta, (0x0002, 0x0002), b"UI", RT_DOSE_STORAGE.as_bytes())?;
+        element(&mut meta, (0x0002, 0x0003), b"UI", sop_instance.as_bytes())?;
+        element(&mut meta, (0x0002, 0x0010), b"UI", EXPLICIT_VR_LITTLE_ENDIAN.as_bytes())?;
+        element(&mut meta, (0x0002, 0x0012), b"UI", IMPLEMENTATION_CLASS_UID.as_bytes())?;
+        element(&mut meta, (0x0002, 0x0013), b"SH", b"DOSE2GMSH")?;
+        // preamble, prefix, then the file meta information and its length
+        file.write_all(&[0; 128])?;
+        file.write_all(b"DICM")?;
+        element(file, (0x0002, 0x0000), b"UL", &(meta.len() as u32).to_le_bytes())?;
+        file.write_all(&meta)?;
+
+        // coordinates carry single precision noise, round to the nearest 1e-6 mm
+        let mm = |cm: f64| ds((cm * 1.0e7).round() / 1.0e6);
+        let (cxs, cys, czs) = (centroids(&self.xs), centroids(&self.ys), centroids(&self.zs));
+        let position = format!("{}\\{}\\{}", mm(cxs[0]), mm(cys[0]), mm(czs[0]));
+        let offsets: Vec<String> = czs.iter().map(|z| mm(z - czs[0])).collect();
+        let thickness = uniform_along(&self.zs, SPACING_TOLERANCE).map(mm).unwrap_or_default();
+        let finite = self.doses.iter().cloned().filter(|dose| dose.is_finite());
+        let max_dose = finite.fold(0.0, f64::max);
+        // headroom below u32::MAX for the rounding of the scaling to a decimal string
+        let scaling = if max_dose > 0.0 { ds(max_dose / 4.0e9) } else { "1".to_string() };
+        let us = |value: usize| (value as u16).to_le_bytes();
+
+        let text: &[((u16, u16), &[u8; 2], String)] = &[
+            ((0x0008, 0x0016), b"UI", RT_DOSE_STORAGE.to_string()),
+            ((0x0008, 0x0018), b"UI", sop_instance),
+            ((0x0008, 0x0020), b"DA", String::new()),
+            ((0x0008, 0x0030), b"TM", String::new()),
+            ((0x0008, 0x0050), b"SH", String::new()),
+            ((0x0008, 0x0060), b"CS", "RTDOSE".to_string()),
+            ((0x0008, 0x0070), b"LO", String::new()),
+            ((0x0008, 0x0090), b"PN", String::new()),
+            ((0x0010, 0x0010), b"PN", String::new()),
+            ((0x0010, 0x0020), b"LO", String::new()),
+            ((0x0010, 0x0030), b"DA", String::new()),
+            ((0x0010, 0x0040), b"CS", String::new()),
+            ((0x0018, 0x0050), b"DS", thickness),
+            ((0x0020, 0x000D), b"UI", new_uid()),
+            ((0x0020, 0x000E), b"UI", new_uid()),
+            ((0x0020, 0x0010), b"SH", String::new()),
+            ((0x0020, 0x0011), b"IS", String::new()),
+            ((0x0020, 0x0013), b"IS", "1".to_string()),
+            ((0x0020, 0x0032), b"DS", position),
+            ((0x0020, 0x0037), b"DS", "1\\0\\0\\0\\1\\0".to_string()),
+            ((0x0020, 0x0052), b"UI", new_uid()),
+            ((0x0020, 0x1040), b"LO", String::new()),
+        ];
+        for (tag, vr, value) in text {
+            element(file, *tag, vr, value.as_bytes())?;
+        }
+        element(file, (0x0028, 0x0002), b"US", &us(1))?;
+        element(file, (0x0028, 0x0004), b"CS", b"MONOCHROME2")?;
+        element(file, (0x0028, 0x0008), b"IS", self.num_z().to_string().as_bytes())?;
+        // frames are stepped through by GridFrameOffsetVector
+        element(file, (0x0028, 0x0009), b"AT", &[0x04, 0x30, 0x0C, 0x00])?;
+        element(file, (0x0028, 0x0010), b"US", &us(self.num_y()))?;
+        element(file, (0x0028, 0x0011), b"US", &us(self.num_x()))?;
+        // row spacing, then column spacing
+        element(file, (0x0028, 0x0030), b"DS", format!("{}\\{}", mm(dy), mm(dx)).as_bytes())?;
+        // 32-bit unsigned pixels
+        element(file, (0x0028, 0x0100), b"US", &us(32))?;
+        element(file, (0x0028, 0x0101), b"US", &us(32))?;
+        element(file, (0x0028, 0x0102), b"US", &us(31))?;
+        element(file, (0x0028, 0x0103), b"US", &us(0))?;
+        element(file, (0x3004, 0x0002), b"CS", b"GY")?;
+        element(file, (0x3004, 0x0004), b"CS", b"PHYSICAL")?;
+        element(file, (0x3004, 0x000A), b"CS", b"PLAN")?;
+        element(file, (0x3004, 0x000C), b"DS", offsets.join("\\").as_bytes())?;
+        element(file, (0x3004, 0x000E), b"DS", scaling.as_bytes())?;
+
+        // the pixels are in doses order: x along each row, rows up in y, frames up in z
+        let scaling: f64 = scaling.parse().expect("scaling is a number");
+        header(file, (0x7FE0, 0x0010), b"OW", 4 * self.num_voxels())?;
+        for dose in &self.doses {
+            // saturating, with NaN as 0
+            let pixel = (dose / scaling).round() as u32;
+            file.write_all(&pixel.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// The voxel size along *x* and *y*, or [`DoseError::NonUniformGrid`] if either axis is
+    /// unevenly spaced.
+    fn pixel_spacing(&self) -> Result<[f64; 2], DoseError> {
+        let spacing = |pts| uniform_along(pts, SPACING_TOLERANCE).ok_or(DoseError::NonUniformGrid);
+        Ok([spacing(&self.xs)?, spacing(&self.ys)?])
+    }
+}
+
+/// A new unique identifier, a random UUID as a decimal under the `2.25` root.
+fn new_uid() -> String {
+    format!("2.25.{}", rand::random::<u128>())
+}
+
+/// A decimal string, as the `DS` value representation holds, of at most 16 characters.
+fn ds(value: f64) -> String {
+    let shortest = [value.to_string(), format!("{:e}", value)];
+    if let Some(fits) = shortest.iter().find(|repr| repr.len() <= 16) {
+        return fits.clone();
+    }
+    (0..16)
+        .rev()
+        .map(|digits| format!("{:.*e}", digits, value))
+        .find(|exp| exp.len() <= 16)
+        .expect("a one digit mantissa fits")
+}
+
+/// Write the tag, value representation and length of an explicit VR little endian element.
+fn header<W: Write>(
+    file: &mut W,
+    (group, element): (u16, u16),
+    vr: &[u8; 2],
+    len: usize,
+) -> Result<(), std::io::Error> {
+    file.write_all(&group.to_le_bytes())?;
+    file.write_all(&element.to_le_bytes())?;
+    file.write_all(vr)?;
+    match vr {
+        // binary and sequence representations have a reserved word and a 4-byte length
+        b"OB" | b"OW" | b"OF" | b"SQ" | b"UT" | b"UN" => {
+            file.write_all(&[0, 0])?;
+            file.write_all(&(len as u32).to_le_bytes())
+        }
+        _ => file.write_all(&(len as u16).to_le_bytes()),
+    }
+}
+
+/// Write an explicit VR little endian element, padding `value` to an even length with a
+/// null for UIDs or a space for text.
+fn element<W: Write>(
+    file: &mut W,
+    tag: (u16, u16),
+    vr: &[u8; 2],
+    value: &[u8],
+) -> Result<(), std::io::Error> {
+    let padding: &[u8] = match (value.len() % 2, vr) {
+        (0, _) => &[],
+        (_, b"UI") | (_, b"OB") => &[0],
+        _ => b" ",
+    };
+    header(file, tag, vr, value.len() + padding.len())?;
+    file.write_all(value)?;
+    file.write_all(padding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::convert::TryInto;
+
+    /// The elements of a Part 10 file by tag, as value representation and value.
+    fn parse_dicom(bytes: &[u8]) -> HashMap<(u16, u16), (String, Vec<u8>)> {
+        assert_eq!(&bytes[128..132], b"DICM");
+        let mut rest = &bytes[132..];
+        let mut elements = HashMap::new();
+        while !rest.is_empty() {
+            let u16_at = |at: usize| u16::from_le_bytes(rest[at..at + 2].try_into().unwrap());
+            let tag = (u16_at(0), u16_at(2));
+            let vr = String::from_utf8(rest[4..6].to_vec()).unwrap();
+            let (len, start) = match vr.as_str() {
+                "OB" | "OW" => (u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize, 12),
+                _ => (u16_at(6) as usize, 8),
+            };
+            assert_eq!(len % 2, 0, "{:?}", tag);
+            elements.insert(tag, (vr, rest[start..start + len].to_vec()));
+            rest = &rest[start + len..];
+        }
+        elements
+    }
+
+    #[test]
+    fn write_dicom_rtdose() {
+        // 3 x 2 x 3 voxels of 2 x 5 mm, with uneven slices
+        let mut data = DoseBlock {
+            xs: vec![-0.3, -0.1, 0.1, 0.3],
+            ys: vec![1.0, 1.5, 2.0],
+            zs: vec![0.0, 0.2, 0.3, 0.7],
+            doses: (0..18).map(|i| 1.0e-3 * i as f64).collect(),
+            uncerts: vec![0.0; 18],
+            variant: None,
+        };
+        data.doses[4] = -1.0;
+        let mut out = Vec::new();
+        data.write_dicom_rtdose_to(&mut out).unwrap();
+        let elements = parse_dicom(&out);
+        let text = |group, element| {
+            let value = &elements[&(group, element)].1;
+            String::from_utf8(value.clone()).unwrap().trim_end_matches(&[' ', '\0'][..]).to_string()
+        };
+        let us = |group, element| {
+            u16::from_le_bytes(elements[&(group, element)].1[..].try_into().unwrap())
+        };
+
+        assert_eq!(text(0x0002, 0x0010), EXPLICIT_VR_LITTLE_ENDIAN);
+        assert_eq!(text(0x0008, 0x0016), RT_DOSE_STORAGE);
+        assert_eq!(text(0x0008, 0x0018), text(0x0002, 0x0003));
+        assert!(text(0x0020, 0x000D).starts_with("2.25.") && text(0x0020, 0x000D).len() <= 64);
+        assert_eq!(text(0x0008, 0x0060), "RTDOSE");
+        assert_eq!(text(0x0028, 0x0008), "3");
+        assert_eq!((us(0x0028, 0x0010), us(0x0028, 0x0011)), (2, 3));
+        assert_eq!(text(0x0028, 0x0030), "5\\2");
+        assert_eq!(text(0x0020, 0x0032), "-2\\12.5\\1");
+        assert_eq!(text(0x3004, 0x000C), "0\\1.5\\4");
+        assert_eq!(text(0x0018, 0x0050), "");
+        let meta_len = u32::from_le_bytes(elements[&(0x0002, 0x0000)].1[..].try_into().unwrap());
+        let meta: usize = elements
+            .iter()
+            .filter(|((group, element), _)| *group == 2 && *element != 0)
+            .map(|(_, (vr, value))| value.len() + if vr == "OB" { 12 } else { 8 })
+            .sum();
+        assert_eq!(meta_len as usize, meta);
+
+        let scaling: f64 = text(0x3004, 0x000E).parse().unwrap();
+        let pixels = &elements[&(0x7FE0, 0x0010)].1;
+        assert_eq!(pixels.len(), 4 * 18);
+        for (index, pixel) in pixels.chunks(4).enumerate() {
+            let dose = scaling * u32::from_le_bytes(pixel.try_into().unwrap()) as f64;
+            let expected = data.doses[index].max(0.0);
+            approx::assert_abs_diff_eq!(dose, expected, epsilon = 1.0e-9 * 0.017);
+        }
+
+        data.ys[1] = 1.4;
+        let err = data.write_dicom_rtdose_to(&mut Vec::new());
+        assert!(matches!(err, Err(DoseError::NonUniformGrid)));
+
+        // decimal strings fit in 16 characters
+        assert_eq!(ds(12.5), "12.5");
+        assert_eq!(ds(1.0e-20), "1e-20");
+        assert_eq!(ds(0.1 + 0.2), "3.00000000000e-1");
+        assert!(ds(-1.234_567_891_234_567e-300).len() <= 16);
+    }
+}
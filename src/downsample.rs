@@ -0,0 +1,182 @@
+//! Merging blocks of voxels into a coarser grid.
+
+use crate::{DoseBlock, DoseError};
+
+/// How the fine voxels merged into one coarse voxel are combined.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum DownsampleMode {
+    /// Sum of the fine doses.
+    Sum,
+    /// Volume-weighted mean of the fine doses, the dose of the coarse voxel as a whole.
+    #[default]
+    Mean,
+    /// Mean weighted by the inverse variance of each fine dose, the lowest-noise estimate if the
+    /// true dose is the same throughout the coarse voxel.
+    InverseVarianceMean,
+}
+
+impl std::str::FromStr for DownsampleMode {
+    type Err = String;
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "sum" => Ok(DownsampleMode::Sum),
+            "mean" => Ok(DownsampleMode::Mean),
+            "ivw" => Ok(DownsampleMode::InverseVarianceMean),
+            _ => Err(format!("Unknown downsample mode {}, expected sum, mean or ivw", mode)),
+        }
+    }
+}
+
+impl DoseBlock {
+    /// Merge blocks of `fx` x `fy` x `fz` voxels into one, combining doses as `mode` says.
+    ///
+    /// Each factor must be positive and divide the number of voxels along its axis, otherwise
+    /// this fails with [`DoseError::InvalidParameter`] rather than leaving a ragged last block.
+    /// The coarse grid keeps every `f`-th node.
+    ///
+    /// Uncertainties are propagated assuming the fine voxels are statistically independent,
+    /// as they are for separately scored `3ddose` voxels:
+    /// * [`DownsampleMode::Sum`] and [`DownsampleMode::Mean`] add the absolute variances
+    ///   `(uncert * dose)^2` of the fine voxels with the squares of their weights.
+    /// * [`DownsampleMode::InverseVarianceMean`] weights each fine voxel by `1 / variance`, giving
+    ///   a variance of `1 / sum(1 / variance)`. Voxels with no positive variance, such as the
+    ///   zero-dose voxels of a `3ddose` file, carry no information about the noise and are left
+    ///   out; a coarse voxel made only of those falls back to the volume-weighted mean. Weights
+    ///   estimated from the doses themselves pull the result slightly towards lower doses,
+    ///   since those tend to have smaller absolute variances, which is negligible once
+    ///   uncertainties are a few percent.
+    ///
+    /// The coarse uncertainty is a fraction of the coarse dose like any other, zero where the
+    /// coarse dose is zero.
+    pub fn downsample(
+        &self,
+        factors: [usize; 3],
+        mode: DownsampleMode,
+    ) -> Result<DoseBlock, DoseError> {
+        let sizes = [self.num_x(), self.num_y(), self.num_z()];
+        for (&(name, factor), &size) in [("fx", factors[0]), ("fy", factors[1]), ("fz", factors[2])]
+            .iter()
+            .zip(&sizes)
+        {
+            if factor == 0 || size % factor != 0 {
+                return Err(DoseError::InvalidParameter { name, value: factor as f64 });
+            }
+        }
+
+        let every = |pts: &[f64], factor: usize| pts.iter().step_by(factor).cloned().collect();
+        let mut coarse = DoseBlock {
+            xs: every(&self.xs, factors[0]),
+            ys: every(&self.ys, factors[1]),
+            zs: every(&self.zs, factors[2]),
+            doses: Vec::new(),
+            uncerts: Vec::new(),
+            variant: None,
+        };
+        let widths = |pts: &[f64]| pts.windows(2).map(|pair| pair[1] - pair[0]).collect::<Vec<_>>();
+        let (dxs, dys, dzs) = (widths(&self.xs), widths(&self.ys), widths(&self.zs));
+
+        for k in 0..coarse.num_z() {
+            for j in 0..coarse.num_y() {
+                for i in 0..coarse.num_x() {
+                    // (dose, absolute variance, volume) of each fine voxel
+                    let mut fine = Vec::with_capacity(factors.iter().product());
+                    for (fk, dz) in block(&dzs, k, factors[2]) {
+                        for (fj, dy) in block(&dys, j, factors[1]) {
+                            for (fi, dx) in block(&dxs, i, factors[0]) {
+                                let index = self.voxel_index(fi, fj, fk);
+                                let dose = self.doses[index];
+                                let variance = (self.uncerts[index] * dose).powi(2);
+                                fine.push((dose, variance, dx * dy * dz));
+                            }
+                        }
+                    }
+                    let (dose, variance) = combine(&fine, mode);
+                    coarse.doses.push(dose);
+                    let uncert = if dose != 0.0 { variance.sqrt() / dose.abs() } else { 0.0 };
+                    coarse.uncerts.push(uncert);
+                }
+            }
+        }
+        Ok(coarse)
+    }
+}
+
+/// Fine voxel indices and widths along one axis making up coarse voxel `coarse`.
+fn block(widths: &[f64], coarse: usize, factor: usize) -> impl Iterator<Item = (usize, f64)> + '_ {
+    widths.iter().cloned().enumerate().skip(coarse * factor).take(factor)
+}
+
+/// Combined dose and absolute variance of `(dose, variance, volume)` fine voxels.
+fn combine(fine: &[(f64, f64, f64)], mode: DownsampleMode) -> (f64, f64) {
+    // weighted sum of doses and of variances with normalized weights
+    let weighted = |weight: &dyn Fn(f64, f64) -> f64| {
+        let total: f64 = fine.iter().map(|&(_, variance, volume)| weight(variance, volume)).sum();
+        fine.iter().fold((0.0, 0.0), |(dose, var), &(d, v, volume)| {
+            let w = weight(v, volume) / total;
+            (dose + w * d, var + w * w * v)
+        })
+    };
+    match mode {
+        DownsampleMode::Sum => {
+            fine.iter().fold((0.0, 0.0), |(dose, var), &(d, v, _)| (dose + d, var + v))
+        }
+        DownsampleMode::Mean => weighted(&|_, volume| volume),
+        DownsampleMode::InverseVarianceMean => {
+            if fine.iter().any(|&(_, variance, _)| variance > 0.0) {
+                weighted(&|variance, _| if variance > 0.0 { 1.0 / variance } else { 0.0 })
+            } else {
+                combine(fine, DownsampleMode::Mean)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downsample() {
+        // 4 x 1 x 1 voxels, the first two twice as wide
+        let data = DoseBlock {
+            xs: vec![0.0, 2.0, 4.0, 5.0, 6.0],
+            ys: vec![0.0, 1.0],
+            zs: vec![0.0, 1.0],
+            doses: vec![10.0, 20.0, 30.0, 0.0],
+            uncerts: vec![0.1, 0.1, 0.02, 1.0],
+            variant: None,
+        };
+
+        let sum = data.downsample([2, 1, 1], DownsampleMode::Sum).unwrap();
+        assert_eq!(sum.xs, vec![0.0, 4.0, 6.0]);
+        assert_eq!((sum.ys.clone(), sum.zs.clone()), (data.ys.clone(), data.zs.clone()));
+        assert_eq!(sum.doses, vec![30.0, 30.0]);
+        // sqrt(1 + 4) / 30
+        approx::assert_relative_eq!(sum.uncerts[0], 5.0_f64.sqrt() / 30.0);
+        approx::assert_relative_eq!(sum.uncerts[1], 0.02);
+
+        let mean = data.downsample([2, 1, 1], DownsampleMode::Mean).unwrap();
+        assert_eq!(mean.doses, vec![15.0, 15.0]);
+        approx::assert_relative_eq!(mean.uncerts[0], 5.0_f64.sqrt() / 30.0);
+        // the zero-dose voxel adds no variance, but halves the dose
+        approx::assert_relative_eq!(mean.uncerts[1], 0.02);
+
+        let ivw = data.downsample([2, 1, 1], DownsampleMode::InverseVarianceMean).unwrap();
+        // weights 1 and 1/4
+        approx::assert_relative_eq!(ivw.doses[0], 12.0);
+        approx::assert_relative_eq!(ivw.uncerts[0], (1.0_f64 / 1.25).sqrt() / 12.0);
+        // the zero-dose voxel has no variance and is left out
+        approx::assert_relative_eq!(ivw.doses[1], 30.0);
+
+        let all = data.downsample([4, 1, 1], DownsampleMode::Sum).unwrap();
+        assert_eq!((all.xs.clone(), all.doses.clone()), (vec![0.0, 6.0], vec![60.0]));
+        assert_eq!(data.downsample([1, 1, 1], DownsampleMode::Mean).unwrap().doses, data.doses);
+
+        assert!(matches!(
+            data.downsample([3, 1, 1], DownsampleMode::Mean),
+            Err(DoseError::InvalidParameter { name: "fx", .. })
+        ));
+        assert!(data.downsample([2, 0, 1], DownsampleMode::Mean).is_err());
+        assert_eq!("ivw".parse(), Ok(DownsampleMode::InverseVarianceMean));
+    }
+}
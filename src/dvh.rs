@@ -0,0 +1,114 @@
+//! Cumulative dose-volume histograms, the usual radiotherapy summary of a dose distribution.
+
+use std::io::Write;
+
+use crate::{DoseBlock, Field};
+
+/// Number of bins [`Fmt::Dvh`](crate::Fmt::Dvh) output divides the dose range into.
+pub const DVH_BINS: usize = 100;
+
+impl DoseBlock {
+    /// The cumulative dose-volume histogram of the dose, as `(dose level, volume fraction)`
+    /// pairs.
+    ///
+    /// The `bins + 1` dose levels run evenly from 0 to the maximum dose, and each is paired with
+    /// the fraction of the grid's volume receiving at least that dose. Voxels are weighted by
+    /// their volume, so uneven grids aren't skewed towards their finely divided regions. The
+    /// first fraction is 1, and the last is the fraction receiving the maximum dose. An all-zero
+    /// dose gives every level as 0 with a fraction of 1.
+    ///
+    /// Panics if `bins` is 0.
+    pub fn dvh(&self, bins: usize) -> Vec<(f64, f64)> {
+        self.cumulative_histogram(&self.doses, bins)
+    }
+
+    /// Write the cumulative volume histogram of `field` as csv, a header naming the field then
+    /// a line per level as in [`DoseBlock::dvh`].
+    pub fn write_dvh_fields_to<W: Write>(
+        &self,
+        file: &mut W,
+        field: &Field,
+        bins: usize,
+    ) -> Result<(), std::io::Error> {
+        self.check_field_lengths(std::slice::from_ref(field))?;
+        writeln!(file, "{},Volume fraction", field.name)?;
+        for (level, fraction) in self.cumulative_histogram(field.values, bins) {
+            writeln!(file, "{},{}", level, fraction)?;
+        }
+        Ok(())
+    }
+
+    /// Volume-weighted cumulative histogram of one value per voxel, from 0 to the largest.
+    fn cumulative_histogram(&self, values: &[f64], bins: usize) -> Vec<(f64, f64)> {
+        assert!(bins > 0, "a histogram needs at least one bin");
+        let max = values.iter().cloned().fold(0.0, f64::max);
+
+        // volume whose value reaches level i but not level i + 1
+        let mut volumes = vec![0.0; bins + 1];
+        let mut below = 0.0;
+        for (&value, volume) in values.iter().zip(self.voxel_volumes()) {
+            // negative values don't reach even level 0, and with no positive values every
+            // level is 0
+            if value >= 0.0 {
+                let level = if max > 0.0 {
+                    ((value / max * bins as f64).floor() as usize).min(bins)
+                } else {
+                    bins
+                };
+                volumes[level] += volume;
+            } else {
+                below += volume;
+            }
+        }
+
+        // summed from the top level down, so level 0 is the whole volume reaching 0 exactly
+        for level in (0..bins).rev() {
+            volumes[level] += volumes[level + 1];
+        }
+        let total = volumes[0] + below;
+        volumes
+            .iter()
+            .enumerate()
+            .map(|(level, volume)| (max * level as f64 / bins as f64, volume / total))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_abs_diff_eq;
+
+    #[test]
+    fn dvh() {
+        // 3 x 1 x 1 voxels of 1, 2 and 1 cm3
+        let mut data = DoseBlock {
+            xs: vec![0.0, 1.0, 3.0, 4.0],
+            ys: vec![0.0, 1.0],
+            zs: vec![0.0, 1.0],
+            doses: vec![4.0, 1.0, 2.0],
+            uncerts: vec![0.1; 3],
+            variant: None,
+        };
+        let dvh = data.dvh(4);
+        let expected = [(0.0, 1.0), (1.0, 1.0), (2.0, 0.5), (3.0, 0.25), (4.0, 0.25)];
+        assert_eq!(dvh.len(), expected.len());
+        for ((level, fraction), (expected_level, expected_fraction)) in dvh.iter().zip(&expected) {
+            assert_abs_diff_eq!(level, expected_level);
+            assert_abs_diff_eq!(fraction, expected_fraction);
+        }
+        assert!(dvh.windows(2).all(|pair| pair[1].1 <= pair[0].1));
+
+        let mut out = Vec::new();
+        let field = Field { name: "Dose [Gy cm2]", values: &data.doses };
+        data.write_dvh_fields_to(&mut out, &field, 2).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Dose [Gy cm2],Volume fraction\n0,1\n2,0.5\n4,0.25\n"
+        );
+
+        // no dose anywhere still covers the whole volume at 0
+        data.doses = vec![0.0; 3];
+        assert_eq!(data.dvh(3), vec![(0.0, 1.0); 4]);
+    }
+}
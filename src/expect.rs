@@ -0,0 +1,120 @@
+//! Checks that a dose block has the geometry a pipeline expects, to catch converting the wrong file.
+
+use crate::{DoseBlock, DoseError};
+
+/// Default tolerance `[cm]` for [`DoseBlock::expect_extent`] on the command line.
+///
+/// Generous next to the single precision noise of `3ddose` coordinates, tight next to any
+/// real difference in geometry.
+pub const EXTENT_TOLERANCE: f64 = 1.0e-3;
+
+impl DoseBlock {
+    /// Check the number of voxels along *x*, *y* and *z* is exactly `dims`.
+    ///
+    /// Fails with [`DoseError::UnexpectedGrid`] otherwise.
+    pub fn expect_dims(&self, dims: [usize; 3]) -> Result<(), DoseError> {
+        let found = [self.num_x(), self.num_y(), self.num_z()];
+        if found != dims {
+            let format = |dims: [usize; 3]| format!("{}x{}x{} voxels", dims[0], dims[1], dims[2]);
+            let (expected, found) = (format(dims), format(found));
+            return Err(DoseError::UnexpectedGrid { expected, found });
+        }
+        Ok(())
+    }
+
+    /// Check the first and last nodes along *x*, *y* and *z* are within `tol` `[cm]` of
+    /// `extent`, see [`DoseBlock::bounds`].
+    ///
+    /// Fails with [`DoseError::UnexpectedGrid`] otherwise.
+    pub fn expect_extent(&self, extent: [(f64, f64); 3], tol: f64) -> Result<(), DoseError> {
+        let found = self.bounds();
+        let matches = found
+            .iter()
+            .zip(&extent)
+            .all(|(&(lo, hi), &(min, max))| (lo - min).abs() <= tol && (hi - max).abs() <= tol);
+        if !matches {
+            let format = |extent: [(f64, f64); 3]| {
+                let ranges: Vec<String> =
+                    extent.iter().map(|(lo, hi)| format!("{}:{}", lo, hi)).collect();
+                format!("extent {} cm", ranges.join(","))
+            };
+            let (expected, found) = (format(extent), format(found));
+            return Err(DoseError::UnexpectedGrid { expected, found });
+        }
+        Ok(())
+    }
+}
+
+/// Parse voxel counts like `40x40x60` for `--expect-dims`.
+pub(crate) fn parse_dims(value: &str) -> Result<[usize; 3], String> {
+    let dims: Vec<usize> = value
+        .split('x')
+        .map(|dim| dim.trim().parse::<usize>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("Could not parse {} as voxel counts, e.g. 40x40x60", value))?;
+    match dims[..] {
+        [nx, ny, nz] => Ok([nx, ny, nz]),
+        _ => Err(format!("Expected three voxel counts NXxNYxNZ, found {}", value)),
+    }
+}
+
+/// Parse node ranges like `-10:10,-10:10,0:30` for `--expect-extent`.
+pub(crate) fn parse_extent(value: &str) -> Result<[(f64, f64); 3], String> {
+    let invalid = || format!("Could not parse {} as an extent, e.g. -10:10,-10:10,0:30", value);
+    let ranges: Vec<(f64, f64)> = value
+        .split(',')
+        .map(|range| {
+            let mut ends = range.splitn(2, ':').map(|end| end.trim().parse::<f64>());
+            match (ends.next(), ends.next()) {
+                (Some(Ok(lo)), Some(Ok(hi))) => Ok((lo, hi)),
+                _ => Err(invalid()),
+            }
+        })
+        .collect::<Result<_, _>>()?;
+    match ranges[..] {
+        [x, y, z] => Ok([x, y, z]),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expect_geometry() {
+        let data = DoseBlock {
+            xs: vec![-1.0, 0.0, 1.0],
+            ys: vec![0.0, 0.5],
+            zs: vec![0.0, 1.0, 2.0, 3.0],
+            doses: vec![0.0; 6],
+            uncerts: vec![0.0; 6],
+            variant: None,
+        };
+        assert!(data.expect_dims([2, 1, 3]).is_ok());
+        match data.expect_dims([2, 3, 1]) {
+            Err(err) => assert_eq!(err.to_string(), "expected 2x3x1 voxels, found 2x1x3 voxels"),
+            Ok(()) => panic!("dims should not match"),
+        }
+
+        let extent = [(-1.0, 1.0), (0.0, 0.5), (0.0, 3.0)];
+        assert!(data.expect_extent(extent, EXTENT_TOLERANCE).is_ok());
+        let close = [(-1.0, 1.0), (0.0, 0.5), (0.0, 3.0005)];
+        assert!(data.expect_extent(close, EXTENT_TOLERANCE).is_ok());
+        assert!(matches!(
+            data.expect_extent([(-1.0, 1.0), (0.0, 0.5), (0.0, 3.1)], EXTENT_TOLERANCE),
+            Err(DoseError::UnexpectedGrid { .. })
+        ));
+
+        use structopt::StructOpt;
+        let args = crate::Cli::from_iter(&["dose2gmsh", "input.3ddose", "--expect-dims=2x1x3"]);
+        assert_eq!((args.expect_dims, args.extent_tol), (Some([2, 1, 3]), EXTENT_TOLERANCE));
+
+        assert_eq!(parse_dims("40x40x60"), Ok([40, 40, 60]));
+        assert!(parse_dims("40x40").is_err());
+        assert!(parse_dims("40x40x6.5").is_err());
+        assert_eq!(parse_extent("-1:1, 0:0.5,0:3"), Ok(extent));
+        assert!(parse_extent("-1:1,0:0.5").is_err());
+        assert!(parse_extent("-1,1,0:0.5,0:3").is_err());
+    }
+}
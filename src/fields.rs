@@ -0,0 +1,199 @@
+//! Derived per-voxel fields, looked up by [`FieldKind`].
+
+use std::borrow::Cow;
+
+use crate::{centroids, DoseBlock, FieldKind};
+
+/// Parameters of the derived fields, see [`DoseBlock::field_values`].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FieldOptions {
+    /// Floor for [`FieldKind::Log10`], see [`DoseBlock::log_dose`].
+    pub log_floor: f64,
+    /// Coverage factor `k` for [`FieldKind::DoseLow`] and [`FieldKind::DoseHigh`], e.g. `1.96`
+    /// for a 95 % interval.
+    pub coverage: f64,
+    /// Clamp negative lower confidence bounds to zero.
+    pub clamp_low: bool,
+}
+
+impl Default for FieldOptions {
+    /// A log floor of `1e-6`, bounds one standard uncertainty either side and no clamping.
+    fn default() -> Self {
+        FieldOptions { log_floor: 1.0e-6, coverage: 1.0, clamp_low: false }
+    }
+}
+
+/// Whether uncertainties are written as fractions of the dose or in its units, see
+/// [`UncertMode::select`].
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum UncertMode {
+    /// Fractions of the dose, as `3ddose` files hold them.
+    #[default]
+    Fractional,
+    /// In the units of the dose, see [`DoseBlock::absolute_uncerts`].
+    Absolute,
+}
+
+impl UncertMode {
+    /// `kind`, with [`FieldKind::Uncert`] swapped for [`FieldKind::AbsUncert`] when absolute.
+    pub fn select(self, kind: FieldKind) -> FieldKind {
+        match (self, kind) {
+            (UncertMode::Absolute, FieldKind::Uncert) => FieldKind::AbsUncert,
+            (_, kind) => kind,
+        }
+    }
+}
+
+impl std::str::FromStr for UncertMode {
+    type Err = String;
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "fractional" => Ok(UncertMode::Fractional),
+            "absolute" => Ok(UncertMode::Absolute),
+            _ => Err(format!("Unknown uncertainty mode {}, expected absolute or fractional", mode)),
+        }
+    }
+}
+
+impl DoseBlock {
+    /// Values of a field, one per voxel in `doses` order.
+    ///
+    /// Dose and uncertainty are borrowed, derived fields are computed with `options`.
+    pub fn field_values(&self, kind: FieldKind, options: &FieldOptions) -> Cow<'_, [f64]> {
+        let bounds = |high: bool| {
+            let (low_bounds, high_bounds) = self.confidence_bounds(options.coverage);
+            match (high, options.clamp_low) {
+                (true, _) => high_bounds,
+                (false, true) => {
+                    // `<=` so that -0 for zero dose is clamped too
+                    low_bounds.into_iter().map(|low| if low <= 0.0 { 0.0 } else { low }).collect()
+                }
+                (false, false) => low_bounds,
+            }
+        };
+        match kind {
+            FieldKind::Dose => Cow::Borrowed(&self.doses),
+            FieldKind::Uncert => Cow::Borrowed(&self.uncerts),
+            FieldKind::AbsUncert => Cow::Owned(self.abs_uncerts()),
+            FieldKind::Log10 => Cow::Owned(self.log_dose(options.log_floor)),
+            FieldKind::Gradient => Cow::Owned(self.dose_gradient()),
+            FieldKind::DoseLow => Cow::Owned(bounds(false)),
+            FieldKind::DoseHigh => Cow::Owned(bounds(true)),
+        }
+    }
+
+    /// Lower and upper bounds of the interval `dose * (1 -/+ k * uncert)` around each voxel
+    /// dose, for a coverage factor `k`.
+    ///
+    /// With `k = 1` the bounds are one standard uncertainty either side; `k = 1.96` gives a
+    /// 95 % interval for normally distributed dose. Lower bounds of voxels with uncertainties
+    /// above `1 / k` are negative.
+    pub fn confidence_bounds(&self, k: f64) -> (Vec<f64>, Vec<f64>) {
+        self.doses
+            .iter()
+            .zip(&self.uncerts)
+            .map(|(dose, uncert)| (dose * (1.0 - k * uncert), dose * (1.0 + k * uncert)))
+            .unzip()
+    }
+
+    /// Absolute uncertainty of each voxel dose, `|dose| * uncert`, in the units of the dose.
+    pub fn abs_uncerts(&self) -> Vec<f64> {
+        self.doses.iter().zip(&self.uncerts).map(|(dose, uncert)| dose.abs() * uncert).collect()
+    }
+
+    /// Absolute uncertainty of each voxel dose in `[Gy · cm2]`, the same as
+    /// [`DoseBlock::abs_uncerts`]. Zero-dose voxels get zero.
+    pub fn absolute_uncerts(&self) -> Vec<f64> {
+        self.abs_uncerts()
+    }
+
+    /// Magnitude of the dose gradient at each voxel in `[Gy · cm]`, from differences between
+    /// the doses of neighbouring voxels divided by the distance between their centroids.
+    ///
+    /// Differences are central inside the grid and one-sided at its edges. An axis with a
+    /// single voxel contributes nothing.
+    pub fn dose_gradient(&self) -> Vec<f64> {
+        let cs = [centroids(&self.xs), centroids(&self.ys), centroids(&self.zs)];
+        (0..self.num_voxels())
+            .map(|index| {
+                let (i, j, k) = self.voxel_ijk(index);
+                let ijk = [i, j, k];
+                (0..3)
+                    .map(|axis| {
+                        let (n, cs) = (ijk[axis], &cs[axis]);
+                        if cs.len() < 2 {
+                            return 0.0;
+                        }
+                        let (lo, hi) = (n.saturating_sub(1), (n + 1).min(cs.len() - 1));
+                        let dose = |n: usize| {
+                            let mut at = ijk;
+                            at[axis] = n;
+                            self.doses[self.voxel_index(at[0], at[1], at[2])]
+                        };
+                        (dose(hi) - dose(lo)) / (cs[hi] - cs[lo])
+                    })
+                    .map(|derivative| derivative * derivative)
+                    .sum::<f64>()
+                    .sqrt()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Fmt, Quantity};
+
+    #[test]
+    fn field_values() {
+        // 3 x 2 x 1 voxels, dose rising by 2 per cm in x and 12 per 2 cm in y
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 2.0, 3.0],
+            ys: vec![0.0, 2.0, 4.0],
+            zs: vec![0.0, 1.0],
+            doses: vec![0.0, 2.0, 4.0, 12.0, 14.0, 16.0],
+            uncerts: vec![0.5, 0.5, 0.25, 0.1, 0.1, 0.1],
+            variant: None,
+        };
+        let options = FieldOptions { log_floor: 1.0, ..Default::default() };
+        assert!(matches!(data.field_values(FieldKind::Dose, &options), Cow::Borrowed(_)));
+        assert_eq!(data.field_values(FieldKind::Uncert, &options).as_ref(), &data.uncerts[..]);
+        assert_eq!(data.abs_uncerts()[..3], [0.0, 1.0, 1.0]);
+        assert_eq!(data.absolute_uncerts(), data.abs_uncerts());
+        assert_eq!(data.field_values(FieldKind::Log10, &options)[..2], [0.0, 2.0_f64.log10()]);
+
+        let gradient = data.dose_gradient();
+        for &g in &gradient {
+            approx::assert_relative_eq!(g, (2.0_f64 * 2.0 + 6.0 * 6.0).sqrt());
+        }
+        assert_eq!(gradient, data.field_values(FieldKind::Gradient, &options).into_owned());
+
+        let (low, high) = data.confidence_bounds(2.0);
+        assert_eq!((&low[1..3], &high[1..3]), (&[0.0, 2.0][..], &[4.0, 6.0][..]));
+        assert_eq!(data.field_values(FieldKind::DoseHigh, &options)[1], 3.0);
+        let options = FieldOptions { coverage: 3.0, clamp_low: true, ..options };
+        assert_eq!(data.field_values(FieldKind::DoseLow, &options)[..3], [0.0, 0.0, 1.0]);
+        let options = FieldOptions { clamp_low: false, ..options };
+        assert_eq!(data.field_values(FieldKind::DoseLow, &options)[1], -1.0);
+
+        assert_eq!("abs-uncert".parse(), Ok(FieldKind::AbsUncert));
+        assert!("gradients".parse::<FieldKind>().is_err());
+        assert_eq!(FieldKind::Dose.label(Fmt::Csv, Quantity::DoseRate), "Dose rate [Gy cm2/s]");
+        assert_eq!(FieldKind::Gradient.label(Fmt::Msh2, Quantity::Dose), "Dose gradient [Gy·cm]");
+
+        use structopt::StructOpt;
+        let cli = ["dose2gmsh", "in.3ddose", "--fields=dose,gradient", "--field=log10"];
+        let args = crate::Cli::from_iter(&cli);
+        assert_eq!(args.select_fields, vec![FieldKind::Dose, FieldKind::Gradient]);
+        assert_eq!(args.fields, vec![FieldKind::Log10]);
+
+        let args = crate::Cli::from_iter(&["dose2gmsh", "in.3ddose", "--uncert=absolute"]);
+        assert_eq!(args.uncert, UncertMode::Absolute);
+        assert_eq!(args.uncert.select(FieldKind::Uncert), FieldKind::AbsUncert);
+        assert_eq!(args.uncert.select(FieldKind::Dose), FieldKind::Dose);
+        let args = crate::Cli::from_iter(&["dose2gmsh", "in.3ddose"]);
+        assert_eq!(args.uncert.select(FieldKind::Uncert), FieldKind::Uncert);
+        assert!("relative".parse::<UncertMode>().is_err());
+    }
+}
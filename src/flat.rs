@@ -0,0 +1,114 @@
+//! 2D quadrilateral msh output for grids one voxel thick.
+
+use std::io::Write;
+
+use crate::{write_msh2_nodes, Axis, DoseBlock};
+
+impl DoseBlock {
+    /// The one axis with a single voxel along it, which [`MshOptions::flatten_2d`] drops.
+    ///
+    /// Fails describing the voxel counts unless exactly one axis is one voxel thick.
+    ///
+    /// [`MshOptions::flatten_2d`]: crate::MshOptions::flatten_2d
+    pub fn thin_axis(&self) -> Result<Axis, String> {
+        let axes = [Axis::X, Axis::Y, Axis::Z];
+        let thin: Vec<Axis> =
+            axes.iter().cloned().filter(|&axis| self.nodes(axis).len() == 2).collect();
+        match thin[..] {
+            [axis] => Ok(axis),
+            _ => Err(format!(
+                "2d output needs exactly one axis one voxel thick, the grid is {} x {} x {}",
+                self.num_x(),
+                self.num_y(),
+                self.num_z()
+            )),
+        }
+    }
+
+    /// The node coordinates along the two axes other than [`DoseBlock::thin_axis`], and the
+    /// 1-based node ids of each voxel's 4-node quadrilateral, numbering the nodes of the plane
+    /// the first of those axes fastest.
+    ///
+    /// Dropping the thin axis leaves voxels in `doses` order, so element data is written as for
+    /// hexahedra.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn quad4_mesh(
+        &self,
+    ) -> Result<(&[f64], &[f64], impl Iterator<Item = [usize; 4]>), std::io::Error> {
+        let thin = self.thin_axis();
+        let thin = thin.map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        let (u, v) = thin.transverse();
+        let (us, vs) = (self.nodes(u), self.nodes(v));
+        let (row, nu, nv) = (us.len(), us.len() - 1, vs.len() - 1);
+        let quads = (0..nv).flat_map(move |b| {
+            (0..nu).map(move |a| {
+                // counter-clockwise from the lowest corner, as gmsh orders quadrangles
+                let first = a + row * b + 1;
+                let above = first + row;
+                [first, first + 1, above + 1, above]
+            })
+        });
+        Ok((us, vs, quads))
+    }
+
+    /// Write the `$Nodes` and `$Elements` sections of 4-node quadrilaterals, one per voxel, in
+    /// the plane of the two axes other than [`DoseBlock::thin_axis`].
+    ///
+    /// Those axes become *x* and *y*, keeping their order, at *z* = 0, so for a grid one voxel
+    /// thick in *x*, the file's *x* is the grid's *y*.
+    pub(crate) fn write_msh2_quad4<W: Write>(
+        &self,
+        filestream: &mut W,
+        tags: &str,
+    ) -> Result<(), std::io::Error> {
+        let (us, vs, quads) = self.quad4_mesh()?;
+        write_msh2_nodes(filestream, us, vs, &[0.0])?;
+
+        writeln!(filestream, "$Elements\n{}", self.num_voxels())?;
+        for (index, [a, b, c, d]) in quads.enumerate() {
+            // 3 is the gmsh magic number for a 4-node quadrangle
+            writeln!(filestream, "{} 3 2 {} {} {} {} {}", index + 1, tags, a, b, c, d)?;
+        }
+        writeln!(filestream, "$EndElements")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DoseBlock, Field, MshOptions};
+
+    #[test]
+    fn write_msh2_quad4() {
+        // 2 x 1 x 3 voxels, one thick in y
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 2.0],
+            ys: vec![5.0, 5.5],
+            zs: vec![0.0, 1.0, 2.0, 4.0],
+            doses: vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+            uncerts: vec![0.1; 6],
+            variant: None,
+        };
+        let physical_name = Some("slab".to_string());
+        let options = MshOptions { flatten_2d: true, physical_name, ..Default::default() };
+        let mut out = Vec::new();
+        let fields = [Field { name: "Dose", values: &data.doses }];
+        data.write_msh2_fields_to(&mut out, &fields, &options).unwrap();
+        let msh = String::from_utf8(out).unwrap();
+        assert!(msh.contains("$PhysicalNames\n1\n2 1 \"slab\"\n$EndPhysicalNames\n"));
+        // z becomes y
+        assert!(msh.contains("$Nodes\n12\n1 0 0 0\n2 1 0 0\n3 2 0 0\n4 0 1 0\n"));
+        assert!(msh.contains("12 2 4 0\n$EndNodes\n"));
+        assert!(msh.contains("$Elements\n6\n1 3 2 1 1 1 2 5 4\n2 3 2 1 1 2 3 6 5\n"));
+        assert!(msh.contains("\n3 3 2 1 1 4 5 8 7\n"));
+        assert!(msh.contains("6 3 2 1 1 8 9 12 11\n$EndElements\n"));
+        assert!(msh.contains("\"Dose\"\n1\n0.0\n3\n0\n1\n6\n1 1\n2 2\n3 3\n"));
+
+        let cube = DoseBlock { xs: vec![0.0, 1.0], zs: vec![0.0, 1.0], doses: vec![1.0], ..data };
+        let err = cube.write_msh2_fields_to(&mut Vec::new(), &[], &options).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "2d output needs exactly one axis one voxel thick, the grid is 1 x 1 x 1"
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! Gamma-index comparison of two dose blocks on the same grid.
+
+use std::fmt;
+
+use crate::{centroids, DoseBlock, DoseError};
+
+/// Distances beyond this many times the distance tolerance aren't searched.
+///
+/// A voxel that far away gives a gamma of at least this value, well past failing.
+const SEARCH_RADIUS_DTAS: f64 = 3.0;
+
+/// How the dose-difference tolerance is scaled.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum GammaNorm {
+    /// A fraction of the maximum reference dose, the same for every voxel.
+    Global,
+    /// A fraction of the reference dose in each voxel, stricter in low-dose regions.
+    Local,
+}
+
+/// Gamma-index acceptance criteria.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GammaCriteria {
+    /// Dose-difference tolerance as a fraction, e.g. `0.03` for 3 %.
+    pub dose_tol: f64,
+    /// Distance-to-agreement tolerance in `[cm]`.
+    pub dist_tol: f64,
+    /// Reference voxels below this fraction of the maximum reference dose are skipped.
+    pub threshold: f64,
+    /// Normalization of the dose-difference tolerance.
+    pub norm: GammaNorm,
+}
+
+impl Default for GammaCriteria {
+    /// The common 3 %/3 mm global criteria with a 10 % low-dose threshold.
+    fn default() -> Self {
+        GammaCriteria { dose_tol: 0.03, dist_tol: 0.3, threshold: 0.1, norm: GammaNorm::Global }
+    }
+}
+
+/// Gamma index of `evaluated` against `reference`, which must share a grid.
+///
+/// The result has the gamma value of each reference voxel in `doses` and zero `uncerts`, so it
+/// can be written out like any other dose block. Voxels are compared by centroid, searching
+/// evaluated voxels within three distance tolerances of each reference voxel without
+/// interpolating between them. Voxels under the dose threshold, or with zero dose under local
+/// normalization, aren't evaluated and get a `NaN` gamma.
+pub fn gamma(
+    reference: &DoseBlock,
+    evaluated: &DoseBlock,
+    criteria: &GammaCriteria,
+) -> Result<DoseBlock, DoseError> {
+    reference.check_same_grid(evaluated)?;
+    for &(name, value) in &[("dose_tol", criteria.dose_tol), ("dist_tol", criteria.dist_tol)] {
+        if !(value.is_finite() && value > 0.0) {
+            return Err(DoseError::InvalidParameter { name, value });
+        }
+    }
+
+    let max_dose = reference.doses.iter().cloned().fold(0.0, f64::max);
+    let xcs = centroids(&reference.xs);
+    let ycs = centroids(&reference.ys);
+    let zcs = centroids(&reference.zs);
+    // voxels with centroids within `radius` of `pt` along one axis
+    let window = |cs: &[f64], pt: f64, radius: f64| {
+        cs.partition_point(|&c| c < pt - radius)..cs.partition_point(|&c| c <= pt + radius)
+    };
+
+    let mut map = reference.clone();
+    map.uncerts = vec![0.0; reference.num_voxels()];
+    for (index, gamma) in map.doses.iter_mut().enumerate() {
+        let ref_dose = reference.doses[index];
+        let dose_norm = criteria.dose_tol
+            * match criteria.norm {
+                GammaNorm::Global => max_dose,
+                GammaNorm::Local => ref_dose,
+            };
+        if ref_dose < criteria.threshold * max_dose || dose_norm <= 0.0 {
+            *gamma = f64::NAN;
+            continue;
+        }
+
+        // no voxel further away than the gamma of this one can do better
+        let mut best_sq = ((evaluated.doses[index] - ref_dose) / dose_norm).powi(2);
+        let radius = criteria.dist_tol * best_sq.sqrt().min(SEARCH_RADIUS_DTAS);
+
+        let (i, j, k) = reference.voxel_ijk(index);
+        for ek in window(&zcs, zcs[k], radius) {
+            for ej in window(&ycs, ycs[j], radius) {
+                for ei in window(&xcs, xcs[i], radius) {
+                    let dist_sq = (xcs[ei] - xcs[i]).powi(2)
+                        + (ycs[ej] - ycs[j]).powi(2)
+                        + (zcs[ek] - zcs[k]).powi(2);
+                    let dose_diff = evaluated.doses[evaluated.voxel_index(ei, ej, ek)] - ref_dose;
+                    let gamma_sq = dist_sq / criteria.dist_tol.powi(2) + (dose_diff / dose_norm).powi(2);
+                    best_sq = best_sq.min(gamma_sq);
+                }
+            }
+        }
+        *gamma = best_sq.sqrt();
+    }
+    Ok(map)
+}
+
+/// Pass rate and headline numbers of a gamma map from [`gamma`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct GammaSummary {
+    /// Number of voxels with a gamma value, i.e. not skipped.
+    pub evaluated: usize,
+    /// Number of evaluated voxels with gamma at most 1.
+    pub passed: usize,
+    /// Mean gamma over evaluated voxels.
+    pub mean: f64,
+    /// Largest gamma.
+    pub max: f64,
+}
+
+impl GammaSummary {
+    /// Summarize the `NaN`-free voxels of a gamma map.
+    pub fn new(map: &DoseBlock) -> Self {
+        let values: Vec<f64> = map.doses.iter().cloned().filter(|gamma| !gamma.is_nan()).collect();
+        GammaSummary {
+            evaluated: values.len(),
+            passed: values.iter().filter(|&&gamma| gamma <= 1.0).count(),
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            max: values.iter().cloned().fold(0.0, f64::max),
+        }
+    }
+
+    /// Fraction of evaluated voxels passing, `NaN` if none were evaluated.
+    pub fn pass_rate(&self) -> f64 {
+        self.passed as f64 / self.evaluated as f64
+    }
+}
+
+impl fmt::Display for GammaSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "pass rate:         {:.2} %", 100.0 * self.pass_rate())?;
+        writeln!(f, "evaluated voxels:  {}", self.evaluated)?;
+        writeln!(f, "passed voxels:     {}", self.passed)?;
+        writeln!(f, "mean gamma:        {:.3}", self.mean)?;
+        write!(f, "max gamma:         {:.3}", self.max)
+    }
+}
+
+/// Parse a tolerance like `3%` into a fraction; a bare number is a percentage too.
+pub(crate) fn parse_percent(value: &str) -> Result<f64, String> {
+    let number = value.strip_suffix('%').unwrap_or(value).trim();
+    number
+        .parse::<f64>()
+        .map(|pct| pct / 100.0)
+        .map_err(|_| format!("Could not parse {} as a percentage", value))
+}
+
+/// Parse a distance like `3mm` or `0.3cm` into `[cm]`; a bare number is in millimetres.
+pub(crate) fn parse_distance(value: &str) -> Result<f64, String> {
+    let (number, mm_per_unit) = match value.strip_suffix("cm") {
+        Some(cm) => (cm, 10.0),
+        None => (value.strip_suffix("mm").unwrap_or(value), 1.0),
+    };
+    number
+        .trim()
+        .parse::<f64>()
+        .map(|dist| dist * mm_per_unit / 10.0)
+        .map_err(|_| format!("Could not parse {} as a distance, e.g. 3mm", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 1 cm voxels along x with the given doses.
+    fn line(doses: Vec<f64>) -> DoseBlock {
+        DoseBlock {
+            xs: (0..=doses.len()).map(|x| x as f64).collect(),
+            ys: vec![0.0, 1.0],
+            zs: vec![0.0, 1.0],
+            uncerts: vec![0.0; doses.len()],
+            doses,
+            variant: None,
+        }
+    }
+
+    #[test]
+    fn gamma_index() {
+        let reference = line(vec![100.0, 100.0, 50.0, 1.0]);
+        let criteria = GammaCriteria { dist_tol: 1.0, ..Default::default() };
+
+        let same = gamma(&reference, &reference, &criteria).unwrap();
+        assert_eq!(same.doses[..3], [0.0, 0.0, 0.0]);
+        assert!(same.doses[3].is_nan());
+
+        // 1.5 % off everywhere: half the dose tolerance
+        let evaluated = line(vec![101.5, 101.5, 51.5, 2.5]);
+        let map = gamma(&reference, &evaluated, &criteria).unwrap();
+        approx::assert_relative_eq!(map.doses[0], 0.5);
+        // voxel 1 is matched by the voxel 1 cm away, not its own evaluated dose
+        let evaluated = line(vec![100.0, 50.0, 50.0, 1.0]);
+        let map = gamma(&reference, &evaluated, &criteria).unwrap();
+        approx::assert_relative_eq!(map.doses[1], 1.0);
+        let summary = GammaSummary::new(&map);
+        assert_eq!((summary.evaluated, summary.passed), (3, 3));
+
+        // local normalization is stricter in the 50 % voxel
+        let evaluated = line(vec![100.0, 100.0, 51.5, 1.0]);
+        let local = GammaCriteria { norm: GammaNorm::Local, ..criteria };
+        approx::assert_relative_eq!(gamma(&reference, &evaluated, &criteria).unwrap().doses[2], 0.5);
+        approx::assert_relative_eq!(gamma(&reference, &evaluated, &local).unwrap().doses[2], 1.0);
+
+        assert!(matches!(
+            gamma(&reference, &line(vec![1.0]), &criteria),
+            Err(DoseError::MismatchedGrid { .. })
+        ));
+    }
+
+    #[test]
+    fn parse_tolerances() {
+        assert_eq!(parse_percent("3%"), Ok(0.03));
+        assert_eq!(parse_percent("2"), Ok(0.02));
+        assert_eq!(parse_distance("3mm"), Ok(0.3));
+        assert_eq!(parse_distance("0.5cm"), Ok(0.5));
+        assert_eq!(parse_distance("2"), Ok(0.2));
+        assert!(parse_distance("3in").is_err());
+
+        use structopt::StructOpt;
+        let args = ["dose2gmsh", "gamma", "a.3ddose", "b.3ddose", "--dose-tol=2%"];
+        let compare = |args: &[&str]| match crate::Cli::from_iter_safe(args)?.cmd {
+            Some(crate::Command::Compare { files, .. }) => files.paths(),
+            cmd => panic!("{:?}", cmd),
+        };
+        let expected = ("a.3ddose".into(), "b.3ddose".into());
+        assert_eq!(compare(&args).unwrap(), expected);
+        match crate::Cli::from_iter(&args).cmd {
+            Some(crate::Command::Compare { dose_tol, .. }) => assert_eq!(dose_tol, 0.02),
+            cmd => panic!("{:?}", cmd),
+        }
+
+        // by option, in either order, or mixed with a file given by position
+        let both = ["dose2gmsh", "gamma", "--evaluated", "b.3ddose", "--reference", "a.3ddose"];
+        assert_eq!(compare(&both).unwrap(), expected);
+        let mixed = ["dose2gmsh", "gamma", "--reference", "a.3ddose", "b.3ddose"];
+        assert_eq!(compare(&mixed).unwrap(), expected);
+        let extra = ["dose2gmsh", "gamma", "--evaluated", "b.3ddose", "a.3ddose", "c.3ddose"];
+        assert!(compare(&extra).is_err());
+        assert!(compare(&["dose2gmsh", "gamma", "--reference", "a.3ddose"]).is_err());
+    }
+}
@@ -0,0 +1,78 @@
+//! Gmsh `.geo` scripts for opening msh output with a sensible view.
+
+use std::io::Write;
+
+use crate::DoseBlock;
+
+/// Dose percentiles spanning the colour range of the dose view in [`DoseBlock::write_geo_to`]
+/// by default.
+pub const GEO_PERCENTILES: (f64, f64) = (1.0, 99.0);
+
+impl DoseBlock {
+    /// Write a Gmsh `.geo` script that merges the msh file `msh_file` written from this block.
+    ///
+    /// If the msh file has a dose field, `dose_view` is its index among the `$ElementData`
+    /// sections, and the script sets that view's colour range to the dose at `percentiles`,
+    /// see [`DoseBlock::percentile_range`], so a few hot voxels don't wash out the rest. Gmsh
+    /// looks for `msh_file` relative to the script, so it's usually just the file name. File
+    /// names can't contain double quotes.
+    pub fn write_geo_to<W: Write>(
+        &self,
+        file: &mut W,
+        msh_file: &str,
+        dose_view: Option<usize>,
+        percentiles: (f64, f64),
+    ) -> Result<(), std::io::Error> {
+        if msh_file.contains('"') {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "msh file names in geo scripts can't contain quotes",
+            ));
+        }
+        writeln!(file, "// open with `gmsh <this file>` to view {}", msh_file)?;
+        writeln!(file, "Merge \"{}\";", msh_file)?;
+        if let Some(view) = dose_view {
+            let (p_lo, p_hi) = percentiles;
+            let (lo, hi) = self.percentile_range(p_lo, p_hi);
+            writeln!(file, "// colour range from the dose percentiles {} and {}", p_lo, p_hi)?;
+            // range type 2 is a custom range
+            writeln!(file, "View[{}].RangeType = 2;", view)?;
+            writeln!(file, "View[{}].CustomMin = {};", view, lo)?;
+            writeln!(file, "View[{}].CustomMax = {};", view, hi)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_geo() {
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 2.0, 3.0],
+            ys: vec![0.0, 1.0],
+            zs: vec![0.0, 1.0],
+            doses: vec![1.0, 2.0, 100.0],
+            uncerts: vec![0.1; 3],
+            variant: None,
+        };
+        let mut out = Vec::new();
+        data.write_geo_to(&mut out, "out.msh", Some(1), (0.0, 50.0)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "// open with `gmsh <this file>` to view out.msh\n\
+             Merge \"out.msh\";\n\
+             // colour range from the dose percentiles 0 and 50\n\
+             View[1].RangeType = 2;\n\
+             View[1].CustomMin = 1;\n\
+             View[1].CustomMax = 2;\n"
+        );
+
+        let mut out = Vec::new();
+        data.write_geo_to(&mut out, "out.msh", None, GEO_PERCENTILES).unwrap();
+        assert!(!String::from_utf8(out).unwrap().contains("View"));
+        assert!(data.write_geo_to(&mut Vec::new(), "\".msh", None, GEO_PERCENTILES).is_err());
+    }
+}
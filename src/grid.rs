@@ -0,0 +1,107 @@
+//! Grid-only files, for sharing a voxel grid without its dose.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::stream::Tokens;
+use crate::{DoseBlock, DoseError};
+
+impl DoseBlock {
+    /// Write the grid alone, without doses or uncertainties, to a file that
+    /// [`DoseBlock::from_grid`] reads back.
+    ///
+    /// The format is the header of a `3ddose` file, four lines of space-separated values:
+    /// 1. the number of voxels along *x*, *y* and *z*,
+    /// 2. the `num_x + 1` *x* node coordinates in `[cm]`,
+    /// 3. the `num_y + 1` *y* node coordinates,
+    /// 4. the `num_z + 1` *z* node coordinates.
+    ///
+    /// Coordinates are written with as many digits as it takes to read back the same `f64`.
+    pub fn write_grid<P: AsRef<Path>>(&self, output: P) -> Result<(), std::io::Error> {
+        let mut file = BufWriter::new(File::create(output)?);
+        self.write_grid_to(&mut file)?;
+        file.flush()
+    }
+
+    /// Write the grid alone to any writer, see [`DoseBlock::write_grid`].
+    pub fn write_grid_to<W: Write>(&self, file: &mut W) -> Result<(), std::io::Error> {
+        writeln!(file, "{} {} {}", self.num_x(), self.num_y(), self.num_z())?;
+        for pts in &[&self.xs, &self.ys, &self.zs] {
+            let pts: Vec<String> = pts.iter().map(|pt| pt.to_string()).collect();
+            writeln!(file, "{}", pts.join(" "))?;
+        }
+        Ok(())
+    }
+
+    /// Read a grid written by [`DoseBlock::write_grid`] as a block with zero dose and
+    /// uncertainty in every voxel, ready to be filled in.
+    ///
+    /// Anything after the *z* nodes is ignored, so the grid of a whole `3ddose` file can be
+    /// read this way too. Fails with [`DoseError::EmptyGrid`] if the grid has no voxels,
+    /// [`DoseError::TooManyVoxels`] if its voxel counts are too large to index, and
+    /// [`DoseError::NonMonotonic`] if its node coordinates aren't strictly increasing.
+    pub fn from_grid<P: AsRef<Path>>(input: P) -> Result<DoseBlock, DoseError> {
+        let mut tokens = Tokens::new(BufReader::new(File::open(input)?));
+        let num_x: usize = match tokens.next_token()? {
+            Some(token) => token.parse().map_err(|_| tokens.invalid("voxel number"))?,
+            None => return Err(DoseError::EmptyGrid),
+        };
+        let [num_x, num_y, num_z] = tokens.voxel_counts(num_x)?;
+        let num_voxels = num_x * num_y * num_z;
+        let grid = DoseBlock {
+            xs: tokens.parse_n(num_x + 1, "x-coordinate")?,
+            ys: tokens.parse_n(num_y + 1, "y-coordinate")?,
+            zs: tokens.parse_n(num_z + 1, "z-coordinate")?,
+            doses: vec![0.0; num_voxels],
+            uncerts: vec![0.0; num_voxels],
+            variant: None,
+        };
+        grid.check_monotonic()?;
+        Ok(grid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid_round_trip() {
+        let data = DoseBlock {
+            xs: vec![-1.5, 0.1, 0.30000000000000004],
+            ys: vec![0.0, 2.0],
+            zs: vec![1e-7, 1.0, 2.5, 4.0],
+            doses: vec![1.0; 6],
+            uncerts: vec![0.5; 6],
+            variant: None,
+        };
+        let mut out = Vec::new();
+        data.write_grid_to(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "2 1 3\n-1.5 0.1 0.30000000000000004\n0 2\n0.0000001 1 2.5 4\n"
+        );
+
+        let dir = std::env::temp_dir().join("dose2gmsh_grid_round_trip");
+        std::fs::create_dir_all(&dir).unwrap();
+        data.write_grid(dir.join("run.grid")).unwrap();
+        let grid = DoseBlock::from_grid(dir.join("run.grid")).unwrap();
+        assert_eq!((&grid.xs, &grid.ys, &grid.zs), (&data.xs, &data.ys, &data.zs));
+        assert_eq!((grid.doses, grid.uncerts), (vec![0.0; 6], vec![0.0; 6]));
+
+        std::fs::write(dir.join("short.grid"), "2 1 3\n0 1 2\n0 1\n0 1").unwrap();
+        assert!(DoseBlock::from_grid(dir.join("short.grid")).is_err());
+        std::fs::write(dir.join("empty.grid"), "0 1 3\n0\n0 1\n0 1 2 3").unwrap();
+        assert!(matches!(DoseBlock::from_grid(dir.join("empty.grid")), Err(DoseError::EmptyGrid)));
+        std::fs::remove_dir_all(dir).unwrap();
+
+        // a whole 3ddose file reads as its grid
+        let mut input = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        input.push("props");
+        input.push("asym_2x3x4.3ddose");
+        let full = DoseBlock::from_3d_dose(&input).unwrap();
+        let grid = DoseBlock::from_grid(&input).unwrap();
+        assert_eq!((grid.xs, grid.ys, grid.zs), (full.xs, full.ys, full.zs));
+    }
+}
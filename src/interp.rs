@@ -0,0 +1,261 @@
+//! Dose at arbitrary points, interpolated between voxel centroids.
+
+use crate::{centroids, DoseBlock, DoseError};
+
+/// How doses are evaluated between voxel centroids.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum Interp {
+    /// The dose of the voxel containing the point, keeping sharp edges such as material
+    /// boundaries sharp.
+    Nearest,
+    /// Trilinear interpolation between the eight surrounding centroids.
+    #[default]
+    Trilinear,
+}
+
+impl std::str::FromStr for Interp {
+    type Err = String;
+    fn from_str(interp: &str) -> Result<Self, Self::Err> {
+        match interp {
+            "nearest" => Ok(Interp::Nearest),
+            "trilinear" => Ok(Interp::Trilinear),
+            _ => Err(format!("Unknown interpolation {}, expected nearest or trilinear", interp)),
+        }
+    }
+}
+
+impl DoseBlock {
+    /// The `(i, j, k)` indices of the voxel containing a point in `[cm]`, or `None` outside the
+    /// grid.
+    ///
+    /// Each voxel holds its lower bounds but not its upper ones, so a point on a node between
+    /// two voxels belongs to the one above it, and points on the last node along any axis are
+    /// outside. Nodes are searched by bisection, so non-uniform grids are fine.
+    pub fn voxel_at(&self, x: f64, y: f64, z: f64) -> Option<(usize, usize, usize)> {
+        Some((voxel_along(&self.xs, x)?, voxel_along(&self.ys, y)?, voxel_along(&self.zs, z)?))
+    }
+
+    /// Divide every dose by the dose of the voxel containing a reference point in `[cm]`, e.g.
+    /// a prescription point, so that voxel has a dose of 1.
+    ///
+    /// The voxel is the one [`DoseBlock::voxel_at`] finds. A point outside the grid is a
+    /// [`DoseError::OutsideGrid`] error, and a reference dose that isn't positive and finite a
+    /// [`DoseError::InvalidParameter`] one, leaving the doses unchanged. Fractional
+    /// uncertainties are unchanged by the constant scale.
+    pub fn normalize_to_point(&mut self, x: f64, y: f64, z: f64) -> Result<(), DoseError> {
+        let outside = DoseError::OutsideGrid { point: [x, y, z] };
+        let (i, j, k) = self.voxel_at(x, y, z).ok_or(outside)?;
+        let reference = self.doses[self.voxel_index(i, j, k)];
+        if !(reference.is_finite() && reference > 0.0) {
+            return Err(DoseError::InvalidParameter { name: "reference dose", value: reference });
+        }
+        self.doses.iter_mut().for_each(|dose| *dose /= reference);
+        Ok(())
+    }
+
+    /// Dose at a point in `[cm]`, interpolated trilinearly between voxel centroids, the same as
+    /// [`DoseBlock::dose_at_with`] with [`Interp::Trilinear`].
+    pub fn dose_at(&self, x: f64, y: f64, z: f64) -> Option<f64> {
+        self.dose_at_with(x, y, z, Interp::Trilinear)
+    }
+
+    /// Dose at a point in `[cm]`, interpolated between voxel centroids as `interp` says.
+    ///
+    /// Returns `None` outside the grid envelope, i.e. beyond the first or last node along any
+    /// axis. Between the outermost centroid and the grid edge there's no neighbour to
+    /// interpolate towards, so the trilinear dose is clamped to the edge voxel along that
+    /// axis. [`Interp::Nearest`] takes the dose of the voxel [`DoseBlock::voxel_at`] finds,
+    /// or of the last voxel for points on the last node.
+    pub fn dose_at_with(&self, x: f64, y: f64, z: f64, interp: Interp) -> Option<f64> {
+        let cs = [centroids(&self.xs), centroids(&self.ys), centroids(&self.zs)];
+        self.interpolate(&self.doses, &cs, [x, y, z], interp)
+    }
+
+    /// Interpolation of per-voxel `values` at `pt`, given the centroids along each axis. See
+    /// [`DoseBlock::dose_at_with`].
+    pub(crate) fn interpolate(
+        &self,
+        values: &[f64],
+        cs: &[Vec<f64>; 3],
+        pt: [f64; 3],
+        interp: Interp,
+    ) -> Option<f64> {
+        let nodes = [&self.xs, &self.ys, &self.zs];
+        let mut brackets = [(0, 0, 0.0); 3];
+        for axis in 0..3 {
+            let (first, last) = (nodes[axis][0], nodes[axis][nodes[axis].len() - 1]);
+            if !(first <= pt[axis] && pt[axis] <= last) {
+                return None;
+            }
+            brackets[axis] = match interp {
+                Interp::Nearest => {
+                    let last_voxel = nodes[axis].len() - 2;
+                    let voxel = voxel_along(nodes[axis], pt[axis]).unwrap_or(last_voxel);
+                    (voxel, voxel, 0.0)
+                }
+                Interp::Trilinear => bracket(&cs[axis], pt[axis]),
+            };
+        }
+
+        let [(i0, i1, tx), (j0, j1, ty), (k0, k1, tz)] = brackets;
+        let mut dose = 0.0;
+        for &(k, wz) in &[(k0, 1.0 - tz), (k1, tz)] {
+            for &(j, wy) in &[(j0, 1.0 - ty), (j1, ty)] {
+                for &(i, wx) in &[(i0, 1.0 - tx), (i1, tx)] {
+                    dose += wx * wy * wz * values[self.voxel_index(i, j, k)];
+                }
+            }
+        }
+        Some(dose)
+    }
+}
+
+/// The voxel between nodes `pts` containing `pt`, from its lower node up to but not including
+/// its upper one.
+fn voxel_along(pts: &[f64], pt: f64) -> Option<usize> {
+    match (pts.first(), pts.last()) {
+        (Some(&first), Some(&last)) if first <= pt && pt < last => {
+            Some(pts.partition_point(|&node| node <= pt) - 1)
+        }
+        _ => None,
+    }
+}
+
+/// The centroids either side of `pt` and the fraction of the way from the first to the second.
+///
+/// Points outside the outermost centroids are clamped to them.
+fn bracket(cs: &[f64], pt: f64) -> (usize, usize, f64) {
+    let above = cs.partition_point(|&c| c < pt);
+    if above == 0 {
+        (0, 0, 0.0)
+    } else if above == cs.len() {
+        (cs.len() - 1, cs.len() - 1, 0.0)
+    } else {
+        let (lo, hi) = (above - 1, above);
+        (lo, hi, (pt - cs[lo]) / (cs[hi] - cs[lo]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dose_at() {
+        // 2 x 2 x 1 voxels, dose linear in x and y
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 3.0],
+            ys: vec![0.0, 1.0, 2.0],
+            zs: vec![0.0, 1.0],
+            doses: vec![0.0, 1.0, 10.0, 11.0],
+            uncerts: vec![0.0; 4],
+            variant: None,
+        };
+        let dose_at = |x, y, z| data.dose_at(x, y, z);
+        // centroids
+        assert_eq!(dose_at(0.5, 0.5, 0.5), Some(0.0));
+        assert_eq!(dose_at(2.0, 1.5, 0.5), Some(11.0));
+        // halfway between centroids at x = 0.5 and x = 2
+        approx::assert_relative_eq!(dose_at(1.25, 1.0, 0.5).unwrap(), 5.5);
+        // clamped to the edge voxel near the boundary
+        assert_eq!(dose_at(0.0, 0.0, 1.0), Some(0.0));
+        approx::assert_relative_eq!(dose_at(3.0, 1.0, 0.0).unwrap(), 6.0);
+
+        assert_eq!(dose_at(-0.1, 1.0, 0.5), None);
+        assert_eq!(dose_at(1.0, 2.5, 0.5), None);
+        assert_eq!(dose_at(1.0, 1.0, f64::NAN), None);
+        assert_eq!(data.dose_at_with(-0.1, 1.0, 0.5, Interp::Nearest), None);
+        // trilinear unless told otherwise
+        let trilinear = data.dose_at_with(1.25, 1.0, 0.5, Interp::Trilinear);
+        assert_eq!(data.dose_at(1.25, 1.0, 0.5), trilinear);
+        assert_ne!(data.dose_at_with(1.25, 1.0, 0.5, Interp::Nearest), trilinear);
+    }
+
+    #[test]
+    fn normalize_to_point() {
+        let mut data = DoseBlock {
+            xs: vec![0.0, 1.0, 3.0],
+            ys: vec![0.0, 1.0],
+            zs: vec![0.0, 1.0],
+            doses: vec![2.0, 8.0],
+            uncerts: vec![0.1, 0.2],
+            variant: None,
+        };
+        data.normalize_to_point(2.5, 0.5, 0.5).unwrap();
+        assert_eq!(data.doses, vec![0.25, 1.0]);
+        assert_eq!(data.uncerts, vec![0.1, 0.2]);
+
+        assert!(matches!(
+            data.normalize_to_point(3.0, 0.5, 0.5),
+            Err(DoseError::OutsideGrid { point: [x, _, _] }) if x == 3.0
+        ));
+        data.doses[0] = 0.0;
+        assert!(matches!(
+            data.normalize_to_point(0.5, 0.5, 0.5),
+            Err(DoseError::InvalidParameter { name: "reference dose", .. })
+        ));
+        assert_eq!(data.doses, vec![0.0, 1.0]);
+    }
+
+    #[test]
+    fn interp_modes() {
+        // dose 2x + 3y + z at the centroids of 4 x 3 x 2 uneven voxels
+        let xs = vec![0.0, 1.0, 3.0, 4.0, 7.0];
+        let (ys, zs) = (vec![0.0, 2.0, 3.0, 5.0], vec![0.0, 1.0, 4.0]);
+        let field = |x: f64, y: f64, z: f64| 2.0 * x + 3.0 * y + z;
+        let mut doses = Vec::new();
+        for &z in &centroids(&zs) {
+            for &y in &centroids(&ys) {
+                for &x in &centroids(&xs) {
+                    doses.push(field(x, y, z));
+                }
+            }
+        }
+        let data = DoseBlock { xs, ys, zs, doses, uncerts: vec![0.0; 24], variant: None };
+
+        // trilinear is exact for a linear field between the outermost centroids
+        for &(x, y, z) in &[(0.5, 1.0, 0.5), (1.7, 2.2, 1.9), (3.6, 3.9, 2.5), (5.5, 4.0, 2.5)] {
+            let dose = data.dose_at_with(x, y, z, Interp::Trilinear).unwrap();
+            approx::assert_relative_eq!(dose, field(x, y, z), epsilon = 1e-12);
+        }
+
+        // nearest is the centroid dose of the voxel containing the point, even when another
+        // centroid is closer: x = 1.1 lies in [1, 3] but nearer the centroid at 0.5 than 2
+        let nearest = |x, y, z| data.dose_at_with(x, y, z, Interp::Nearest).unwrap();
+        assert_eq!(nearest(1.1, 0.1, 0.1), field(2.0, 1.0, 0.5));
+        assert_eq!(nearest(2.9, 4.9, 3.9), field(2.0, 4.0, 2.5));
+        // on a node the voxel above wins, and the last node belongs to the last voxel
+        assert_eq!(nearest(1.0, 0.0, 0.0), field(2.0, 1.0, 0.5));
+        assert_eq!(nearest(7.0, 5.0, 4.0), field(5.5, 4.0, 2.5));
+
+        assert_eq!("nearest".parse(), Ok(Interp::Nearest));
+        assert_eq!(Interp::default(), Interp::Trilinear);
+        assert!("cubic".parse::<Interp>().is_err());
+    }
+
+    #[test]
+    fn voxel_at() {
+        // 3 x 1 x 2 voxels, uneven in x
+        let data = DoseBlock {
+            xs: vec![-1.0, 0.0, 0.5, 3.0],
+            ys: vec![0.0, 1.0],
+            zs: vec![0.0, 1.0, 2.0],
+            doses: vec![0.0; 6],
+            uncerts: vec![0.0; 6],
+            variant: None,
+        };
+        assert_eq!(data.voxel_at(0.7, 0.5, 1.5), Some((2, 0, 1)));
+        assert_eq!(data.voxel_at(-0.9, 0.99, 0.1), Some((0, 0, 0)));
+        // lower bounds are inside, upper bounds belong to the next voxel or are outside
+        assert_eq!(data.voxel_at(-1.0, 0.0, 0.0), Some((0, 0, 0)));
+        assert_eq!(data.voxel_at(0.0, 0.0, 1.0), Some((1, 0, 1)));
+        assert_eq!(data.voxel_at(0.5, 0.5, 0.5), Some((2, 0, 0)));
+        assert_eq!(data.voxel_at(3.0, 0.5, 0.5), None);
+        assert_eq!(data.voxel_at(1.0, 1.0, 0.5), None);
+        assert_eq!(data.voxel_at(1.0, 0.5, 2.0), None);
+        assert_eq!(data.voxel_at(-1.1, 0.5, 0.5), None);
+        assert_eq!(data.voxel_at(f64::NAN, 0.5, 0.5), None);
+        // the last node still has a nearest dose
+        assert_eq!(data.dose_at_with(3.0, 1.0, 2.0, Interp::Nearest), Some(0.0));
+    }
+}
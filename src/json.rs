@@ -0,0 +1,83 @@
+//! Caching parsed dose blocks as JSON, so later steps of a pipeline needn't parse `3ddose` text.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::{DoseBlock, DoseError};
+
+impl DoseBlock {
+    /// Write the block as pretty-printed JSON, see [`DoseBlock::write_json_to`].
+    pub fn write_json<P: AsRef<Path>>(&self, output: P) -> Result<(), DoseError> {
+        let mut file = BufWriter::new(File::create(output)?);
+        self.write_json_to(&mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Write the block as pretty-printed JSON to any writer, an object with the fields of
+    /// [`DoseBlock`] that [`DoseBlock::from_json_reader`] reads back.
+    ///
+    /// JSON has no `NaN` or infinity, so non-finite values are written as `null` and the
+    /// block won't read back. Check with [`DoseBlock::check_finite`] first if that matters.
+    pub fn write_json_to<W: Write>(&self, file: &mut W) -> Result<(), DoseError> {
+        serde_json::to_writer_pretty(&mut *file, self).map_err(std::io::Error::from)?;
+        writeln!(file)?;
+        Ok(())
+    }
+
+    /// Read a block written by [`DoseBlock::write_json`].
+    pub fn from_json<P: AsRef<Path>>(input: P) -> Result<DoseBlock, DoseError> {
+        DoseBlock::from_json_reader(BufReader::new(File::open(input)?))
+    }
+
+    /// Read a block written by [`DoseBlock::write_json_to`] from any reader.
+    ///
+    /// Malformed JSON is an [`std::io::ErrorKind::InvalidData`] error, and JSON cut off partway
+    /// an [`std::io::ErrorKind::UnexpectedEof`] one. The block is then checked as
+    /// [`DoseBlock::new`] checks its arguments.
+    pub fn from_json_reader<R: Read>(reader: R) -> Result<DoseBlock, DoseError> {
+        let DoseBlock { xs, ys, zs, doses, uncerts, variant } =
+            serde_json::from_reader(reader).map_err(std::io::Error::from)?;
+        Ok(DoseBlock { variant, ..DoseBlock::new(xs, ys, zs, doses, uncerts)? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DoseVariant;
+
+    #[test]
+    fn json_round_trip() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("props");
+        path.push("asym_2x3x4.3ddose");
+        let data = DoseBlock::from_3d_dose(&path).unwrap();
+
+        let mut json = Vec::new();
+        data.write_json_to(&mut json).unwrap();
+        let text = String::from_utf8(json).unwrap();
+        assert!(text.starts_with("{\n  \"xs\": [\n"));
+        assert!(text.contains("\"variant\": \"Classic\""));
+        assert_eq!(DoseBlock::from_json_reader(text.as_bytes()).unwrap(), data);
+
+        let file = std::env::temp_dir().join("dose2gmsh_block.json");
+        let mut wrapped = data.clone();
+        wrapped.variant = Some(DoseVariant::Wrapped);
+        wrapped.write_json(&file).unwrap();
+        assert_eq!(DoseBlock::from_json(&file).unwrap(), wrapped);
+        std::fs::remove_file(&file).unwrap();
+
+        let short = r#"{"xs": [0, 1], "ys": [0, 1], "zs": [0, 1], "doses": [], "uncerts": [0.1],
+            "variant": null}"#;
+        assert!(matches!(
+            DoseBlock::from_json_reader(short.as_bytes()),
+            Err(DoseError::LengthMismatch { field: "dose value", expected: 1, found: 0 })
+        ));
+        match DoseBlock::from_json_reader(&b"{\"xs\": "[..]) {
+            Err(DoseError::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof),
+            other => panic!("expected an io error, got {:?}", other),
+        }
+    }
+}
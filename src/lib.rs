@@ -4,24 +4,520 @@
 
 use std::fmt::Debug;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::str::FromStr;
 
+use structopt::clap::{AppSettings, Shell};
 use structopt::StructOpt;
 
+mod coalesce;
+mod combine;
+#[cfg(feature = "gzip")]
+mod compress;
+#[cfg(feature = "config")]
+mod config;
+mod connectivity;
+mod crop;
+mod dicom;
+mod downsample;
+mod dvh;
+mod expect;
+mod fields;
+mod flat;
+mod gamma;
+mod geo;
+mod grid;
+mod interp;
+#[cfg(feature = "serde")]
+mod json;
+mod msh2_binary;
+mod msh4;
+mod multi;
+mod npy;
+mod profile;
+mod progress;
+mod quality;
+mod raw;
+mod rectilinear;
+mod single;
+#[cfg(feature = "image")]
+mod render;
+mod slice;
+mod smooth;
+mod stats;
+mod stream;
+mod variant;
+mod vtk;
+mod voxel;
+mod vtk_xml;
+mod writer;
+#[cfg(feature = "config")]
+pub use config::Config;
+pub use coalesce::{CoalescedElement, CoalescedMesh};
+pub use combine::{DoseSum, WeightedFile, MAGNITUDE_WARNING_DECADES};
+#[cfg(feature = "gzip")]
+pub use compress::Compression;
+pub use downsample::DownsampleMode;
+pub use dvh::DVH_BINS;
+pub use expect::EXTENT_TOLERANCE;
+pub use fields::{FieldOptions, UncertMode};
+pub use gamma::{gamma, GammaCriteria, GammaNorm, GammaSummary};
+pub use geo::GEO_PERCENTILES;
+pub use interp::Interp;
+pub use profile::ProfileLine;
+pub use progress::Progress;
+pub use quality::{UncertaintyReport, UNCERT_THRESHOLDS};
+pub use rectilinear::{RectilinearCheck, RectilinearGrid};
+#[cfg(feature = "image")]
+pub use render::{Colormap, OrthosliceOptions, SliceImageOptions};
+pub use slice::{Slice2D, SlicePlane};
+pub use smooth::Kernel;
+pub use stats::{DoseInfo, DoseStats, SPACING_TOLERANCE};
+pub use stream::{
+    convert_3ddose_reader_to_msh, convert_3ddose_to_msh, convert_3ddose_to_msh_buffered,
+    TokenPosition,
+};
+pub use variant::DoseVariant;
+pub use voxel::Voxel;
+pub use vtk::VtkOptions;
+pub use writer::{
+    CsvWriter, DoseWriter, DvhWriter, Msh4Writer, MshWriter, VtiWriter, VtkWriter, VtrWriter,
+};
+
 /// Command line input parameters.
 #[derive(StructOpt, Debug)]
 #[structopt(name = "dose2gmsh", author = "Max Orok <maxwellorok@gmail.com>", about = "Convert dosxyznrc 3ddose files to Gmsh msh files")]
+#[structopt(setting = AppSettings::ArgsNegateSubcommands)]
 pub struct Cli {
     /// The input 3ddose file
     #[structopt(parse(from_os_str))]
-    pub input_file: std::path::PathBuf,
-    /// The output file name, defaults to <input_file>
+    pub input_file: Option<std::path::PathBuf>,
+    /// The output file name, defaults to <input_file>; - writes to stdout
     #[structopt(parse(from_os_str), short, long)]
     pub output_file: Option<std::path::PathBuf>,
-    /// The output format (msh2 or csv)
-    #[structopt(short, long, default_value = "msh2")]
-    pub format: Fmt
+    /// The output format (msh2, msh4, csv, raw, mhd, vtk, vtr, vti, dicom, npy or dvh)
+    /// [default: from the output file extension, else msh2]
+    #[structopt(short, long)]
+    pub format: Option<Fmt>,
+    /// Multiply dose by this factor, e.g. the number of histories for a per-history dose
+    #[structopt(long, value_name = "factor")]
+    pub scale: Option<f64>,
+    /// Normalize dose per monitor unit, delivering this many MU
+    #[structopt(long)]
+    pub mu: Option<f64>,
+    /// Calibration factor applied with --mu [default: 1.0]
+    #[structopt(long, requires = "mu")]
+    pub cal_factor: Option<f64>,
+    /// Divide dose by the dose of the voxel containing this point in cm, e.g. a prescription
+    /// point
+    #[structopt(
+        long,
+        value_name = "x,y,z",
+        conflicts_with = "mu",
+        parse(try_from_str = parse_triple)
+    )]
+    pub normalize_point: Option<[f64; 3]>,
+    /// Write dose rate instead of dose, dividing by this irradiation time in seconds
+    #[structopt(long)]
+    pub time: Option<f64>,
+    /// Fields to write, from dose, uncert, abs-uncert, log10, gradient, dose-low and dose-high
+    /// [default: dose,uncert]
+    #[structopt(name = "fields", long, value_name = "a,b,c", use_delimiter = true)]
+    pub select_fields: Vec<FieldKind>,
+    /// Write the uncertainty field as a fraction of the dose or in its units, fractional or
+    /// absolute
+    #[structopt(long, default_value = "fractional")]
+    pub uncert: UncertMode,
+    /// Extra field to write after the --fields, may be repeated (see --fields)
+    #[structopt(name = "field", long, number_of_values = 1)]
+    pub fields: Vec<FieldKind>,
+    /// Hexahedron order for msh output, 1 (8 nodes) or 2 (27 nodes)
+    #[structopt(long, default_value = "1")]
+    pub order: ElementOrder,
+    /// Name a physical volume holding all msh elements
+    #[structopt(long)]
+    pub phys_name: Option<String>,
+    /// Write 2D quadrilaterals instead of hexahedra for a grid one voxel thick along one axis
+    #[structopt(long, conflicts_with = "coalesce")]
+    pub flatten_to_2d: bool,
+    /// Write msh2 nodes, elements and data in binary, smaller and faster to load for large grids
+    #[structopt(long, conflicts_with = "coalesce")]
+    pub binary: bool,
+    /// Record a suggested dose display range between these percentiles in msh and vtk output
+    #[structopt(long, value_name = "lo,hi", parse(try_from_str = stats::parse_percentiles))]
+    pub display_range: Option<(f64, f64)>,
+    /// Merge boxes of voxels with doses within this fraction of the maximum into single msh
+    /// elements, e.g. 0 for equal doses only or 0.1%
+    #[structopt(long, value_name = "tol", parse(try_from_str = gamma::parse_percent))]
+    pub coalesce: Option<f64>,
+    /// Also write a Gmsh .geo script opening the msh output, with the dose colour range from
+    /// --display-range [default: 1,99]
+    #[structopt(long)]
+    pub emit_geo: bool,
+    /// Add the dose of another 3ddose file on the same grid times a weight, e.g. a beam of a plan
+    /// as beam2.3ddose:0.8; repeat for more files
+    #[structopt(long, value_name = "file:weight", number_of_values = 1)]
+    pub add: Vec<WeightedFile>,
+    /// Keep dose only inside a mask file of 0/1 values, in dose order (x fastest, then y, z)
+    #[structopt(long, parse(from_os_str))]
+    pub mask: Option<std::path::PathBuf>,
+    /// Replace the dose of voxels with a fractional uncertainty above this, e.g. 0.3 for 30 %
+    #[structopt(long, value_name = "frac")]
+    pub max_uncert: Option<f64>,
+    /// Dose given to voxels masked by --max-uncert, or nan to set them apart from zero dose
+    /// [default: 0]
+    #[structopt(long, value_name = "dose", requires = "max-uncert")]
+    pub uncert_fill: Option<f64>,
+    /// Show progress reading and writing files, only the totals when stderr isn't a terminal
+    /// [default: when stderr is a terminal]
+    #[structopt(long, conflicts_with = "quiet")]
+    pub progress: bool,
+    /// Show no progress, even on a terminal
+    #[structopt(long)]
+    pub quiet: bool,
+    /// Refuse to convert a file with NaN or infinite doses or uncertainties, rather than warning
+    #[structopt(long)]
+    pub strict: bool,
+    /// Refuse to convert grids with more voxels than this, unless --force is given
+    #[structopt(long)]
+    pub max_voxels: Option<u64>,
+    /// Refuse to write an output estimated to be larger than this many bytes, unless --force is given
+    #[structopt(long)]
+    pub max_output_bytes: Option<u64>,
+    /// Convert even if --max-voxels or --max-output-bytes are exceeded
+    #[structopt(long)]
+    pub force: bool,
+    /// Refuse to convert unless the grid has exactly this many voxels, e.g. 40x40x60
+    #[structopt(long, value_name = "NXxNYxNZ", parse(try_from_str = expect::parse_dims))]
+    pub expect_dims: Option<[usize; 3]>,
+    /// Refuse to convert unless the first and last nodes match, e.g. -10:10,-10:10,0:30 (cm)
+    #[structopt(long, value_name = "x0:x1,y0:y1,z0:z1", parse(try_from_str = expect::parse_extent))]
+    pub expect_extent: Option<[(f64, f64); 3]>,
+    /// Tolerance in cm for --expect-extent
+    #[structopt(long, default_value = "0.001")]
+    pub extent_tol: f64,
+    /// Crop to the voxels with centroids inside a box, e.g. -5:5,-5:5,0:10 (cm)
+    #[structopt(long, value_name = "x0:x1,y0:y1,z0:z1", parse(try_from_str = expect::parse_extent))]
+    pub crop: Option<[(f64, f64); 3]>,
+    /// Crop to the voxels around the dose, dropping surrounding air below --air-threshold
+    #[structopt(long)]
+    pub trim_air: bool,
+    /// Dose threshold for --trim-air, relative to the maximum dose
+    #[structopt(long, default_value = "1%", parse(try_from_str = gamma::parse_percent))]
+    pub air_threshold: f64,
+    /// Mirror the grid in x (x becomes -x), e.g. if the dose appears mirrored in a viewer
+    #[structopt(long)]
+    pub flip_x: bool,
+    /// Mirror the grid in y (y becomes -y)
+    #[structopt(long)]
+    pub flip_y: bool,
+    /// Mirror the grid in z (z becomes -z)
+    #[structopt(long)]
+    pub flip_z: bool,
+    /// Scale node coordinates by sx,sy,sz, scaling dose by sx*sy to match (see scale_coords)
+    #[structopt(long, value_name = "sx,sy,sz", parse(try_from_str = parse_triple))]
+    pub scale_coords: Option<[f64; 3]>,
+    /// Merge blocks of fx,fy,fz voxels into one, each factor dividing the voxel count
+    #[structopt(long, value_name = "fx,fy,fz", parse(try_from_str = parse_triple))]
+    pub downsample: Option<[usize; 3]>,
+    /// How --downsample combines voxels: sum, mean (volume-weighted) or ivw (inverse variance)
+    #[structopt(long, default_value = "mean")]
+    pub downsample_mode: DownsampleMode,
+    /// Smooth the dose for viewing, e.g. gaussian:0.2 or box:0.5 (sigma or radius in cm)
+    #[structopt(long)]
+    pub smooth: Option<Kernel>,
+    /// Add an element id column to csv output, numbered from 1 like msh elements
+    #[structopt(long)]
+    pub ids: bool,
+    /// Also write the uncertainty as a second MetaImage with mhd output
+    #[structopt(long)]
+    pub uncert_image: bool,
+    /// Voxel order of csv rows and raw values: x (x varies fastest, as in 3ddose files) or z (z fastest)
+    #[structopt(long, default_value = "x")]
+    pub voxel_order: Order,
+    /// Number csv element ids from 0 (implies --ids); msh ids always start from 1
+    #[structopt(long)]
+    pub zero_based: bool,
+    /// Dose floor for the log10 field, defaults to 1e-6 of the maximum dose
+    #[structopt(long)]
+    pub log_floor: Option<f64>,
+    /// Add dose-low and dose-high fields bounding dose * (1 -/+ k * uncert), e.g. 1.96 for 95 %
+    #[structopt(long, value_name = "k")]
+    pub ci: Option<f64>,
+    /// Clamp negative dose-low bounds to zero
+    #[structopt(long)]
+    pub clamp_ci: bool,
+    /// Print how well converged the dose is, from the uncertainties of the voxels with dose
+    #[structopt(long)]
+    pub report_uncertainty_quality: bool,
+    /// Print dose and uncertainty statistics to stderr before converting
+    #[structopt(long)]
+    pub stats: bool,
+    /// Print the statistics as --stats does, and write no output
+    #[structopt(long)]
+    pub stats_only: bool,
+    /// Print the integral dose, dose times voxel volume summed over the grid, to stderr
+    #[structopt(long)]
+    pub integral: bool,
+    /// Write a csv dose profile along a line instead of converting, e.g. z@0,0 for the z-axis at x = y = 0
+    #[structopt(long)]
+    pub profile: Option<ProfileLine>,
+    /// Evaluate the profile at its exact position, nearest or trilinear, rather than through the
+    /// nearest voxel centroids
+    #[structopt(long)]
+    pub interp: Option<Interp>,
+    /// Write one plane of voxels as csv instead of converting, e.g. z=20 for voxel index 20 in z
+    #[structopt(long, value_name = "axis=index", conflicts_with = "profile")]
+    pub slice: Option<SlicePlane>,
+    /// Convert to msh2 in a single pass without holding the dose in memory, for very large files
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "format", "mu", "time", "flip-x", "flip-y", "flip-z", "scale-coords", "downsample",
+            "downsample-mode", "add", "mask", "fields", "field", "order", "voxel-order",
+            "phys-name", "display-range", "emit-geo", "profile", "interp", "expect-dims", "expect-extent",
+            "trim-air", "smooth", "ci", "clamp-ci", "coalesce", "report-uncertainty-quality",
+            "flatten-to-2d", "binary", "stats", "stats-only", "crop", "slice",
+            "integral", "max-uncert", "uncert-fill", "strict",
+            "progress", "uncert", "normalize-point", "scale",
+        ]
+    )]
+    pub stream: bool,
+    /// Convert to msh2 holding the dose and uncertainty as f32, for half the memory; only the
+    /// dose, uncert and abs-uncert fields can be written
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "stream", "format", "mu", "time", "flip-x", "flip-y", "flip-z", "scale-coords",
+            "downsample", "downsample-mode", "add", "mask", "order", "voxel-order", "phys-name",
+            "display-range", "emit-geo", "profile", "interp", "expect-dims", "expect-extent",
+            "trim-air", "smooth", "ci", "clamp-ci", "coalesce", "report-uncertainty-quality",
+            "flatten-to-2d", "binary", "stats", "stats-only", "crop", "slice",
+            "integral", "max-uncert", "uncert-fill", "strict",
+            "progress", "normalize-point", "scale",
+        ]
+    )]
+    pub single_precision: bool,
+    /// Seed for random voxel sampling, so repeated runs pick the same voxels [default: 3141592653]
+    #[structopt(long, global = true)]
+    pub seed: Option<u64>,
+    /// Size in bytes of the buffers files are read and written through [default: 1048576]
+    #[structopt(long, global = true, value_name = "bytes", parse(try_from_str = parse_buffer_size))]
+    pub buffer_size: Option<usize>,
+    /// Read options from a TOML file of long option names and values, e.g. format = "vtk";
+    /// options given on the command line take precedence
+    #[cfg(feature = "config")]
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<std::path::PathBuf>,
+    /// Gzip text output (msh, csv, vtk, vtr, vti); implied by an output name ending in .gz
+    #[cfg(feature = "gzip")]
+    #[structopt(long, conflicts_with_all = &["stream", "single-precision"])]
+    pub compress: Option<Compression>,
+    /// Print the SHA-256 digest of the output file
+    #[cfg(feature = "hash")]
+    #[structopt(long)]
+    pub hash: bool,
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+impl Cli {
+    /// A usage error if `--stream` or `--single-precision`, which write plain msh2 to a file
+    /// of their own, are given an output they can't write: stdout for `--stream`, or a name
+    /// ending in `.gz`, which would otherwise be written uncompressed.
+    pub fn check_msh_only_output(&self) -> Result<(), structopt::clap::Error> {
+        use structopt::clap::{Error, ErrorKind};
+        let output_file = match &self.output_file {
+            Some(output_file) if self.stream || self.single_precision => output_file,
+            _ => return Ok(()),
+        };
+        let option = if self.stream { "--stream" } else { "--single-precision" };
+        if self.stream && output_file.as_os_str() == "-" {
+            return Err(Error::with_description(
+                "--stream can't write to stdout, give an output file name",
+                ErrorKind::ArgumentConflict,
+            ));
+        }
+        if output_file.extension().is_some_and(|ext| ext == "gz") {
+            return Err(Error::with_description(
+                &format!(
+                    "{} writes uncompressed msh, give an output file name without .gz",
+                    option
+                ),
+                ErrorKind::ArgumentConflict,
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Command line subcommands, used instead of converting an input file.
+// (no doc comment: structopt would use it as the `about` text for the whole app)
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// List the output formats with their file extensions
+    Formats,
+    /// Print a summary of a 3ddose file without writing any output
+    Info {
+        /// The input 3ddose file
+        #[structopt(parse(from_os_str))]
+        input_file: std::path::PathBuf,
+        /// Print the summary as a JSON object
+        #[cfg(feature = "serde")]
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Write dose and uncertainty pairs to csv for plotting uncertainty against dose
+    Scatter {
+        /// The input 3ddose file
+        #[structopt(parse(from_os_str))]
+        input_file: std::path::PathBuf,
+        /// The output file name, defaults to <input_file>_scatter.csv
+        #[structopt(parse(from_os_str), short, long)]
+        output_file: Option<std::path::PathBuf>,
+        /// Write a random sample of this many voxels instead of all of them
+        #[structopt(long)]
+        sample: Option<usize>,
+    },
+    /// Compare two 3ddose files on the same grid with the gamma index and print the pass rate
+    #[structopt(alias = "gamma")]
+    Compare {
+        #[structopt(flatten)]
+        files: CompareFiles,
+        /// Dose-difference tolerance, e.g. 3%
+        #[structopt(long, default_value = "3%", parse(try_from_str = gamma::parse_percent))]
+        dose_tol: f64,
+        /// Distance-to-agreement tolerance, e.g. 3mm or 0.3cm
+        #[structopt(long, default_value = "3mm", parse(try_from_str = gamma::parse_distance))]
+        dist_tol: f64,
+        /// Skip reference voxels below this percentage of the maximum reference dose
+        #[structopt(long, default_value = "10%", parse(try_from_str = gamma::parse_percent))]
+        threshold: f64,
+        /// Scale the dose tolerance by each voxel's reference dose instead of the maximum
+        #[structopt(long)]
+        local: bool,
+        /// Write the gamma map to this file, as msh, csv, vtk, vtr or vti by its extension
+        #[structopt(parse(from_os_str), short, long)]
+        output_file: Option<std::path::PathBuf>,
+    },
+    /// Sum independent runs on the same grid, e.g. the partial 3ddose files of a split job
+    Combine {
+        /// The 3ddose files to combine
+        #[structopt(parse(from_os_str), required = true)]
+        input_files: Vec<std::path::PathBuf>,
+        /// The output file name, defaults to <first input_file>_combined.msh
+        #[structopt(parse(from_os_str), short, long)]
+        output_file: Option<std::path::PathBuf>,
+        /// Average the doses instead of summing them, for runs each normalized per history
+        #[structopt(long)]
+        mean: bool,
+    },
+    /// Subtract the dose of one 3ddose file from another on the same grid and write it as msh
+    Diff {
+        /// The 3ddose file to subtract from
+        #[structopt(parse(from_os_str))]
+        minuend: std::path::PathBuf,
+        /// The 3ddose file to subtract
+        #[structopt(parse(from_os_str))]
+        subtrahend: std::path::PathBuf,
+        /// The output file name, defaults to <minuend>_diff.msh
+        #[structopt(parse(from_os_str), short, long)]
+        output_file: Option<std::path::PathBuf>,
+    },
+    /// Print a shell completion script for dose2gmsh, e.g. to save in bash-completion's directory
+    #[structopt(setting = AppSettings::Hidden)]
+    Completions {
+        /// The shell to complete in
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+    /// Write the xy, xz and yz slices through a voxel side by side as a colormapped PNG
+    #[cfg(feature = "image")]
+    Orthoslices {
+        /// The input 3ddose file
+        #[structopt(parse(from_os_str))]
+        input_file: std::path::PathBuf,
+        /// The output file name, defaults to <input_file>_orthoslices.png
+        #[structopt(parse(from_os_str), short, long)]
+        output_file: Option<std::path::PathBuf>,
+        /// Voxel indices i,j,k the slices pass through, defaults to the central voxel
+        #[structopt(long, value_name = "i,j,k", parse(try_from_str = parse_triple))]
+        at: Option<[usize; 3]>,
+        /// Colormap, gray, hot, jet or viridis
+        #[structopt(long, default_value = "jet")]
+        colormap: Colormap,
+        /// Width and height of each voxel in pixels
+        #[structopt(long, default_value = "4")]
+        pixels_per_voxel: usize,
+    },
+    /// Write one plane of voxels as a colormapped PNG, one pixel per voxel by default
+    #[cfg(feature = "image")]
+    SlicePng {
+        /// The input 3ddose file
+        #[structopt(parse(from_os_str))]
+        input_file: std::path::PathBuf,
+        /// The plane to draw, e.g. z=20 for voxel index 20 in z
+        #[structopt(value_name = "axis=index")]
+        plane: SlicePlane,
+        /// The output file name, defaults to <input_file>_slice.png
+        #[structopt(parse(from_os_str), short, long)]
+        output_file: Option<std::path::PathBuf>,
+        /// Colormap, gray, hot, jet or viridis
+        #[structopt(long, default_value = "jet")]
+        colormap: Colormap,
+        /// Doses at the ends of the colormap, defaults to the minimum and maximum of the slice
+        #[structopt(long, value_name = "lo,hi", parse(try_from_str = render::parse_dose_range))]
+        range: Option<(f64, f64)>,
+        /// Width and height of each voxel in pixels
+        #[structopt(long, default_value = "1")]
+        pixels_per_voxel: usize,
+    },
+}
+
+/// The reference and evaluated files of `compare`, each given by position or as an option, e.g.
+/// `gamma a.3ddose b.3ddose` or `gamma --reference a.3ddose --evaluated b.3ddose`.
+#[derive(Debug, StructOpt)]
+pub struct CompareFiles {
+    /// The reference 3ddose file
+    #[structopt(parse(from_os_str))]
+    reference: Option<std::path::PathBuf>,
+    /// The 3ddose file to evaluate against the reference
+    #[structopt(parse(from_os_str))]
+    evaluated: Option<std::path::PathBuf>,
+    /// The reference 3ddose file, instead of giving it first
+    #[structopt(long = "reference", value_name = "file", parse(from_os_str))]
+    reference_file: Option<std::path::PathBuf>,
+    /// The 3ddose file to evaluate, instead of giving it after the reference
+    #[structopt(long = "evaluated", value_name = "file", parse(from_os_str))]
+    evaluated_file: Option<std::path::PathBuf>,
+}
+
+impl CompareFiles {
+    /// The reference and evaluated files, with files given by position filling in, in order,
+    /// whichever of `--reference` and `--evaluated` is missing.
+    ///
+    /// A missing file, or a positional file left over, is a usage error.
+    pub fn paths(self) -> Result<(std::path::PathBuf, std::path::PathBuf), structopt::clap::Error> {
+        use structopt::clap::{Error, ErrorKind};
+        let mut positional = self.reference.into_iter().chain(self.evaluated);
+        let reference = self.reference_file.or_else(|| positional.next());
+        let evaluated = self.evaluated_file.or_else(|| positional.next());
+        match (reference, evaluated, positional.next()) {
+            (Some(reference), Some(evaluated), None) => Ok((reference, evaluated)),
+            (_, _, Some(extra)) => Err(Error::with_description(
+                &format!(
+                    "{} is one file too many, the reference and evaluated files are already given",
+                    extra.display()
+                ),
+                ErrorKind::UnknownArgument,
+            )),
+            _ => Err(Error::with_description(
+                "compare needs a reference and an evaluated file, by position or with --reference \
+                 and --evaluated",
+                ErrorKind::MissingRequiredArgument,
+            )),
+        }
+    }
 }
 
 /// Converter output format
@@ -29,6 +525,24 @@ pub struct Cli {
 pub enum Fmt {
     Csv,
     Msh2,
+    /// Gmsh mesh version 4.1, see [`DoseBlock::write_msh4`].
+    Msh4,
+    /// Binary dose and uncertainty volumes with a text header, see [`DoseBlock::write_raw`].
+    Raw,
+    /// MetaImage header and binary dose volume, see [`DoseBlock::write_mhd`].
+    Mhd,
+    /// Legacy VTK rectilinear grid, see [`DoseBlock::write_vtk`].
+    Vtk,
+    /// VTK XML rectilinear grid, see [`DoseBlock::write_vtr`].
+    Vtr,
+    /// VTK XML image data for uniform grids, see [`DoseBlock::write_vti`].
+    Vti,
+    /// DICOM RT Dose, see [`DoseBlock::write_dicom_rtdose`].
+    Dicom,
+    /// NumPy arrays of the dose, uncertainty and node coordinates, see [`DoseBlock::write_npy`].
+    Npy,
+    /// A cumulative dose-volume histogram as csv, see [`DoseBlock::write_dvh_fields_to`].
+    Dvh,
 }
 
 impl std::str::FromStr for Fmt {
@@ -37,11 +551,343 @@ impl std::str::FromStr for Fmt {
         match fmt {
             "csv" => Ok(Fmt::Csv),
             "msh2" => Ok(Fmt::Msh2),
+            "msh4" => Ok(Fmt::Msh4),
+            "raw" => Ok(Fmt::Raw),
+            "mhd" => Ok(Fmt::Mhd),
+            "vtk" => Ok(Fmt::Vtk),
+            "vtr" => Ok(Fmt::Vtr),
+            "vti" => Ok(Fmt::Vti),
+            "dicom" => Ok(Fmt::Dicom),
+            "npy" => Ok(Fmt::Npy),
+            "dvh" => Ok(Fmt::Dvh),
             _ => Err("Could not parse the format".to_string()),
         }
     }
 }
 
+/// Per-voxel values that can be written as output fields, see [`DoseBlock::field_values`].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FieldKind {
+    /// Dose, or dose rate after [`DoseBlock::to_rate`].
+    Dose,
+    /// Fractional uncertainty.
+    Uncert,
+    /// Absolute uncertainty in the units of the dose, see [`DoseBlock::abs_uncerts`].
+    AbsUncert,
+    /// Base 10 logarithm of the dose, see [`DoseBlock::log_dose`].
+    Log10,
+    /// Magnitude of the dose gradient, see [`DoseBlock::dose_gradient`].
+    Gradient,
+    /// Lower confidence bound on the dose, see [`DoseBlock::confidence_bounds`].
+    DoseLow,
+    /// Upper confidence bound on the dose, see [`DoseBlock::confidence_bounds`].
+    DoseHigh,
+}
+
+impl FieldKind {
+    /// Field name including units, as written by each format.
+    pub fn label(self, format: Fmt, quantity: Quantity) -> &'static str {
+        let msh = matches!(format, Fmt::Msh2 | Fmt::Msh4);
+        match (self, quantity, msh) {
+            (FieldKind::Dose, _, _) => quantity.label(format),
+            (FieldKind::Uncert, _, _) => "Uncertainty fraction",
+            (FieldKind::AbsUncert, Quantity::Dose, true) => "Uncertainty [Gy·cm2]",
+            (FieldKind::AbsUncert, Quantity::Dose, false) => "Uncertainty [Gy cm2]",
+            (FieldKind::AbsUncert, Quantity::DoseRate, true) => "Uncertainty [Gy·cm2/s]",
+            (FieldKind::AbsUncert, Quantity::DoseRate, false) => "Uncertainty [Gy cm2/s]",
+            (FieldKind::Log10, Quantity::Dose, true) => "log10 Dose [Gy·cm2]",
+            (FieldKind::Log10, Quantity::Dose, false) => "log10 Dose [Gy cm2]",
+            (FieldKind::Log10, Quantity::DoseRate, true) => "log10 Dose rate [Gy·cm2/s]",
+            (FieldKind::Log10, Quantity::DoseRate, false) => "log10 Dose rate [Gy cm2/s]",
+            (FieldKind::Gradient, Quantity::Dose, true) => "Dose gradient [Gy·cm]",
+            (FieldKind::Gradient, Quantity::Dose, false) => "Dose gradient [Gy cm]",
+            (FieldKind::Gradient, Quantity::DoseRate, true) => "Dose rate gradient [Gy·cm/s]",
+            (FieldKind::Gradient, Quantity::DoseRate, false) => "Dose rate gradient [Gy cm/s]",
+            (FieldKind::DoseLow, Quantity::Dose, true) => "Dose low [Gy·cm2]",
+            (FieldKind::DoseLow, Quantity::Dose, false) => "Dose low [Gy cm2]",
+            (FieldKind::DoseLow, Quantity::DoseRate, true) => "Dose rate low [Gy·cm2/s]",
+            (FieldKind::DoseLow, Quantity::DoseRate, false) => "Dose rate low [Gy cm2/s]",
+            (FieldKind::DoseHigh, Quantity::Dose, true) => "Dose high [Gy·cm2]",
+            (FieldKind::DoseHigh, Quantity::Dose, false) => "Dose high [Gy cm2]",
+            (FieldKind::DoseHigh, Quantity::DoseRate, true) => "Dose rate high [Gy·cm2/s]",
+            (FieldKind::DoseHigh, Quantity::DoseRate, false) => "Dose rate high [Gy cm2/s]",
+        }
+    }
+}
+
+impl std::str::FromStr for FieldKind {
+    type Err = String;
+    fn from_str(field: &str) -> Result<Self, Self::Err> {
+        match field {
+            "dose" => Ok(FieldKind::Dose),
+            "uncert" => Ok(FieldKind::Uncert),
+            "abs-uncert" => Ok(FieldKind::AbsUncert),
+            "log10" => Ok(FieldKind::Log10),
+            "gradient" => Ok(FieldKind::Gradient),
+            "dose-low" => Ok(FieldKind::DoseLow),
+            "dose-high" => Ok(FieldKind::DoseHigh),
+            _ => Err(format!(
+                "Unknown field {}, expected dose, uncert, abs-uncert, log10, gradient, dose-low \
+                 or dose-high",
+                field
+            )),
+        }
+    }
+}
+
+/// What the `doses` of a block hold, for labelling output fields.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Quantity {
+    /// Dose, as read from a `3ddose` file.
+    Dose,
+    /// Dose per second, see [`DoseBlock::to_rate`].
+    DoseRate,
+}
+
+impl Quantity {
+    /// Field name including units, as written by each format.
+    pub fn label(self, format: Fmt) -> &'static str {
+        match (self, format) {
+            (Quantity::Dose, Fmt::Msh2 | Fmt::Msh4) => "Dose [Gy·cm2]",
+            (Quantity::Dose, _) => "Dose [Gy cm2]",
+            (Quantity::DoseRate, Fmt::Msh2 | Fmt::Msh4) => "Dose rate [Gy·cm2/s]",
+            (Quantity::DoseRate, _) => "Dose rate [Gy cm2/s]",
+        }
+    }
+}
+
+/// A named value per voxel, in the same order as `doses`, for writing to an output file.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Field<'a> {
+    /// Label written to the output file, including units.
+    pub name: &'a str,
+    /// One value per voxel.
+    pub values: &'a [f64],
+}
+
+/// One of the three grid axes.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Axis {
+    X = 0,
+    Y = 1,
+    Z = 2,
+}
+
+impl Axis {
+    /// The other two axes, in *x*, *y*, *z* order.
+    pub fn transverse(self) -> (Axis, Axis) {
+        match self {
+            Axis::X => (Axis::Y, Axis::Z),
+            Axis::Y => (Axis::X, Axis::Z),
+            Axis::Z => (Axis::X, Axis::Y),
+        }
+    }
+}
+
+impl std::str::FromStr for Axis {
+    type Err = String;
+    fn from_str(axis: &str) -> Result<Self, Self::Err> {
+        match axis {
+            "x" => Ok(Axis::X),
+            "y" => Ok(Axis::Y),
+            "z" => Ok(Axis::Z),
+            _ => Err(format!("Unknown axis {}, expected x, y or z", axis)),
+        }
+    }
+}
+
+/// Options for the Gmsh `.msh` writers.
+#[derive(Debug, Clone, Default)]
+pub struct MshOptions {
+    /// Put every element in a physical volume with this name, written to `$PhysicalNames`.
+    ///
+    /// Gmsh uses physical groups to select and label parts of a mesh. Names can't contain
+    /// double quotes.
+    pub physical_name: Option<String>,
+    /// Write linear 8-node or quadratic 27-node hexahedra.
+    pub element_order: ElementOrder,
+    /// Suggested dose display range, written as `dose_display_range = lo hi` to a
+    /// `$Comments` section that Gmsh skips, e.g. from [`DoseBlock::percentile_range`].
+    pub display_range: Option<(f64, f64)>,
+    /// Write 4-node quadrilaterals, Gmsh element type 3, instead of hexahedra for a grid one
+    /// voxel thick along one axis, see [`DoseBlock::write_msh2_quad4`]. Only linear elements
+    /// can be flattened.
+    pub flatten_2d: bool,
+    /// Write nodes, elements and element data as binary records, see
+    /// [`DoseBlock::write_msh2_binary_fields_to`]. Only version 2.2 output is written in binary.
+    pub binary: bool,
+}
+
+/// Hexahedron order for Gmsh output.
+///
+/// Dose is per voxel either way. Quadratic elements add nodes at the middle of each voxel
+/// edge, face and volume for finite element tools that want a higher-order mesh.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum ElementOrder {
+    /// 8-node hexahedra, Gmsh element type 5.
+    #[default]
+    Linear,
+    /// 27-node hexahedra, Gmsh element type 12.
+    Quadratic,
+}
+
+impl std::str::FromStr for ElementOrder {
+    type Err = String;
+    fn from_str(order: &str) -> Result<Self, Self::Err> {
+        match order {
+            "1" => Ok(ElementOrder::Linear),
+            "2" => Ok(ElementOrder::Quadratic),
+            _ => Err("Element order must be 1 or 2".to_string()),
+        }
+    }
+}
+
+/// Options for the `csv` writers.
+#[derive(Debug, Clone, Default)]
+pub struct CsvOptions {
+    /// Add a leading `id` column numbering voxels in `doses` order, starting from this value.
+    ///
+    /// Use `1` to match the element numbers in Gmsh output, which must start from 1. Ids
+    /// follow the native voxel order whatever `order` is.
+    pub id_base: Option<usize>,
+    /// Order to write voxel rows in.
+    pub order: Order,
+}
+
+/// Traversal order of voxels in a flat array of per-voxel values.
+///
+/// `3ddose` files and [`DoseBlock::doses`] always use [`Order::XFastest`]; other orders are for
+/// exchanging data with tools that expect a different memory layout.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum Order {
+    /// *x* varies fastest, then *y*, then *z*, the native `3ddose` (Fortran-style) order:
+    /// `for k in 0..nz { for j in 0..ny { for i in 0..nx { .. } } }`.
+    #[default]
+    XFastest,
+    /// *z* varies fastest, then *y*, then *x*, i.e. C order for an `[nx][ny][nz]` array:
+    /// `for i in 0..nx { for j in 0..ny { for k in 0..nz { .. } } }`.
+    ZFastest,
+}
+
+impl std::str::FromStr for Order {
+    type Err = String;
+    fn from_str(order: &str) -> Result<Self, Self::Err> {
+        match order {
+            "x" => Ok(Order::XFastest),
+            "z" => Ok(Order::ZFastest),
+            _ => Err(format!("Unknown voxel order {}, expected x or z (the fastest varying axis)", order)),
+        }
+    }
+}
+
+/// Errors from reading, transforming, or writing dose data.
+#[derive(Debug)]
+pub enum DoseError {
+    /// Reading or writing a file failed.
+    Io(std::io::Error),
+    /// A numeric parameter was outside its valid range.
+    InvalidParameter { name: &'static str, value: f64 },
+    /// Two dose blocks were combined but don't share the same voxel grid, with a line per
+    /// axis saying where they differ, see [`DoseBlock::describe_mismatch`].
+    MismatchedGrid { details: String },
+    /// The output would exceed a size limit.
+    TooLarge { what: &'static str, size: u64, limit: u64 },
+    /// Per-voxel data had the wrong number of values.
+    LengthMismatch { field: &'static str, expected: usize, found: usize },
+    /// The output format needs evenly spaced voxels along each axis, but the grid isn't.
+    NonUniformGrid,
+    /// A value in an input file couldn't be parsed.
+    InvalidToken { field: &'static str, token: String, at: TokenPosition },
+    /// An input file ended partway through a record, before its last `field` value.
+    UnexpectedEof { field: &'static str },
+    /// The grid doesn't have the voxel counts or extent it was expected to have.
+    UnexpectedGrid { expected: String, found: String },
+    /// The input has no voxels, being empty or having a voxel count of zero.
+    EmptyGrid,
+    /// Imported voxel centroids don't lie on a rectilinear grid, see [`RectilinearCheck`].
+    NotRectilinear { details: String },
+    /// Node coordinates along `axis` aren't strictly increasing at node `index`, which would
+    /// give degenerate or inverted voxels.
+    NonMonotonic { axis: Axis, index: usize },
+    /// The voxel counts at the start of a block, ending `at`, give more voxels or nodes than
+    /// can be indexed.
+    TooManyVoxels { counts: [usize; 3], at: TokenPosition },
+    /// The `field` of voxel `index`, in `doses` order, is `NaN` or infinite.
+    NonFinite { field: &'static str, index: usize },
+    /// A point in `[cm]` lies outside the voxel grid.
+    OutsideGrid { point: [f64; 3] },
+    /// Block `block` of a multi-block file, counting from one, ended before its last `field`
+    /// value, with the last value read `at`, see [`DoseBlock::from_3d_dose_multi`].
+    TruncatedBlock { block: usize, field: &'static str, at: TokenPosition },
+}
+
+impl std::fmt::Display for DoseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            // the io::Error itself is the source
+            DoseError::Io(_) => write!(f, "input/output error"),
+            DoseError::InvalidParameter { name, value } => write!(f, "invalid {}: {}", name, value),
+            DoseError::MismatchedGrid { details } => {
+                write!(f, "the dose blocks don't share a voxel grid")?;
+                details.lines().try_for_each(|line| write!(f, "\n  {}", line))
+            }
+            DoseError::TooLarge { what, size, limit } => {
+                write!(f, "too many {}: {}, the limit is {}", what, size, limit)
+            }
+            DoseError::LengthMismatch { field, expected, found } => {
+                write!(f, "wrong length for {}: expected {}, found {}", field, expected, found)
+            }
+            DoseError::NonUniformGrid => write!(f, "the voxels aren't evenly spaced along every axis"),
+            DoseError::InvalidToken { field, token, at } => {
+                write!(f, "could not parse {:?} as a {} at {}", token, field, at)
+            }
+            DoseError::UnexpectedEof { field } => {
+                write!(f, "the file ended before the last {}", field)
+            }
+            DoseError::UnexpectedGrid { expected, found } => {
+                write!(f, "expected {}, found {}", expected, found)
+            }
+            DoseError::EmptyGrid => write!(f, "the dose grid has no voxels"),
+            DoseError::NotRectilinear { details } => {
+                write!(f, "the voxel centroids don't form a rectilinear grid: {}", details)
+            }
+            DoseError::NonMonotonic { axis, index } => write!(
+                f,
+                "{}-coordinates are not strictly increasing at node {}",
+                ["x", "y", "z"][*axis as usize],
+                index
+            ),
+            DoseError::TooManyVoxels { counts: [x, y, z], at } => {
+                write!(f, "{} x {} x {} voxels at {} are too many to address", x, y, z, at)
+            }
+            DoseError::NonFinite { field, index } => {
+                write!(f, "non-finite {} at voxel {}", field, index)
+            }
+            DoseError::OutsideGrid { point: [x, y, z] } => {
+                write!(f, "the point ({}, {}, {}) cm is outside the dose grid", x, y, z)
+            }
+            DoseError::TruncatedBlock { block, field, at } => {
+                write!(f, "block {} ended before its last {}, after {}", block, field, at)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DoseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DoseError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DoseError {
+    fn from(err: std::io::Error) -> Self {
+        DoseError::Io(err)
+    }
+}
+
 /// Dose and uncertainty data for a 3D rectilinear hexahedral mesh.
 ///
 /// ## Units
@@ -51,6 +897,11 @@ impl std::str::FromStr for Fmt {
 /// * Number of voxels can vary in each direction.
 /// * Number of nodes in each direction is `num_voxels + 1`.
 ///
+/// Doses and uncertainties are `f64` unless the block is read with
+/// [`DoseBlock::from_3d_dose_f32`], which halves their memory for very large grids, as the
+/// `--single-precision` switch does. Everything besides the grid queries and
+/// [`DoseBlock::write_msh2_f32`] needs `f64` values, see [`DoseBlock::to_f64`].
+///
 /// ```no_run
 /// //             ------------
 /// //             |\         |\
@@ -65,7 +916,7 @@ impl std::str::FromStr for Fmt {
 /// // this example uses a 40 x 40 x 40 dose block
 /// # use dose2gmsh::DoseBlock;
 /// # use std::path::PathBuf;
-/// # fn main() -> Result<(), std::io::Error> {
+/// # fn main() -> Result<(), dose2gmsh::DoseError> {
 /// # let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
 /// # path.push("props");
 /// # path.push("water_block.3ddose");
@@ -86,8 +937,9 @@ impl std::str::FromStr for Fmt {
 /// # Ok(())
 /// # }
 /// ```
-#[derive(Debug, Clone)]
-pub struct DoseBlock {
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DoseBlock<F = f64> {
     /// Node coordinates along *x* in `[cm]`.
     pub xs: Vec<f64>,
     /// Node coordinates along *y* in `[cm]`.
@@ -95,80 +947,28 @@ pub struct DoseBlock {
     /// Node coordinates along *z* in `[cm]`.
     pub zs: Vec<f64>,
     /// Unnormalized voxel dose per fluence data in `[Gy · cm2]`.
-    pub doses: Vec<f64>,
+    pub doses: Vec<F>,
     /// Fractional dose uncertainties.
-    pub uncerts: Vec<f64>,
+    pub uncerts: Vec<F>,
+    /// Layout of the `3ddose` file this block was read from, `None` for blocks built in memory,
+    /// including new blocks computed from others. Informational only, see [`DoseVariant`].
+    pub variant: Option<DoseVariant>,
 }
 
-impl DoseBlock {
-    /// Create a new `DoseBlock` by parsing a `3ddose` data file.
-    pub fn from_3d_dose<P: AsRef<std::path::Path>>(input_file: P) -> Result<DoseBlock, std::io::Error> {
-        let dose_input = BufReader::new(File::open(input_file)?);
-
-        let mut lines = dose_input.lines().map(|l| l.unwrap());
-        // first line is number of x, y, z voxels
-        let (num_x, num_y, num_z) = {
-            let voxel_nums = lines.next().expect("voxel numbers");
-            let voxel_nums = parse_simple_line::<usize>(voxel_nums, "voxel number", 3);
-            (voxel_nums[0], voxel_nums[1], voxel_nums[2])
-        };
-
-        // second line is x-coordinates
-        let xs = parse_simple_line::<f64>(
-            lines.next().expect("x-coordinates"),
-            "x-coordinate",
-            num_x + 1,
-        );
-
-        // third is y-coordinates
-        let ys = parse_simple_line::<f64>(
-            lines.next().expect("y-coordinates"),
-            "y-coordinate",
-            num_y + 1,
-        );
-
-        // fourth is z-coordinates
-        let zs = parse_simple_line::<f64>(
-            lines.next().expect("z-coordinates"),
-            "z-coordinate",
-            num_z + 1,
-        );
-
-        let num_voxels = num_x * num_y * num_z;
-
-        // fifth is deposited dose
-        let doses = parse_simple_line::<f64>(lines.next().expect("doses"), "dose value", num_voxels);
-
-        // sixth is uncertainty values
-        let uncerts = parse_simple_line::<f64>(
-            lines.next().expect("uncerts"),
-            "uncertainty value",
-            num_voxels,
-        );
-
-        Ok(DoseBlock {
-            xs,
-            ys,
-            zs,
-            doses,
-            uncerts,
-        })
-
-    }
-
+impl<F> DoseBlock<F> {
     /// Number of voxels in the *x*-direction.
     pub fn num_x(&self) -> usize {
-        self.xs.len() - 1
+        self.xs.len().saturating_sub(1)
     }
 
     /// Number of voxels in the *y*-direction.
     pub fn num_y(&self) -> usize {
-        self.ys.len() - 1
+        self.ys.len().saturating_sub(1)
     }
 
     /// Number of voxels in the *z*-direction.
     pub fn num_z(&self) -> usize {
-        self.zs.len() - 1
+        self.zs.len().saturating_sub(1)
     }
 
     /// Total number of mesh voxels.
@@ -181,45 +981,428 @@ impl DoseBlock {
         self.xs.len() * self.ys.len() * self.zs.len()
     }
 
+    /// Node coordinates along one axis in `[cm]`.
+    pub fn nodes(&self, axis: Axis) -> &[f64] {
+        match axis {
+            Axis::X => &self.xs,
+            Axis::Y => &self.ys,
+            Axis::Z => &self.zs,
+        }
+    }
+
+    /// `[i, j, k]` voxel list indexing, matching the order of `doses` and `uncerts`.
+    pub fn voxel_index(&self, i: usize, j: usize, k: usize) -> usize {
+        i + self.num_x() * j + self.num_x() * self.num_y() * k
+    }
+
+    /// Inverse of [`DoseBlock::voxel_index`], giving the `(i, j, k)` voxel for an index into `doses`.
+    pub fn voxel_ijk(&self, index: usize) -> (usize, usize, usize) {
+        let layer = self.num_x() * self.num_y();
+        (index % self.num_x(), (index % layer) / self.num_x(), index / layer)
+    }
+}
+
+impl DoseBlock {
+    /// Build a block in memory from node coordinates along each axis, then a dose and an
+    /// uncertainty per voxel with *x* varying fastest, then *y*, then *z*.
+    ///
+    /// Fails with [`DoseError::EmptyGrid`] if an axis has fewer than two nodes,
+    /// [`DoseError::LengthMismatch`] naming `dose value` or `uncertainty value` if there isn't
+    /// one per voxel, and [`DoseError::NonMonotonic`] if the nodes along an axis aren't
+    /// strictly increasing.
+    pub fn new(
+        xs: Vec<f64>,
+        ys: Vec<f64>,
+        zs: Vec<f64>,
+        doses: Vec<f64>,
+        uncerts: Vec<f64>,
+    ) -> Result<DoseBlock, DoseError> {
+        if xs.len() < 2 || ys.len() < 2 || zs.len() < 2 {
+            return Err(DoseError::EmptyGrid);
+        }
+        let block = DoseBlock { xs, ys, zs, doses, uncerts, variant: None };
+        let records = [("dose value", &block.doses), ("uncertainty value", &block.uncerts)];
+        for (field, values) in records {
+            if values.len() != block.num_voxels() {
+                return Err(DoseError::LengthMismatch {
+                    field,
+                    expected: block.num_voxels(),
+                    found: values.len(),
+                });
+            }
+        }
+        block.check_monotonic()?;
+        Ok(block)
+    }
+
+    /// Create a new `DoseBlock` by parsing a `3ddose` data file.
+    ///
+    /// Values are read as whitespace-separated tokens, so records can be on one line each, the
+    /// [`DoseVariant::Classic`] layout, or wrapped over many lines as DOSXYZnrc does for large
+    /// grids, the [`DoseVariant::Wrapped`] layout. Anything after the last uncertainty is
+    /// ignored. An empty file or one with zero voxels along an axis is a
+    /// [`DoseError::EmptyGrid`] error, and node coordinates that aren't strictly increasing
+    /// are a [`DoseError::NonMonotonic`] error.
+    ///
+    /// Gzip-compressed files, e.g. `run.3ddose.gz`, are recognized by their first bytes and
+    /// decompressed as they're read under the `gzip` feature.
+    pub fn from_3d_dose<P: AsRef<std::path::Path>>(input_file: P) -> Result<DoseBlock, DoseError> {
+        DoseBlock::from_3d_dose_buffered(input_file, DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Parse a `3ddose` data file as [`DoseBlock::from_3d_dose`] does, reading through a buffer
+    /// of `buffer_size` bytes rather than [`DEFAULT_BUFFER_SIZE`].
+    ///
+    /// A zero `buffer_size` is a [`DoseError::InvalidParameter`] error.
+    pub fn from_3d_dose_buffered<P: AsRef<std::path::Path>>(
+        input_file: P,
+        buffer_size: usize,
+    ) -> Result<DoseBlock, DoseError> {
+        DoseBlock::from_reader(open_3ddose(input_file, buffer_size)?)
+    }
+
+    /// Parse `3ddose` data from any buffered reader, e.g. an in-memory buffer or a decompressed
+    /// stream, as [`DoseBlock::from_3d_dose`] parses a file.
+    ///
+    /// The input is read once from start to end, so it needn't be seekable. Reading stops after
+    /// the last uncertainty value.
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<DoseBlock, DoseError> {
+        match multi::read_block(&mut stream::Tokens::new(reader))? {
+            Some(block) => Ok(block),
+            None => Err(DoseError::EmptyGrid),
+        }
+    }
+
+    /// Rough size in bytes of this block written in `format` with `num_fields` fields.
+    ///
+    /// Assumes every number is printed at full precision, so it's usually an overestimate.
+    /// Meant for guarding against accidentally huge outputs, not for preallocation.
+    pub fn estimated_output_bytes(&self, format: Fmt, num_fields: usize) -> u64 {
+        // widest f64 `{}` output is around 24 characters (e.g. -0.00000000000012345678901234)
+        // but typical values are closer to 20, plus a separator
+        const NUMBER: u64 = 21;
+        let id = self.num_nodes().to_string().len() as u64 + 1;
+        let (nodes, voxels, fields) =
+            (self.num_nodes() as u64, self.num_voxels() as u64, num_fields as u64);
+        match format {
+            Fmt::Csv => voxels * (3 + fields) * NUMBER,
+            // node: id and 3 coordinates
+            // element: id, 4 tags and 8 node ids
+            // data: id and value per voxel, per field
+            // version 4.1 has the same numbers, without element types and tags
+            Fmt::Msh2 | Fmt::Msh4 => {
+                nodes * (id + 3 * NUMBER) + voxels * (9 * id + 8) + fields * voxels * (id + NUMBER)
+            }
+            // dose and uncertainty only, plus node coordinates in the header
+            Fmt::Raw => 2 * 8 * voxels + (self.xs.len() + self.ys.len() + self.zs.len()) as u64 * NUMBER,
+            // dose and at most the uncertainty, the headers are tiny
+            Fmt::Mhd => 2 * 8 * voxels,
+            // 4-byte pixels, a header and a frame offset per slice
+            Fmt::Dicom => 4 * voxels + 1024 + self.zs.len() as u64 * NUMBER,
+            // dose and uncertainty, node coordinates and 64-byte headers
+            Fmt::Npy => 2 * 8 * voxels + 8 * (self.xs.len() + self.ys.len() + self.zs.len()) as u64,
+            // a level and a fraction per bin
+            Fmt::Dvh => (DVH_BINS as u64 + 2) * 2 * NUMBER,
+            // node coordinates per axis, then one value per voxel per field
+            Fmt::Vtk | Fmt::Vtr | Fmt::Vti => {
+                (self.xs.len() + self.ys.len() + self.zs.len()) as u64 * NUMBER + fields * voxels * NUMBER
+            }
+        }
+    }
+
     /// `[i, j, k]` node list indexing.
     pub fn grid_index(&self, i: usize, j: usize, k: usize) -> usize {
         i + self.xs.len() * j + self.xs.len() * self.ys.len() * k
     }
 
+    /// Multiply every dose by `factor`, e.g. the number of simulated histories for a dose
+    /// tallied per history.
+    ///
+    /// Fractional uncertainties are unchanged by the constant scale. Nothing else changes
+    /// either: field names keep their `[Gy · cm2]` units, and any other step towards absolute
+    /// dose, such as dividing out the voxel mass of an energy tally, is left to the caller.
+    pub fn scale_dose(&mut self, factor: f64) {
+        self.doses.iter_mut().for_each(|dose| *dose *= factor);
+    }
+
+    /// Normalize the dose to dose per monitor unit (MU).
+    ///
+    /// Each dose is replaced by `dose * cal_factor / mu`. The calibration factor converts the
+    /// simulated dose per incident particle into absolute dose for `mu` monitor units, so with
+    /// `cal_factor` in `[cm-2]` (particles per unit area delivered for `mu` MU) the result is in
+    /// `[Gy / MU]`. Fractional uncertainties are unchanged by the constant scale.
+    ///
+    /// `mu` must be positive and finite, and `cal_factor` finite.
+    pub fn normalize_per_mu(&mut self, mu: f64, cal_factor: f64) -> Result<(), DoseError> {
+        if !(mu.is_finite() && mu > 0.0) {
+            return Err(DoseError::InvalidParameter { name: "mu", value: mu });
+        }
+        if !cal_factor.is_finite() {
+            return Err(DoseError::InvalidParameter { name: "cal_factor", value: cal_factor });
+        }
+        let scale = cal_factor / mu;
+        self.doses.iter_mut().for_each(|dose| *dose *= scale);
+        Ok(())
+    }
+
+    /// Convert dose to dose rate, dividing by the irradiation time in `[s]`.
+    ///
+    /// Fractional uncertainties are unchanged. Label the result with [`Quantity::DoseRate`] when
+    /// writing it out. `seconds` must be positive and finite.
+    pub fn to_rate(&mut self, seconds: f64) -> Result<(), DoseError> {
+        if !(seconds.is_finite() && seconds > 0.0) {
+            return Err(DoseError::InvalidParameter { name: "seconds", value: seconds });
+        }
+        self.doses.iter_mut().for_each(|dose| *dose /= seconds);
+        Ok(())
+    }
+
+    /// Mirror the grid through the plane where the `axis` coordinate is zero, e.g. to match a
+    /// viewer whose axis points the other way.
+    ///
+    /// Node coordinates along `axis` are negated and reversed so they still increase, and dose
+    /// and uncertainty are re-indexed to match, so voxel `i` along the axis becomes voxel
+    /// `n - 1 - i`. Flipping the same axis twice gives back the original block exactly.
+    pub fn flip_axis(&mut self, axis: Axis) {
+        let nodes = match axis {
+            Axis::X => &mut self.xs,
+            Axis::Y => &mut self.ys,
+            Axis::Z => &mut self.zs,
+        };
+        nodes.reverse();
+        // `0.0 - x` rather than `-x` so a node at zero doesn't become -0
+        nodes.iter_mut().for_each(|x| *x = 0.0 - *x);
+
+        let flipped = |index: usize| {
+            let (i, j, k) = self.voxel_ijk(index);
+            match axis {
+                Axis::X => self.voxel_index(self.num_x() - 1 - i, j, k),
+                Axis::Y => self.voxel_index(i, self.num_y() - 1 - j, k),
+                Axis::Z => self.voxel_index(i, j, self.num_z() - 1 - k),
+            }
+        };
+        let doses = (0..self.num_voxels()).map(|index| self.doses[flipped(index)]).collect();
+        let uncerts = (0..self.num_voxels()).map(|index| self.uncerts[flipped(index)]).collect();
+        self.doses = doses;
+        self.uncerts = uncerts;
+    }
+
+    /// Scale the node coordinates along *x*, *y* and *z* by `sx`, `sy` and `sz`, e.g. to correct
+    /// an aspect ratio or apply a magnification.
+    ///
+    /// `3ddose` doses are per unit incident fluence, i.e. per particle per `[cm2]` of the *xy*
+    /// plane transverse to the DOSXYZnrc beam along *z*, hence the `[Gy cm2]` units. Stretching
+    /// that plane by `sx * sy` spreads the same particles over that much more area, so to keep
+    /// the dose per voxel the dose per unit fluence must grow by the same factor, and doses are
+    /// multiplied by `sx * sy`. `sz` only moves the depth of each voxel and leaves the dose
+    /// alone. Fractional uncertainties are unchanged.
+    ///
+    /// Every scale must be positive and finite; use [`DoseBlock::flip_axis`] to mirror an axis.
+    pub fn scale_coords(&mut self, sx: f64, sy: f64, sz: f64) -> Result<(), DoseError> {
+        for &(name, scale) in &[("sx", sx), ("sy", sy), ("sz", sz)] {
+            if !(scale.is_finite() && scale > 0.0) {
+                return Err(DoseError::InvalidParameter { name, value: scale });
+            }
+        }
+        self.xs.iter_mut().for_each(|x| *x *= sx);
+        self.ys.iter_mut().for_each(|y| *y *= sy);
+        self.zs.iter_mut().for_each(|z| *z *= sz);
+        self.doses.iter_mut().for_each(|dose| *dose *= sx * sy);
+        Ok(())
+    }
+
+    /// Keep dose only inside a mask, zeroing the dose and uncertainty of voxels outside it.
+    ///
+    /// The mask holds one value per voxel in the same order as `doses`: *x* varies fastest,
+    /// then *y*, then *z*, i.e. `mask[self.voxel_index(i, j, k)]`. Voxels where the mask is
+    /// `false` are zeroed.
+    pub fn apply_mask(&mut self, mask: &[bool]) -> Result<(), DoseError> {
+        if mask.len() != self.num_voxels() {
+            return Err(DoseError::LengthMismatch {
+                field: "mask",
+                expected: self.num_voxels(),
+                found: mask.len(),
+            });
+        }
+        for ((dose, uncert), &keep) in self.doses.iter_mut().zip(&mut self.uncerts).zip(mask) {
+            if !keep {
+                *dose = 0.0;
+                *uncert = 0.0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the dose of each voxel whose fractional uncertainty exceeds `max_frac` with
+    /// `fill`, returning how many were replaced.
+    ///
+    /// Poorly converged voxels otherwise show as hot and cold speckle. Their uncertainties are
+    /// kept, so the uncertainty field still shows why they were masked.
+    ///
+    /// A `fill` of 0 is safe everywhere, while `NaN` sets the masked voxels apart from real
+    /// zero dose. The text formats write it as `NaN` and the binary formats store it as is;
+    /// ParaView draws such voxels in its NaN colour, but not every reader accepts them. `NaN`
+    /// doses also carry through later smoothing or downsampling and into the mean dose of
+    /// [`DoseBlock::stats`].
+    pub fn mask_by_uncertainty(&mut self, max_frac: f64, fill: f64) -> usize {
+        let mut masked = 0;
+        for (dose, &uncert) in self.doses.iter_mut().zip(&self.uncerts) {
+            if uncert > max_frac {
+                *dose = fill;
+                masked += 1;
+            }
+        }
+        masked
+    }
+
+    /// Base 10 logarithm of each voxel dose, for visualizing doses spanning many decades.
+    ///
+    /// Doses below `floor` are raised to `floor` first, so zero-dose voxels map to
+    /// `log10(floor)` rather than negative infinity. The floor should be positive and is
+    /// typically a small fraction of the maximum dose, e.g. `1e-6 * max`.
+    pub fn log_dose(&self, floor: f64) -> Vec<f64> {
+        self.doses.iter().map(|dose| dose.max(floor).log10()).collect()
+    }
+
     /// Convert the `3ddose` data to a Gmsh `.msh` file (version 2.2).
     pub fn write_msh2<P: AsRef<std::path::Path>>(&self, output: P) -> Result<(), std::io::Error> {
-        use itertools::Itertools;
+        let mut filestream = BufWriter::with_capacity(DEFAULT_BUFFER_SIZE, File::create(output)?);
+        self.write_msh2_to(&mut filestream)?;
+        filestream.flush()
+    }
 
-        let mut filestream = BufWriter::new(File::create(output)?);
+    /// Write the `3ddose` data in Gmsh `.msh` format (version 2.2) to any writer.
+    pub fn write_msh2_to<W: Write>(&self, filestream: &mut W) -> Result<(), std::io::Error> {
+        self.write_msh2_fields_to(filestream, &self.default_fields(Fmt::Msh2), &MshOptions::default())
+    }
+
+    /// Convert the mesh and the given fields to a Gmsh `.msh` file (version 2.2).
+    pub fn write_msh2_fields<P: AsRef<std::path::Path>>(
+        &self,
+        output: P,
+        fields: &[Field],
+        options: &MshOptions,
+    ) -> Result<(), std::io::Error> {
+        let mut filestream = BufWriter::with_capacity(DEFAULT_BUFFER_SIZE, File::create(output)?);
+        self.write_msh2_fields_to(&mut filestream, fields, options)?;
+        filestream.flush()
+    }
+
+    /// Write the mesh and the given fields in Gmsh `.msh` format (version 2.2) to any writer.
+    ///
+    /// Each field becomes an `$ElementData` section, in order. With [`MshOptions::binary`] the
+    /// file is written by [`DoseBlock::write_msh2_binary_fields_to`] instead.
+    pub fn write_msh2_fields_to<W: Write>(
+        &self,
+        filestream: &mut W,
+        fields: &[Field],
+        options: &MshOptions,
+    ) -> Result<(), std::io::Error> {
+        if options.binary {
+            return self.write_msh2_binary_fields_to(filestream, fields, options);
+        }
+        self.check_field_lengths(fields)?;
+
+        // lines are formatted straight into the writer: callers buffer it, and formatting each
+        // line into a reused String first benchmarked no faster (see benches/msh.rs)
 
         // gmsh header
-        writeln!(&mut filestream, "$MeshFormat\n2.2 0 8\n$EndMeshFormat")?;
-        // nodes
-        write!(&mut filestream, "$Nodes\n{}\n", self.num_nodes())?;
-        for (k, z) in self.zs.iter().enumerate() {
-            for (j, y) in self.ys.iter().enumerate() {
-                for (i, x) in self.xs.iter().enumerate() {
-                    // gmsh expects 1-indexing
-                    writeln!(
-                        &mut filestream,
-                        "{} {} {} {}",
-                        self.grid_index(i, j, k) + 1,
-                        x,
-                        y,
-                        z
-                    )?;
-                }
+        writeln!(filestream, "$MeshFormat\n2.2 0 8\n$EndMeshFormat")?;
+        // element tags: physical group and elementary entity
+        let tags = if write_msh_names(filestream, options)? { "1 1" } else { "0 0" };
+        match options.element_order {
+            ElementOrder::Linear if options.flatten_2d => self.write_msh2_quad4(filestream, tags)?,
+            ElementOrder::Quadratic if options.flatten_2d => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "only linear elements can be flattened to 2d",
+                ));
+            }
+            ElementOrder::Linear => {
+                write_msh2_nodes(filestream, &self.xs, &self.ys, &self.zs)?;
+                self.write_msh2_hex8(filestream, tags)?;
+            }
+            ElementOrder::Quadratic => {
+                let xs = with_midpoints(&self.xs);
+                let ys = with_midpoints(&self.ys);
+                let zs = with_midpoints(&self.zs);
+                write_msh2_nodes(filestream, &xs, &ys, &zs)?;
+                self.write_msh2_hex27(filestream, tags)?;
+            }
+        }
+
+        for field in fields {
+            write_msh2_element_data(filestream, field.name, field.values)?;
+        }
+        Ok(())
+    }
+
+    /// The 1-based node ids of each voxel's quadratic hexahedron in gmsh order, in `doses`
+    /// order, numbering the nodes of the grid refined with midpoints *x* fastest.
+    pub(crate) fn hex27_elements(&self) -> impl Iterator<Item = [usize; 27]> + '_ {
+        // refined grid offsets of each node in gmsh order
+        // source: http://gmsh.info/doc/texinfo/gmsh.html#High_002dorder-elements
+        //   0-7 corners, as for the linear hexahedron
+        //   8-19 edge midpoints: 0-1 0-3 0-4 1-2 1-5 2-3 2-6 3-7 4-5 4-7 5-6 6-7
+        //   20-25 face centres: 0123 0154 0374 1265 2376 4567
+        //   26 volume centre
+        const OFFSETS: [(usize, usize, usize); 27] = [
+            (0, 0, 0), (2, 0, 0), (2, 2, 0), (0, 2, 0), (0, 0, 2), (2, 0, 2), (2, 2, 2), (0, 2, 2),
+            (1, 0, 0), (0, 1, 0), (0, 0, 1), (2, 1, 0), (2, 0, 1), (1, 2, 0),
+            (2, 2, 1), (0, 2, 1), (1, 0, 2), (0, 1, 2), (2, 1, 2), (1, 2, 2),
+            (1, 1, 0), (1, 0, 1), (0, 1, 1), (2, 1, 1), (1, 2, 1), (1, 1, 2),
+            (1, 1, 1),
+        ];
+        let (nx, ny) = (2 * self.num_x() + 1, 2 * self.num_y() + 1);
+        (0..self.num_voxels()).map(move |index| {
+            let (i, j, k) = self.voxel_ijk(index);
+            let mut nodes = [0; 27];
+            for (node, (di, dj, dk)) in nodes.iter_mut().zip(OFFSETS.iter()) {
+                // gmsh expects 1-indexing
+                *node = (2 * i + di) + nx * (2 * j + dj) + nx * ny * (2 * k + dk) + 1;
             }
+            nodes
+        })
+    }
+
+    /// Write the `$Elements` section of linear 8-node hexahedra, one per voxel.
+    fn write_msh2_hex8<W: Write>(&self, filestream: &mut W, tags: &str) -> Result<(), std::io::Error> {
+        debug_assert_eq!(self.verify_connectivity(), Ok(()));
+
+        writeln!(filestream, "$Elements\n{}", self.num_voxels())?;
+        for (index, nodes) in self.hex_elements().enumerate() {
+            writeln!(
+                filestream,
+                // 5 is the gmsh magic number for a hexahedron
+                // 2 tags: physical group (0 if unnamed) and elementary entity -- see element
+                // type section of gmsh doc for more: http://gmsh.info/doc/texinfo/gmsh.html#MSH-file-format
+                "{} 5 2 {} {} {} {} {} {} {} {} {}",
+                index + 1,
+                tags,
+                nodes[0],
+                nodes[1],
+                nodes[2],
+                nodes[3],
+                nodes[4],
+                nodes[5],
+                nodes[6],
+                nodes[7],
+            )?;
         }
-        writeln!(&mut filestream, "$EndNodes")?;
+        writeln!(filestream, "$EndElements")?;
+        Ok(())
+    }
+
+    /// The 1-based node ids of each voxel's linear hexahedron in gmsh order, in `doses` order.
+    pub(crate) fn hex_elements(&self) -> impl Iterator<Item = [usize; 8]> + '_ {
+        use itertools::Itertools;
 
         // todo find exact len
-        let mut x_nodes = 1..=self.num_nodes();
-        let gmsh_y_index = |x_index| x_index + self.xs.len();
-        let gmsh_z_index = |x_index| x_index + self.xs.len() * self.ys.len();
+        let x_nodes = 1..=self.num_nodes();
+        let gmsh_y_index = move |x_index| x_index + self.xs.len();
+        let gmsh_z_index = move |x_index| x_index + self.xs.len() * self.ys.len();
 
-        writeln!(&mut filestream, "$Elements\n{}", self.num_voxels())?;
-        for index in 0..self.num_voxels() {
+        (0..self.num_voxels()).scan(x_nodes, move |x_nodes, index| {
             // we order nodes following the gmsh numbering
             // source: http://gmsh.info/doc/texinfo/gmsh.html#Low-order-elements
             //               v
@@ -238,15 +1421,15 @@ impl DoseBlock {
 
             // skip rightmost node to start a new row
             if index != 0 && index % self.num_x() == 0 {
-                x_nodes = x_nodes.dropping(1);
+                *x_nodes = x_nodes.clone().dropping(1);
             }
 
             // skip top row of nodes to move to the next x-y block
             if index != 0 && index % (self.num_x() * self.num_y()) == 0 {
-                x_nodes = x_nodes.dropping(self.xs.len());
+                *x_nodes = x_nodes.clone().dropping(self.xs.len());
             }
 
-            let xl = x_nodes.next().unwrap(); // 0 node
+            let xl = x_nodes.next()?; // 0 node
             let xr = xl + 1; // 1
 
             let yl = gmsh_y_index(xl); // 3
@@ -258,113 +1441,899 @@ impl DoseBlock {
             let yzl = gmsh_z_index(yl); // 7
             let yzr = yzl + 1; // 6
 
-            writeln!(
-                &mut filestream,
-                // 5 is the gmsh magic number for a hexahedron
-                // 2 0 0 doesn't matter for us -- see element type section of
-                // gmsh doc for more: http://gmsh.info/doc/texinfo/gmsh.html#MSH-file-format
-                "{} 5 2 0 0 {} {} {} {} {} {} {} {}",
-                index + 1,
-                xl,  // 0
-                xr,  // 1
-                yr,  // 2
-                yl,  // 3
-                zl,  // 4
-                zr,  // 5
-                yzr, // 6
-                yzl, // 7
-            )?;
+            Some([xl, xr, yr, yl, zl, zr, yzr, yzl])
+        })
+    }
+
+    fn write_msh2_hex27<W: Write>(&self, filestream: &mut W, tags: &str) -> Result<(), std::io::Error> {
+        writeln!(filestream, "$Elements\n{}", self.num_voxels())?;
+        for (index, nodes) in self.hex27_elements().enumerate() {
+            // 12 is the gmsh magic number for a 27-node hexahedron
+            write!(filestream, "{} 12 2 {}", index + 1, tags)?;
+            for node in nodes.iter() {
+                write!(filestream, " {}", node)?;
+            }
+            writeln!(filestream)?;
         }
-        writeln!(&mut filestream, "$EndElements")?;
-
-        let mut write_elt_data = |name: &str, data: &Vec<f64>| -> Result<(), std::io::Error> {
-            writeln!(&mut filestream, "$ElementData")?;
-            // one string - the field name
-            writeln!(&mut filestream, "1\n{}", name)?;
-            // one real value - the time
-            writeln!(&mut filestream, "1\n0.0")?;
-            // three int tags
-            //   timestep 0
-            //   1-component (scalar) field
-            //   num_elt values
-            writeln!(&mut filestream, "3\n0\n1\n{}", data.len())?;
-            for (index, val) in data.iter().enumerate() {
-                writeln!(&mut filestream, "{} {}", index + 1, val)?;
-            }
-            writeln!(&mut filestream, "$EndElementData")?;
-            Ok(())
-        };
+        writeln!(filestream, "$EndElements")?;
+        Ok(())
+    }
 
-        write_elt_data(r#""Dose [Gy·cm2]""#, &self.doses)?;
-        write_elt_data(r#""Uncertainty fraction""#, &self.uncerts)
+    /// Check if `other` has the same node coordinates, to within `tol` `[cm]` on every node.
+    pub fn same_grid(&self, other: &DoseBlock, tol: f64) -> bool {
+        let axis_matches = |a: &[f64], b: &[f64]| {
+            a.len() == b.len() && a.iter().zip(b).all(|(p, q)| (p - q).abs() <= tol)
+        };
+        axis_matches(&self.xs, &other.xs)
+            && axis_matches(&self.ys, &other.ys)
+            && axis_matches(&self.zs, &other.zs)
     }
 
-    /// Convert the `3ddose` data to `csv`.
-    pub fn write_csv<P: AsRef<std::path::Path>>(&self, output: P) -> Result<(), std::io::Error> {
-        let calc_centroids = |pts: &Vec<f64>| -> Vec<f64> {
-            let num_centroids = pts.len() - 1;
-            let mut cs = Vec::with_capacity(num_centroids);
-            for i in 0..num_centroids {
-                cs.push((pts[i] + pts[i+1]) / 2.0);
+    /// Describe how the grid of `other` differs from this one, or `None` if they match to
+    /// within [`GRID_TOLERANCE`].
+    ///
+    /// There is one line per axis, giving the voxel count and extent of both grids and the
+    /// first node that differs, e.g. to find which run of a batch used a different grid.
+    pub fn describe_mismatch(&self, other: &DoseBlock) -> Option<String> {
+        if self.same_grid(other, GRID_TOLERANCE) {
+            return None;
+        }
+        let describe = |pts: &[f64]| match (pts.first(), pts.last()) {
+            (Some(first), Some(last)) => {
+                format!("{} voxels from {} to {} cm", pts.len() - 1, first, last)
             }
-            cs
+            _ => "no nodes".to_string(),
         };
+        let lines: Vec<String> = [Axis::X, Axis::Y, Axis::Z]
+            .iter()
+            .zip(&["x", "y", "z"])
+            .map(|(&axis, name)| {
+                let (a, b) = (self.nodes(axis), other.nodes(axis));
+                let first_diff = a.iter().zip(b).position(|(p, q)| (p - q).abs() > GRID_TOLERANCE);
+                match first_diff {
+                    None if a.len() == b.len() => format!("{}: {} in both", name, describe(a)),
+                    None => format!("{}: {} vs {}", name, describe(a), describe(b)),
+                    Some(node) => format!(
+                        "{}: {} vs {}, first differing at node {}: {} vs {} cm",
+                        name,
+                        describe(a),
+                        describe(b),
+                        node,
+                        a[node],
+                        b[node]
+                    ),
+                }
+            })
+            .collect();
+        Some(lines.join("\n"))
+    }
 
-        let voxel_idx = |i: usize, j: usize, k: usize| -> usize {
-            i + self.num_x() * j + self.num_x() * self.num_y() * k
-        };
+    /// Fail with [`DoseError::MismatchedGrid`] unless `other` is on the same grid.
+    pub(crate) fn check_same_grid(&self, other: &DoseBlock) -> Result<(), DoseError> {
+        match self.describe_mismatch(other) {
+            Some(details) => Err(DoseError::MismatchedGrid { details }),
+            None => Ok(()),
+        }
+    }
 
+    /// Write `(dose, fractional uncertainty)` pairs to a `csv` file for plotting uncertainty
+    /// against dose.
+    ///
+    /// With `sample: Some(n)`, only `n` voxels chosen by [`DoseBlock::sample_voxels`] are
+    /// written, which keeps plots of large grids manageable. Rows are in `doses` order.
+    pub fn write_dose_uncert_scatter<P: AsRef<std::path::Path>, R: rand::Rng + ?Sized>(
+        &self,
+        output: P,
+        sample: Option<usize>,
+        rng: &mut R,
+    ) -> Result<(), std::io::Error> {
         let mut file = BufWriter::new(File::create(output)?);
-        writeln!(&mut file, "xc [cm],yc [cm],zc [cm],Dose [Gy cm2],Uncertainty fraction")?;
-        for (k, z) in calc_centroids(&self.zs).into_iter().enumerate() {
-            for (j, y) in calc_centroids(&self.ys).into_iter().enumerate() {
-                for (i, x) in calc_centroids(&self.xs).into_iter().enumerate() {
-                    writeln!(&mut file, "{},{},{},{},{}", x, y, z,
-                             self.doses[voxel_idx(i, j, k)],
-                             self.uncerts[voxel_idx(i, j, k)])?;
-                }
-            }
+        self.write_dose_uncert_scatter_to(&mut file, sample, rng)?;
+        file.flush()
+    }
+
+    /// Write `(dose, fractional uncertainty)` pairs in `csv` format to any writer.
+    ///
+    /// See [`DoseBlock::write_dose_uncert_scatter`].
+    pub fn write_dose_uncert_scatter_to<W: Write, R: rand::Rng + ?Sized>(
+        &self,
+        file: &mut W,
+        sample: Option<usize>,
+        rng: &mut R,
+    ) -> Result<(), std::io::Error> {
+        let indices = match sample {
+            Some(amount) => self.sample_voxels(amount, rng),
+            None => (0..self.num_voxels()).collect(),
+        };
+
+        writeln!(file, "Dose [Gy cm2],Uncertainty fraction")?;
+        for index in indices {
+            writeln!(file, "{},{}", self.doses[index], self.uncerts[index])?;
         }
         Ok(())
     }
-}
 
-fn parse_simple_line<T>(line: String, title: &'static str, expect_len: usize) -> Vec<T>
-where
-    T: FromStr,
-    <T as std::str::FromStr>::Err: Debug,
-{
-    let entries: Vec<T> = line
-        .trim()
-        .split_whitespace()
-        .map(|num| num.parse::<T>().expect(title))
-        .collect();
-    assert!(entries.len() == expect_len);
-    entries
-}
+    /// Choose `amount` distinct voxels at random, returning their indices into `doses` in
+    /// increasing order. Every voxel is returned if `amount` is at least [`DoseBlock::num_voxels`].
+    ///
+    /// Pass an RNG seeded from a fixed value, e.g. [`SAMPLE_SEED`], to get the same sample on
+    /// every run:
+    ///
+    /// ```
+    /// # use dose2gmsh::{DoseBlock, SAMPLE_SEED};
+    /// use rand::{rngs::StdRng, SeedableRng};
+    /// # let data = DoseBlock {
+    /// #     xs: vec![0.0, 1.0, 2.0], ys: vec![0.0, 1.0], zs: vec![0.0, 1.0],
+    /// #     doses: vec![1.0, 2.0], uncerts: vec![0.1, 0.2], variant: None,
+    /// # };
+    /// let first = data.sample_voxels(1, &mut StdRng::seed_from_u64(SAMPLE_SEED));
+    /// let again = data.sample_voxels(1, &mut StdRng::seed_from_u64(SAMPLE_SEED));
+    /// assert_eq!(first, again);
+    /// ```
+    pub fn sample_voxels<R: rand::Rng + ?Sized>(&self, amount: usize, rng: &mut R) -> Vec<usize> {
+        if amount >= self.num_voxels() {
+            return (0..self.num_voxels()).collect();
+        }
+        let mut indices = rand::seq::index::sample(rng, self.num_voxels(), amount).into_vec();
+        indices.sort_unstable();
+        indices
+    }
 
-#[cfg(test)]
-mod tests {
+    /// Index into `doses` of the voxel at `position` in a traversal in `order`.
+    pub fn ordered_index(&self, position: usize, order: Order) -> usize {
+        match order {
+            Order::XFastest => position,
+            Order::ZFastest => {
+                let (num_y, num_z) = (self.num_y(), self.num_z());
+                let (i, j, k) = (position / (num_y * num_z), (position / num_z) % num_y, position % num_z);
+                self.voxel_index(i, j, k)
+            }
+        }
+    }
 
-    use super::*;
+    /// Indices into `doses` of every voxel, visited in `order`.
+    pub fn ordered_indices(&self, order: Order) -> impl Iterator<Item = usize> + '_ {
+        (0..self.num_voxels()).map(move |position| self.ordered_index(position, order))
+    }
 
-    #[test]
-    fn read_3ddose() {
-        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        path.push("props");
-        path.push("water_block.3ddose");
+    /// Read one value per voxel from a raw binary file of little-endian `f64`s with no header,
+    /// stored in `order`, returning them in native order like `doses`.
+    pub fn read_raw_field<P: AsRef<std::path::Path>>(
+        &self,
+        input: P,
+        order: Order,
+    ) -> Result<Vec<f64>, DoseError> {
+        let bytes = std::fs::read(input)?;
+        if bytes.len() != 8 * self.num_voxels() {
+            return Err(DoseError::LengthMismatch {
+                field: "raw values",
+                expected: self.num_voxels(),
+                found: bytes.len() / 8,
+            });
+        }
+        let mut values = vec![0.0; self.num_voxels()];
+        for (index, chunk) in self.ordered_indices(order).zip(bytes.chunks_exact(8)) {
+            let mut le_bytes = [0; 8];
+            le_bytes.copy_from_slice(chunk);
+            values[index] = f64::from_le_bytes(le_bytes);
+        }
+        Ok(values)
+    }
 
-        let data = DoseBlock::from_3d_dose(&path).expect("couldn't parse 3ddose file");
+    /// Find every voxel whose dose and uncertainty satisfy a predicate.
+    ///
+    /// The closure receives `(dose, uncert)` so voxels can be selected on either value.
+    /// Matches are returned in `doses` order as `(voxel index, [xc, yc, zc], dose, uncert)`,
+    /// where `[xc, yc, zc]` is the voxel centroid in `[cm]`, see [`DoseBlock::voxels`].
+    ///
+    /// ```
+    /// # use dose2gmsh::DoseBlock;
+    /// # let data = DoseBlock {
+    /// #     xs: vec![0.0, 1.0, 2.0], ys: vec![0.0, 1.0], zs: vec![0.0, 1.0],
+    /// #     doses: vec![1.0, 5.0], uncerts: vec![0.5, 0.01], variant: None,
+    /// # };
+    /// // well-converged voxels only
+    /// let hits = data.find_voxels(|_dose, uncert| uncert < 0.05);
+    /// assert_eq!(hits, vec![(1, [1.5, 0.5, 0.5], 5.0, 0.01)]);
+    /// ```
+    pub fn find_voxels<F>(&self, pred: F) -> Vec<(usize, [f64; 3], f64, f64)>
+    where
+        F: Fn(f64, f64) -> bool,
+    {
+        self.voxels()
+            .filter(|voxel| pred(voxel.dose, voxel.uncert))
+            .map(|voxel| (voxel.index, voxel.centroid, voxel.dose, voxel.uncert))
+            .collect()
+    }
 
-        assert_eq!(data.num_voxels(), 40 * 40 * 40);
-        assert_eq!(data.num_nodes(), 41 * 41 * 41);
-        // x-nodes come first, then y, then z
+    /// Convert the `3ddose` data to `csv`.
+    pub fn write_csv<P: AsRef<std::path::Path>>(&self, output: P) -> Result<(), std::io::Error> {
+        let mut file = BufWriter::with_capacity(DEFAULT_BUFFER_SIZE, File::create(output)?);
+        self.write_csv_to(&mut file)?;
+        file.flush()
+    }
+
+    /// Write the `3ddose` data in `csv` format to any writer.
+    pub fn write_csv_to<W: Write>(&self, file: &mut W) -> Result<(), std::io::Error> {
+        self.write_csv_fields_to(file, &self.default_fields(Fmt::Csv), &CsvOptions::default())
+    }
+
+    /// Convert the voxel centroids and the given fields to `csv`.
+    pub fn write_csv_fields<P: AsRef<std::path::Path>>(
+        &self,
+        output: P,
+        fields: &[Field],
+        options: &CsvOptions,
+    ) -> Result<(), std::io::Error> {
+        let mut file = BufWriter::with_capacity(DEFAULT_BUFFER_SIZE, File::create(output)?);
+        self.write_csv_fields_to(&mut file, fields, options)?;
+        file.flush()
+    }
+
+    /// Write the voxel centroids and the given fields in `csv` format to any writer.
+    ///
+    /// Each field becomes a column after the centroid coordinates, in order. Rows follow
+    /// `options.order`.
+    pub fn write_csv_fields_to<W: Write>(
+        &self,
+        file: &mut W,
+        fields: &[Field],
+        options: &CsvOptions,
+    ) -> Result<(), std::io::Error> {
+        self.check_field_lengths(fields)?;
+
+        if options.id_base.is_some() {
+            write!(file, "id,")?;
+        }
+        write!(file, "xc [cm],yc [cm],zc [cm]")?;
+        for field in fields {
+            write!(file, ",{}", field.name)?;
+        }
+        writeln!(file)?;
+
+        let centroids = voxel::Centroids::new(self);
+
+        #[cfg(not(feature = "rayon"))]
+        {
+            self.write_csv_rows(file, fields, options, &centroids, 0..self.num_voxels())
+        }
+
+        // format chunks of rows in parallel, a batch of chunks at a time to bound memory use,
+        // then write them out in row order
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+
+            const CHUNK_ROWS: usize = 4096;
+            let num_voxels = self.num_voxels();
+            let num_chunks = num_voxels.div_ceil(CHUNK_ROWS);
+            let batch_size = 4 * rayon::current_num_threads();
+            for batch_start in (0..num_chunks).step_by(batch_size) {
+                let batch_end = num_chunks.min(batch_start + batch_size);
+                let chunks = (batch_start..batch_end)
+                    .into_par_iter()
+                    .map(|chunk| {
+                        let start = chunk * CHUNK_ROWS;
+                        let end = num_voxels.min(start + CHUNK_ROWS);
+                        let mut rows = Vec::new();
+                        self.write_csv_rows(&mut rows, fields, options, &centroids, start..end)?;
+                        Ok(rows)
+                    })
+                    .collect::<Result<Vec<_>, std::io::Error>>()?;
+                for rows in chunks {
+                    file.write_all(&rows)?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Write a range of `csv` rows, counted in `options.order`.
+    fn write_csv_rows<W: Write>(
+        &self,
+        file: &mut W,
+        fields: &[Field],
+        options: &CsvOptions,
+        centroids: &voxel::Centroids,
+        rows: std::ops::Range<usize>,
+    ) -> Result<(), std::io::Error> {
+        for row in rows {
+            let index = self.ordered_index(row, options.order);
+            if let Some(base) = options.id_base {
+                write!(file, "{},", index + base)?;
+            }
+            let [x, y, z] = centroids.voxel(self, index).centroid;
+            write!(file, "{},{},{}", x, y, z)?;
+            for field in fields {
+                write!(file, ",{}", field.values[index])?;
+            }
+            writeln!(file)?;
+        }
+        Ok(())
+    }
+
+    /// The dose and uncertainty fields, labelled as the given format has always written them.
+    pub fn default_fields(&self, format: Fmt) -> Vec<Field<'_>> {
+        self.quantity_fields(format, Quantity::Dose)
+    }
+
+    /// The dose and uncertainty fields, with the dose labelled as `quantity`.
+    pub fn quantity_fields(&self, format: Fmt, quantity: Quantity) -> Vec<Field<'_>> {
+        let [dose_name, uncert_name] = DoseBlock::field_names(format, quantity);
+        vec![
+            Field { name: dose_name, values: &self.doses },
+            Field { name: uncert_name, values: &self.uncerts },
+        ]
+    }
+
+    /// Names of the dose and uncertainty fields in [`DoseBlock::quantity_fields`].
+    fn field_names(format: Fmt, quantity: Quantity) -> [&'static str; 2] {
+        [quantity.label(format), "Uncertainty fraction"]
+    }
+
+    /// An [`std::io::ErrorKind::InvalidInput`] error unless every field has one value per voxel,
+    /// for the writers taking caller-built fields to check before writing anything.
+    fn check_field_lengths(&self, fields: &[Field]) -> Result<(), std::io::Error> {
+        match fields.iter().find(|field| field.values.len() != self.num_voxels()) {
+            Some(field) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "field {} has {} values for {} voxels",
+                    field.name,
+                    field.values.len(),
+                    self.num_voxels()
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Read a voxel mask from a text file of whitespace-separated `0` and `1` values.
+///
+/// Values can be split over any number of lines and are in `doses` order, see
+/// [`DoseBlock::apply_mask`].
+pub fn read_mask<P: AsRef<std::path::Path>>(input_file: P) -> Result<Vec<bool>, DoseError> {
+    let mut tokens = stream::Tokens::new(BufReader::new(File::open(input_file)?));
+    let mut mask = Vec::new();
+    while let Some(token) = tokens.next_token()? {
+        match token {
+            "0" => mask.push(false),
+            "1" => mask.push(true),
+            _ => return Err(tokens.invalid("mask value")),
+        }
+    }
+    Ok(mask)
+}
+
+/// Default seed for the random number generator used when sampling voxels, if `--seed` isn't given.
+///
+/// Sampling is reproducible for a given seed and version of the `rand` crate.
+pub const SAMPLE_SEED: u64 = 3_141_592_653;
+
+/// Capacity in bytes of the buffers files are read and written through, unless given.
+///
+/// Much larger than the standard library's 8 KiB default, which costs a round trip every few
+/// thousand values on network filesystems.
+pub const DEFAULT_BUFFER_SIZE: usize = 1 << 20;
+
+/// Node coordinate tolerance `[cm]` used when checking two dose blocks share a grid.
+///
+/// `3ddose` coordinates are written in single precision, so grids from separate runs can
+/// differ in the last few digits.
+pub const GRID_TOLERANCE: f64 = 1.0e-6;
+
+/// Write a per-voxel comparison of two dose blocks on the same grid to a `csv` file.
+///
+/// Rows follow the same voxel order as [`DoseBlock::write_csv`]. See [`write_diff_csv_to`] for the
+/// columns.
+pub fn write_diff_csv<P: AsRef<std::path::Path>>(
+    reference: &DoseBlock,
+    evaluated: &DoseBlock,
+    output: P,
+) -> Result<(), DoseError> {
+    reference.check_same_grid(evaluated)?;
+    let mut file = BufWriter::new(File::create(output)?);
+    write_diff_csv_to(reference, evaluated, &mut file)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Write a per-voxel comparison of two dose blocks on the same grid in `csv` format.
+///
+/// Each row holds the voxel centroid, the reference and evaluated doses, the difference
+/// `evaluated - reference` and that difference as a percentage of the reference dose.
+/// The percentage is left empty for voxels with zero reference dose.
+pub fn write_diff_csv_to<W: Write>(
+    reference: &DoseBlock,
+    evaluated: &DoseBlock,
+    file: &mut W,
+) -> Result<(), DoseError> {
+    reference.check_same_grid(evaluated)?;
+    writeln!(
+        file,
+        "xc [cm],yc [cm],zc [cm],Reference dose [Gy cm2],Evaluated dose [Gy cm2],\
+         Difference [Gy cm2],Difference [%]"
+    )?;
+    for voxel in reference.voxels() {
+        let [x, y, z] = voxel.centroid;
+        let (ref_dose, eval_dose) = (voxel.dose, evaluated.doses[voxel.index]);
+        let diff = eval_dose - ref_dose;
+        write!(file, "{},{},{},{},{},{},", x, y, z, ref_dose, eval_dose, diff)?;
+        if ref_dose != 0.0 {
+            write!(file, "{}", 100.0 * diff / ref_dose)?;
+        }
+        writeln!(file)?;
+    }
+    Ok(())
+}
+
+/// Write the dose difference of `evaluated` from `reference` to a Gmsh `.msh` file (version
+/// 2.2) on the reference grid.
+///
+/// See [`diff_fields`] for the fields written.
+pub fn write_diff_msh2<P: AsRef<std::path::Path>>(
+    reference: &DoseBlock,
+    evaluated: &DoseBlock,
+    output: P,
+    interp: Interp,
+) -> Result<(), DoseError> {
+    let mut file = BufWriter::with_capacity(DEFAULT_BUFFER_SIZE, File::create(output)?);
+    write_diff_msh2_to(reference, evaluated, &mut file, interp)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Write the dose difference of `evaluated` from `reference` in Gmsh `.msh` format (version
+/// 2.2) to any writer, see [`write_diff_msh2`].
+pub fn write_diff_msh2_to<W: Write>(
+    reference: &DoseBlock,
+    evaluated: &DoseBlock,
+    file: &mut W,
+    interp: Interp,
+) -> Result<(), DoseError> {
+    let (diff, percent) = diff_fields(reference, evaluated, interp)?;
+    let fields = [
+        Field { name: "Difference [Gy·cm2]", values: &diff },
+        Field { name: "Difference [%]", values: &percent },
+    ];
+    reference.write_msh2_fields_to(file, &fields, &MshOptions::default())?;
+    Ok(())
+}
+
+/// Write the dose difference of `evaluated` from `reference` to a legacy VTK file on the
+/// reference grid.
+///
+/// See [`diff_fields`] for the fields written.
+pub fn write_diff_vtk<P: AsRef<std::path::Path>>(
+    reference: &DoseBlock,
+    evaluated: &DoseBlock,
+    output: P,
+    interp: Interp,
+) -> Result<(), DoseError> {
+    let mut file = BufWriter::with_capacity(DEFAULT_BUFFER_SIZE, File::create(output)?);
+    write_diff_vtk_to(reference, evaluated, &mut file, interp)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Write the dose difference of `evaluated` from `reference` in legacy VTK format to any
+/// writer, see [`write_diff_vtk`].
+pub fn write_diff_vtk_to<W: Write>(
+    reference: &DoseBlock,
+    evaluated: &DoseBlock,
+    file: &mut W,
+    interp: Interp,
+) -> Result<(), DoseError> {
+    let (diff, percent) = diff_fields(reference, evaluated, interp)?;
+    let fields = [
+        Field { name: "Difference [Gy cm2]", values: &diff },
+        Field { name: "Difference [%]", values: &percent },
+    ];
+    reference.write_vtk_fields_to(file, &fields, &VtkOptions::default())?;
+    Ok(())
+}
+
+/// Per-voxel difference `evaluated - reference` on the reference grid, and that difference as
+/// a percentage of the reference dose.
+///
+/// Unlike [`write_diff_csv`], the grids don't have to match: on a different grid the evaluated
+/// dose is interpolated at each reference centroid with [`DoseBlock::dose_at_with`] as `interp`
+/// says, which fails with [`DoseError::MismatchedGrid`] if a centroid lies outside the
+/// evaluated grid. The percentage is `NaN` for voxels with zero reference dose, like skipped
+/// voxels in a [`gamma`] map.
+pub fn diff_fields(
+    reference: &DoseBlock,
+    evaluated: &DoseBlock,
+    interp: Interp,
+) -> Result<(Vec<f64>, Vec<f64>), DoseError> {
+    let eval_doses = if reference.same_grid(evaluated, GRID_TOLERANCE) {
+        evaluated.doses.clone()
+    } else {
+        let eval_cs = [centroids(&evaluated.xs), centroids(&evaluated.ys), centroids(&evaluated.zs)];
+        reference
+            .voxels()
+            .map(|voxel| {
+                let pt = voxel.centroid;
+                evaluated.interpolate(&evaluated.doses, &eval_cs, pt, interp).ok_or_else(|| {
+                    let details = format!(
+                        "reference centroid ({}, {}, {}) cm is outside the evaluated grid",
+                        pt[0], pt[1], pt[2]
+                    );
+                    DoseError::MismatchedGrid { details }
+                })
+            })
+            .collect::<Result<_, _>>()?
+    };
+
+    let diff: Vec<f64> = eval_doses.iter().zip(&reference.doses).map(|(eval, re)| eval - re).collect();
+    let percent = diff
+        .iter()
+        .zip(&reference.doses)
+        .map(|(diff, &re)| if re != 0.0 { 100.0 * diff / re } else { f64::NAN })
+        .collect();
+    Ok((diff, percent))
+}
+
+/// Write the `$Nodes` section for a structured grid, numbering nodes *x* fastest.
+fn write_msh2_nodes<W: Write>(filestream: &mut W, xs: &[f64], ys: &[f64], zs: &[f64]) -> Result<(), std::io::Error> {
+    write!(filestream, "$Nodes\n{}\n", xs.len() * ys.len() * zs.len())?;
+    for (k, z) in zs.iter().enumerate() {
+        for (j, y) in ys.iter().enumerate() {
+            for (i, x) in xs.iter().enumerate() {
+                // gmsh expects 1-indexing
+                writeln!(
+                    filestream,
+                    "{} {} {} {}",
+                    i + xs.len() * j + xs.len() * ys.len() * k + 1,
+                    x,
+                    y,
+                    z
+                )?;
+            }
+        }
+    }
+    writeln!(filestream, "$EndNodes")?;
+    Ok(())
+}
+
+/// Write the `$Comments` and `$PhysicalNames` sections that `options` ask for, returning whether
+/// there's a physical group, numbered 1, for the elements to be tagged with.
+///
+/// The group is 3D, or 2D for [`MshOptions::flatten_2d`].
+pub(crate) fn write_msh_names<W: Write>(
+    filestream: &mut W,
+    options: &MshOptions,
+) -> Result<bool, std::io::Error> {
+    if let Some((lo, hi)) = options.display_range {
+        writeln!(filestream, "$Comments\ndose_display_range = {} {}\n$EndComments", lo, hi)?;
+    }
+    match &options.physical_name {
+        Some(name) => {
+            if name.contains('"') {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "physical names can't contain quotes",
+                ));
+            }
+            let dim = if options.flatten_2d { 2 } else { 3 };
+            writeln!(filestream, "$PhysicalNames\n1\n{} 1 \"{}\"\n$EndPhysicalNames", dim, name)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Write an `$ElementData` section with one value per element, numbering elements from 1.
+pub(crate) fn write_msh2_element_data<W: Write>(
+    filestream: &mut W,
+    name: &str,
+    data: &[f64],
+) -> Result<(), std::io::Error> {
+    writeln!(filestream, "$ElementData")?;
+    // one string - the field name
+    writeln!(filestream, "1\n\"{}\"", name)?;
+    // one real value - the time
+    writeln!(filestream, "1\n0.0")?;
+    // three int tags
+    //   timestep 0
+    //   1-component (scalar) field
+    //   num_elt values
+    writeln!(filestream, "3\n0\n1\n{}", data.len())?;
+    for (index, val) in data.iter().enumerate() {
+        writeln!(filestream, "{} {}", index + 1, val)?;
+    }
+    writeln!(filestream, "$EndElementData")?;
+    Ok(())
+}
+
+/// Node coordinates with the midpoint of each voxel inserted, for quadratic elements, empty
+/// for an axis without nodes.
+pub(crate) fn with_midpoints(pts: &[f64]) -> Vec<f64> {
+    let last = match pts.last() {
+        Some(&last) => last,
+        None => return Vec::new(),
+    };
+    let mut refined = Vec::with_capacity(2 * pts.len() - 1);
+    for pair in pts.windows(2) {
+        refined.push(pair[0]);
+        refined.push((pair[0] + pair[1]) / 2.0);
+    }
+    refined.push(last);
+    refined
+}
+
+/// Midpoints between consecutive nodes, i.e. the voxel centroids along one axis.
+fn centroids(pts: &[f64]) -> Vec<f64> {
+    pts.windows(2).map(|pair| (pair[0] + pair[1]) / 2.0).collect()
+}
+
+/// A writer that computes the SHA-256 digest of everything written through it.
+///
+/// Wrap the innermost sink (e.g. the `File`) so the digest covers exactly the bytes on disk
+/// without reading the output back.
+#[cfg(feature = "hash")]
+pub struct HashWriter<W> {
+    inner: W,
+    hasher: sha2::Sha256,
+}
+
+#[cfg(feature = "hash")]
+impl<W: Write> HashWriter<W> {
+    /// Start hashing writes to `inner`.
+    pub fn new(inner: W) -> Self {
+        use sha2::Digest;
+        HashWriter { inner, hasher: sha2::Sha256::new() }
+    }
+
+    /// Return the inner writer and the lowercase hex digest of all bytes written.
+    pub fn finish(self) -> (W, String) {
+        use sha2::Digest;
+        let digest = self.hasher.finalize();
+        let hex = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+        (self.inner, hex)
+    }
+}
+
+#[cfg(feature = "hash")]
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        use sha2::Digest;
+        // only hash what the inner writer actually accepted
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// First two bytes of every gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Open a `3ddose` file for reading through a buffer of `buffer_size` bytes, decompressing it
+/// on the way if it starts with the gzip magic number, whatever its name.
+///
+/// Without the `gzip` feature a compressed file is an [`std::io::ErrorKind::InvalidData`] error
+/// saying so, rather than a confusing parse error.
+pub(crate) fn open_3ddose<P: AsRef<std::path::Path>>(
+    input_file: P,
+    buffer_size: usize,
+) -> Result<Box<dyn BufRead>, DoseError> {
+    check_buffer_size(buffer_size)?;
+    open_3ddose_from(File::open(input_file)?, buffer_size)
+}
+
+/// Read `3ddose` data from `file` as [`open_3ddose`] reads a file.
+pub(crate) fn open_3ddose_from<R: Read + 'static>(
+    file: R,
+    buffer_size: usize,
+) -> Result<Box<dyn BufRead>, DoseError> {
+    check_buffer_size(buffer_size)?;
+    let mut reader = BufReader::with_capacity(buffer_size, file);
+    if !reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        return Ok(Box::new(reader));
+    }
+    #[cfg(feature = "gzip")]
+    {
+        let decoder = flate2::bufread::GzDecoder::new(reader);
+        Ok(Box::new(BufReader::with_capacity(buffer_size, decoder)))
+    }
+    #[cfg(not(feature = "gzip"))]
+    {
+        let message = "the file is gzip-compressed, reading it needs the gzip feature";
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message).into())
+    }
+}
+
+/// Check a buffer capacity given as `buffer_size` isn't zero.
+pub(crate) fn check_buffer_size(buffer_size: usize) -> Result<(), DoseError> {
+    if buffer_size == 0 {
+        return Err(DoseError::InvalidParameter { name: "buffer_size", value: 0.0 });
+    }
+    Ok(())
+}
+
+/// Parse a buffer capacity in bytes for `--buffer-size`, which must be at least one byte.
+pub(crate) fn parse_buffer_size(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(0) => Err("The buffer size must be at least 1 byte".to_string()),
+        Ok(size) => Ok(size),
+        Err(_) => Err(format!("Could not parse buffer size {}, expected a number of bytes", value)),
+    }
+}
+
+/// Parse three comma-separated values like `1,1,2.5`, e.g. per-axis scales or voxel indices.
+pub(crate) fn parse_triple<T: FromStr + Copy>(value: &str) -> Result<[T; 3], String> {
+    let values: Vec<T> = value
+        .split(',')
+        .map(|part| part.trim().parse::<T>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("Could not parse {} as three comma-separated values", value))?;
+    match values[..] {
+        [a, b, c] => Ok([a, b, c]),
+        _ => Err(format!("Expected three comma-separated values, found {}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn read_3ddose() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("props");
+        path.push("water_block.3ddose");
+
+        let data = DoseBlock::from_3d_dose(&path).expect("couldn't parse 3ddose file");
+
+        assert_eq!(data.num_voxels(), 40 * 40 * 40);
+        assert_eq!(data.num_nodes(), 41 * 41 * 41);
+        // x-nodes come first, then y, then z
         assert_eq!(data.grid_index(0, 0, 0), 0);
         assert_eq!(data.grid_index(0, 1, 0), 41);
         assert_eq!(data.grid_index(0, 0, 1), 1681);
         assert_eq!(data.grid_index(0, 0, 1), 1681);
         // a random uncertainty to check
         assert_eq!(data.uncerts[21503], 0.37652693977336593);
+        assert_eq!(data.variant, Some(DoseVariant::Classic));
+        assert!(data.info().to_string().starts_with("layout:            classic, one line per record\n"));
+    }
+
+    #[test]
+    fn read_wrapped_3ddose() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("props");
+        path.push("water_block.3ddose");
+        let classic = DoseBlock::from_3d_dose(&path).unwrap();
+
+        // the same block with every record wrapped five values to a line
+        path.set_file_name("water_block_wrapped.3ddose");
+        let wrapped = DoseBlock::from_3d_dose(&path).unwrap();
+        assert_eq!(wrapped.variant, Some(DoseVariant::Wrapped));
+        assert_eq!(wrapped.doses.len(), 64000);
+        assert_eq!(wrapped, DoseBlock { variant: Some(DoseVariant::Wrapped), ..classic });
+    }
+
+    #[test]
+    fn read_gzipped_3ddose() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("props");
+        path.push("asym_2x3x4.3ddose");
+        let plain = DoseBlock::from_3d_dose(&path).unwrap();
+        assert_eq!(plain.num_voxels(), 24);
+        path.set_file_name("asym_2x3x4.3ddose.gz");
+        let gzipped = DoseBlock::from_3d_dose(&path);
+
+        #[cfg(feature = "gzip")]
+        {
+            assert_eq!(gzipped.unwrap(), plain);
+            assert_eq!(DoseBlock::from_3d_dose_buffered(&path, 7).unwrap(), plain);
+            assert_eq!(DoseBlock::from_3d_dose_multi(&path).unwrap(), vec![plain.clone()]);
+            let output = std::env::temp_dir().join("dose2gmsh_gzipped.msh");
+            convert_3ddose_to_msh(&path, &output).unwrap();
+            let mut expected = Vec::new();
+            plain.write_msh2_to(&mut expected).unwrap();
+            assert!(std::fs::read(&output).unwrap() == expected);
+            std::fs::remove_file(output).unwrap();
+        }
+        #[cfg(not(feature = "gzip"))]
+        match gzipped {
+            Err(DoseError::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::InvalidData),
+            other => panic!("expected an error for gzipped input, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn new() {
+        let nodes = || vec![0.0, 1.0, 2.0];
+        let data = DoseBlock::new(nodes(), nodes(), vec![0.0, 0.5], vec![1.0; 4], vec![0.1; 4]);
+        let data = data.unwrap();
+        assert_eq!((data.num_voxels(), data.variant), (4, None));
+
+        let short = DoseBlock::new(nodes(), nodes(), vec![0.0, 0.5], vec![1.0; 4], vec![0.1; 3]);
+        assert_eq!(
+            short.unwrap_err().to_string(),
+            "wrong length for uncertainty value: expected 4, found 3"
+        );
+        let long = DoseBlock::new(nodes(), nodes(), vec![0.0, 0.5], vec![1.0; 5], vec![0.1; 4]);
+        assert!(matches!(long, Err(DoseError::LengthMismatch { field: "dose value", .. })));
+        let flat = DoseBlock::new(nodes(), nodes(), vec![0.0], vec![], vec![]);
+        assert!(matches!(flat, Err(DoseError::EmptyGrid)));
+        let reversed = DoseBlock::new(nodes(), vec![1.0, 0.0], nodes(), vec![1.0; 4], vec![0.1; 4]);
+        assert!(matches!(reversed, Err(DoseError::NonMonotonic { axis: Axis::Y, index: 1 })));
+    }
+
+    #[test]
+    fn from_reader() {
+        let text = "2 1 1\n0 1 2\n0 1\n0 1\n1.5 2.5\n0.1 0.2\n";
+        let data = DoseBlock::from_reader(text.as_bytes()).unwrap();
+        assert_eq!(data.xs, vec![0.0, 1.0, 2.0]);
+        assert_eq!((data.doses, data.uncerts), (vec![1.5, 2.5], vec![0.1, 0.2]));
+        assert_eq!(data.variant, Some(DoseVariant::Classic));
+
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("props");
+        path.push("asym_2x3x4.3ddose");
+        let file = std::io::BufReader::new(File::open(&path).unwrap());
+        assert_eq!(DoseBlock::from_reader(file).unwrap(), DoseBlock::from_3d_dose(&path).unwrap());
+
+        assert!(matches!(DoseBlock::from_reader(&b" \n"[..]), Err(DoseError::EmptyGrid)));
+        let truncated = DoseBlock::from_reader(&text.as_bytes()[..24]);
+        assert!(matches!(truncated, Err(DoseError::UnexpectedEof { field: "dose value" })));
+
+        // swapped and repeated nodes
+        let text = "2 1 1\n0 2 1\n0 1\n0 1\n1.5 2.5\n0.1 0.2\n";
+        let swapped = DoseBlock::from_reader(text.as_bytes());
+        assert!(matches!(swapped, Err(DoseError::NonMonotonic { axis: Axis::X, index: 2 })));
+        let repeated = DoseBlock::from_reader("1 1 1\n0 1\n0 1\n1 1\n1.5\n0.1\n".as_bytes());
+        assert!(matches!(repeated, Err(DoseError::NonMonotonic { axis: Axis::Z, index: 1 })));
+
+        // non-finite values still parse, for inspection, unless checked for
+        let text = "2 1 1\n0 1 2\n0 1\n0 1\n1.5 2.5\n0.1 NaN\n";
+        let data = DoseBlock::from_reader(text.as_bytes()).unwrap();
+        assert_eq!(data.num_non_finite(), 1);
+        let err = data.check_finite().unwrap_err();
+        assert!(matches!(err, DoseError::NonFinite { field: "uncertainty value", index: 1 }));
+        assert_eq!(err.to_string(), "non-finite uncertainty value at voxel 1");
+        let text = "1 1 1\n0 1\n0 1\n0 1\n-inf\n0.1\n";
+        let err = DoseBlock::from_reader(text.as_bytes()).unwrap().check_finite();
+        assert!(matches!(err, Err(DoseError::NonFinite { field: "dose value", index: 0 })));
+    }
+
+    #[test]
+    fn read_asymmetric_3ddose() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("props");
+        path.push("asym_2x3x4.3ddose");
+
+        // voxel (i, j, k) has dose 100k + 10j + i + 1, so swapped axes show up in the values
+        let data = DoseBlock::from_3d_dose(&path).expect("couldn't parse 3ddose file");
+        assert_eq!((data.num_x(), data.num_y(), data.num_z()), (2, 3, 4));
+        assert_eq!(data.num_nodes(), 3 * 4 * 5);
+        assert_eq!(data.grid_index(1, 2, 3), 43);
+        assert_eq!(data.voxel_index(1, 2, 3), 23);
+        assert_eq!(data.voxel_ijk(23), (1, 2, 3));
+        assert_eq!(data.doses[data.voxel_index(1, 0, 0)], 2.0);
+        assert_eq!(data.doses[data.voxel_index(0, 1, 0)], 11.0);
+        assert_eq!(data.doses[data.voxel_index(0, 0, 1)], 101.0);
+        assert_eq!(data.doses[23], 322.0);
+        assert_eq!(data.uncerts[23], 0.024);
+
+        let mut csv = Vec::new();
+        data.write_csv_to(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().nth(24), Some("1.25,1.5,2.5,322,0.024"));
+
+        let mut msh = Vec::new();
+        data.write_msh2_to(&mut msh).unwrap();
+        let msh = String::from_utf8(msh).unwrap();
+        assert!(msh.contains("\n60 2.5 2 4\n$EndNodes\n"));
+        assert!(msh.contains("\n24 5 2 0 0 44 45 48 47 56 57 60 59\n$EndElements\n"));
     }
 
     #[test]
@@ -375,6 +2344,7 @@ mod tests {
             zs: vec![0.0, 2.0, 4.0, 8.0],
             doses: vec![10.0, 20.0, 30.0, 40.0, 50.0, 60.0],
             uncerts: vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6],
+            variant: None,
         };
 
         let file = "tmp.csv";
@@ -409,4 +2379,734 @@ mod tests {
 
         std::fs::remove_file(file).unwrap();
     }
+
+    #[test]
+    fn completions() {
+        for &shell in &[Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let mut script = Vec::new();
+            Cli::clap().gen_completions_to("dose2gmsh", shell, &mut script);
+            let script = String::from_utf8(script).unwrap();
+            assert!(script.contains("scale-coords"), "{:?}", shell);
+        }
+        assert!(matches!(
+            Cli::from_iter(&["dose2gmsh", "completions", "zsh"]).cmd,
+            Some(Command::Completions { shell: Shell::Zsh })
+        ));
+    }
+
+    #[test]
+    fn error_display() {
+        use std::error::Error;
+
+        let err = DoseError::from(std::io::Error::new(std::io::ErrorKind::NotFound, "no such file"));
+        assert_eq!(err.to_string(), "input/output error");
+        assert_eq!(err.source().unwrap().to_string(), "no such file");
+
+        let err = DoseError::LengthMismatch { field: "mask", expected: 4, found: 3 };
+        assert_eq!(err.to_string(), "wrong length for mask: expected 4, found 3");
+        assert!(err.source().is_none());
+        let at = TokenPosition { line: 5, byte: 1032 };
+        let err = DoseError::InvalidToken { field: "dose value", token: "abc".to_string(), at };
+        assert_eq!(err.to_string(), "could not parse \"abc\" as a dose value at line 5, byte 1032");
+        let err = DoseError::TooLarge { what: "voxels", size: 64000, limit: 1000 };
+        assert_eq!(err.to_string(), "too many voxels: 64000, the limit is 1000");
+        let err = DoseError::NonMonotonic { axis: Axis::Y, index: 2 };
+        assert_eq!(err.to_string(), "y-coordinates are not strictly increasing at node 2");
+        let err = DoseError::OutsideGrid { point: [1.5, -2.0, 0.0] };
+        assert_eq!(err.to_string(), "the point (1.5, -2, 0) cm is outside the dose grid");
+        let at = TokenPosition { line: 12, byte: 340 };
+        let err = DoseError::TruncatedBlock { block: 2, field: "uncertainty value", at };
+        assert_eq!(
+            err.to_string(),
+            "block 2 ended before its last uncertainty value, after line 12, byte 340"
+        );
+
+        // asym_2x3x4.3ddose cut off partway through the doses
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("props");
+        path.push("asym_2x3x4.3ddose");
+        let text = std::fs::read_to_string(&path).unwrap();
+        let truncated = std::env::temp_dir().join("dose2gmsh_truncated.3ddose");
+        let lines: Vec<&str> = text.lines().collect();
+        let doses: Vec<&str> = lines[4].split_whitespace().take(3).collect();
+        let text = format!("{}\n{}", lines[..4].join("\n"), doses.join(" "));
+        std::fs::write(&truncated, text).unwrap();
+        let err = DoseBlock::from_3d_dose(&truncated).unwrap_err();
+        std::fs::remove_file(&truncated).unwrap();
+        assert!(matches!(err, DoseError::UnexpectedEof { field: "dose value" }));
+        assert_eq!(err.to_string(), "the file ended before the last dose value");
+    }
+
+    #[test]
+    fn scale_coords() {
+        let mut data = counting_block(2, 1, 1);
+        data.uncerts = vec![0.1, 0.2];
+        data.scale_coords(2.0, 0.5, 3.0).unwrap();
+        assert_eq!(data.xs, vec![0.0, 2.0, 4.0]);
+        assert_eq!(data.ys, vec![0.0, 0.5]);
+        assert_eq!(data.zs, vec![0.0, 3.0]);
+        assert_eq!(data.doses, vec![0.0, 1.0]);
+        assert_eq!(data.uncerts, vec![0.1, 0.2]);
+        data.scale_coords(3.0, 1.0, 1.0).unwrap();
+        assert_eq!(data.doses, vec![0.0, 3.0]);
+
+        assert!(matches!(
+            data.scale_coords(1.0, 0.0, 1.0),
+            Err(DoseError::InvalidParameter { name: "sy", .. })
+        ));
+        assert!(data.scale_coords(1.0, 1.0, -2.0).is_err());
+        assert_eq!(data.xs, vec![0.0, 6.0, 12.0]);
+
+        assert_eq!(parse_triple("1, 2,0.5"), Ok([1.0, 2.0, 0.5]));
+        assert_eq!(parse_triple("1,2,3"), Ok([1_usize, 2, 3]));
+        assert!(parse_triple::<f64>("1,2").is_err());
+        assert!(parse_triple::<f64>("1,x,2").is_err());
+        assert!(parse_triple::<usize>("1,-2,3").is_err());
+    }
+
+    #[test]
+    fn to_rate() {
+        let mut data = counting_block(2, 1, 1);
+        data.to_rate(4.0).unwrap();
+        assert_eq!(data.doses, vec![0.0, 0.25]);
+        assert!(data.to_rate(0.0).is_err());
+        assert!(data.to_rate(f64::NAN).is_err());
+
+        let fields = data.quantity_fields(Fmt::Msh2, Quantity::DoseRate);
+        assert_eq!(fields[0].name, "Dose rate [Gy·cm2/s]");
+        let fields = data.quantity_fields(Fmt::Csv, Quantity::DoseRate);
+        let mut out = Vec::new();
+        data.write_csv_fields_to(&mut out, &fields, &CsvOptions::default()).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with(
+            "xc [cm],yc [cm],zc [cm],Dose rate [Gy cm2/s],Uncertainty fraction\n"
+        ));
+    }
+
+    #[test]
+    fn msh_only_output() {
+        let check = |args: &[&str]| {
+            let cli = Cli::from_iter_safe(args).unwrap();
+            cli.check_msh_only_output().map_err(|err| err.kind)
+        };
+        let conflict = Err(structopt::clap::ErrorKind::ArgumentConflict);
+        for option in &["--stream", "--single-precision"] {
+            assert_eq!(check(&["dose2gmsh", "in.3ddose", option, "-o", "out.msh.gz"]), conflict);
+            assert_eq!(check(&["dose2gmsh", "in.3ddose", option, "-o", "out.msh"]), Ok(()));
+        }
+        assert_eq!(check(&["dose2gmsh", "in.3ddose", "--stream", "-o", "-"]), conflict);
+        assert_eq!(check(&["dose2gmsh", "in.3ddose", "--single-precision", "-o", "-"]), Ok(()));
+        // the in-memory path gzips output named .gz
+        assert_eq!(check(&["dose2gmsh", "in.3ddose", "-o", "out.msh.gz"]), Ok(()));
+    }
+
+    #[test]
+    fn field_length_mismatch() {
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 2.0],
+            ys: vec![0.0, 1.0],
+            zs: vec![0.0, 1.0],
+            doses: vec![2.0, 4.0],
+            uncerts: vec![0.1, 0.2],
+            variant: None,
+        };
+        let dose = data.default_fields(Fmt::Csv)[0];
+        let fields = [dose, Field { name: "short", values: &[1.0] }];
+        let binary = MshOptions { binary: true, ..Default::default() };
+        for &format in &Fmt::ALL {
+            let writer = format.writer(Default::default(), Default::default(), Default::default());
+            if let Some(writer) = writer {
+                let mut out = Vec::new();
+                let err = writer.write_fields(&data, &fields[1..], &mut out).unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{:?}", format);
+                assert!(out.is_empty(), "{:?}", format);
+            }
+        }
+        let err = data.write_msh2_fields_to(&mut Vec::new(), &fields, &binary).unwrap_err();
+        assert_eq!(err.to_string(), "field short has 1 values for 2 voxels");
+    }
+
+    #[test]
+    fn normalize_per_mu() {
+        let mut data = DoseBlock {
+            xs: vec![0.0, 1.0, 2.0],
+            ys: vec![0.0, 1.0],
+            zs: vec![0.0, 1.0],
+            doses: vec![2.0, 4.0],
+            uncerts: vec![0.1, 0.2],
+            variant: None,
+        };
+
+        data.normalize_per_mu(200.0, 1.0e4).unwrap();
+        assert_eq!(data.doses, vec![100.0, 200.0]);
+        assert_eq!(data.uncerts, vec![0.1, 0.2]);
+
+        assert!(matches!(
+            data.normalize_per_mu(0.0, 1.0),
+            Err(DoseError::InvalidParameter { name: "mu", .. })
+        ));
+        assert!(matches!(
+            data.normalize_per_mu(1.0, f64::NAN),
+            Err(DoseError::InvalidParameter { name: "cal_factor", .. })
+        ));
+        assert_eq!(data.doses, vec![100.0, 200.0]);
+
+        data.scale_dose(1.0e-2);
+        assert_eq!(data.doses, vec![1.0, 2.0]);
+        assert_eq!(data.uncerts, vec![0.1, 0.2]);
+    }
+
+    #[cfg(feature = "hash")]
+    #[test]
+    fn hash_writer() {
+        let mut writer = HashWriter::new(Vec::new());
+        write!(writer, "a").unwrap();
+        write!(writer, "bc").unwrap();
+        let (bytes, digest) = writer.finish();
+        assert_eq!(bytes, b"abc");
+        assert_eq!(digest, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn buffer_size() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("props");
+        path.push("asym_2x3x4.3ddose");
+        // a buffer far smaller than a line reads the same
+        let expected = DoseBlock::from_3d_dose(&path).unwrap();
+        assert_eq!(DoseBlock::from_3d_dose_buffered(&path, 3).unwrap(), expected);
+        assert!(matches!(
+            DoseBlock::from_3d_dose_buffered(&path, 0),
+            Err(DoseError::InvalidParameter { name: "buffer_size", .. })
+        ));
+        let output = std::env::temp_dir().join("dose2gmsh_buffer_size.msh");
+        assert!(convert_3ddose_to_msh_buffered(&path, &output, 0).is_err());
+        assert!(!output.exists());
+
+        assert_eq!(parse_buffer_size("65536"), Ok(65536));
+        assert!(parse_buffer_size("0").is_err());
+        assert!(parse_buffer_size("1MiB").is_err());
+    }
+
+    #[test]
+    fn empty_grid() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("props");
+        path.push("empty_0x0x0.3ddose");
+        assert!(matches!(DoseBlock::from_3d_dose(&path), Err(DoseError::EmptyGrid)));
+        assert!(matches!(DoseBlock::from_3d_dose_multi(&path), Err(DoseError::EmptyGrid)));
+        let output = std::env::temp_dir().join("dose2gmsh_empty.msh");
+        assert!(matches!(convert_3ddose_to_msh(&path, &output), Err(DoseError::EmptyGrid)));
+
+        let empty_file = std::env::temp_dir().join("dose2gmsh_empty.3ddose");
+        std::fs::write(&empty_file, "").unwrap();
+        assert!(matches!(DoseBlock::from_3d_dose(&empty_file), Err(DoseError::EmptyGrid)));
+        assert_eq!(DoseBlock::from_3d_dose_multi(&empty_file).unwrap(), vec![]);
+        assert!(matches!(convert_3ddose_to_msh(&empty_file, &output), Err(DoseError::EmptyGrid)));
+        assert_eq!(DoseError::EmptyGrid.to_string(), "the dose grid has no voxels");
+
+        // blocks built in memory can still be empty, with or without nodes
+        for xs in &[vec![0.0], vec![]] {
+            let data = DoseBlock {
+                xs: xs.clone(),
+                ys: vec![0.0, 1.0],
+                zs: vec![0.0, 1.0],
+                doses: vec![],
+                uncerts: vec![],
+                variant: None,
+            };
+            assert_eq!((data.num_x(), data.num_voxels()), (0, 0));
+            let stats = data.stats();
+            assert!(stats.num_voxels == 0 && stats.max_dose.is_nan());
+            assert!(data.info().to_string().contains("voxels:"));
+            assert_eq!(data.verify_connectivity(), Ok(()));
+            let (lo, hi) = data.percentile_range(1.0, 99.0);
+            assert!(lo.is_nan() && hi.is_nan());
+
+            let mut msh = Vec::new();
+            data.write_msh2_to(&mut msh).unwrap();
+            assert!(String::from_utf8(msh).unwrap().contains("$Elements\n0\n$EndElements\n"));
+            let element_order = ElementOrder::Quadratic;
+            let quadratic = MshOptions { element_order, ..Default::default() };
+            let fields = data.default_fields(Fmt::Msh2);
+            let mut msh = Vec::new();
+            data.write_msh2_fields_to(&mut msh, &fields, &quadratic).unwrap();
+            assert!(String::from_utf8(msh).unwrap().contains("$Elements\n0\n$EndElements\n"));
+            data.write_msh2_binary_fields_to(&mut Vec::new(), &fields, &quadratic).unwrap();
+            data.write_csv_to(&mut Vec::new()).unwrap();
+            data.write_vtk_to(&mut Vec::new()).unwrap();
+            assert!(data.coalesce_equal(0.0).elements.is_empty());
+
+            let (doses, uncerts) = (vec![1.0], vec![0.1]);
+            let grid = DoseBlock { xs: vec![0.0, 1.0], doses, uncerts, ..data.clone() };
+            let details = data.describe_mismatch(&grid).unwrap();
+            assert!(details.starts_with("x: "));
+            assert!(matches!(
+                DoseBlock::sum_doses(&[data.clone(), grid]),
+                Err(DoseError::MismatchedGrid { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn write_diff_csv() {
+        let reference = DoseBlock {
+            xs: vec![0.0, 2.0, 4.0],
+            ys: vec![0.0, 2.0],
+            zs: vec![0.0, 2.0],
+            doses: vec![10.0, 0.0],
+            uncerts: vec![0.1, 0.0],
+            variant: None,
+        };
+        let mut evaluated = reference.clone();
+        evaluated.doses = vec![11.0, 1.0];
+
+        let mut out = Vec::new();
+        write_diff_csv_to(&reference, &evaluated, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let rows: Vec<&str> = out.lines().skip(1).collect();
+        assert_eq!(rows, vec!["1,1,1,10,11,1,10", "3,1,1,0,1,1,"]);
+
+        assert_eq!(reference.describe_mismatch(&evaluated), None);
+        evaluated.xs[1] += 0.5;
+        evaluated.zs.push(4.0);
+        assert_eq!(
+            reference.describe_mismatch(&evaluated).unwrap(),
+            "x: 2 voxels from 0 to 4 cm vs 2 voxels from 0 to 4 cm, first differing at node 1: 2 vs 2.5 cm\n\
+             y: 1 voxels from 0 to 2 cm in both\n\
+             z: 1 voxels from 0 to 2 cm vs 2 voxels from 0 to 4 cm"
+        );
+        match write_diff_csv_to(&reference, &evaluated, &mut Vec::new()) {
+            Err(err @ DoseError::MismatchedGrid { .. }) => assert!(err
+                .to_string()
+                .starts_with("the dose blocks don't share a voxel grid\n  x: 2 voxels")),
+            other => panic!("expected a grid mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn diff_fields() {
+        let reference = counting_block(2, 1, 1);
+        let mut evaluated = reference.clone();
+        evaluated.doses = vec![1.0, 3.0];
+        let (diff, percent) = super::diff_fields(&reference, &evaluated, Interp::Trilinear).unwrap();
+        assert_eq!(diff, vec![1.0, 2.0]);
+        assert!(percent[0].is_nan());
+        assert_eq!(percent[1], 200.0);
+
+        // finer evaluated grid with dose 2x, interpolated at the reference centroids
+        let finer = DoseBlock {
+            xs: vec![0.0, 0.5, 1.0, 1.5, 2.0],
+            ys: vec![0.0, 1.0],
+            zs: vec![0.0, 1.0],
+            doses: vec![0.5, 1.5, 2.5, 3.5],
+            uncerts: vec![0.0; 4],
+            variant: None,
+        };
+        let (diff, _) = super::diff_fields(&reference, &finer, Interp::Trilinear).unwrap();
+        assert_eq!(diff, vec![1.0, 2.0]);
+        // the reference centroids sit on evaluated nodes, taking the voxel above
+        let (diff, _) = super::diff_fields(&reference, &finer, Interp::Nearest).unwrap();
+        assert_eq!(diff, vec![1.5, 2.5]);
+
+        let dir = std::env::temp_dir().join("dose2gmsh_diff_fields");
+        std::fs::create_dir_all(&dir).unwrap();
+        write_diff_msh2(&reference, &finer, dir.join("diff.msh"), Interp::default()).unwrap();
+        let msh = std::fs::read_to_string(dir.join("diff.msh")).unwrap();
+        assert!(msh.contains("\"Difference [Gy·cm2]\"\n1\n0.0\n3\n0\n1\n2\n1 1\n2 2\n"));
+        assert!(msh.contains("\"Difference [%]\"\n1\n0.0\n3\n0\n1\n2\n1 NaN\n2 200\n"));
+        write_diff_vtk(&reference, &finer, dir.join("diff.vtk"), Interp::default()).unwrap();
+        let vtk = std::fs::read_to_string(dir.join("diff.vtk")).unwrap();
+        assert!(vtk.ends_with("SCALARS Difference_[%] double 1\nLOOKUP_TABLE default\nNaN 200\n"));
+        std::fs::remove_dir_all(dir).unwrap();
+        let mut out = Vec::new();
+        write_diff_msh2_to(&reference, &finer, &mut out, Interp::default()).unwrap();
+        assert!(out == msh.as_bytes());
+        out.clear();
+        write_diff_vtk_to(&reference, &finer, &mut out, Interp::default()).unwrap();
+        assert!(out == vtk.as_bytes());
+
+        // the reference centroid at x = 1.5 is outside the evaluated grid
+        let mut smaller = finer;
+        smaller.xs = vec![0.0, 0.25, 0.5, 0.75, 1.0];
+        assert!(matches!(
+            super::diff_fields(&reference, &smaller, Interp::Nearest),
+            Err(DoseError::MismatchedGrid { .. })
+        ));
+    }
+
+    // uniform unit grid with doses counting up in voxel order
+    fn counting_block(num_x: usize, num_y: usize, num_z: usize) -> DoseBlock {
+        let nodes = |n: usize| (0..=n).map(|i| i as f64).collect::<Vec<_>>();
+        let num_voxels = num_x * num_y * num_z;
+        DoseBlock {
+            xs: nodes(num_x),
+            ys: nodes(num_y),
+            zs: nodes(num_z),
+            doses: (0..num_voxels).map(|i| i as f64).collect(),
+            uncerts: vec![0.0; num_voxels],
+            variant: None,
+        }
+    }
+
+    // (element id, node ids) for each hexahedron in a msh2 file
+    fn msh2_elements(msh: &str) -> Vec<(usize, Vec<usize>)> {
+        msh.lines()
+            .skip_while(|line| *line != "$Elements")
+            .skip(2)
+            .take_while(|line| *line != "$EndElements")
+            .map(|line| {
+                let fields: Vec<usize> = line.split_whitespace().map(|f| f.parse().unwrap()).collect();
+                (fields[0], fields[5..].to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn thin_grid_numbering() {
+        for &(num_x, num_y, num_z) in &[(1, 1, 5), (1, 5, 1), (5, 1, 1), (1, 1, 1), (2, 3, 4)] {
+            let data = counting_block(num_x, num_y, num_z);
+            let mut msh = Vec::new();
+            data.write_msh2_to(&mut msh).unwrap();
+            let elements = msh2_elements(&String::from_utf8(msh).unwrap());
+            assert_eq!(elements.len(), data.num_voxels());
+
+            for k in 0..num_z {
+                for j in 0..num_y {
+                    for i in 0..num_x {
+                        let node = |di, dj, dk| data.grid_index(i + di, j + dj, k + dk) + 1;
+                        let expected = vec![
+                            node(0, 0, 0), node(1, 0, 0), node(1, 1, 0), node(0, 1, 0),
+                            node(0, 0, 1), node(1, 0, 1), node(1, 1, 1), node(0, 1, 1),
+                        ];
+                        let index = data.voxel_index(i, j, k);
+                        assert_eq!(elements[index], (index + 1, expected), "{}x{}x{}", num_x, num_y, num_z);
+                    }
+                }
+            }
+
+            // the dose at each centroid counts up in voxel order
+            for (index, [x, y, z], dose, _) in data.find_voxels(|_, _| true) {
+                assert_eq!(dose, index as f64);
+                let (i, j, k) = ((x - 0.5) as usize, (y - 0.5) as usize, (z - 0.5) as usize);
+                assert_eq!(data.voxel_index(i, j, k), index);
+                assert_eq!(data.voxel_ijk(index), (i, j, k));
+            }
+        }
+    }
+
+    #[test]
+    fn log_dose() {
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 2.0, 3.0],
+            ys: vec![0.0, 1.0],
+            zs: vec![0.0, 1.0],
+            doses: vec![0.0, 1.0e-3, 100.0],
+            uncerts: vec![0.0, 0.1, 0.01],
+            variant: None,
+        };
+        assert_eq!(data.log_dose(1.0e-5), vec![-5.0, -3.0, 2.0]);
+
+        let log = data.log_dose(1.0e-5);
+        let mut fields = data.default_fields(Fmt::Csv);
+        fields.push(Field { name: "log10 Dose [Gy cm2]", values: &log });
+        let mut out = Vec::new();
+        data.write_csv_fields_to(&mut out, &fields, &CsvOptions::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let mut lines = out.lines();
+        assert_eq!(
+            lines.next().unwrap(),
+            "xc [cm],yc [cm],zc [cm],Dose [Gy cm2],Uncertainty fraction,log10 Dose [Gy cm2]"
+        );
+        assert_eq!(lines.next().unwrap(), "0.5,0.5,0.5,0,0,-5");
+
+        let mut msh = Vec::new();
+        data.write_msh2_fields_to(&mut msh, &fields[2..], &MshOptions::default()).unwrap();
+        let msh = String::from_utf8(msh).unwrap();
+        assert_eq!(msh.matches("$ElementData").count(), 1);
+        assert!(msh.contains("1\n\"log10 Dose [Gy cm2]\"\n1\n0.0\n3\n0\n1\n3\n1 -5\n2 -3\n3 2\n$EndElementData"));
+    }
+
+    #[test]
+    fn physical_name() {
+        let data = counting_block(2, 1, 1);
+        let fields = data.default_fields(Fmt::Msh2);
+
+        let mut plain = Vec::new();
+        data.write_msh2_to(&mut plain).unwrap();
+        let plain = String::from_utf8(plain).unwrap();
+        assert!(!plain.contains("$PhysicalNames"));
+        assert!(plain.contains("\n1 5 2 0 0 1 2 5 4 7 8 11 10\n"));
+
+        let options = MshOptions { physical_name: Some("dose_volume".to_string()), ..Default::default() };
+        let mut named = Vec::new();
+        data.write_msh2_fields_to(&mut named, &fields, &options).unwrap();
+        let named = String::from_utf8(named).unwrap();
+        assert!(named.starts_with(
+            "$MeshFormat\n2.2 0 8\n$EndMeshFormat\n\
+             $PhysicalNames\n1\n3 1 \"dose_volume\"\n$EndPhysicalNames\n$Nodes\n"
+        ));
+        assert!(named.contains("\n1 5 2 1 1 1 2 5 4 7 8 11 10\n2 5 2 1 1 2 3 6 5 8 9 12 11\n"));
+
+        let options = MshOptions { physical_name: Some("\"".to_string()), ..Default::default() };
+        assert!(data.write_msh2_fields_to(&mut Vec::new(), &fields, &options).is_err());
+
+        let options = MshOptions { display_range: Some((0.5, 2.0)), ..Default::default() };
+        let mut ranged = Vec::new();
+        data.write_msh2_fields_to(&mut ranged, &fields, &options).unwrap();
+        assert!(String::from_utf8(ranged).unwrap().starts_with(
+            "$MeshFormat\n2.2 0 8\n$EndMeshFormat\n\
+             $Comments\ndose_display_range = 0.5 2\n$EndComments\n$Nodes\n"
+        ));
+    }
+
+    #[test]
+    fn quadratic_elements() {
+        let mut data = counting_block(2, 1, 2);
+        data.xs = vec![0.0, 1.0, 3.0];
+        let options = MshOptions { element_order: ElementOrder::Quadratic, ..Default::default() };
+        let mut out = Vec::new();
+        data.write_msh2_fields_to(&mut out, &data.default_fields(Fmt::Msh2), &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        let nodes: Vec<[f64; 3]> = out
+            .lines()
+            .skip_while(|line| *line != "$Nodes")
+            .skip(2)
+            .take_while(|line| *line != "$EndNodes")
+            .map(|line| {
+                let fields: Vec<f64> = line.split_whitespace().map(|f| f.parse().unwrap()).collect();
+                [fields[1], fields[2], fields[3]]
+            })
+            .collect();
+        assert_eq!(nodes.len(), 5 * 3 * 5);
+        assert!(nodes.contains(&[2.0, 0.5, 1.5]));
+
+        // every higher-order node sits at the mean of the corners gmsh defines it from
+        let parents: [&[usize]; 19] = [
+            &[0, 1], &[0, 3], &[0, 4], &[1, 2], &[1, 5], &[2, 3],
+            &[2, 6], &[3, 7], &[4, 5], &[4, 7], &[5, 6], &[6, 7],
+            &[0, 1, 2, 3], &[0, 1, 5, 4], &[0, 3, 7, 4], &[1, 2, 6, 5], &[2, 3, 7, 6], &[4, 5, 6, 7],
+            &[0, 1, 2, 3, 4, 5, 6, 7],
+        ];
+        let elements = msh2_elements(&out);
+        assert_eq!(elements.len(), 4);
+        assert!(out.contains("\n1 12 2 0 0 1 3 13 11 "));
+        for (_, element) in elements {
+            assert_eq!(element.len(), 27);
+            let node = |local: usize| nodes[element[local] - 1];
+            for (local, corners) in parents.iter().enumerate() {
+                for axis in 0..3 {
+                    let mean = corners.iter().map(|&c| node(c)[axis]).sum::<f64>() / corners.len() as f64;
+                    assert_eq!(node(8 + local)[axis], mean);
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_csv_matches_serial() {
+        // several chunks with a ragged last one
+        let data = counting_block(30, 20, 17);
+        let fields = data.default_fields(Fmt::Csv);
+
+        let options = CsvOptions { id_base: Some(0), ..Default::default() };
+        let mut parallel = Vec::new();
+        data.write_csv_fields_to(&mut parallel, &fields, &options).unwrap();
+
+        let mut serial = b"id,xc [cm],yc [cm],zc [cm],Dose [Gy cm2],Uncertainty fraction\n".to_vec();
+        let centroids = voxel::Centroids::new(&data);
+        data.write_csv_rows(&mut serial, &fields, &options, &centroids, 0..data.num_voxels()).unwrap();
+
+        assert!(parallel == serial);
+    }
+
+    #[test]
+    fn csv_ids() {
+        let data = counting_block(2, 1, 1);
+        let fields = data.default_fields(Fmt::Csv);
+        let csv_with = |id_base| {
+            let options = CsvOptions { id_base, ..Default::default() };
+            let mut out = Vec::new();
+            data.write_csv_fields_to(&mut out, &fields, &options).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert!(csv_with(None).starts_with("xc [cm],"));
+        assert_eq!(
+            csv_with(Some(0)),
+            "id,xc [cm],yc [cm],zc [cm],Dose [Gy cm2],Uncertainty fraction\n\
+             0,0.5,0.5,0.5,0,0\n1,1.5,0.5,0.5,1,0\n"
+        );
+        assert!(csv_with(Some(1)).ends_with("\n1,0.5,0.5,0.5,0,0\n2,1.5,0.5,0.5,1,0\n"));
+    }
+
+    #[test]
+    fn voxel_order() {
+        let data = counting_block(2, 3, 4);
+        let z_fastest: Vec<usize> = data.ordered_indices(Order::ZFastest).collect();
+        assert_eq!(z_fastest[..5], [0, 6, 12, 18, 2]);
+        let mut sorted = z_fastest.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..24).collect::<Vec<_>>());
+
+        let options = CsvOptions { id_base: Some(1), order: Order::ZFastest };
+        let mut out = Vec::new();
+        data.write_csv_fields_to(&mut out, &data.default_fields(Fmt::Csv), &options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let ids: Vec<&str> = out.lines().skip(1).take(3).map(|row| row.split(',').next().unwrap()).collect();
+        assert_eq!(ids, vec!["1", "7", "13"]);
+
+        let path = "tmp_voxel_order.raw";
+        let raw: Vec<u8> = z_fastest.iter().flat_map(|&index| (index as f64).to_le_bytes()).collect();
+        std::fs::write(path, &raw).unwrap();
+        assert_eq!(data.read_raw_field(path, Order::ZFastest).unwrap(), data.doses);
+        assert_ne!(data.read_raw_field(path, Order::XFastest).unwrap(), data.doses);
+        std::fs::write(path, &raw[8..]).unwrap();
+        assert!(matches!(
+            data.read_raw_field(path, Order::XFastest),
+            Err(DoseError::LengthMismatch { expected: 24, found: 23, .. })
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn flip_axis() {
+        let data = counting_block(2, 3, 4);
+        for &axis in &[Axis::X, Axis::Y, Axis::Z] {
+            let mut flipped = data.clone();
+            flipped.flip_axis(axis);
+            assert_ne!(flipped.doses, data.doses);
+            flipped.flip_axis(axis);
+            assert_eq!(flipped, data);
+        }
+
+        // dose rising along z falls after a z flip, on a mirrored grid
+        let mut flipped = data.clone();
+        flipped.flip_axis(Axis::Z);
+        assert_eq!(flipped.zs, vec![-4.0, -3.0, -2.0, -1.0, 0.0]);
+        assert_eq!(flipped.xs, data.xs);
+        let column: Vec<f64> = (0..4).map(|k| flipped.doses[flipped.voxel_index(1, 2, k)]).collect();
+        assert_eq!(column, vec![23.0, 17.0, 11.0, 5.0]);
+        assert!(flipped.warnings().is_empty());
+    }
+
+    #[test]
+    fn apply_mask() {
+        let mut data = counting_block(2, 2, 1);
+        data.uncerts = vec![0.5; 4];
+
+        let file = "tmp_mask.txt";
+        std::fs::write(file, "1 0\n0\n  1\n").unwrap();
+        let mask = read_mask(file).unwrap();
+        std::fs::remove_file(file).unwrap();
+        assert_eq!(mask, vec![true, false, false, true]);
+
+        data.apply_mask(&mask).unwrap();
+        assert_eq!(data.doses, vec![0.0, 0.0, 0.0, 3.0]);
+        assert_eq!(data.uncerts, vec![0.5, 0.0, 0.0, 0.5]);
+
+        assert!(matches!(
+            data.apply_mask(&mask[1..]),
+            Err(DoseError::LengthMismatch { field: "mask", expected: 4, found: 3 })
+        ));
+
+        let mut data = counting_block(2, 2, 1);
+        data.uncerts = vec![0.1, 0.3, 0.31, 0.9];
+        assert_eq!(data.mask_by_uncertainty(0.3, 0.0), 2);
+        assert_eq!(data.doses, vec![0.0, 1.0, 0.0, 0.0]);
+        assert_eq!(data.uncerts, vec![0.1, 0.3, 0.31, 0.9]);
+        data.mask_by_uncertainty(0.2, f64::NAN);
+        assert!(data.doses[0] == 0.0 && data.doses[1..].iter().all(|dose| dose.is_nan()));
+    }
+
+    #[test]
+    fn estimated_output_bytes() {
+        let mut data = counting_block(20, 15, 10);
+        data.doses.iter_mut().for_each(|dose| *dose *= 1.234_567_891_234_567e-12);
+        data.uncerts.iter_mut().enumerate().for_each(|(i, uncert)| *uncert = 1.0 / (i + 3) as f64);
+        data.xs.iter_mut().for_each(|x| *x *= -0.123_456_789);
+
+        for &format in &Fmt::ALL {
+            let mut out = Vec::new();
+            match format {
+                Fmt::Csv => data.write_csv_to(&mut out).unwrap(),
+                Fmt::Msh2 => data.write_msh2_to(&mut out).unwrap(),
+                Fmt::Msh4 => data.write_msh4_to(&mut out).unwrap(),
+                Fmt::Raw => {
+                    data.write_raw_field_to(&mut out, &data.doses, Order::XFastest).unwrap();
+                    data.write_raw_field_to(&mut out, &data.uncerts, Order::XFastest).unwrap();
+                    data.write_raw_header_to(&mut out, "dose.raw", "uncert.raw", Order::XFastest).unwrap();
+                }
+                Fmt::Mhd => {
+                    data.write_raw_field_to(&mut out, &data.doses, Order::XFastest).unwrap();
+                    data.write_raw_field_to(&mut out, &data.uncerts, Order::XFastest).unwrap();
+                }
+                Fmt::Vtk => data.write_vtk_to(&mut out).unwrap(),
+                Fmt::Vtr => data.write_vtr_to(&mut out).unwrap(),
+                Fmt::Vti => data.write_vti_to(&mut out).unwrap(),
+                Fmt::Dicom => data.write_dicom_rtdose_to(&mut out).unwrap(),
+                Fmt::Npy => {
+                    data.write_npy_field_to(&mut out, &data.doses).unwrap();
+                    data.write_npy_field_to(&mut out, &data.uncerts).unwrap();
+                }
+                Fmt::Dvh => DvhWriter::default().write(&data, &mut out).unwrap(),
+            }
+            let estimate = data.estimated_output_bytes(format, 2) as f64;
+            let actual = out.len() as f64;
+            assert!(estimate > 0.5 * actual && estimate < 2.0 * actual, "{:?} {} {}", format, estimate, actual);
+        }
+    }
+
+    #[test]
+    fn dose_uncert_scatter() {
+        use rand::SeedableRng;
+
+        let data = counting_block(10, 10, 10);
+        let scatter_seeded = |sample, seed| {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            let mut out = Vec::new();
+            data.write_dose_uncert_scatter_to(&mut out, sample, &mut rng).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let scatter = |sample| scatter_seeded(sample, SAMPLE_SEED);
+
+        let all = scatter(None);
+        assert_eq!(all.lines().count(), 1001);
+        assert!(all.starts_with("Dose [Gy cm2],Uncertainty fraction\n0,0\n1,0\n"));
+        assert_eq!(scatter(Some(5000)), all);
+
+        // reproducible, sorted and without repeats
+        let sampled = scatter(Some(50));
+        assert_eq!(sampled, scatter(Some(50)));
+        assert_ne!(sampled, scatter_seeded(Some(50), SAMPLE_SEED + 1));
+        let doses: Vec<f64> = sampled
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(doses.len(), 50);
+        assert!(doses.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn find_voxels() {
+        let data = DoseBlock {
+            xs: vec![0.0, 2.0],
+            ys: vec![0.0, 2.0, 4.0],
+            zs: vec![0.0, 2.0, 4.0, 8.0],
+            doses: vec![10.0, 20.0, 30.0, 40.0, 50.0, 60.0],
+            uncerts: vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6],
+            variant: None,
+        };
+
+        let hot = data.find_voxels(|dose, _| dose >= 50.0);
+        assert_eq!(hot, vec![(4, [1.0, 1.0, 6.0], 50.0, 0.5), (5, [1.0, 3.0, 6.0], 60.0, 0.6)]);
+
+        let noisy = data.find_voxels(|_, uncert| uncert > 0.25 && uncert < 0.35);
+        assert_eq!(noisy, vec![(2, [1.0, 1.0, 3.0], 30.0, 0.3)]);
+
+        assert!(data.find_voxels(|_, _| false).is_empty());
+        assert_eq!(data.find_voxels(|_, _| true).len(), data.num_voxels());
+    }
 }
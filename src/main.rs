@@ -1,23 +1,735 @@
-use dose2gmsh::{Cli, DoseBlock, Fmt};
+use dose2gmsh::{
+    Axis, Cli, Command, CsvOptions, DoseBlock, DoseError, DoseSum, ElementOrder, Field, FieldKind,
+    FieldOptions, Fmt, GammaCriteria, GammaNorm, GammaSummary, MshOptions, Order, Quantity,
+    UncertMode, VtkOptions, DEFAULT_BUFFER_SIZE, SAMPLE_SEED,
+};
+use rand::{rngs::StdRng, SeedableRng};
+use std::fs::File;
+use std::io::{BufWriter, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use structopt::clap::{Error, ErrorKind};
 use structopt::StructOpt;
 
-fn main() -> Result<(), std::io::Error> {
+fn main() {
+    if let Err(err) = run() {
+        eprint!("error: {}", err);
+        let mut source = std::error::Error::source(&err);
+        while let Some(cause) = source {
+            eprint!(": {}", cause);
+            source = cause.source();
+        }
+        eprintln!();
+        if let DoseError::TooLarge { .. } = err {
+            eprintln!("use --force to convert anyway");
+        }
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), DoseError> {
     let args = Cli::from_args();
-    let data = DoseBlock::from_3d_dose(&args.input_file)?;
+    #[cfg(feature = "config")]
+    let args = match &args.config {
+        Some(path) => dose2gmsh::Config::from_file(path)?.apply(std::env::args_os(), path)?,
+        None => args,
+    };
+
+    let buffer_size = args.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+    let mut kinds = selected_kinds(&args);
+    args.check_msh_only_output().unwrap_or_else(|err| err.exit());
+    if let Some(cmd) = args.cmd {
+        return run_command(cmd, args.seed, buffer_size);
+    }
+
+    let input_file = match args.input_file {
+        Some(input_file) => input_file,
+        None => Error::with_description(
+            "The following required arguments were not provided:\n    <input-file>",
+            ErrorKind::MissingRequiredArgument,
+        )
+        .exit(),
+    };
+
+    // `-o -` pipes the output to another program, e.g. csv into `column`
+    let to_stdout = args.output_file.as_ref().is_some_and(|name| name.as_os_str() == "-");
+    let format = output_format(args.format, args.output_file.as_deref());
+    if to_stdout && matches!(format, Fmt::Raw | Fmt::Mhd | Fmt::Npy | Fmt::Dicom) {
+        Error::with_description(
+            "raw, mhd, npy and dicom output are binary files, which can't be written to stdout",
+            ErrorKind::ArgumentConflict,
+        )
+        .exit()
+    }
+
+    if args.stream {
+        let mut output_name = args.output_file.unwrap_or_else(|| output_base(&input_file));
+        output_name.set_extension("msh");
+        return dose2gmsh::convert_3ddose_to_msh_buffered(&input_file, output_name, buffer_size);
+    }
+
+    if args.single_precision {
+        let data = DoseBlock::from_3d_dose_f32_buffered(&input_file, buffer_size)?;
+        let mut output_name = args.output_file.unwrap_or_else(|| output_base(&input_file));
+        if !to_stdout {
+            output_name.set_extension("msh");
+        }
+        let output = create_output(&output_name, to_stdout)?;
+        let mut out = BufWriter::with_capacity(buffer_size, output);
+        data.write_msh2_f32_to(&kinds, &mut out)?;
+        out.flush()?;
+        return Ok(());
+    }
+
+    let progress = !args.quiet && (args.progress || std::io::stderr().is_terminal());
+    let mut data = if progress {
+        let mut bar = ProgressBar::new("reading", Some(std::fs::metadata(&input_file)?.len()));
+        DoseBlock::from_3d_dose_with_progress(&input_file, buffer_size, move |bytes| {
+            bar.update(bytes)
+        })?
+    } else {
+        DoseBlock::from_3d_dose_buffered(&input_file, buffer_size)?
+    };
+    if args.strict {
+        data.check_finite()?;
+    } else if data.num_non_finite() > 0 {
+        eprintln!(
+            "warning: {} voxels have a non-finite dose or uncertainty",
+            data.num_non_finite()
+        );
+    }
+
+    // check the file before changing anything
+    if let Some(dims) = args.expect_dims {
+        data.expect_dims(dims)?;
+    }
+    if let Some(extent) = args.expect_extent {
+        data.expect_extent(extent, args.extent_tol)?;
+    }
+
+    if !args.add.is_empty() {
+        let mut blocks = vec![(1.0, data)];
+        for added in &args.add {
+            let block = DoseBlock::from_3d_dose_buffered(&added.path, buffer_size)?;
+            warn_magnitude_gap(blocks[0].1.magnitude_gap(&block));
+            blocks.push((added.weight, block));
+        }
+        data = DoseBlock::sum_weighted(&blocks)?;
+    }
+    if let Some(mask) = args.mask {
+        data.apply_mask(&dose2gmsh::read_mask(mask)?)?;
+    }
+    if let Some(max_frac) = args.max_uncert {
+        let masked = data.mask_by_uncertainty(max_frac, args.uncert_fill.unwrap_or(0.0));
+        eprintln!("masked {} voxels above {} % uncertainty", masked, 100.0 * max_frac);
+    }
+    if let Some([x, y, z]) = args.crop {
+        data = data.crop(x, y, z)?;
+    }
+    if args.trim_air {
+        let volume = data.volume();
+        data = data.auto_crop(args.air_threshold);
+        let trimmed = volume - data.volume();
+        eprintln!(
+            "trimmed {:.3} of {:.3} cm3 ({:.1} %) below {} % of the maximum dose",
+            trimmed,
+            volume,
+            100.0 * trimmed / volume,
+            100.0 * args.air_threshold
+        );
+    }
+
+    for &(flip, axis) in &[(args.flip_x, Axis::X), (args.flip_y, Axis::Y), (args.flip_z, Axis::Z)] {
+        if flip {
+            data.flip_axis(axis);
+        }
+    }
+    if let Some([sx, sy, sz]) = args.scale_coords {
+        data.scale_coords(sx, sy, sz)?;
+    }
+    if let Some(factors) = args.downsample {
+        data = data.downsample(factors, args.downsample_mode)?;
+    }
+    if let Some(kernel) = args.smooth {
+        data = data.smooth(kernel)?;
+    }
+
+    if let Some(factor) = args.scale {
+        data.scale_dose(factor);
+    }
+    if let Some(mu) = args.mu {
+        data.normalize_per_mu(mu, args.cal_factor.unwrap_or(1.0))?;
+    }
+    if let Some([x, y, z]) = args.normalize_point {
+        data.normalize_to_point(x, y, z)?;
+    }
+
+    let quantity = match args.time {
+        Some(seconds) => {
+            data.to_rate(seconds)?;
+            Quantity::DoseRate
+        }
+        None => Quantity::Dose,
+    };
+
+    if args.report_uncertainty_quality {
+        eprintln!("{}", data.uncertainty_quality());
+    }
+    if args.integral {
+        eprintln!("integral dose:     {:e} Gy·cm2·cm3", data.integral_dose());
+    }
+    if args.stats || args.stats_only {
+        eprintln!("{}", data.stats());
+        if args.stats_only {
+            return Ok(());
+        }
+    }
+
+    if let Some(line) = args.profile {
+        let interp = args.interp;
+        if to_stdout {
+            let mut out = BufWriter::with_capacity(buffer_size, std::io::stdout().lock());
+            data.write_profile_csv_to(&mut out, line, interp)?;
+            out.flush()?;
+            return Ok(());
+        }
+        let output_name = args.output_file.unwrap_or_else(|| {
+            let base = output_base(&input_file);
+            let mut name = base.file_stem().unwrap_or_default().to_os_string();
+            name.push("_profile.csv");
+            base.with_file_name(name)
+        });
+        data.write_profile_csv(output_name, line, interp)?;
+        return Ok(());
+    }
+    if args.interp.is_some() {
+        eprintln!("warning: --interp only applies to --profile");
+    }
+    if let Some(plane) = args.slice {
+        if to_stdout {
+            let mut out = BufWriter::with_capacity(buffer_size, std::io::stdout().lock());
+            data.write_slice_csv_to(&mut out, plane, quantity)?;
+            out.flush()?;
+            return Ok(());
+        }
+        let output_name = args.output_file.unwrap_or_else(|| {
+            let base = output_base(&input_file);
+            let mut name = base.file_stem().unwrap_or_default().to_os_string();
+            name.push("_slice.csv");
+            base.with_file_name(name)
+        });
+        data.write_slice_csv(output_name, plane, quantity)?;
+        return Ok(());
+    }
 
+    // image data needs even spacing, which a rectilinear grid doesn't
+    let format = match format {
+        Fmt::Vti if data.uniform_spacing().is_none() => {
+            eprintln!("warning: the voxels aren't evenly spaced, writing vtr instead of vti");
+            Fmt::Vtr
+        }
+        format => format,
+    };
     let mut output_name = match args.output_file {
         Some(name) => name,
-        None => args.input_file.clone(),
+        None => output_base(&input_file),
+    };
+    #[cfg(feature = "gzip")]
+    let gzip = {
+        let named = dose2gmsh::Compression::from_path(&output_name);
+        if named.is_some() {
+            // out.msh.gz is out.msh compressed
+            output_name.set_extension("");
+        }
+        match (args.compress.or(named), format) {
+            (Some(_), Fmt::Raw | Fmt::Mhd | Fmt::Npy | Fmt::Dicom) => {
+                eprintln!("warning: compression only applies to msh, csv and VTK output");
+                false
+            }
+            (compress, _) => compress.is_some(),
+        }
     };
+    #[cfg(not(feature = "gzip"))]
+    let gzip = false;
+    if gzip && to_stdout && std::io::stdout().is_terminal() {
+        eprintln!("warning: writing gzipped output to a terminal");
+    }
+
+    if !to_stdout {
+        output_name.set_extension(format.extension());
+    }
+    // the file written, which differs from the output name for compressed output
+    let output_path = if gzip {
+        let mut name = output_name.clone().into_os_string();
+        name.push(".gz");
+        std::path::PathBuf::from(name)
+    } else {
+        output_name.clone()
+    };
+
+    let log_floor = match args.log_floor {
+        Some(floor) if !(floor.is_finite() && floor > 0.0) => {
+            return Err(DoseError::InvalidParameter { name: "log_floor", value: floor });
+        }
+        Some(floor) => floor,
+        None => 1.0e-6 * data.doses.iter().cloned().fold(f64::MIN_POSITIVE, f64::max),
+    };
+    let coverage = match args.ci {
+        Some(k) if !(k.is_finite() && k > 0.0) => {
+            return Err(DoseError::InvalidParameter { name: "ci", value: k });
+        }
+        Some(k) => {
+            kinds.extend(&[FieldKind::DoseLow, FieldKind::DoseHigh]);
+            k
+        }
+        None => 1.0,
+    };
+    let field_options = FieldOptions { log_floor, coverage, clamp_low: args.clamp_ci };
+    let values: Vec<_> = kinds.iter().map(|&kind| data.field_values(kind, &field_options)).collect();
+    let fields: Vec<Field> = kinds
+        .iter()
+        .zip(&values)
+        .map(|(kind, values)| Field { name: kind.label(format, quantity), values })
+        .collect();
+
+    let msh = matches!(format, Fmt::Msh2 | Fmt::Msh4);
+    if args.phys_name.is_some() && !msh {
+        eprintln!("warning: --phys-name only applies to msh output");
+    }
+    if args.order != ElementOrder::Linear && !msh {
+        eprintln!("warning: --order only applies to msh output");
+    }
+    if args.display_range.is_some() && !(msh || matches!(format, Fmt::Vtk | Fmt::Vtr | Fmt::Vti)) {
+        eprintln!("warning: --display-range only applies to msh and vtk output");
+    }
+    let display_range = args.display_range.map(|(lo, hi)| data.percentile_range(lo, hi));
+    if args.flatten_to_2d && !msh {
+        eprintln!("warning: --flatten-to-2d only applies to msh output");
+    }
+    let binary = match (args.binary, format) {
+        (true, Fmt::Msh2) => true,
+        (true, _) => {
+            eprintln!("warning: --binary only applies to msh2 output");
+            false
+        }
+        (false, _) => false,
+    };
+    if binary && !gzip && to_stdout && std::io::stdout().is_terminal() {
+        eprintln!("warning: writing binary output to a terminal");
+    }
+    let msh_options = MshOptions {
+        physical_name: args.phys_name,
+        element_order: args.order,
+        display_range,
+        flatten_2d: args.flatten_to_2d,
+        binary,
+    };
+    let vtk_options = VtkOptions { display_range };
+
+    if args.voxel_order != Order::XFastest && !matches!(format, Fmt::Csv | Fmt::Raw) {
+        eprintln!("warning: --voxel-order only applies to csv and raw output");
+    }
+
+    if args.uncert_image && !matches!(format, Fmt::Mhd) {
+        eprintln!("warning: --uncert-image only applies to mhd output");
+    }
 
-    match args.format {
-        Fmt::Csv => {
-            output_name.set_extension("csv");
-            data.write_csv(&output_name)
+    // gmsh requires 1-based ids
+    if args.zero_based && msh {
+        eprintln!("warning: --zero-based is ignored for msh output, which is always 1-based");
+    }
+    let csv_options = CsvOptions {
+        id_base: match (args.zero_based, args.ids) {
+            (true, _) => Some(0),
+            (false, true) => Some(1),
+            (false, false) => None,
         },
-        Fmt::Msh2 => {
-            output_name.set_extension("msh");
-            data.write_msh2(&output_name)
+        order: args.voxel_order,
+    };
+
+    if !args.force {
+        let num_voxels = data.num_voxels() as u64;
+        if let Some(limit) = args.max_voxels.filter(|&limit| num_voxels > limit) {
+            return Err(DoseError::TooLarge { what: "voxels", size: num_voxels, limit });
+        }
+        let size = data.estimated_output_bytes(format, fields.len());
+        if let Some(limit) = args.max_output_bytes.filter(|&limit| size > limit) {
+            return Err(DoseError::TooLarge { what: "estimated output bytes", size, limit });
+        }
+    }
+
+    // raw, mhd and npy output are several files of dose and uncertainty only, dicom of dose only
+    if let Fmt::Raw | Fmt::Mhd | Fmt::Npy | Fmt::Dicom = format {
+        let absolute = args.uncert == UncertMode::Absolute;
+        if !args.fields.is_empty() || !args.select_fields.is_empty() || absolute {
+            eprintln!(
+                "warning: --field, --fields and --uncert only apply to msh, csv and VTK output"
+            );
+        }
+        match format {
+            Fmt::Raw => data.write_raw(&output_name, args.voxel_order)?,
+            Fmt::Mhd => data.write_mhd(&output_name, args.uncert_image)?,
+            Fmt::Npy => data.write_npy(&output_name)?,
+            _ => data.write_dicom_rtdose(&output_name)?,
+        }
+        return Ok(());
+    }
+
+    let coalesce = match (args.coalesce, format) {
+        (Some(_), Fmt::Msh2) if kinds != [FieldKind::Dose, FieldKind::Uncert] => {
+            eprintln!("warning: --coalesce writes dose and uncertainty only, ignoring other fields");
+            args.coalesce
+        }
+        (Some(_), Fmt::Msh2) => args.coalesce,
+        (Some(_), _) => {
+            eprintln!("warning: --coalesce only applies to msh2 output");
+            None
+        }
+        (None, _) => None,
+    };
+
+    if args.emit_geo {
+        if to_stdout {
+            eprintln!("warning: --emit-geo needs an output file, not stdout");
+        } else if msh {
+            let msh_file = output_path.file_name().unwrap_or_default().to_string_lossy();
+            let dose_view = match coalesce {
+                // dose and uncertainty only
+                Some(_) => Some(0),
+                None => kinds.iter().position(|&kind| kind == FieldKind::Dose),
+            };
+            let percentiles = args.display_range.unwrap_or(dose2gmsh::GEO_PERCENTILES);
+            let mut geo = BufWriter::new(File::create(output_name.with_extension("geo"))?);
+            data.write_geo_to(&mut geo, &msh_file, dose_view, percentiles)?;
+            geo.flush()?;
+        } else {
+            eprintln!("warning: --emit-geo only applies to msh output");
+        }
+    }
+
+    if let Some(frac) = coalesce {
+        let max_dose = data.doses.iter().cloned().fold(0.0, f64::max);
+        let mesh = data.coalesce_equal(frac * max_dose);
+        eprintln!("coalesced {} voxels into {} elements", data.num_voxels(), mesh.elements.len());
+        let file = with_progress(create_output(&output_path, to_stdout)?, progress);
+        write_output(file, gzip, buffer_size, |mut out| mesh.write_msh2_to(&mut out, quantity))?;
+        return Ok(());
+    }
+
+    let writer = format.writer(csv_options, msh_options, vtk_options);
+    let writer = writer.expect("single-file format");
+    let file = with_progress(create_output(&output_path, to_stdout)?, progress);
+    let write = |out: &mut dyn Write| writer.write_fields(&data, &fields, out);
+
+    #[cfg(feature = "hash")]
+    {
+        if args.hash {
+            let file = write_output(dose2gmsh::HashWriter::new(file), gzip, buffer_size, write)?;
+            let (_, digest) = file.finish();
+            if to_stdout {
+                // keep the digest out of the piped output
+                eprintln!("-: {}", digest);
+            } else {
+                println!("{}: {}", output_path.display(), digest);
+            }
+            return Ok(());
+        }
+    }
+
+    write_output(file, gzip, buffer_size, write)?;
+    Ok(())
+}
+
+/// Warn that two doses look differently normalized, given the decades between them from
+/// [`DoseBlock::magnitude_gap`].
+fn warn_magnitude_gap(gap: Option<f64>) {
+    if let Some(gap) = gap {
+        eprintln!(
+            "warning: total doses differ by {:.1} orders of magnitude, \
+             check both files use the same normalization",
+            gap
+        );
+    }
+}
+
+/// Fields chosen with --fields, --field and --uncert, before any added by other options.
+fn selected_kinds(args: &Cli) -> Vec<FieldKind> {
+    let mut kinds = if args.select_fields.is_empty() {
+        vec![FieldKind::Dose, FieldKind::Uncert]
+    } else {
+        args.select_fields.clone()
+    };
+    kinds.extend(&args.fields);
+    kinds.into_iter().map(|kind| args.uncert.select(kind)).collect()
+}
+
+/// The file at `path` to write output to, or stdout if `to_stdout`.
+fn create_output(path: &Path, to_stdout: bool) -> std::io::Result<Box<dyn Write>> {
+    if to_stdout {
+        Ok(Box::new(std::io::stdout().lock()))
+    } else {
+        Ok(Box::new(File::create(path)?))
+    }
+}
+
+/// `file`, showing how much has been written to it if `progress`.
+fn with_progress(file: Box<dyn Write>, progress: bool) -> Box<dyn Write> {
+    if !progress {
+        return file;
+    }
+    let mut bar = ProgressBar::new("writing", None);
+    Box::new(dose2gmsh::Progress::new(file, move |bytes| bar.update(bytes)))
+}
+
+/// A one-line progress display on stderr, redrawn at most every 100 ms and ended when dropped.
+///
+/// When stderr isn't a terminal, e.g. a log file with `--progress`, nothing is redrawn and only
+/// the final line is written, so the output doesn't fill up with carriage returns.
+struct ProgressBar {
+    label: &'static str,
+    /// Bytes expected, if known.
+    total: Option<u64>,
+    bytes: u64,
+    drawn: Option<Instant>,
+    terminal: bool,
+}
+
+impl ProgressBar {
+    const WIDTH: usize = 30;
+
+    fn new(label: &'static str, total: Option<u64>) -> Self {
+        let terminal = std::io::stderr().is_terminal();
+        ProgressBar { label, total, bytes: 0, drawn: None, terminal }
+    }
+
+    fn update(&mut self, bytes: u64) {
+        self.bytes = bytes;
+        if !self.terminal {
+            return;
+        }
+        if self.drawn.is_none_or(|at| at.elapsed() >= Duration::from_millis(100)) {
+            self.draw();
+        }
+    }
+
+    fn draw(&mut self) {
+        let megabytes = |bytes: u64| bytes as f64 / 1.0e6;
+        let start = if self.terminal { "\r" } else { "" };
+        match self.total {
+            Some(total) if total > 0 => {
+                let fraction = (self.bytes as f64 / total as f64).min(1.0);
+                let filled = (fraction * Self::WIDTH as f64) as usize;
+                eprint!(
+                    "{}{} [{}{}] {:3.0} % of {:.1} MB",
+                    start,
+                    self.label,
+                    "#".repeat(filled),
+                    " ".repeat(Self::WIDTH - filled),
+                    100.0 * fraction,
+                    megabytes(total)
+                );
+            }
+            _ => eprint!("{}{} {:.1} MB", start, self.label, megabytes(self.bytes)),
+        }
+        self.drawn = Some(Instant::now());
+    }
+}
+
+impl Drop for ProgressBar {
+    fn drop(&mut self) {
+        if self.drawn.is_some() || (!self.terminal && self.bytes > 0) {
+            self.draw();
+            eprintln!();
+        }
+    }
+}
+
+/// Run `write` on `file` through a buffer of `buffer_size` bytes, gzipping what it writes if
+/// `gzip`, and return `file` once everything is written.
+#[cfg_attr(not(feature = "gzip"), allow(unused_variables))]
+fn write_output<W, F>(file: W, gzip: bool, buffer_size: usize, write: F) -> std::io::Result<W>
+where
+    W: Write,
+    F: FnOnce(&mut dyn Write) -> std::io::Result<()>,
+{
+    #[cfg(feature = "gzip")]
+    {
+        if gzip {
+            let encoder = dose2gmsh::Compression::Gzip.writer(file);
+            let mut out = BufWriter::with_capacity(buffer_size, encoder);
+            write(&mut out)?;
+            return out.into_inner().map_err(|err| err.into_error())?.finish();
+        }
+    }
+    let mut out = BufWriter::with_capacity(buffer_size, file);
+    write(&mut out)?;
+    out.into_inner().map_err(|err| err.into_error())
+}
+
+/// The writer for a map a subcommand writes, e.g. the gamma map of `compare`, in the format its
+/// file extension names. Exits with a usage error for extensions of multi-file formats and
+/// extensions naming no format.
+fn map_writer(output_file: &Path) -> Box<dyn dose2gmsh::DoseWriter> {
+    let extension = output_file.extension().and_then(|ext| ext.to_str());
+    let format = extension.and_then(Fmt::from_extension);
+    let writer = format.and_then(|format| {
+        format.writer(CsvOptions::default(), MshOptions::default(), VtkOptions::default())
+    });
+    writer.unwrap_or_else(|| {
+        Error::with_description(
+            &format!(
+                "can't write {} as msh, csv or VTK, name it .msh, .csv, .vtk, .vtr or .vti",
+                output_file.display()
+            ),
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    })
+}
+
+/// The input file name without a `.gz` extension, to build default output names from, so
+/// `run.3ddose.gz` converts to `run.msh` rather than to `run.3ddose.msh`, gzipped.
+fn output_base(input_file: &Path) -> PathBuf {
+    match input_file.extension() {
+        Some(ext) if ext == "gz" => input_file.with_extension(""),
+        _ => input_file.to_path_buf(),
+    }
+}
+
+/// The output format `--format` gives, or else the one the output file extension names, looking
+/// past `.gz`, or msh2. `--format` wins over an extension naming another format, with a warning.
+fn output_format(format: Option<Fmt>, output_file: Option<&Path>) -> Fmt {
+    let name = output_file.map(output_base);
+    let extension = name.as_deref().and_then(Path::extension).and_then(|ext| ext.to_str());
+    match (format, extension.and_then(Fmt::from_extension)) {
+        (Some(format), Some(named)) if format.extension() != named.extension() => {
+            eprintln!(
+                "warning: writing {} output as --format says, not {} as the .{} extension says",
+                format.name(),
+                named.name(),
+                extension.unwrap_or_default()
+            );
+            format
+        }
+        (Some(format), _) => format,
+        (None, named) => named.unwrap_or(Fmt::Msh2),
+    }
+}
+
+fn run_command(cmd: Command, seed: Option<u64>, buffer_size: usize) -> Result<(), DoseError> {
+    match cmd {
+        Command::Formats => {
+            for format in &Fmt::ALL {
+                let extension = format!(".{}", format.extension());
+                println!("{:<6}{:<6}{}", format.name(), extension, format.description());
+            }
+        }
+        Command::Info { input_file, #[cfg(feature = "serde")] json } => {
+            let info = DoseBlock::from_3d_dose_buffered(&input_file, buffer_size)?.info();
+            #[cfg(feature = "serde")]
+            {
+                if json {
+                    let json = serde_json::to_string_pretty(&info).expect("info is serializable");
+                    println!("{}", json);
+                    return Ok(());
+                }
+            }
+            println!("{}", info);
+        }
+        Command::Scatter { input_file, output_file, sample } => {
+            let data = DoseBlock::from_3d_dose_buffered(&input_file, buffer_size)?;
+            let output_file = output_file.unwrap_or_else(|| {
+                let base = output_base(&input_file);
+                let mut name = base.file_stem().unwrap_or_default().to_os_string();
+                name.push("_scatter.csv");
+                base.with_file_name(name)
+            });
+            let mut rng = StdRng::seed_from_u64(seed.unwrap_or(SAMPLE_SEED));
+            data.write_dose_uncert_scatter(output_file, sample, &mut rng)?;
+        }
+        Command::Compare { files, dose_tol, dist_tol, threshold, local, output_file } => {
+            let (reference, evaluated) = files.paths().unwrap_or_else(|err| err.exit());
+            // before the comparison, which can take a while
+            let writer = output_file.as_deref().map(map_writer);
+            let reference = DoseBlock::from_3d_dose_buffered(reference, buffer_size)?;
+            let evaluated = DoseBlock::from_3d_dose_buffered(evaluated, buffer_size)?;
+            let norm = if local { GammaNorm::Local } else { GammaNorm::Global };
+            let criteria = GammaCriteria { dose_tol, dist_tol, threshold, norm };
+            let map = dose2gmsh::gamma(&reference, &evaluated, &criteria)?;
+            println!("{}", GammaSummary::new(&map));
+            if let (Some(output_file), Some(writer)) = (output_file, writer) {
+                let fields = [Field { name: "Gamma", values: &map.doses }];
+                write_output(File::create(output_file)?, false, buffer_size, |out| {
+                    writer.write_fields(&map, &fields, out)
+                })?;
+            }
+        }
+        Command::Combine { input_files, output_file, mean } => {
+            // summed here rather than with `DoseBlock::sum_dose_files` to check each file
+            let first = DoseBlock::from_3d_dose_buffered(&input_files[0], buffer_size)?;
+            let mut sum = DoseSum::new(&first, 1.0);
+            for input_file in &input_files[1..] {
+                let block = DoseBlock::from_3d_dose_buffered(input_file, buffer_size)?;
+                warn_magnitude_gap(sum.magnitude_gap(&block));
+                sum.add(&block, 1.0)?;
+            }
+            let mut combined = sum.finish();
+            if mean {
+                let count = input_files.len() as f64;
+                combined.doses.iter_mut().for_each(|dose| *dose /= count);
+            }
+            let output_file = output_file.unwrap_or_else(|| {
+                let base = output_base(&input_files[0]);
+                let mut name = base.file_stem().unwrap_or_default().to_os_string();
+                name.push("_combined.msh");
+                base.with_file_name(name)
+            });
+            combined.write_msh2(output_file)?;
+        }
+        Command::Diff { minuend, subtrahend, output_file } => {
+            let minuend_data = DoseBlock::from_3d_dose_buffered(&minuend, buffer_size)?;
+            let subtrahend = DoseBlock::from_3d_dose_buffered(subtrahend, buffer_size)?;
+            warn_magnitude_gap(minuend_data.magnitude_gap(&subtrahend));
+            let diff = minuend_data.difference(&subtrahend)?;
+            let output_file = output_file.unwrap_or_else(|| {
+                let base = output_base(&minuend);
+                let mut name = base.file_stem().unwrap_or_default().to_os_string();
+                name.push("_diff.msh");
+                base.with_file_name(name)
+            });
+            let fields = [
+                Field { name: "Dose difference [Gy·cm2]", values: &diff.doses },
+                Field { name: "Uncertainty fraction", values: &diff.uncerts },
+            ];
+            diff.write_msh2_fields(output_file, &fields, &MshOptions::default())?;
+        }
+        Command::Completions { shell } => {
+            Cli::clap().gen_completions_to("dose2gmsh", shell, &mut std::io::stdout());
+        }
+        #[cfg(feature = "image")]
+        Command::Orthoslices { input_file, output_file, at, colormap, pixels_per_voxel } => {
+            let data = DoseBlock::from_3d_dose_buffered(&input_file, buffer_size)?;
+            let output_file = output_file.unwrap_or_else(|| {
+                let base = output_base(&input_file);
+                let mut name = base.file_stem().unwrap_or_default().to_os_string();
+                name.push("_orthoslices.png");
+                base.with_file_name(name)
+            });
+            let options = dose2gmsh::OrthosliceOptions { at, colormap, pixels_per_voxel };
+            data.write_orthoslices_png(output_file, &options)?;
+        }
+        #[cfg(feature = "image")]
+        Command::SlicePng { input_file, plane, output_file, colormap, range, pixels_per_voxel } => {
+            let data = DoseBlock::from_3d_dose_buffered(&input_file, buffer_size)?;
+            let output_file = output_file.unwrap_or_else(|| {
+                let base = output_base(&input_file);
+                let mut name = base.file_stem().unwrap_or_default().to_os_string();
+                name.push("_slice.png");
+                base.with_file_name(name)
+            });
+            let options = dose2gmsh::SliceImageOptions { colormap, range, pixels_per_voxel };
+            data.write_slice_png(output_file, plane, &options)?;
         }
     }
+    Ok(())
 }
@@ -0,0 +1,282 @@
+//! Gmsh `.msh` version 2.2 output in binary, for meshes too large to write comfortably as text.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::{
+    with_midpoints, write_msh_names, DoseBlock, ElementOrder, Field, Fmt, MshOptions,
+    DEFAULT_BUFFER_SIZE,
+};
+
+impl DoseBlock {
+    /// Convert the `3ddose` data to a binary Gmsh `.msh` file (version 2.2).
+    pub fn write_msh2_binary<P: AsRef<std::path::Path>>(
+        &self,
+        output: P,
+    ) -> Result<(), std::io::Error> {
+        let mut filestream = BufWriter::with_capacity(DEFAULT_BUFFER_SIZE, File::create(output)?);
+        self.write_msh2_binary_to(&mut filestream)?;
+        filestream.flush()
+    }
+
+    /// Write the `3ddose` data in binary Gmsh `.msh` format (version 2.2) to any writer.
+    pub fn write_msh2_binary_to<W: Write>(&self, filestream: &mut W) -> Result<(), std::io::Error> {
+        let fields = self.default_fields(Fmt::Msh2);
+        self.write_msh2_binary_fields_to(filestream, &fields, &MshOptions::default())
+    }
+
+    /// Write the mesh and the given fields in binary Gmsh `.msh` format (version 2.2) to any
+    /// writer, whatever [`MshOptions::binary`] says.
+    ///
+    /// The header is `2.2 1 8` followed by the int 1, which readers use to check the byte
+    /// order. Ints are 4 bytes and reals 8, all little-endian. `$Nodes` holds an id and three
+    /// coordinates per node, `$Elements` one block of elements, each an id, its two tags and its
+    /// nodes, and each `$ElementData` section keeps its text tags but holds an id and a value per
+    /// element. Section names and counts stay text, and the mesh is otherwise the same as
+    /// [`DoseBlock::write_msh2_fields_to`] writes.
+    ///
+    /// Grids with more than `i32::MAX` nodes are an [`std::io::ErrorKind::InvalidInput`]
+    /// error.
+    pub fn write_msh2_binary_fields_to<W: Write>(
+        &self,
+        filestream: &mut W,
+        fields: &[Field],
+        options: &MshOptions,
+    ) -> Result<(), std::io::Error> {
+        self.check_field_lengths(fields)?;
+
+        write!(filestream, "$MeshFormat\n2.2 1 8\n")?;
+        filestream.write_all(&1i32.to_le_bytes())?;
+        writeln!(filestream, "\n$EndMeshFormat")?;
+        // element tags: physical group and elementary entity
+        let tags = if write_msh_names(filestream, options)? { [1, 1] } else { [0, 0] };
+        match options.element_order {
+            ElementOrder::Linear if options.flatten_2d => {
+                let (us, vs, quads) = self.quad4_mesh()?;
+                write_binary_nodes(filestream, us, vs, &[0.0])?;
+                // 3 is the gmsh magic number for a 4-node quadrangle
+                write_binary_elements(filestream, 3, self.num_voxels(), tags, quads)?;
+            }
+            ElementOrder::Quadratic if options.flatten_2d => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "only linear elements can be flattened to 2d",
+                ));
+            }
+            ElementOrder::Linear => {
+                write_binary_nodes(filestream, &self.xs, &self.ys, &self.zs)?;
+                // 5 is the gmsh magic number for an 8-node hexahedron
+                let hexes = self.hex_elements();
+                write_binary_elements(filestream, 5, self.num_voxels(), tags, hexes)?;
+            }
+            ElementOrder::Quadratic => {
+                let xs = with_midpoints(&self.xs);
+                let ys = with_midpoints(&self.ys);
+                let zs = with_midpoints(&self.zs);
+                write_binary_nodes(filestream, &xs, &ys, &zs)?;
+                // 12 is the gmsh magic number for a 27-node hexahedron
+                let hexes = self.hex27_elements();
+                write_binary_elements(filestream, 12, self.num_voxels(), tags, hexes)?;
+            }
+        }
+
+        for field in fields {
+            write_binary_element_data(filestream, field.name, field.values)?;
+        }
+        Ok(())
+    }
+}
+
+/// A node or element id, or other count, as the 4-byte little-endian int binary msh files use.
+fn int(value: usize) -> Result<[u8; 4], std::io::Error> {
+    match i32::try_from(value) {
+        Ok(value) => Ok(value.to_le_bytes()),
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is too large for a binary msh file's 4-byte ints", value),
+        )),
+    }
+}
+
+/// Write the `$Nodes` section for the grid of nodes `xs`, `ys` and `zs`, numbered from 1
+/// *x* fastest.
+fn write_binary_nodes<W: Write>(
+    filestream: &mut W,
+    xs: &[f64],
+    ys: &[f64],
+    zs: &[f64],
+) -> Result<(), std::io::Error> {
+    let num_nodes = xs.len() * ys.len() * zs.len();
+    int(num_nodes)?;
+    write!(filestream, "$Nodes\n{}\n", num_nodes)?;
+    let mut id = 0i32;
+    for z in zs {
+        for y in ys {
+            for x in xs {
+                id += 1;
+                filestream.write_all(&id.to_le_bytes())?;
+                for coord in &[x, y, z] {
+                    filestream.write_all(&coord.to_le_bytes())?;
+                }
+            }
+        }
+    }
+    writeln!(filestream, "\n$EndNodes")?;
+    Ok(())
+}
+
+/// Write the `$Elements` section as one block of `count` elements of gmsh type `elm_type`,
+/// numbered from 1, each with the same two `tags`.
+fn write_binary_elements<W: Write, const N: usize>(
+    filestream: &mut W,
+    elm_type: i32,
+    count: usize,
+    tags: [i32; 2],
+    elements: impl Iterator<Item = [usize; N]>,
+) -> Result<(), std::io::Error> {
+    write!(filestream, "$Elements\n{}\n", count)?;
+    // block header: element type, number of elements, number of tags
+    filestream.write_all(&elm_type.to_le_bytes())?;
+    filestream.write_all(&int(count)?)?;
+    filestream.write_all(&2i32.to_le_bytes())?;
+    for (index, nodes) in elements.enumerate() {
+        filestream.write_all(&int(index + 1)?)?;
+        for tag in &tags {
+            filestream.write_all(&tag.to_le_bytes())?;
+        }
+        for &node in &nodes {
+            filestream.write_all(&int(node)?)?;
+        }
+    }
+    writeln!(filestream, "\n$EndElements")?;
+    Ok(())
+}
+
+/// Write an `$ElementData` section with text tags, as in text files, and one binary id and
+/// value per element, numbering elements from 1.
+fn write_binary_element_data<W: Write>(
+    filestream: &mut W,
+    name: &str,
+    data: &[f64],
+) -> Result<(), std::io::Error> {
+    // the field name, the time, then timestep 0 of a 1-component field with one value per
+    // element
+    write!(filestream, "$ElementData\n1\n\"{}\"\n1\n0.0\n3\n0\n1\n{}\n", name, data.len())?;
+    for (index, value) in data.iter().enumerate() {
+        filestream.write_all(&int(index + 1)?)?;
+        filestream.write_all(&value.to_le_bytes())?;
+    }
+    writeln!(filestream, "\n$EndElementData")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    /// Read the 4-byte ints and 8-byte reals of binary msh data in turn.
+    struct Cursor<'a>(&'a [u8]);
+
+    impl Cursor<'_> {
+        fn text(&mut self, expected: &str) {
+            assert_eq!(std::str::from_utf8(&self.0[..expected.len()]).unwrap(), expected);
+            self.0 = &self.0[expected.len()..];
+        }
+
+        fn int(&mut self) -> i32 {
+            let (bytes, rest) = self.0.split_at(4);
+            self.0 = rest;
+            i32::from_le_bytes(bytes.try_into().unwrap())
+        }
+
+        fn real(&mut self) -> f64 {
+            let (bytes, rest) = self.0.split_at(8);
+            self.0 = rest;
+            f64::from_le_bytes(bytes.try_into().unwrap())
+        }
+    }
+
+    #[test]
+    fn write_msh2_binary() {
+        // 2 x 3 x 1 voxels, uneven in y
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 2.0],
+            ys: vec![-1.0, 0.0, 0.5, 2.0],
+            zs: vec![0.0, 1.0],
+            doses: (0..6).map(|i| i as f64 / 4.0).collect(),
+            uncerts: vec![0.125; 6],
+            variant: None,
+        };
+        let mut out = Vec::new();
+        data.write_msh2_binary_to(&mut out).unwrap();
+        let mut msh = Cursor(&out);
+        msh.text("$MeshFormat\n2.2 1 8\n");
+        assert_eq!(msh.int(), 1);
+        msh.text("\n$EndMeshFormat\n$Nodes\n24\n");
+        for k in 0..2 {
+            for j in 0..4 {
+                for i in 0..3 {
+                    assert_eq!(msh.int() as usize, data.grid_index(i, j, k) + 1);
+                    assert_eq!([msh.real(), msh.real(), msh.real()], [
+                        data.xs[i], data.ys[j], data.zs[k]
+                    ]);
+                }
+            }
+        }
+        msh.text("\n$EndNodes\n$Elements\n6\n");
+        assert_eq!([msh.int(), msh.int(), msh.int()], [5, 6, 2]);
+        for (index, nodes) in data.hex_elements().enumerate() {
+            assert_eq!([msh.int(), msh.int(), msh.int()], [index as i32 + 1, 0, 0]);
+            for node in nodes.iter() {
+                assert_eq!(msh.int() as usize, *node);
+            }
+        }
+        msh.text("\n$EndElements\n");
+        for (name, values) in data.default_fields(Fmt::Msh2).iter().map(|f| (f.name, f.values)) {
+            msh.text(&format!("$ElementData\n1\n\"{}\"\n1\n0.0\n3\n0\n1\n6\n", name));
+            for (index, value) in values.iter().enumerate() {
+                assert_eq!((msh.int(), msh.real()), (index as i32 + 1, *value));
+            }
+            msh.text("\n$EndElementData\n");
+        }
+        assert!(msh.0.is_empty());
+
+        // options carry over from text output, and set the binary flag for it
+        let options = MshOptions {
+            physical_name: Some("water".to_string()),
+            element_order: ElementOrder::Quadratic,
+            binary: true,
+            ..Default::default()
+        };
+        let fields = [Field { name: "Dose", values: &data.doses }];
+        let mut out = Vec::new();
+        data.write_msh2_fields_to(&mut out, &fields, &options).unwrap();
+        let mut msh = Cursor(&out);
+        msh.text("$MeshFormat\n2.2 1 8\n");
+        msh.int();
+        msh.text("\n$EndMeshFormat\n$PhysicalNames\n1\n3 1 \"water\"\n$EndPhysicalNames\n");
+        msh.text("$Nodes\n105\n");
+        msh.0 = &msh.0[105 * 28..];
+        msh.text("\n$EndNodes\n$Elements\n6\n");
+        assert_eq!([msh.int(), msh.int(), msh.int()], [12, 6, 2]);
+        let first = data.hex27_elements().next().unwrap();
+        assert_eq!([msh.int(), msh.int(), msh.int()], [1, 1, 1]);
+        assert!(first.iter().all(|&node| msh.int() as usize == node));
+
+        // flattened, z is the thin axis
+        let options = MshOptions { flatten_2d: true, binary: true, ..Default::default() };
+        let mut out = Vec::new();
+        data.write_msh2_fields_to(&mut out, &fields, &options).unwrap();
+        let elements = out.windows(12).position(|w| w == b"$Elements\n6\n").unwrap();
+        let mut msh = Cursor(&out[elements + 12..]);
+        assert_eq!([msh.int(), msh.int(), msh.int()], [3, 6, 2]);
+        assert_eq!([msh.int(), msh.int(), msh.int()], [1, 0, 0]);
+        assert_eq!([msh.int(), msh.int(), msh.int(), msh.int()], [1, 2, 5, 4]);
+
+        let quadratic = MshOptions { element_order: ElementOrder::Quadratic, ..options };
+        let err = data.write_msh2_binary_fields_to(&mut Vec::new(), &fields, &quadratic);
+        assert_eq!(err.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,232 @@
+//! Gmsh `.msh` version 4.1 output, grouping nodes and elements into entity blocks.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::{
+    write_msh2_element_data, write_msh_names, DoseBlock, ElementOrder, Field, Fmt, MshOptions,
+    DEFAULT_BUFFER_SIZE,
+};
+
+impl DoseBlock {
+    /// Convert the `3ddose` data to a Gmsh `.msh` file (version 4.1).
+    pub fn write_msh4<P: AsRef<std::path::Path>>(&self, output: P) -> Result<(), std::io::Error> {
+        let mut filestream = BufWriter::with_capacity(DEFAULT_BUFFER_SIZE, File::create(output)?);
+        self.write_msh4_to(&mut filestream)?;
+        filestream.flush()
+    }
+
+    /// Write the `3ddose` data in Gmsh `.msh` format (version 4.1) to any writer.
+    pub fn write_msh4_to<W: Write>(&self, filestream: &mut W) -> Result<(), std::io::Error> {
+        self.write_msh4_fields_to(filestream, &self.default_fields(Fmt::Msh4), &MshOptions::default())
+    }
+
+    /// Convert the mesh and the given fields to a Gmsh `.msh` file (version 4.1).
+    pub fn write_msh4_fields<P: AsRef<std::path::Path>>(
+        &self,
+        output: P,
+        fields: &[Field],
+        options: &MshOptions,
+    ) -> Result<(), std::io::Error> {
+        let mut filestream = BufWriter::with_capacity(DEFAULT_BUFFER_SIZE, File::create(output)?);
+        self.write_msh4_fields_to(&mut filestream, fields, options)?;
+        filestream.flush()
+    }
+
+    /// Write the mesh and the given fields in Gmsh `.msh` format (version 4.1) to any writer.
+    ///
+    /// The grid is a single volume entity, tagged 1, holding one block of nodes and one block
+    /// of linear hexahedra. Nodes and elements are numbered as in
+    /// [`DoseBlock::write_msh2_fields_to`], so both versions describe the same mesh, and each
+    /// field becomes an `$ElementData` section, in order, laid out as in version 2.2.
+    ///
+    /// A physical name tags the volume with physical group 1. Quadratic elements,
+    /// [`MshOptions::flatten_2d`] and [`MshOptions::binary`] are only written in version 2.2,
+    /// and are an
+    /// [`std::io::ErrorKind::InvalidInput`] error here.
+    pub fn write_msh4_fields_to<W: Write>(
+        &self,
+        filestream: &mut W,
+        fields: &[Field],
+        options: &MshOptions,
+    ) -> Result<(), std::io::Error> {
+        self.check_field_lengths(fields)?;
+        let invalid = |message| Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message));
+        if options.element_order != ElementOrder::Linear {
+            return invalid("msh 4.1 output only has linear elements, use msh2 for quadratic ones");
+        }
+        if options.flatten_2d {
+            return invalid("msh 4.1 output can't be flattened to 2d, use msh2");
+        }
+        if options.binary {
+            return invalid("msh 4.1 output is only written as text, use msh2 for binary");
+        }
+
+        writeln!(filestream, "$MeshFormat\n4.1 0 8\n$EndMeshFormat")?;
+        // physical tags of the volume: a count, then the tags
+        let physical_tags = if write_msh_names(filestream, options)? { "1 1" } else { "0" };
+
+        // no points, curves or surfaces, and one volume with its bounding box and no
+        // bounding surfaces
+        let [(x0, x1), (y0, y1), (z0, z1)] = self.bounds();
+        writeln!(filestream, "$Entities\n0 0 0 1")?;
+        writeln!(filestream, "1 {} {} {} {} {} {} {} 0", x0, y0, z0, x1, y1, z1, physical_tags)?;
+        writeln!(filestream, "$EndEntities")?;
+
+        self.write_msh4_nodes(filestream)?;
+
+        // one block of hexahedra (gmsh type 5) in volume 1, with the version 2.2 connectivity
+        let num_voxels = self.num_voxels();
+        writeln!(filestream, "$Elements\n1 {} 1 {}\n3 1 5 {}", num_voxels, num_voxels, num_voxels)?;
+        for (index, nodes) in self.hex_elements().enumerate() {
+            write!(filestream, "{}", index + 1)?;
+            for node in &nodes {
+                write!(filestream, " {}", node)?;
+            }
+            writeln!(filestream)?;
+        }
+        writeln!(filestream, "$EndElements")?;
+
+        for field in fields {
+            write_msh2_element_data(filestream, field.name, field.values)?;
+        }
+        Ok(())
+    }
+
+    /// Write the `$Nodes` section as one block in volume 1, numbering nodes *x* fastest as
+    /// version 2.2 output does: all the node tags first, then all the coordinates.
+    fn write_msh4_nodes<W: Write>(&self, filestream: &mut W) -> Result<(), std::io::Error> {
+        let num_nodes = self.num_nodes();
+        // one block of nodes, numbered 1 to num_nodes, in volume 1 without parametric coordinates
+        writeln!(filestream, "$Nodes\n1 {} 1 {}\n3 1 0 {}", num_nodes, num_nodes, num_nodes)?;
+        for tag in 1..=num_nodes {
+            writeln!(filestream, "{}", tag)?;
+        }
+        for z in &self.zs {
+            for y in &self.ys {
+                for x in &self.xs {
+                    writeln!(filestream, "{} {} {}", x, y, z)?;
+                }
+            }
+        }
+        writeln!(filestream, "$EndNodes")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// The parts of a msh 4.1 file the tests check, read as a minimal parser would.
+    struct Msh4 {
+        nodes: Vec<(usize, [f64; 3])>,
+        elements: Vec<(usize, Vec<usize>)>,
+        data: Vec<(String, Vec<f64>)>,
+    }
+
+    fn parse_msh4(text: &str) -> Msh4 {
+        let mut sections: HashMap<&str, Vec<&str>> = HashMap::new();
+        let mut data = Vec::new();
+        let mut lines = text.lines();
+        while let Some(start) = lines.next() {
+            let name = start.strip_prefix('$').expect("section start");
+            let end = format!("$End{}", name);
+            let body: Vec<&str> = lines.by_ref().take_while(|&line| line != end).collect();
+            if name == "ElementData" {
+                // string tag, real tag, three int tags, then values
+                let value = |line: &&str| line.split(' ').nth(1).unwrap().parse().unwrap();
+                let name = body[1].trim_matches('"').to_string();
+                data.push((name, body[8..].iter().map(value).collect()));
+            } else {
+                sections.insert(name, body);
+            }
+        }
+        assert_eq!(sections["MeshFormat"], ["4.1 0 8"]);
+        let numbers =
+            |line: &str| -> Vec<usize> { line.split(' ').map(|n| n.parse().unwrap()).collect() };
+
+        let nodes = &sections["Nodes"];
+        let [blocks, num_nodes, min_tag, max_tag] = numbers(nodes[0])[..] else { panic!() };
+        assert_eq!((blocks, min_tag, max_tag), (1, 1, num_nodes));
+        assert_eq!(numbers(nodes[1]), [3, 1, 0, num_nodes]);
+        let tags = nodes[2..2 + num_nodes].iter().map(|line| line.parse().unwrap());
+        let coords = nodes[2 + num_nodes..].iter().map(|line| {
+            let xyz: Vec<f64> = line.split(' ').map(|c| c.parse().unwrap()).collect();
+            [xyz[0], xyz[1], xyz[2]]
+        });
+        let nodes: Vec<_> = tags.zip(coords).collect();
+        assert_eq!(nodes.len(), num_nodes);
+
+        let elements = &sections["Elements"];
+        let [blocks, num_elements, min_tag, max_tag] = numbers(elements[0])[..] else { panic!() };
+        assert_eq!((blocks, min_tag, max_tag), (1, 1, num_elements));
+        assert_eq!(numbers(elements[1]), [3, 1, 5, num_elements]);
+        let elements: Vec<_> = elements[2..]
+            .iter()
+            .map(|line| (numbers(line)[0], numbers(line)[1..].to_vec()))
+            .collect();
+        assert_eq!(elements.len(), num_elements);
+
+        assert_eq!(sections["Entities"][0], "0 0 0 1");
+        Msh4 { nodes, elements, data }
+    }
+
+    #[test]
+    fn write_msh4() {
+        // 2 x 3 x 4 voxels, uneven in z
+        let mut data = DoseBlock {
+            xs: vec![0.0, 1.0, 2.0],
+            ys: vec![-1.0, 0.0, 1.0, 2.0],
+            zs: vec![0.0, 0.5, 1.5, 3.0, 5.0],
+            doses: (0..24).map(|i| i as f64).collect(),
+            uncerts: vec![0.25; 24],
+            variant: None,
+        };
+        let mut out = Vec::new();
+        data.write_msh4_to(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("$Entities\n0 0 0 1\n1 0 -1 0 2 2 5 0 0\n$EndEntities\n"));
+        let msh = parse_msh4(&text);
+
+        assert_eq!(msh.nodes.len(), 3 * 4 * 5);
+        assert_eq!(msh.nodes[0], (1, [0.0, -1.0, 0.0]));
+        let node = data.grid_index(1, 2, 3);
+        assert_eq!(msh.nodes[node], (node + 1, [1.0, 1.0, 3.0]));
+        assert_eq!(msh.elements.len(), 24);
+        for (index, expected) in data.hex_elements().enumerate() {
+            assert_eq!(msh.elements[index], (index + 1, expected.to_vec()));
+        }
+        assert_eq!(msh.data.len(), 2);
+        assert_eq!(msh.data[0], ("Dose [Gy·cm2]".to_string(), data.doses.clone()));
+        assert_eq!(msh.data[1].1, data.uncerts);
+
+        // the same sections as version 2.2 after the mesh
+        let mut msh2 = Vec::new();
+        data.write_msh2_to(&mut msh2).unwrap();
+        let msh2 = String::from_utf8(msh2).unwrap();
+        let element_data = |text: &str| text[text.find("$ElementData").unwrap()..].to_string();
+        assert_eq!(element_data(&text), element_data(&msh2));
+
+        let options = MshOptions { physical_name: Some("water".to_string()), ..Default::default() };
+        let mut out = Vec::new();
+        let fields = [Field { name: "Dose", values: &data.doses }];
+        data.write_msh4_fields_to(&mut out, &fields, &options).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("$PhysicalNames\n1\n3 1 \"water\"\n$EndPhysicalNames\n"));
+        assert!(text.contains("\n1 0 -1 0 2 2 5 1 1 0\n"));
+        assert_eq!(parse_msh4(&text).data.len(), 1);
+
+        let options = MshOptions { element_order: ElementOrder::Quadratic, ..Default::default() };
+        let err = data.write_msh4_fields_to(&mut Vec::new(), &fields, &options).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        data.zs.truncate(2);
+        data.doses.truncate(6);
+        let options = MshOptions { flatten_2d: true, ..Default::default() };
+        let fields = [Field { name: "Dose", values: &data.doses }];
+        assert!(data.write_msh4_fields_to(&mut Vec::new(), &fields, &options).is_err());
+        let options = MshOptions { binary: true, ..Default::default() };
+        assert!(data.write_msh4_fields_to(&mut Vec::new(), &fields, &options).is_err());
+    }
+}
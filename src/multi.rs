@@ -0,0 +1,113 @@
+//! Reading several `3ddose` datasets concatenated into one file.
+
+use std::io::BufRead;
+
+use crate::stream::Tokens;
+use crate::{open_3ddose, DoseBlock, DoseError, DoseVariant, DEFAULT_BUFFER_SIZE};
+
+impl DoseBlock {
+    /// Read every dose block from a file of concatenated `3ddose` datasets, e.g. one per energy
+    /// bin or beam, in file order.
+    ///
+    /// Blocks follow each other directly, each starting with its own voxel counts, and can
+    /// have different grids. A file holding a single dataset gives a single block, the same as
+    /// [`DoseBlock::from_3d_dose`]. An empty file gives no blocks. A last block cut off before
+    /// its final uncertainty value is a [`DoseError::TruncatedBlock`] error naming the block and
+    /// where its data stops, rather than being dropped.
+    pub fn from_3d_dose_multi<P: AsRef<std::path::Path>>(
+        input_file: P,
+    ) -> Result<Vec<DoseBlock>, DoseError> {
+        let mut tokens = Tokens::new(open_3ddose(input_file, DEFAULT_BUFFER_SIZE)?);
+        let mut blocks = Vec::new();
+        loop {
+            match read_block(&mut tokens) {
+                Ok(Some(block)) => blocks.push(block),
+                Ok(None) => return Ok(blocks),
+                Err(DoseError::UnexpectedEof { field }) => {
+                    let (block, at) = (blocks.len() + 1, tokens.position());
+                    return Err(DoseError::TruncatedBlock { block, field, at });
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Read the next block, or `None` if the input ends before it starts.
+pub(crate) fn read_block<R: BufRead>(
+    tokens: &mut Tokens<R>,
+) -> Result<Option<DoseBlock>, DoseError> {
+    let num_x: usize = match tokens.next_token()? {
+        Some(token) => token.parse().map_err(|_| tokens.invalid("voxel number"))?,
+        None => return Ok(None),
+    };
+    let first_line = tokens.line();
+    let [num_x, num_y, num_z] = tokens.voxel_counts(num_x)?;
+    let num_voxels = num_x * num_y * num_z;
+
+    // line each record ends on, relative to the voxel counts
+    let mut last_lines = [tokens.line() - first_line; 6];
+    let mut record = |tokens: &mut Tokens<R>, index: usize, len: usize, field| {
+        let values = tokens.parse_floats(len, field)?;
+        last_lines[index] = tokens.line() - first_line;
+        Ok::<_, DoseError>(values)
+    };
+    let xs = record(tokens, 1, num_x + 1, "x-coordinate")?;
+    let ys = record(tokens, 2, num_y + 1, "y-coordinate")?;
+    let zs = record(tokens, 3, num_z + 1, "z-coordinate")?;
+    let doses = record(tokens, 4, num_voxels, "dose value")?;
+    let uncerts = record(tokens, 5, num_voxels, "uncertainty value")?;
+    let variant = Some(DoseVariant::from_record_lines(last_lines));
+    let block = DoseBlock { xs, ys, zs, doses, uncerts, variant };
+    block.check_monotonic()?;
+    Ok(Some(block))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_3d_dose_multi() {
+        let mut input = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        input.push("props");
+        input.push("asym_2x3x4.3ddose");
+        let single = DoseBlock::from_3d_dose(&input).unwrap();
+        assert_eq!(DoseBlock::from_3d_dose_multi(&input).unwrap(), vec![single.clone()]);
+
+        let text = std::fs::read_to_string(&input).unwrap();
+        let dir = std::env::temp_dir().join("dose2gmsh_multi");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // a different grid after the first block, without a trailing newline
+        let second = "1 1 1\n0 1\n0 2\n0 3\n5.5\n0.25";
+        std::fs::write(dir.join("two.3ddose"), format!("{}{}", text, second)).unwrap();
+        let blocks = DoseBlock::from_3d_dose_multi(dir.join("two.3ddose")).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0], single);
+        assert_eq!((blocks[1].zs.clone(), blocks[1].doses.clone()), (vec![0.0, 3.0], vec![5.5]));
+        assert_eq!(blocks[1].variant, Some(DoseVariant::Classic));
+
+        let wrapped = "1 1 1 0 1\n0 2\n0\n3 5.5 0.25\n";
+        std::fs::write(dir.join("wrapped.3ddose"), format!("{}{}", text, wrapped)).unwrap();
+        let blocks = DoseBlock::from_3d_dose_multi(dir.join("wrapped.3ddose")).unwrap();
+        assert_eq!(blocks[0].variant, Some(DoseVariant::Classic));
+        assert_eq!(blocks[1].variant, Some(DoseVariant::Wrapped));
+        assert_eq!(blocks[1].uncerts, vec![0.25]);
+
+        let truncated = "1 1 1\n0 1\n0 2\n0 3\n5.5\n";
+        std::fs::write(dir.join("truncated.3ddose"), format!("{}{}", text, truncated)).unwrap();
+        let err = DoseBlock::from_3d_dose_multi(dir.join("truncated.3ddose")).unwrap_err();
+        match &err {
+            DoseError::TruncatedBlock { block: 2, field: "uncertainty value", at } => {
+                assert_eq!(at.line, text.lines().count() + 5);
+            }
+            other => panic!("expected a truncation error, got {:?}", other),
+        }
+        assert!(err.to_string().starts_with("block 2 ended before its last uncertainty value"));
+
+        std::fs::write(dir.join("empty.3ddose"), "\n").unwrap();
+        assert!(DoseBlock::from_3d_dose_multi(dir.join("empty.3ddose")).unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
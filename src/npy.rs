@@ -0,0 +1,145 @@
+//! NumPy `.npy` arrays of the dose, uncertainty and node coordinates, for analysis in Python.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::DoseBlock;
+
+impl DoseBlock {
+    /// Write the dose, uncertainty and node coordinates as NumPy arrays of `float64`.
+    ///
+    /// For a `base` of `run`, or `run.npy`, this writes
+    /// * `run_dose.npy` and `run_uncert.npy`, of shape `(num_z, num_y, num_x)`,
+    /// * `run_xs.npy`, `run_ys.npy` and `run_zs.npy`, the node coordinates in `[cm]`.
+    ///
+    /// The volumes are C-ordered, so their last index varies fastest: `dose[k, j, i]` is the
+    /// voxel [`DoseBlock::voxel_index`]`(i, j, k)`, the order of `doses` and of default csv
+    /// output, and lies between `xs[i]` and `xs[i + 1]` along *x*. Load them with
+    /// `numpy.load("run_dose.npy")`.
+    pub fn write_npy<P: AsRef<Path>>(&self, base: P) -> Result<(), std::io::Error> {
+        let volume = [self.num_z(), self.num_y(), self.num_x()];
+        let arrays: [(&str, &[f64], &[usize]); 5] = [
+            ("dose", &self.doses, &volume),
+            ("uncert", &self.uncerts, &volume),
+            ("xs", &self.xs, &[self.xs.len()]),
+            ("ys", &self.ys, &[self.ys.len()]),
+            ("zs", &self.zs, &[self.zs.len()]),
+        ];
+        for (suffix, values, shape) in arrays.iter() {
+            let mut file = BufWriter::new(File::create(npy_path(base.as_ref(), suffix))?);
+            write_npy_array_to(&mut file, values, shape)?;
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write one value per voxel as a `.npy` array of shape `(num_z, num_y, num_x)`, see
+    /// [`DoseBlock::write_npy`].
+    pub fn write_npy_field_to<W: Write>(
+        &self,
+        file: &mut W,
+        values: &[f64],
+    ) -> Result<(), std::io::Error> {
+        write_npy_array_to(file, values, &[self.num_z(), self.num_y(), self.num_x()])
+    }
+}
+
+/// `base` without its extension, followed by `_suffix.npy`.
+fn npy_path(base: &Path, suffix: &str) -> PathBuf {
+    let mut name = base.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!("_{}.npy", suffix));
+    base.with_file_name(name)
+}
+
+/// Write `values` as a C-ordered little-endian `float64` array of the given `shape`, in the
+/// version 1.0 `.npy` format.
+fn write_npy_array_to<W: Write>(
+    file: &mut W,
+    values: &[f64],
+    shape: &[usize],
+) -> Result<(), std::io::Error> {
+    assert_eq!(values.len(), shape.iter().product::<usize>(), "npy values don't fill {:?}", shape);
+    // a python tuple, with a trailing comma for one dimension
+    let dims: Vec<String> = shape.iter().map(|dim| dim.to_string()).collect();
+    let shape = match dims.len() {
+        1 => format!("({},)", dims[0]),
+        _ => format!("({})", dims.join(", ")),
+    };
+    let mut header = format!("{{'descr': '<f8', 'fortran_order': False, 'shape': {}, }}", shape);
+    // magic, version and header length take 10 bytes, and the data starts 64-byte aligned
+    // after the newline ending the header
+    let len = 10 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - len % 64) % 64));
+    header.push('\n');
+
+    file.write_all(b"\x93NUMPY\x01\x00")?;
+    file.write_all(&(header.len() as u16).to_le_bytes())?;
+    file.write_all(header.as_bytes())?;
+    for value in values {
+        file.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    /// The header dict and values of a `.npy` file, checking its framing as `numpy.load` does.
+    fn read_npy(bytes: &[u8]) -> (&str, Vec<f64>) {
+        assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+        let len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!((10 + len) % 64, 0);
+        let header = std::str::from_utf8(&bytes[10..10 + len]).unwrap();
+        assert!(header.ends_with('\n'));
+        let values = bytes[10 + len..]
+            .chunks(8)
+            .map(|value| f64::from_le_bytes(value.try_into().unwrap()))
+            .collect();
+        (header.trim_end(), values)
+    }
+
+    #[test]
+    fn write_npy() {
+        // 3 x 2 x 2 voxels, dose 100k + 10j + i
+        let mut doses = Vec::new();
+        for k in 0..2 {
+            for j in 0..2 {
+                for i in 0..3 {
+                    doses.push((100 * k + 10 * j + i) as f64);
+                }
+            }
+        }
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 2.0, 3.0],
+            ys: vec![-1.0, 0.0, 1.0],
+            zs: vec![5.0, 5.5, 7.0],
+            doses,
+            uncerts: vec![0.5; 12],
+            variant: None,
+        };
+        let mut out = Vec::new();
+        data.write_npy_field_to(&mut out, &data.doses).unwrap();
+        let (header, values) = read_npy(&out);
+        assert_eq!(header, "{'descr': '<f8', 'fortran_order': False, 'shape': (2, 2, 3), }");
+        // C order: value [1, 1, 2] at (k * ny + j) * nx + i
+        assert_eq!(values[(2 + 1) * 3 + 2], 112.0);
+        assert_eq!(values[data.voxel_index(2, 0, 1)], 102.0);
+
+        let dir = std::env::temp_dir().join("dose2gmsh_npy");
+        std::fs::create_dir_all(&dir).unwrap();
+        data.write_npy(dir.join("run.npy")).unwrap();
+        let read = |name: &str| std::fs::read(dir.join(name)).unwrap();
+        let uncert = read("run_uncert.npy");
+        assert_eq!(read_npy(&uncert), (header, data.uncerts.clone()));
+        let zs = read("run_zs.npy");
+        let (header, values) = read_npy(&zs);
+        assert_eq!(header, "{'descr': '<f8', 'fortran_order': False, 'shape': (3,), }");
+        assert_eq!(values, data.zs);
+        assert_eq!(read("run_dose.npy"), out);
+        assert!(dir.join("run_xs.npy").exists() && dir.join("run_ys.npy").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+//! 1D dose profiles along the grid axes, e.g. depth-dose curves.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::{centroids, Axis, DoseBlock, Interp};
+
+/// An axis-aligned line through the grid, parsed from `<axis>@<a>,<b>` on the command line.
+///
+/// `at` holds the two transverse coordinates in `[cm]`, in *x*, *y*, *z* order with the profile
+/// axis left out: `z@1,2` is the line `x = 1, y = 2` and `y@1,2` is the line `x = 1, z = 2`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ProfileLine {
+    /// Axis the profile runs along.
+    pub along: Axis,
+    /// Transverse coordinates of the line in `[cm]`.
+    pub at: (f64, f64),
+}
+
+impl std::str::FromStr for ProfileLine {
+    type Err = String;
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Could not parse profile {}, expected e.g. z@0,0", line);
+        let (along, at) = line.split_once('@').ok_or_else(err)?;
+        let (a, b) = at.split_once(',').ok_or_else(err)?;
+        Ok(ProfileLine {
+            along: along.parse()?,
+            at: (a.trim().parse().map_err(|_| err())?, b.trim().parse().map_err(|_| err())?),
+        })
+    }
+}
+
+impl DoseBlock {
+    /// Dose along an axis-aligned line, as `(position, dose, uncert)` per voxel with the
+    /// position of each voxel centroid in `[cm]`.
+    ///
+    /// The line runs through the voxel centroids nearest to `at`, the transverse coordinates
+    /// described in [`ProfileLine`], so no interpolation is done. Points outside the grid snap
+    /// to the edge voxels.
+    pub fn profile(&self, along: Axis, at: (f64, f64)) -> Vec<(f64, f64, f64)> {
+        let (first, second) = along.transverse();
+        let mut ijk = [0; 3];
+        ijk[first as usize] = nearest(&centroids(self.nodes(first)), at.0);
+        ijk[second as usize] = nearest(&centroids(self.nodes(second)), at.1);
+
+        centroids(self.nodes(along))
+            .into_iter()
+            .enumerate()
+            .map(|(n, position)| {
+                ijk[along as usize] = n;
+                let index = self.voxel_index(ijk[0], ijk[1], ijk[2]);
+                (position, self.doses[index], self.uncerts[index])
+            })
+            .collect()
+    }
+
+    /// Dose along an axis-aligned line at the voxel centroids along it, as `(position, dose)`
+    /// pairs, evaluated at the transverse coordinates `at` themselves as `interp` says.
+    ///
+    /// Unlike [`DoseBlock::profile`], the line doesn't snap to voxel centroids across it.
+    /// Points outside the grid are moved onto its edge.
+    pub fn profile_interp(&self, along: Axis, at: (f64, f64), interp: Interp) -> Vec<(f64, f64)> {
+        let (first, second) = along.transverse();
+        let onto = |axis: Axis, value: f64| {
+            let nodes = self.nodes(axis);
+            value.max(nodes[0]).min(nodes[nodes.len() - 1])
+        };
+        let mut pt = [0.0; 3];
+        pt[first as usize] = onto(first, at.0);
+        pt[second as usize] = onto(second, at.1);
+
+        centroids(self.nodes(along))
+            .into_iter()
+            .map(|position| {
+                pt[along as usize] = position;
+                let dose = self.dose_at_with(pt[0], pt[1], pt[2], interp);
+                (position, dose.expect("profile points are inside the grid"))
+            })
+            .collect()
+    }
+
+    /// Write a dose profile to a `csv` file ready for plotting, see [`DoseBlock::write_profile_csv_to`].
+    pub fn write_profile_csv<P: AsRef<std::path::Path>>(
+        &self,
+        output: P,
+        line: ProfileLine,
+        interp: Option<Interp>,
+    ) -> Result<(), std::io::Error> {
+        let mut file = BufWriter::new(File::create(output)?);
+        self.write_profile_csv_to(&mut file, line, interp)?;
+        file.flush()
+    }
+
+    /// Write a dose profile in `csv` format to any writer, through the nearest voxel centroids
+    /// as [`DoseBlock::profile`] gives it, or at `line` itself as [`DoseBlock::profile_interp`]
+    /// gives it with `interp`.
+    ///
+    /// Columns are `distance_cm`, the distance from the first voxel centroid on the line,
+    /// `dose`, and `dose_pct_max`, the dose as a percentage of the profile maximum, which is
+    /// how depth-dose and beam profile curves are usually plotted. `dose_pct_max` is left
+    /// empty if the profile has no positive dose.
+    pub fn write_profile_csv_to<W: Write>(
+        &self,
+        file: &mut W,
+        line: ProfileLine,
+        interp: Option<Interp>,
+    ) -> Result<(), std::io::Error> {
+        let profile = match interp {
+            Some(interp) => self.profile_interp(line.along, line.at, interp),
+            None => {
+                let snapped = self.profile(line.along, line.at).into_iter();
+                snapped.map(|(position, dose, _)| (position, dose)).collect()
+            }
+        };
+        let start = profile[0].0;
+        let max_dose = profile.iter().map(|&(_, dose)| dose).fold(0.0, f64::max);
+
+        writeln!(file, "distance_cm,dose,dose_pct_max")?;
+        for (position, dose) in profile {
+            write!(file, "{},{},", position - start, dose)?;
+            if max_dose > 0.0 {
+                write!(file, "{}", 100.0 * dose / max_dose)?;
+            }
+            writeln!(file)?;
+        }
+        Ok(())
+    }
+}
+
+/// Index of the value in `pts` closest to `value`, the first if there's a tie.
+fn nearest(pts: &[f64], value: f64) -> usize {
+    let mut best = 0;
+    for (index, pt) in pts.iter().enumerate() {
+        if (pt - value).abs() < (pts[best] - value).abs() {
+            best = index;
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block() -> DoseBlock {
+        // 3 x 2 x 2 voxels, doses counting up in x, y, z order
+        DoseBlock {
+            xs: vec![-1.5, -0.5, 0.5, 1.5],
+            ys: vec![-1.0, 0.0, 1.0],
+            zs: vec![0.0, 1.0, 3.0],
+            doses: vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0, 12.0],
+            uncerts: vec![0.1; 12],
+            variant: None,
+        }
+    }
+
+    #[test]
+    fn profile() {
+        let data = block();
+        // x = 0 is the middle voxel, y = -0.4 snaps to the first
+        assert_eq!(
+            data.profile(Axis::Z, (0.0, -0.4)),
+            vec![(0.5, 2.0, 0.1), (2.0, 8.0, 0.1)]
+        );
+        assert_eq!(
+            data.profile(Axis::X, (0.5, 10.0)),
+            vec![(-1.0, 10.0, 0.1), (0.0, 11.0, 0.1), (1.0, 12.0, 0.1)]
+        );
+        assert_eq!("y@1,2".parse(), Ok(ProfileLine { along: Axis::Y, at: (1.0, 2.0) }));
+        assert!("y@1".parse::<ProfileLine>().is_err());
+        assert!("w@1,2".parse::<ProfileLine>().is_err());
+    }
+
+    #[test]
+    fn profile_csv() {
+        let mut data = block();
+        data.doses[9..].copy_from_slice(&[3.0, 6.0, 12.0]);
+        let line = ProfileLine { along: Axis::X, at: (0.4, 2.0) };
+        let mut out = Vec::new();
+        data.write_profile_csv_to(&mut out, line, Some(Interp::Nearest)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "distance_cm,dose,dose_pct_max\n0,3,25\n1,6,50\n2,12,100\n"
+        );
+        // y = 0.4 is a tenth of the way from the centroid at 0.5 to the one at -0.5
+        let profile = data.profile_interp(Axis::X, (0.4, 2.0), Interp::Trilinear);
+        approx::assert_relative_eq!(profile[0].1, 0.9 * 3.0 + 0.1 * 7.0);
+        // snapped onto the grid edge at z = 3
+        assert_eq!(data.profile_interp(Axis::X, (0.4, 5.0), Interp::Nearest)[2], (1.0, 12.0));
+
+        // z = 1.1 is in the second voxel, 1 to 3, but nearest the first centroid at 0.5, which
+        // --profile snaps to unless --interp is given
+        let doses = |args: &[&str]| {
+            use structopt::StructOpt;
+            let cli = crate::Cli::from_iter_safe(args).unwrap();
+            let mut out = Vec::new();
+            data.write_profile_csv_to(&mut out, cli.profile.unwrap(), cli.interp).unwrap();
+            let csv = String::from_utf8(out).unwrap();
+            let dose = |row: &str| row.split(',').nth(1).unwrap().parse::<f64>().unwrap();
+            csv.lines().skip(1).map(dose).collect::<Vec<_>>()
+        };
+        let args = ["dose2gmsh", "in.3ddose", "--profile", "x@0.4,1.1"];
+        assert_eq!(doses(&args), vec![4.0, 5.0, 6.0]);
+        assert_eq!(doses(&[&args[..], &["--interp", "nearest"]].concat()), vec![3.0, 6.0, 12.0]);
+
+        data.doses = vec![0.0; 12];
+        let mut out = Vec::new();
+        data.write_profile_csv_to(&mut out, line, None).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "distance_cm,dose,dose_pct_max\n0,0,\n1,0,\n2,0,\n");
+    }
+}
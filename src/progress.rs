@@ -0,0 +1,105 @@
+//! Reporting how far a long read or write has got, e.g. for a progress bar.
+
+use std::io::{BufRead, Read, Write};
+
+use crate::{open_3ddose_from, DoseBlock, DoseError};
+
+/// A reader or writer that calls a callback with the number of bytes passed through it so far,
+/// after every read or write.
+///
+/// Wrap the innermost source or sink, e.g. the `File`, so the count is of bytes on disk.
+pub struct Progress<T, F> {
+    inner: T,
+    bytes: u64,
+    progress: F,
+}
+
+impl<T, F: FnMut(u64)> Progress<T, F> {
+    /// Report bytes read from or written to `inner` to `progress`.
+    pub fn new(inner: T, progress: F) -> Self {
+        Progress { inner, bytes: 0, progress }
+    }
+
+    /// Return the inner reader or writer.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn advance(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        (self.progress)(self.bytes);
+    }
+}
+
+impl<R: Read, F: FnMut(u64)> Read for Progress<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.advance(read);
+        Ok(read)
+    }
+}
+
+impl<W: Write, F: FnMut(u64)> Write for Progress<W, F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.advance(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl DoseBlock {
+    /// Parse a `3ddose` file as [`DoseBlock::from_3d_dose_buffered`] does, calling `progress`
+    /// with the number of bytes of the file read so far as parsing goes on.
+    ///
+    /// Bytes are counted as stored, so for a gzipped file they run up to its compressed size.
+    pub fn from_3d_dose_with_progress<P, F>(
+        input_file: P,
+        buffer_size: usize,
+        progress: F,
+    ) -> Result<DoseBlock, DoseError>
+    where
+        P: AsRef<std::path::Path>,
+        F: FnMut(u64) + 'static,
+    {
+        let file = Progress::new(std::fs::File::open(input_file)?, progress);
+        let reader: Box<dyn BufRead> = open_3ddose_from(file, buffer_size)?;
+        DoseBlock::from_reader(reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn progress() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("props");
+        path.push("asym_2x3x4.3ddose");
+        let size = std::fs::metadata(&path).unwrap().len();
+
+        // a small buffer, so the file is read in several steps
+        let (read, calls) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+        let (last, count) = (read.clone(), calls.clone());
+        let data = DoseBlock::from_3d_dose_with_progress(&path, 64, move |bytes| {
+            assert!(bytes >= last.get());
+            last.set(bytes);
+            count.set(count.get() + 1);
+        });
+        assert_eq!(data.unwrap(), DoseBlock::from_3d_dose(&path).unwrap());
+        assert_eq!(read.get(), size);
+        assert!(calls.get() > 3);
+
+        let mut written = 0;
+        let mut out = Progress::new(Vec::new(), |bytes| written = bytes);
+        write!(out, "$MeshFormat").unwrap();
+        assert_eq!(out.into_inner().len(), 11);
+        assert_eq!(written, 11);
+    }
+}
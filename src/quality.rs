@@ -0,0 +1,116 @@
+//! How well converged the Monte Carlo dose is, judged by its uncertainties.
+
+use std::fmt;
+
+use crate::DoseBlock;
+
+/// Fractional uncertainties [`UncertaintyReport::below`] counts voxels under.
+pub const UNCERT_THRESHOLDS: [f64; 4] = [0.01, 0.02, 0.05, 0.10];
+
+/// Convergence summary of a dose block over the voxels that received dose.
+///
+/// Low-dose voxels are always noisy, so the uncertainty in the high-dose region is the usual
+/// check on whether a simulation ran long enough. Values are `NaN` without any dose.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct UncertaintyReport {
+    /// Number of voxels with a positive finite dose, which the other numbers are over.
+    pub dose_voxels: usize,
+    /// `(threshold, fraction)` pairs giving the fraction of those voxels with a fractional
+    /// uncertainty below each of [`UNCERT_THRESHOLDS`].
+    pub below: Vec<(f64, f64)>,
+    /// Mean fractional uncertainty weighted by dose.
+    pub dose_weighted_uncert: f64,
+    /// Largest fractional uncertainty among voxels above half the maximum dose.
+    pub max_high_dose_uncert: f64,
+}
+
+impl DoseBlock {
+    /// Summarize the uncertainties of the voxels carrying dose, see [`UncertaintyReport`].
+    pub fn uncertainty_quality(&self) -> UncertaintyReport {
+        let dosed: Vec<(f64, f64)> = self
+            .doses
+            .iter()
+            .zip(&self.uncerts)
+            .filter(|&(&dose, _)| dose.is_finite() && dose > 0.0)
+            .map(|(&dose, &uncert)| (dose, uncert))
+            .collect();
+        let num_dosed = dosed.len() as f64;
+
+        let below = UNCERT_THRESHOLDS
+            .iter()
+            .map(|&threshold| {
+                let count = dosed.iter().filter(|&&(_, uncert)| uncert < threshold).count();
+                (threshold, count as f64 / num_dosed)
+            })
+            .collect();
+        let total_dose: f64 = dosed.iter().map(|&(dose, _)| dose).sum();
+        let weighted: f64 = dosed.iter().map(|&(dose, uncert)| dose * uncert).sum();
+        let max_dose = dosed.iter().map(|&(dose, _)| dose).fold(0.0, f64::max);
+        let max_high_dose_uncert = dosed
+            .iter()
+            .filter(|&&(dose, _)| dose > 0.5 * max_dose)
+            .map(|&(_, uncert)| uncert)
+            .fold(f64::NAN, f64::max);
+
+        UncertaintyReport {
+            dose_voxels: dosed.len(),
+            below,
+            dose_weighted_uncert: weighted / total_dose,
+            max_high_dose_uncert,
+        }
+    }
+}
+
+impl fmt::Display for UncertaintyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "dosed voxels:      {}", self.dose_voxels)?;
+        for &(threshold, fraction) in &self.below {
+            let label = format!("below {} % uncert:", 100.0 * threshold);
+            writeln!(f, "{:<19}{:.1} % of dosed voxels", label, 100.0 * fraction)?;
+        }
+        let weighted = 100.0 * self.dose_weighted_uncert;
+        writeln!(f, "weighted uncert:   {:.2} %, weighted by dose", weighted)?;
+        write!(
+            f,
+            "high dose uncert:  {:.2} % at most, above 50 % of the max dose",
+            100.0 * self.max_high_dose_uncert
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uncertainty_quality() {
+        // 6 voxels, one without dose
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+            ys: vec![0.0, 1.0],
+            zs: vec![0.0, 1.0],
+            doses: vec![0.0, 1.0, 2.0, 2.0, 10.0, 6.0],
+            uncerts: vec![1.0, 0.5, 0.08, 0.04, 0.005, 0.015],
+            variant: None,
+        };
+        let report = data.uncertainty_quality();
+        assert_eq!(report.dose_voxels, 5);
+        assert_eq!(report.below, vec![(0.01, 0.2), (0.02, 0.4), (0.05, 0.6), (0.1, 0.8)]);
+        // (0.5 + 0.16 + 0.08 + 0.05 + 0.09) / 21
+        approx::assert_relative_eq!(report.dose_weighted_uncert, 0.88 / 21.0);
+        // voxels above 5
+        assert_eq!(report.max_high_dose_uncert, 0.015);
+
+        let text = report.to_string();
+        assert!(text.starts_with("dosed voxels:      5\nbelow 1 % uncert:  20.0 % of dosed voxels"));
+        assert!(text.contains("\nbelow 10 % uncert: 80.0 % of dosed voxels\n"));
+        assert!(text.ends_with("high dose uncert:  1.50 % at most, above 50 % of the max dose"));
+        assert!(data.info().to_string().contains("\ndosed voxels:      5\n"));
+
+        let air = DoseBlock { doses: vec![0.0; 6], ..data };
+        let report = air.uncertainty_quality();
+        assert_eq!(report.dose_voxels, 0);
+        assert!(report.dose_weighted_uncert.is_nan() && report.max_high_dose_uncert.is_nan());
+    }
+}
@@ -0,0 +1,208 @@
+//! Binary dumps of the dose and uncertainty arrays: headerless raw volumes and MetaImage.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::{centroids, DoseBlock, DoseError, Order};
+
+impl DoseBlock {
+    /// Write dose and uncertainty as raw little-endian `f64` volumes with a text sidecar.
+    ///
+    /// For an `output` of `run.raw` this writes
+    /// * `run.raw`, the doses,
+    /// * `run_uncert.raw`, the fractional uncertainties,
+    /// * `run.hdr`, describing the files, see [`DoseBlock::write_raw_header_to`].
+    ///
+    /// The volumes have no header, just one value per voxel in `order`, so they can be loaded
+    /// directly into memory, e.g. with `numpy.fromfile` or ImageJ's raw import.
+    pub fn write_raw<P: AsRef<Path>>(&self, output: P, order: Order) -> Result<(), std::io::Error> {
+        let dose_path = output.as_ref().with_extension("raw");
+        let mut uncert_name = dose_path.file_stem().unwrap_or_default().to_os_string();
+        uncert_name.push("_uncert.raw");
+        let uncert_path = dose_path.with_file_name(uncert_name);
+
+        for (path, values) in [(&dose_path, &self.doses), (&uncert_path, &self.uncerts)].iter() {
+            let mut file = BufWriter::new(File::create(path)?);
+            self.write_raw_field_to(&mut file, values, order)?;
+            file.flush()?;
+        }
+
+        let file_name = |path: &Path| path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let mut header = BufWriter::new(File::create(dose_path.with_extension("hdr"))?);
+        self.write_raw_header_to(&mut header, &file_name(&dose_path), &file_name(&uncert_path), order)?;
+        header.flush()
+    }
+
+    /// Write one value per voxel as little-endian `f64`s in `order`, with no header.
+    ///
+    /// `values` are in native order, like `doses`.
+    pub fn write_raw_field_to<W: Write>(
+        &self,
+        file: &mut W,
+        values: &[f64],
+        order: Order,
+    ) -> Result<(), std::io::Error> {
+        assert!(
+            values.len() == self.num_voxels(),
+            "{} raw values for {} voxels",
+            values.len(),
+            self.num_voxels()
+        );
+        for index in self.ordered_indices(order) {
+            file.write_all(&values[index].to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Write the text header describing a raw dump, as `key = value` lines.
+    ///
+    /// Keys are `dose_file`, `uncert_file`, `element_type` (`float64`), `byte_order`
+    /// (`little_endian`), `voxel_order` (`x_fastest` or `z_fastest`, see [`Order`]),
+    /// `dimensions` (voxels along *x*, *y*, *z*), and `x_nodes`, `y_nodes`, `z_nodes`, the node
+    /// coordinates in `[cm]`, which also give the voxel spacing.
+    pub fn write_raw_header_to<W: Write>(
+        &self,
+        file: &mut W,
+        dose_file: &str,
+        uncert_file: &str,
+        order: Order,
+    ) -> Result<(), std::io::Error> {
+        let voxel_order = match order {
+            Order::XFastest => "x_fastest",
+            Order::ZFastest => "z_fastest",
+        };
+        writeln!(file, "# dose2gmsh raw dump, dose in [Gy cm2] and fractional uncertainty")?;
+        writeln!(file, "dose_file = {}", dose_file)?;
+        writeln!(file, "uncert_file = {}", uncert_file)?;
+        writeln!(file, "element_type = float64")?;
+        writeln!(file, "byte_order = little_endian")?;
+        writeln!(file, "voxel_order = {}", voxel_order)?;
+        writeln!(file, "dimensions = {} {} {}", self.num_x(), self.num_y(), self.num_z())?;
+        for (axis, pts) in [("x", &self.xs), ("y", &self.ys), ("z", &self.zs)].iter() {
+            let pts: Vec<String> = pts.iter().map(|pt| pt.to_string()).collect();
+            writeln!(file, "{}_nodes = {}", axis, pts.join(" "))?;
+        }
+        Ok(())
+    }
+
+    /// Write the dose as a MetaImage, for 3D Slicer, ImageJ and ITK-based tools.
+    ///
+    /// For an `output` of `run.mhd` this writes the `run.mhd` header and the `run.raw` volume of
+    /// little-endian `f64` doses in native order. With `uncert`, the fractional uncertainties go
+    /// in a second image, `run_uncert.mhd` and `run_uncert.raw`.
+    ///
+    /// MetaImage describes a grid by one spacing per axis, so this fails with
+    /// [`DoseError::NonUniformGrid`] unless [`DoseBlock::uniform_spacing`] finds one. Spacing
+    /// and offset are written in `[mm]`, the unit these tools assume.
+    pub fn write_mhd<P: AsRef<Path>>(&self, output: P, uncert: bool) -> Result<(), DoseError> {
+        let header_path = output.as_ref().with_extension("mhd");
+        let mut uncert_name = header_path.file_stem().unwrap_or_default().to_os_string();
+        uncert_name.push("_uncert.mhd");
+
+        let mut images = vec![(header_path.clone(), &self.doses)];
+        if uncert {
+            images.push((header_path.with_file_name(uncert_name), &self.uncerts));
+        }
+        for (header_path, values) in images {
+            let data_path = header_path.with_extension("raw");
+            let data_file = data_path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            // check the grid before writing anything
+            let mut header = Vec::new();
+            self.write_mhd_header_to(&mut header, &data_file)?;
+            std::fs::write(&header_path, header)?;
+
+            let mut file = BufWriter::new(File::create(&data_path)?);
+            self.write_raw_field_to(&mut file, values, Order::XFastest)?;
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write a MetaImage header for a volume in `data_file`, see [`DoseBlock::write_mhd`].
+    pub fn write_mhd_header_to<W: Write>(&self, file: &mut W, data_file: &str) -> Result<(), DoseError> {
+        let spacing = self.uniform_spacing().ok_or(DoseError::NonUniformGrid)?;
+        // MetaImage offset is the centre of the first voxel
+        let first = [centroids(&self.xs)[0], centroids(&self.ys)[0], centroids(&self.zs)[0]];
+        // coordinates carry single precision noise, round to the nearest 1e-6 mm
+        let mm = |cm: [f64; 3]| {
+            let mm: Vec<String> = cm.iter().map(|cm| ((cm * 1.0e7).round() / 1.0e6).to_string()).collect();
+            mm.join(" ")
+        };
+
+        writeln!(file, "ObjectType = Image")?;
+        writeln!(file, "NDims = 3")?;
+        writeln!(file, "BinaryData = True")?;
+        writeln!(file, "BinaryDataByteOrderMSB = False")?;
+        writeln!(file, "CompressedData = False")?;
+        writeln!(file, "TransformMatrix = 1 0 0 0 1 0 0 0 1")?;
+        writeln!(file, "Offset = {}", mm(first))?;
+        writeln!(file, "ElementSpacing = {}", mm(spacing))?;
+        writeln!(file, "DimSize = {} {} {}", self.num_x(), self.num_y(), self.num_z())?;
+        writeln!(file, "ElementType = MET_DOUBLE")?;
+        // must come last
+        writeln!(file, "ElementDataFile = {}", data_file)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_raw() {
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 3.0],
+            ys: vec![0.0, 0.5],
+            zs: vec![-1.0, 0.0, 1.0],
+            doses: vec![1.0, 2.0, 3.0, 4.0],
+            uncerts: vec![0.1, 0.2, 0.3, 0.4],
+            variant: None,
+        };
+        let dir = std::env::temp_dir().join("dose2gmsh_write_raw");
+        std::fs::create_dir_all(&dir).unwrap();
+        data.write_raw(dir.join("run.raw"), Order::ZFastest).unwrap();
+
+        let doses = std::fs::read(dir.join("run.raw")).unwrap();
+        assert_eq!(doses.len(), 4 * 8);
+        // z fastest: (0, 0, 0), (0, 0, 1), (1, 0, 0), (1, 0, 1)
+        assert_eq!(doses[8..16], 3.0_f64.to_le_bytes());
+        assert_eq!(data.read_raw_field(dir.join("run.raw"), Order::ZFastest).unwrap(), data.doses);
+        assert_eq!(data.read_raw_field(dir.join("run_uncert.raw"), Order::ZFastest).unwrap(), data.uncerts);
+
+        let header = std::fs::read_to_string(dir.join("run.hdr")).unwrap();
+        assert!(header.contains("\ndose_file = run.raw\nuncert_file = run_uncert.raw\n"));
+        assert!(header.contains("\nvoxel_order = z_fastest\ndimensions = 2 1 2\nx_nodes = 0 1 3\n"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn write_mhd() {
+        let mut data = DoseBlock {
+            xs: vec![0.0, 0.5, 1.0],
+            ys: vec![-1.0, 0.0],
+            zs: vec![2.0, 2.2, 2.4],
+            doses: vec![1.0, 2.0, 3.0, 4.0],
+            uncerts: vec![0.1, 0.2, 0.3, 0.4],
+            variant: None,
+        };
+        let dir = std::env::temp_dir().join("dose2gmsh_write_mhd");
+        std::fs::create_dir_all(&dir).unwrap();
+        data.write_mhd(dir.join("run"), true).unwrap();
+
+        let header = std::fs::read_to_string(dir.join("run.mhd")).unwrap();
+        assert!(header.starts_with("ObjectType = Image\nNDims = 3\n"));
+        assert!(header.contains("\nOffset = 2.5 -5 21\nElementSpacing = 5 10 2\nDimSize = 2 1 2\n"));
+        assert!(header.ends_with("\nElementType = MET_DOUBLE\nElementDataFile = run.raw\n"));
+        assert_eq!(data.read_raw_field(dir.join("run.raw"), Order::XFastest).unwrap(), data.doses);
+        let uncert_header = std::fs::read_to_string(dir.join("run_uncert.mhd")).unwrap();
+        assert!(uncert_header.ends_with("ElementDataFile = run_uncert.raw\n"));
+        assert_eq!(data.read_raw_field(dir.join("run_uncert.raw"), Order::XFastest).unwrap(), data.uncerts);
+
+        data.zs[2] = 3.0;
+        assert!(matches!(data.write_mhd(dir.join("bad"), false), Err(DoseError::NonUniformGrid)));
+        assert!(!dir.join("bad.mhd").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+//! Checks that imported voxel centroids lie on a rectilinear grid.
+
+use crate::{DoseError, GRID_TOLERANCE};
+
+/// Check a set of voxel centroids forms a tensor-product grid, the only kind a [`DoseBlock`]
+/// can describe with its three node arrays.
+///
+/// `3ddose` files are rectilinear by construction, but centroids listed one per voxel, as in
+/// a `csv` file, could come from a sheared or rotated grid. Reading those into node arrays
+/// would silently scramble the voxels, so importers should run this check first.
+///
+/// [`DoseBlock`]: crate::DoseBlock
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RectilinearCheck {
+    /// Coordinates `[cm]` closer than this along an axis are taken as the same grid line.
+    pub tol: f64,
+}
+
+impl Default for RectilinearCheck {
+    fn default() -> Self {
+        RectilinearCheck { tol: GRID_TOLERANCE }
+    }
+}
+
+/// The grid found by a [`RectilinearCheck`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RectilinearGrid {
+    /// Distinct centroid coordinates along *x*, *y* and *z*, in increasing order.
+    pub centroids: [Vec<f64>; 3],
+    /// Voxel index, *x* fastest as in [`DoseBlock::voxel_index`], of each centroid checked.
+    ///
+    /// [`DoseBlock::voxel_index`]: crate::DoseBlock::voxel_index
+    pub voxels: Vec<usize>,
+}
+
+impl RectilinearCheck {
+    /// Check `centroids`, in any order, hold exactly one point for every combination of
+    /// their distinct *x*, *y* and *z* coordinates.
+    ///
+    /// Fails with [`DoseError::NotRectilinear`] if a coordinate isn't finite, two centroids
+    /// share a grid position, or some grid positions have no centroid, as happens when the
+    /// grid is sheared or rotated relative to the axes.
+    pub fn check(&self, centroids: &[[f64; 3]]) -> Result<RectilinearGrid, DoseError> {
+        let not_rectilinear = |details: String| Err(DoseError::NotRectilinear { details });
+        if let Some(pt) = centroids.iter().find(|pt| pt.iter().any(|c| !c.is_finite())) {
+            return not_rectilinear(format!("centroid {:?} isn't finite", pt));
+        }
+
+        let mut lines: [Vec<f64>; 3] = Default::default();
+        for (axis, line) in lines.iter_mut().enumerate() {
+            let mut coords: Vec<f64> = centroids.iter().map(|pt| pt[axis]).collect();
+            coords.sort_by(|a, b| a.partial_cmp(b).expect("coordinates are finite"));
+            for coord in coords {
+                if line.last().is_none_or(|&last| coord - last > self.tol) {
+                    line.push(coord);
+                }
+            }
+        }
+
+        let [nx, ny, nz] = [lines[0].len(), lines[1].len(), lines[2].len()];
+        if centroids.len() != nx * ny * nz {
+            return not_rectilinear(format!(
+                "{} centroids, a grid of {} x {} x {} needs {}",
+                centroids.len(),
+                nx,
+                ny,
+                nz,
+                nx * ny * nz
+            ));
+        }
+        // the line within `tol` below each coordinate, which the dedup above kept
+        let line_of = |axis: usize, coord: f64| {
+            lines[axis].partition_point(|&line| line <= coord + self.tol) - 1
+        };
+        let mut seen = vec![false; centroids.len()];
+        let mut voxels = Vec::with_capacity(centroids.len());
+        for pt in centroids {
+            let voxel = line_of(0, pt[0]) + nx * (line_of(1, pt[1]) + ny * line_of(2, pt[2]));
+            if seen[voxel] {
+                return not_rectilinear(format!("centroid {:?} repeats a grid position", pt));
+            }
+            seen[voxel] = true;
+            voxels.push(voxel);
+        }
+        Ok(RectilinearGrid { centroids: lines, voxels })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rectilinear_check() {
+        // 2 x 2 x 1 voxels, shuffled and slightly off in the last digits
+        let centroids = [[1.0, 0.5, 0.0], [0.0, 0.5, 1e-9], [1.0, -0.5, 0.0], [0.0, -0.5, 0.0]];
+        let grid = RectilinearCheck::default().check(&centroids).unwrap();
+        assert_eq!(grid.centroids, [vec![0.0, 1.0], vec![-0.5, 0.5], vec![0.0]]);
+        assert_eq!(grid.voxels, vec![3, 2, 1, 0]);
+
+        // the same voxels sheared in x as y goes up, giving four x lines
+        let sheared = [[1.2, 0.5, 0.0], [0.2, 0.5, 0.0], [1.0, -0.5, 0.0], [0.0, -0.5, 0.0]];
+        let err = RectilinearCheck::default().check(&sheared).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the voxel centroids don't form a rectilinear grid: 4 centroids, a grid of 4 x 2 x 1 \
+             needs 8"
+        );
+
+        // right count, but a position is doubled and another is missing
+        let doubled = [[1.0, 0.5, 0.0], [0.0, 0.5, 0.0], [0.0, 0.5, 0.0], [1.0, -0.5, 0.0]];
+        let err = RectilinearCheck::default().check(&doubled).unwrap_err();
+        assert!(err.to_string().ends_with("centroid [0.0, 0.5, 0.0] repeats a grid position"));
+        // within a coarse tolerance the sheared points line up again
+        assert!(RectilinearCheck { tol: 0.25 }.check(&sheared).is_ok());
+        assert!(RectilinearCheck::default().check(&[[f64::NAN, 0.0, 0.0]]).is_err());
+    }
+}
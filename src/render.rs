@@ -0,0 +1,385 @@
+//! Colormapped PNG previews of dose slices.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::{Axis, DoseBlock, DoseError, SlicePlane};
+
+/// Mapping from relative dose to colour.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum Colormap {
+    /// Black to white.
+    Gray,
+    /// Black through red and yellow to white.
+    Hot,
+    /// Blue through cyan, yellow and red, the usual isodose wash.
+    #[default]
+    Jet,
+    /// Dark purple through blue and green to yellow, evenly bright steps that print well in
+    /// grayscale too.
+    Viridis,
+}
+
+/// Viridis colours at evenly spaced values from 0 to 1, interpolated linearly in between.
+const VIRIDIS: [[u8; 3]; 9] = [
+    [68, 1, 84],
+    [71, 44, 122],
+    [59, 82, 139],
+    [44, 113, 142],
+    [33, 145, 140],
+    [39, 173, 129],
+    [94, 201, 98],
+    [170, 220, 50],
+    [253, 231, 37],
+];
+
+impl Colormap {
+    /// Colour of a value `t` between 0 and 1, clamped to that range.
+    pub fn color(self, t: f64) -> [u8; 3] {
+        let t = if t.is_nan() { 0.0 } else { t.clamp(0.0, 1.0) };
+        let channel = |value: f64| (255.0 * value.clamp(0.0, 1.0)).round() as u8;
+        match self {
+            Colormap::Gray => [channel(t); 3],
+            Colormap::Hot => [channel(3.0 * t), channel(3.0 * t - 1.0), channel(3.0 * t - 2.0)],
+            Colormap::Jet => [
+                channel(1.5 - (4.0 * t - 3.0).abs()),
+                channel(1.5 - (4.0 * t - 2.0).abs()),
+                channel(1.5 - (4.0 * t - 1.0).abs()),
+            ],
+            Colormap::Viridis => {
+                let position = t * (VIRIDIS.len() - 1) as f64;
+                let below = (position.floor() as usize).min(VIRIDIS.len() - 2);
+                let frac = position - below as f64;
+                let (lo, hi) = (VIRIDIS[below], VIRIDIS[below + 1]);
+                let mix = |c: usize| {
+                    (f64::from(lo[c]) + frac * (f64::from(hi[c]) - f64::from(lo[c]))).round() as u8
+                };
+                [mix(0), mix(1), mix(2)]
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for Colormap {
+    type Err = String;
+    fn from_str(colormap: &str) -> Result<Self, Self::Err> {
+        match colormap {
+            "gray" => Ok(Colormap::Gray),
+            "hot" => Ok(Colormap::Hot),
+            "jet" => Ok(Colormap::Jet),
+            "viridis" => Ok(Colormap::Viridis),
+            _ => Err(format!("Unknown colormap {}, expected gray, hot, jet or viridis", colormap)),
+        }
+    }
+}
+
+/// Options for [`DoseBlock::write_orthoslices_png`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrthosliceOptions {
+    /// Voxel `(i, j, k)` the three slices pass through, the central voxel if `None`.
+    pub at: Option<[usize; 3]>,
+    /// Colours for doses from zero to the maximum dose of the block.
+    pub colormap: Colormap,
+    /// Width and height in pixels of each voxel.
+    pub pixels_per_voxel: usize,
+}
+
+impl Default for OrthosliceOptions {
+    fn default() -> Self {
+        OrthosliceOptions { at: None, colormap: Colormap::default(), pixels_per_voxel: 4 }
+    }
+}
+
+/// Options for [`DoseBlock::write_slice_png`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SliceImageOptions {
+    /// Colours for doses from the low to the high end of `range`.
+    pub colormap: Colormap,
+    /// Doses `(lo, hi)` mapped to the ends of the colormap, the minimum and maximum dose of the
+    /// slice if `None`.
+    pub range: Option<(f64, f64)>,
+    /// Width and height in pixels of each voxel.
+    pub pixels_per_voxel: usize,
+}
+
+impl Default for SliceImageOptions {
+    fn default() -> Self {
+        SliceImageOptions { colormap: Colormap::default(), range: None, pixels_per_voxel: 1 }
+    }
+}
+
+/// Pixels between the slices of a montage.
+const GAP: usize = 8;
+
+impl DoseBlock {
+    /// Write the three orthogonal slices through a voxel, *xy*, *xz* and *yz* from left to
+    /// right, as a single colormapped PNG, e.g. for quick QA thumbnails.
+    ///
+    /// Every voxel is drawn as the same square whatever its size, so slices of non-uniform
+    /// grids are distorted. The second axis of each slice points up. All three slices share
+    /// one colour scale, from zero to the maximum dose of the whole block.
+    pub fn write_orthoslices_png<P: AsRef<std::path::Path>>(
+        &self,
+        output: P,
+        options: &OrthosliceOptions,
+    ) -> Result<(), DoseError> {
+        let mut file = BufWriter::new(File::create(output)?);
+        self.write_orthoslices_png_to(&mut file, options)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Write the orthoslice montage of [`DoseBlock::write_orthoslices_png`] to any writer.
+    ///
+    /// Fails with [`DoseError::InvalidParameter`] if `options.at` is outside the grid or
+    /// `options.pixels_per_voxel` is zero.
+    pub fn write_orthoslices_png_to<W: Write>(
+        &self,
+        file: &mut W,
+        options: &OrthosliceOptions,
+    ) -> Result<(), DoseError> {
+        let at = options.at.unwrap_or([self.num_x() / 2, self.num_y() / 2, self.num_z() / 2]);
+        let sizes = [self.num_x(), self.num_y(), self.num_z()];
+        for (&(name, index), &size) in [("i", at[0]), ("j", at[1]), ("k", at[2])].iter().zip(&sizes) {
+            if index >= size {
+                return Err(DoseError::InvalidParameter { name, value: index as f64 });
+            }
+        }
+        let scale = options.pixels_per_voxel;
+        if scale == 0 {
+            return Err(DoseError::InvalidParameter { name: "pixels_per_voxel", value: 0.0 });
+        }
+
+        let panels = [
+            self.plane(Axis::Z, at[2]),
+            self.plane(Axis::Y, at[1]),
+            self.plane(Axis::X, at[0]),
+        ];
+        let width = panels.iter().map(|panel| panel.0 * scale).sum::<usize>() + 2 * GAP;
+        let height = panels.iter().map(|panel| panel.1 * scale).max().unwrap_or(0);
+
+        let max_dose = self.doses.iter().cloned().fold(0.0, f64::max);
+        let mut pixels = vec![0_u8; 3 * width * height];
+        let mut left = 0;
+        for (cols, rows, values) in &panels {
+            // bottom-aligned, second axis pointing up
+            let top = height - rows * scale;
+            for (index, &value) in values.iter().enumerate() {
+                let (col, row) = (index % cols, rows - 1 - index / cols);
+                let relative = if max_dose > 0.0 { value / max_dose } else { 0.0 };
+                let color = options.colormap.color(relative);
+                for y in top + row * scale..top + (row + 1) * scale {
+                    for x in left + col * scale..left + (col + 1) * scale {
+                        pixels[3 * (x + width * y)..][..3].copy_from_slice(&color);
+                    }
+                }
+            }
+            left += cols * scale + GAP;
+        }
+        write_png(file, width, height, &pixels)
+    }
+
+    /// Write one plane of voxels as a colormapped PNG, e.g. the central axial slice for a
+    /// report.
+    ///
+    /// The image has `pixels_per_voxel` pixels per voxel along each in-plane axis, the first of
+    /// [`Axis::transverse`] to the right and the second up, drawing every voxel as the same
+    /// square as [`DoseBlock::write_orthoslices_png`] does.
+    pub fn write_slice_png<P: AsRef<std::path::Path>>(
+        &self,
+        output: P,
+        plane: SlicePlane,
+        options: &SliceImageOptions,
+    ) -> Result<(), DoseError> {
+        let mut file = BufWriter::new(File::create(output)?);
+        self.write_slice_png_to(&mut file, plane, options)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Write the slice image of [`DoseBlock::write_slice_png`] to any writer.
+    ///
+    /// Fails with [`DoseError::InvalidParameter`] if the plane is outside the grid,
+    /// `options.pixels_per_voxel` is zero, or `options.range` isn't an increasing pair of
+    /// finite doses. A slice of one dose throughout is drawn in the lowest colour.
+    pub fn write_slice_png_to<W: Write>(
+        &self,
+        file: &mut W,
+        plane: SlicePlane,
+        options: &SliceImageOptions,
+    ) -> Result<(), DoseError> {
+        let slice = self.slice(plane.normal, plane.index)?;
+        let scale = options.pixels_per_voxel;
+        if scale == 0 {
+            return Err(DoseError::InvalidParameter { name: "pixels_per_voxel", value: 0.0 });
+        }
+        let (lo, hi) = match options.range {
+            Some((lo, hi)) if !(lo.is_finite() && hi.is_finite() && lo < hi) => {
+                return Err(DoseError::InvalidParameter { name: "range", value: hi - lo });
+            }
+            Some(range) => range,
+            None => {
+                let lo = slice.doses.iter().cloned().fold(f64::INFINITY, f64::min);
+                (lo, slice.doses.iter().cloned().fold(lo, f64::max))
+            }
+        };
+
+        let (cols, rows) = (slice.us.len(), slice.vs.len());
+        let (width, height) = (cols * scale, rows * scale);
+        let mut pixels = vec![0_u8; 3 * width * height];
+        for (index, &dose) in slice.doses.iter().enumerate() {
+            // second axis pointing up
+            let (col, row) = (index % cols, rows - 1 - index / cols);
+            let relative = if hi > lo { (dose - lo) / (hi - lo) } else { 0.0 };
+            let color = options.colormap.color(relative);
+            for y in row * scale..(row + 1) * scale {
+                for x in col * scale..(col + 1) * scale {
+                    pixels[3 * (x + width * y)..][..3].copy_from_slice(&color);
+                }
+            }
+        }
+        write_png(file, width, height, &pixels)
+    }
+
+    /// Doses in the plane of voxels with index `index` along `normal`, as
+    /// `(columns, rows, values)` with the first transverse axis fastest.
+    fn plane(&self, normal: Axis, index: usize) -> (usize, usize, Vec<f64>) {
+        let (cols, rows) = match normal {
+            Axis::X => (self.num_y(), self.num_z()),
+            Axis::Y => (self.num_x(), self.num_z()),
+            Axis::Z => (self.num_x(), self.num_y()),
+        };
+        let values = (0..rows)
+            .flat_map(|row| (0..cols).map(move |col| (col, row)))
+            .map(|(col, row)| {
+                let voxel = match normal {
+                    Axis::X => self.voxel_index(index, col, row),
+                    Axis::Y => self.voxel_index(col, index, row),
+                    Axis::Z => self.voxel_index(col, row, index),
+                };
+                self.doses[voxel]
+            })
+            .collect();
+        (cols, rows, values)
+    }
+}
+
+/// Encode `width` x `height` RGB `pixels`, row by row from the top, as an 8-bit PNG.
+fn write_png<W: Write>(
+    file: &mut W,
+    width: usize,
+    height: usize,
+    pixels: &[u8],
+) -> Result<(), DoseError> {
+    let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(std::io::Error::from)?;
+    writer.write_image_data(pixels).map_err(std::io::Error::from)?;
+    writer.finish().map_err(std::io::Error::from)?;
+    Ok(())
+}
+
+/// Parse a dose range `lo,hi` for [`SliceImageOptions::range`].
+pub(crate) fn parse_dose_range(value: &str) -> Result<(f64, f64), String> {
+    let invalid = || format!("Could not parse {} as an increasing dose range, e.g. 0,2e-12", value);
+    let mut parts = value.split(',').map(|part| part.trim().parse::<f64>());
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(lo)), Some(Ok(hi)), None) if lo.is_finite() && hi.is_finite() && lo < hi => {
+            Ok((lo, hi))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colormaps() {
+        assert_eq!(Colormap::Gray.color(0.5), [128, 128, 128]);
+        assert_eq!(Colormap::Hot.color(0.0), [0, 0, 0]);
+        assert_eq!(Colormap::Hot.color(1.0), [255, 255, 255]);
+        assert_eq!(Colormap::Jet.color(0.0), [0, 0, 128]);
+        assert_eq!(Colormap::Jet.color(0.5), [128, 255, 128]);
+        assert_eq!(Colormap::Jet.color(2.0), [128, 0, 0]);
+        assert_eq!(Colormap::Jet.color(f64::NAN), Colormap::Jet.color(0.0));
+        assert_eq!(Colormap::Viridis.color(0.0), [68, 1, 84]);
+        assert_eq!(Colormap::Viridis.color(1.0), [253, 231, 37]);
+        assert_eq!(Colormap::Viridis.color(0.5 / 8.0), [70, 23, 103]);
+        assert_eq!("hot".parse(), Ok(Colormap::Hot));
+        assert_eq!("viridis".parse(), Ok(Colormap::Viridis));
+    }
+
+    /// The size and RGB pixels of the PNG image `write` writes.
+    fn decode(
+        write: impl FnOnce(&mut Vec<u8>) -> Result<(), DoseError>,
+    ) -> (png::OutputInfo, Vec<u8>) {
+        let mut out = Vec::new();
+        write(&mut out).unwrap();
+        let decoder = png::Decoder::new(std::io::Cursor::new(out));
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        (info, pixels)
+    }
+
+    #[test]
+    fn orthoslices_png() {
+        // 2 x 3 x 4 voxels with the maximum dose in the last voxel
+        let num_voxels = 24;
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 2.0],
+            ys: vec![0.0, 1.0, 2.0, 3.0],
+            zs: vec![0.0, 1.0, 2.0, 3.0, 4.0],
+            doses: (0..num_voxels).map(|i| i as f64).collect(),
+            uncerts: vec![0.0; num_voxels],
+            variant: None,
+        };
+        let options = OrthosliceOptions {
+            at: Some([1, 2, 3]),
+            colormap: Colormap::Gray,
+            pixels_per_voxel: 2,
+        };
+        let (info, pixels) = decode(|out| data.write_orthoslices_png_to(out, &options));
+        // xy 2 x 3, xz 2 x 4 and yz 3 x 4 voxels
+        assert_eq!((info.width, info.height), ((2 + 2 + 3) * 2 + 2 * GAP as u32, 4 * 2));
+        let pixel = |x: usize, y: usize| pixels[3 * (x + info.width as usize * y)];
+        // top right of the xy slice is the maximum dose voxel (1, 2, 3)
+        assert_eq!(pixel(3, 2), 255);
+        // the xy slice is shorter than the others, leaving a black strip on top
+        assert_eq!(pixel(3, 1), 0);
+        // bottom left of the yz slice is voxel (1, 0, 0)
+        let yz_left = (2 + 2) * 2 + 2 * GAP;
+        assert_eq!(pixel(yz_left, 7), (255.0 / 23.0_f64).round() as u8);
+
+        let outside = OrthosliceOptions { at: Some([2, 0, 0]), ..options };
+        assert!(matches!(
+            data.write_orthoslices_png_to(&mut Vec::new(), &outside),
+            Err(DoseError::InvalidParameter { name: "i", .. })
+        ));
+
+        // the xz plane at y index 1, 2 x 4 voxels of doses 2 + 6k + i
+        let plane = SlicePlane { normal: Axis::Y, index: 1 };
+        let options = SliceImageOptions { colormap: Colormap::Gray, ..Default::default() };
+        let (info, pixels) = decode(|out| data.write_slice_png_to(out, plane, &options));
+        assert_eq!((info.width, info.height), (2, 4));
+        // scaled from the slice minimum 2 at the bottom left to its maximum 21 at the top right
+        let middle = (255.0 * 7.0 / 19.0_f64).round() as u8;
+        assert_eq!([pixels[3 * 6], pixels[3], pixels[3 * 5]], [0, 255, middle]);
+
+        let range = Some((0.0, 40.0));
+        let options = SliceImageOptions { range, pixels_per_voxel: 3, ..options };
+        let (info, pixels) = decode(|out| data.write_slice_png_to(out, plane, &options));
+        assert_eq!((info.width, info.height), (6, 12));
+        assert_eq!(pixels[3 * 5], (255.0 * 21.0 / 40.0_f64).round() as u8);
+
+        let outside = SlicePlane { normal: Axis::Z, index: 4 };
+        assert!(data.write_slice_png_to(&mut Vec::new(), outside, &options).is_err());
+        let reversed = SliceImageOptions { range: Some((1.0, 1.0)), ..options };
+        assert!(data.write_slice_png_to(&mut Vec::new(), plane, &reversed).is_err());
+        assert_eq!(parse_dose_range("0, 2e-12"), Ok((0.0, 2e-12)));
+        assert!(parse_dose_range("2,1").is_err());
+    }
+}
@@ -0,0 +1,191 @@
+//! Dose blocks holding single-precision doses, for grids too large to hold comfortably as `f64`.
+
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+
+use crate::stream::{read_grid, write_streamed_msh2, Tokens};
+use crate::{open_3ddose, DoseBlock, DoseError, FieldKind, Fmt, Quantity, DEFAULT_BUFFER_SIZE};
+
+impl DoseBlock<f32> {
+    /// Parse a `3ddose` file as [`DoseBlock::from_3d_dose`] does, keeping doses and
+    /// uncertainties as `f32` for half the memory. Node coordinates stay `f64`.
+    ///
+    /// Seven significant digits are well past the statistical precision of Monte Carlo doses,
+    /// but values below about `1e-38` lose digits and those below about `1e-45` become 0. The
+    /// file layout isn't detected, so `variant` is `None`.
+    pub fn from_3d_dose_f32<P: AsRef<std::path::Path>>(
+        input_file: P,
+    ) -> Result<DoseBlock<f32>, DoseError> {
+        DoseBlock::from_3d_dose_f32_buffered(input_file, DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Parse a `3ddose` file as [`DoseBlock::from_3d_dose_f32`] does, reading it through a
+    /// buffer of `buffer_size` bytes.
+    pub fn from_3d_dose_f32_buffered<P: AsRef<std::path::Path>>(
+        input_file: P,
+        buffer_size: usize,
+    ) -> Result<DoseBlock<f32>, DoseError> {
+        DoseBlock::from_reader_f32(open_3ddose(input_file, buffer_size)?)
+    }
+
+    /// Parse `3ddose` data from any buffered reader as [`DoseBlock::from_3d_dose_f32`] parses a
+    /// file.
+    pub fn from_reader_f32<R: BufRead>(reader: R) -> Result<DoseBlock<f32>, DoseError> {
+        let mut tokens = Tokens::new(reader);
+        let DoseBlock { xs, ys, zs, variant, .. } = read_grid(&mut tokens)?;
+        let num_voxels = (xs.len() - 1) * (ys.len() - 1) * (zs.len() - 1);
+        let doses = tokens.parse_n(num_voxels, "dose value")?;
+        let uncerts = tokens.parse_n(num_voxels, "uncertainty value")?;
+        Ok(DoseBlock { xs, ys, zs, doses, uncerts, variant })
+    }
+
+    /// Widen to a [`DoseBlock`] for transforms and the other output formats.
+    pub fn to_f64(&self) -> DoseBlock {
+        let widen = |values: &[f32]| values.iter().map(|&value| f64::from(value)).collect();
+        DoseBlock {
+            xs: self.xs.clone(),
+            ys: self.ys.clone(),
+            zs: self.zs.clone(),
+            doses: widen(&self.doses),
+            uncerts: widen(&self.uncerts),
+            variant: self.variant,
+        }
+    }
+
+    /// Write a Gmsh `.msh` file (version 2.2), see [`DoseBlock::write_msh2_f32_to`].
+    pub fn write_msh2_f32<P: AsRef<std::path::Path>>(
+        &self,
+        kinds: &[FieldKind],
+        output: P,
+    ) -> Result<(), DoseError> {
+        self.check_f32_kinds(kinds)?;
+        let mut file = BufWriter::new(File::create(output)?);
+        self.write_msh2_f32_to(kinds, &mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Write the mesh and a field for each of `kinds`, in order, as
+    /// [`DoseBlock::write_msh2_fields_to`] does, without widening the whole block to `f64`.
+    ///
+    /// Values are printed at `f32` precision, e.g. `0.1` rather than `0.10000000149011612`.
+    /// Only [`FieldKind::Dose`], [`FieldKind::Uncert`] and [`FieldKind::AbsUncert`] can be
+    /// written, any other kind is an [`std::io::ErrorKind::InvalidInput`] error before anything
+    /// is written.
+    pub fn write_msh2_f32_to<W: Write>(
+        &self,
+        kinds: &[FieldKind],
+        file: &mut W,
+    ) -> Result<(), DoseError> {
+        self.check_f32_kinds(kinds)?;
+        let grid = DoseBlock {
+            xs: self.xs.clone(),
+            ys: self.ys.clone(),
+            zs: self.zs.clone(),
+            doses: Vec::new(),
+            uncerts: Vec::new(),
+            variant: None,
+        };
+        write_streamed_msh2(&grid, kinds, file, |kind, index| match kind {
+            FieldKind::Dose => Ok(self.doses[index]),
+            FieldKind::Uncert => Ok(self.uncerts[index]),
+            _ => Ok(self.doses[index].abs() * self.uncerts[index]),
+        })
+    }
+
+    /// An error naming the first of `kinds` that isn't computed in single precision.
+    fn check_f32_kinds(&self, kinds: &[FieldKind]) -> Result<(), std::io::Error> {
+        let unsupported = |kind: &&FieldKind| {
+            !matches!(kind, FieldKind::Dose | FieldKind::Uncert | FieldKind::AbsUncert)
+        };
+        match kinds.iter().find(unsupported) {
+            Some(kind) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} can't be written in single precision, only dose and uncertainties",
+                    kind.label(Fmt::Msh2, Quantity::Dose)
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl DoseBlock {
+    /// Narrow the doses and uncertainties to `f32`, see [`DoseBlock::from_3d_dose_f32`].
+    pub fn to_f32(&self) -> DoseBlock<f32> {
+        let narrow = |values: &[f64]| values.iter().map(|&value| value as f32).collect();
+        DoseBlock {
+            xs: self.xs.clone(),
+            ys: self.ys.clone(),
+            zs: self.zs.clone(),
+            doses: narrow(&self.doses),
+            uncerts: narrow(&self.uncerts),
+            variant: self.variant,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_precision() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("props");
+        path.push("asym_2x3x4.3ddose");
+        let data = DoseBlock::from_3d_dose(&path).unwrap();
+        let single = DoseBlock::from_3d_dose_f32(&path).unwrap();
+        assert_eq!(single.num_voxels(), data.num_voxels());
+        assert_eq!(single, DoseBlock { variant: None, ..data.to_f32() });
+        for (widened, dose) in single.to_f64().doses.iter().zip(&data.doses) {
+            approx::assert_relative_eq!(widened, dose, max_relative = 1e-7);
+        }
+
+        let kinds = [FieldKind::Dose, FieldKind::Uncert];
+        let (mut msh, mut expected) = (Vec::new(), Vec::new());
+        single.write_msh2_f32_to(&kinds, &mut msh).unwrap();
+        data.write_msh2_to(&mut expected).unwrap();
+        let msh = String::from_utf8(msh).unwrap();
+        let expected = String::from_utf8(expected).unwrap();
+        assert_eq!(msh.lines().count(), expected.lines().count());
+        let data_start = |text: &str| text.find("$ElementData").unwrap();
+        assert_eq!(msh[..data_start(&msh)], expected[..data_start(&expected)]);
+
+        // absolute uncertainties first, then the doses
+        let mut msh = Vec::new();
+        single.write_msh2_f32_to(&[FieldKind::AbsUncert, FieldKind::Dose], &mut msh).unwrap();
+        let msh = String::from_utf8(msh).unwrap();
+        let names: Vec<_> = msh.lines().skip_while(|line| *line != "$ElementData").collect();
+        assert_eq!(names[2], "\"Uncertainty [Gy·cm2]\"");
+        let abs_uncert = single.doses[0].abs() * single.uncerts[0];
+        assert_eq!(names[9], format!("1 {}", abs_uncert));
+        assert_eq!(msh.matches("$ElementData").count(), 2);
+
+        let mut msh = Vec::new();
+        let err = single.write_msh2_f32_to(&[FieldKind::Dose, FieldKind::Log10], &mut msh);
+        match err {
+            Err(DoseError::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput),
+            other => panic!("expected an invalid input error, got {:?}", other),
+        }
+        assert!(msh.is_empty());
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        // without the last uncertainty
+        let end = text.trim_end().rfind(' ').unwrap();
+        let truncated = DoseBlock::from_reader_f32(&text.as_bytes()[..end]);
+        assert!(matches!(truncated, Err(DoseError::UnexpectedEof { field: "uncertainty value" })));
+        assert!(matches!(DoseBlock::from_reader_f32(&b""[..]), Err(DoseError::EmptyGrid)));
+    }
+
+    #[test]
+    fn single_precision_cli() {
+        use structopt::StructOpt;
+        let parse = |args: &[&str]| crate::Cli::from_iter_safe(args);
+        let cli = parse(&["dose2gmsh", "in.3ddose", "--single-precision", "--uncert", "absolute"]);
+        assert!(cli.unwrap().single_precision);
+        assert!(parse(&["dose2gmsh", "in.3ddose", "--single-precision", "--stream"]).is_err());
+        assert!(parse(&["dose2gmsh", "in.3ddose", "--single-precision", "--mu", "2"]).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+//! 2D planes of voxels cut from the grid, e.g. the central axial slice.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::{centroids, Axis, DoseBlock, DoseError, Fmt, Quantity};
+
+/// A plane of voxels, parsed from `<axis>=<index>` on the command line.
+///
+/// `z=20` is the plane of voxels with index 20 along *z*, counting from 0.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SlicePlane {
+    /// Axis normal to the plane.
+    pub normal: Axis,
+    /// Voxel index of the plane along `normal`.
+    pub index: usize,
+}
+
+impl std::str::FromStr for SlicePlane {
+    type Err = String;
+    fn from_str(plane: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Could not parse slice {}, expected e.g. z=20", plane);
+        let (normal, index) = plane.split_once('=').ok_or_else(err)?;
+        Ok(SlicePlane { normal: normal.parse()?, index: index.trim().parse().map_err(|_| err())? })
+    }
+}
+
+/// The voxels of one plane of a dose block, see [`DoseBlock::slice`].
+///
+/// The in-plane axes are the two [`Axis::transverse`] to `normal`, called *u* and *v* here, and
+/// `doses` and `uncerts` hold one value per voxel with *u* varying fastest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Slice2D {
+    /// Axis normal to the plane.
+    pub normal: Axis,
+    /// Voxel index of the plane along `normal`.
+    pub index: usize,
+    /// Centroid coordinate of the plane along `normal` in `[cm]`.
+    pub position: f64,
+    /// Voxel centroids along the first in-plane axis in `[cm]`.
+    pub us: Vec<f64>,
+    /// Voxel centroids along the second in-plane axis in `[cm]`.
+    pub vs: Vec<f64>,
+    /// Dose of each voxel in the plane.
+    pub doses: Vec<f64>,
+    /// Fractional uncertainty of each voxel in the plane.
+    pub uncerts: Vec<f64>,
+}
+
+impl DoseBlock {
+    /// The plane of voxels with index `index` along `normal`.
+    ///
+    /// An index past the last voxel along `normal` fails with [`DoseError::InvalidParameter`].
+    pub fn slice(&self, normal: Axis, index: usize) -> Result<Slice2D, DoseError> {
+        let position = match centroids(self.nodes(normal)).get(index) {
+            Some(&position) => position,
+            None => return Err(DoseError::InvalidParameter { name: "slice", value: index as f64 }),
+        };
+        let (u, v) = normal.transverse();
+        let (us, vs) = (centroids(self.nodes(u)), centroids(self.nodes(v)));
+        let mut slice = Slice2D {
+            normal,
+            index,
+            position,
+            doses: Vec::with_capacity(us.len() * vs.len()),
+            uncerts: Vec::with_capacity(us.len() * vs.len()),
+            us,
+            vs,
+        };
+        let mut ijk = [0; 3];
+        ijk[normal as usize] = index;
+        for row in 0..slice.vs.len() {
+            for col in 0..slice.us.len() {
+                ijk[u as usize] = col;
+                ijk[v as usize] = row;
+                let voxel = self.voxel_index(ijk[0], ijk[1], ijk[2]);
+                slice.doses.push(self.doses[voxel]);
+                slice.uncerts.push(self.uncerts[voxel]);
+            }
+        }
+        Ok(slice)
+    }
+
+    /// Write a plane of voxels to a `csv` file, see [`DoseBlock::write_slice_csv_to`].
+    pub fn write_slice_csv<P: AsRef<std::path::Path>>(
+        &self,
+        output: P,
+        plane: SlicePlane,
+        quantity: Quantity,
+    ) -> Result<(), DoseError> {
+        let slice = self.slice(plane.normal, plane.index)?;
+        let mut file = BufWriter::new(File::create(output)?);
+        slice.write_csv_to(&mut file, quantity)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Write a plane of voxels in `csv` format to any writer.
+    ///
+    /// Each row holds the two in-plane centroid coordinates, e.g. `xc [cm]` and `yc [cm]` for
+    /// a plane normal to *z*, then the dose labelled as `quantity` and the uncertainty, with
+    /// the first coordinate varying fastest. Out-of-range planes fail as in
+    /// [`DoseBlock::slice`].
+    pub fn write_slice_csv_to<W: Write>(
+        &self,
+        file: &mut W,
+        plane: SlicePlane,
+        quantity: Quantity,
+    ) -> Result<(), DoseError> {
+        self.slice(plane.normal, plane.index)?.write_csv_to(file, quantity)?;
+        Ok(())
+    }
+}
+
+impl Slice2D {
+    /// Write the plane as [`DoseBlock::write_slice_csv_to`] describes.
+    fn write_csv_to<W: Write>(
+        &self,
+        file: &mut W,
+        quantity: Quantity,
+    ) -> Result<(), std::io::Error> {
+        let name = |axis: Axis| ["xc [cm]", "yc [cm]", "zc [cm]"][axis as usize];
+        let (u, v) = self.normal.transverse();
+        let [dose_name, uncert_name] = DoseBlock::field_names(Fmt::Csv, quantity);
+        writeln!(file, "{},{},{},{}", name(u), name(v), dose_name, uncert_name)?;
+        let mut voxel = 0;
+        for v in &self.vs {
+            for u in &self.us {
+                writeln!(file, "{},{},{},{}", u, v, self.doses[voxel], self.uncerts[voxel])?;
+                voxel += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slice() {
+        // 3 x 2 x 2 voxels, doses counting up in x, y, z order
+        let data = DoseBlock {
+            xs: vec![-1.5, -0.5, 0.5, 1.5],
+            ys: vec![-1.0, 0.0, 1.0],
+            zs: vec![0.0, 1.0, 3.0],
+            doses: (1..=12).map(f64::from).collect(),
+            uncerts: (1..=12).map(|i| f64::from(i) / 100.0).collect(),
+            variant: None,
+        };
+        let xz = data.slice(Axis::Y, 1).unwrap();
+        assert_eq!((xz.position, xz.us, xz.vs), (0.5, vec![-1.0, 0.0, 1.0], vec![0.5, 2.0]));
+        assert_eq!(xz.doses, vec![4.0, 5.0, 6.0, 10.0, 11.0, 12.0]);
+        assert_eq!(xz.uncerts[3], 0.1);
+        assert_eq!(data.slice(Axis::X, 2).unwrap().doses, vec![3.0, 6.0, 9.0, 12.0]);
+        assert!(matches!(data.slice(Axis::Z, 2), Err(DoseError::InvalidParameter { .. })));
+
+        let plane = "z=1".parse().unwrap();
+        assert_eq!(plane, SlicePlane { normal: Axis::Z, index: 1 });
+        let mut out = Vec::new();
+        data.write_slice_csv_to(&mut out, plane, Quantity::Dose).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("xc [cm],yc [cm],Dose [Gy cm2],Uncertainty fraction"));
+        assert_eq!(lines.next(), Some("-1,-0.5,7,0.07"));
+        assert_eq!(lines.last(), Some("1,0.5,12,0.12"));
+        assert!("z".parse::<SlicePlane>().is_err());
+        assert!("w=1".parse::<SlicePlane>().is_err());
+        assert!("z=-1".parse::<SlicePlane>().is_err());
+    }
+}
@@ -0,0 +1,229 @@
+//! Spatial smoothing of noisy dose at the resolution of the grid.
+
+use crate::{Axis, DoseBlock, DoseError};
+
+/// Smoothing kernel, applied separately along *x*, *y* and *z*.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Kernel {
+    /// Uniform average over `radius` `[cm]` either side of each voxel centre.
+    Box { radius: f64 },
+    /// Gaussian with standard deviation `sigma_cm` `[cm]`.
+    Gaussian { sigma_cm: f64 },
+}
+
+/// Gaussian kernels are cut off this many standard deviations from the centre.
+const GAUSSIAN_REACH: f64 = 5.0;
+
+impl Kernel {
+    /// Fraction of the kernel centred at zero below `x` `[cm]`.
+    fn cdf(self, x: f64) -> f64 {
+        let step = if x >= 0.0 { 1.0 } else { 0.0 };
+        match self {
+            Kernel::Box { radius } if radius > 0.0 => ((x + radius) / (2.0 * radius)).clamp(0.0, 1.0),
+            Kernel::Gaussian { sigma_cm } if sigma_cm > 0.0 => {
+                0.5 * (1.0 + erf(x / (sigma_cm * std::f64::consts::SQRT_2)))
+            }
+            _ => step,
+        }
+    }
+
+    /// Distance `[cm]` beyond which the kernel is taken as zero.
+    fn reach(self) -> f64 {
+        match self {
+            Kernel::Box { radius } => radius,
+            Kernel::Gaussian { sigma_cm } => GAUSSIAN_REACH * sigma_cm,
+        }
+    }
+
+    /// Weights of the voxels between nodes `pts` contributing to each voxel, as
+    /// `(voxel, weight)` lists summing to one.
+    ///
+    /// Each weight is the part of the kernel, centred on the output voxel's centroid, lying
+    /// within the contributing voxel. The edge voxels reach out to infinity, so the part of
+    /// the kernel past the grid falls on them, clamping the dose at the edges.
+    fn weights(self, pts: &[f64]) -> Vec<Vec<(usize, f64)>> {
+        let num_voxels = pts.len() - 1;
+        let reach = self.reach();
+        (0..num_voxels)
+            .map(|out| {
+                let centroid = 0.5 * (pts[out] + pts[out + 1]);
+                let mut weights: Vec<(usize, f64)> = (0..num_voxels)
+                    .filter_map(|voxel| {
+                        let lo = if voxel == 0 { f64::NEG_INFINITY } else { pts[voxel] - centroid };
+                        let hi = if voxel == num_voxels - 1 {
+                            f64::INFINITY
+                        } else {
+                            pts[voxel + 1] - centroid
+                        };
+                        if hi < -reach || lo > reach {
+                            return None;
+                        }
+                        let weight = self.cdf(hi) - self.cdf(lo);
+                        if weight > 0.0 { Some((voxel, weight)) } else { None }
+                    })
+                    .collect();
+                // renormalize what the cut-off left out
+                let total: f64 = weights.iter().map(|&(_, weight)| weight).sum();
+                for (_, weight) in &mut weights {
+                    *weight /= total;
+                }
+                weights
+            })
+            .collect()
+    }
+}
+
+impl std::str::FromStr for Kernel {
+    type Err = String;
+    fn from_str(kernel: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Could not parse {} as a kernel, e.g. gaussian:0.2 or box:0.5", kernel);
+        let mut parts = kernel.splitn(2, ':');
+        let (name, width) = (parts.next().unwrap_or_default(), parts.next().ok_or_else(invalid)?);
+        let width: f64 = width.trim().parse().map_err(|_| invalid())?;
+        match name.trim() {
+            "box" => Ok(Kernel::Box { radius: width }),
+            "gaussian" => Ok(Kernel::Gaussian { sigma_cm: width }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl DoseBlock {
+    /// Smooth the dose with `kernel`, keeping the grid, e.g. to see through Monte Carlo noise.
+    ///
+    /// The kernel works in physical units over the possibly non-uniform grid: each voxel
+    /// weighs in by the part of the kernel it covers. Doses past the edges of the grid are
+    /// taken to be those of the edge voxels. A zero width leaves the dose unchanged; a negative
+    /// or non-finite width fails with [`DoseError::InvalidParameter`].
+    ///
+    /// Uncertainties are propagated assuming independent voxels, adding the absolute variances
+    /// with the squares of their weights, so smoothing lowers them.
+    pub fn smooth(&self, kernel: Kernel) -> Result<DoseBlock, DoseError> {
+        let (name, width) = match kernel {
+            Kernel::Box { radius } => ("radius", radius),
+            Kernel::Gaussian { sigma_cm } => ("sigma_cm", sigma_cm),
+        };
+        if !(width.is_finite() && width >= 0.0) {
+            return Err(DoseError::InvalidParameter { name, value: width });
+        }
+
+        let mut doses = self.doses.clone();
+        let mut variances = self.abs_variances();
+        for &axis in &[Axis::X, Axis::Y, Axis::Z] {
+            let weights = kernel.weights(self.nodes(axis));
+            doses = self.convolve(&doses, axis, &weights, |weight| weight);
+            variances = self.convolve(&variances, axis, &weights, |weight| weight * weight);
+        }
+        let uncerts = doses
+            .iter()
+            .zip(&variances)
+            .map(|(&dose, variance)| if dose != 0.0 { variance.sqrt() / dose.abs() } else { 0.0 })
+            .collect();
+
+        Ok(DoseBlock {
+            xs: self.xs.clone(),
+            ys: self.ys.clone(),
+            zs: self.zs.clone(),
+            doses,
+            uncerts,
+            variant: None,
+        })
+    }
+
+    /// Weighted sums of `values` along `axis`, with `weights` from [`Kernel::weights`]
+    /// transformed by `scale`.
+    fn convolve(
+        &self,
+        values: &[f64],
+        axis: Axis,
+        weights: &[Vec<(usize, f64)>],
+        scale: fn(f64) -> f64,
+    ) -> Vec<f64> {
+        let stride = match axis {
+            Axis::X => 1,
+            Axis::Y => self.num_x(),
+            Axis::Z => self.num_x() * self.num_y(),
+        };
+        (0..values.len())
+            .map(|index| {
+                let along = index / stride % weights.len();
+                let start = index - along * stride;
+                weights[along]
+                    .iter()
+                    .map(|&(voxel, weight)| scale(weight) * values[start + voxel * stride])
+                    .sum()
+            })
+            .collect()
+    }
+}
+
+/// Error function, to within 1.5e-7 (Abramowitz and Stegun 7.1.26).
+fn erf(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.327_591_1 * x.abs());
+    let poly = t
+        * (0.254_829_592
+            + t * (-0.284_496_736 + t * (1.421_413_741 + t * (-1.453_152_027 + t * 1.061_405_429))));
+    let erf = 1.0 - poly * (-x * x).exp();
+    if x < 0.0 { -erf } else { erf }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smooth() {
+        // 4 x 1 x 1 voxels of 1 cm with a uniform dose
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 2.0, 3.0, 4.0],
+            ys: vec![0.0, 1.0],
+            zs: vec![0.0, 1.0],
+            doses: vec![10.0; 4],
+            uncerts: vec![0.1; 4],
+            variant: None,
+        };
+        let smoothed = data.smooth(Kernel::Box { radius: 1.0 }).unwrap();
+        assert_eq!((smoothed.xs.clone(), smoothed.ys.clone()), (data.xs.clone(), data.ys.clone()));
+        for dose in &smoothed.doses {
+            approx::assert_relative_eq!(*dose, 10.0);
+        }
+        // weights 1/4, 1/2 and 1/4
+        approx::assert_relative_eq!(smoothed.uncerts[1], 0.375_f64.sqrt() / 10.0);
+        // the edge voxel takes the quarter of the kernel past the grid
+        approx::assert_relative_eq!(smoothed.uncerts[0], 0.625_f64.sqrt() / 10.0);
+
+        let spike = DoseBlock { doses: vec![0.0, 0.0, 12.0, 0.0], ..data.clone() };
+        let smoothed = spike.smooth(Kernel::Box { radius: 1.0 }).unwrap();
+        approx::assert_relative_eq!(smoothed.doses[0], 0.0);
+        approx::assert_relative_eq!(smoothed.doses[1], 3.0);
+        approx::assert_relative_eq!(smoothed.doses[2], 6.0);
+        approx::assert_relative_eq!(smoothed.doses[3], 3.0);
+
+        assert_eq!(data.smooth(Kernel::Gaussian { sigma_cm: 0.0 }).unwrap(), data);
+        assert!(matches!(
+            data.smooth(Kernel::Gaussian { sigma_cm: -0.2 }),
+            Err(DoseError::InvalidParameter { name: "sigma_cm", .. })
+        ));
+        assert_eq!("gaussian:0.2".parse(), Ok(Kernel::Gaussian { sigma_cm: 0.2 }));
+        assert_eq!("box:1".parse(), Ok(Kernel::Box { radius: 1.0 }));
+        assert!("gaussian".parse::<Kernel>().is_err());
+        assert!("median:1".parse::<Kernel>().is_err());
+    }
+
+    #[test]
+    fn smooth_gaussian() {
+        // a spike in the middle of 21 voxels of 1 cm
+        let xs: Vec<f64> = (0..=21).map(|x| x as f64).collect();
+        let mut doses = vec![0.0; 21];
+        doses[10] = 1.0;
+        let (ys, zs) = (vec![0.0, 1.0], vec![0.0, 1.0]);
+        let data = DoseBlock { xs, ys, zs, doses, uncerts: vec![0.0; 21], variant: None };
+        let smoothed = data.smooth(Kernel::Gaussian { sigma_cm: 1.0 }).unwrap();
+        // erf(0.5 / sqrt(2)), the kernel within half a voxel of its centre
+        approx::assert_relative_eq!(smoothed.doses[10], 0.382_924_9, epsilon = 1.0e-6);
+        approx::assert_relative_eq!(smoothed.doses[9], smoothed.doses[11], epsilon = 1.0e-6);
+        approx::assert_relative_eq!(smoothed.doses.iter().sum::<f64>(), 1.0, epsilon = 1.0e-6);
+        // zero-dose voxels without variance stay without uncertainty
+        assert!(smoothed.uncerts.iter().all(|&uncert| uncert == 0.0));
+    }
+}
@@ -0,0 +1,651 @@
+//! Summary statistics and sanity checks for dose blocks.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::voxel::{Centroids, Voxel};
+use crate::{Axis, DoseBlock, DoseError, DoseVariant, UncertaintyReport};
+
+/// Relative difference under which two voxel sizes count as the same spacing.
+///
+/// Coordinates are stored in single precision, so nominally equal spacings differ slightly,
+/// more so far from the origin.
+pub const SPACING_TOLERANCE: f64 = 1.0e-3;
+
+/// The mean spacing of nodes `pts` if every spacing is within a fraction `tol` of the smallest,
+/// see [`DoseBlock::uniform_spacing_within`].
+pub(crate) fn uniform_along(pts: &[f64], tol: f64) -> Option<f64> {
+    let spacings: Vec<f64> = pts.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    let smallest = spacings.iter().cloned().fold(f64::INFINITY, f64::min);
+    let close = |spacing: &f64| spacing - smallest <= tol * smallest.abs();
+    if spacings.is_empty() || !spacings.iter().all(close) {
+        return None;
+    }
+    Some(spacings.iter().sum::<f64>() / spacings.len() as f64)
+}
+
+/// Most distinct spacings along an axis that [`DoseInfo`] lists individually.
+const MAX_LISTED_SPACINGS: usize = 4;
+
+/// Headline dose and uncertainty numbers for a dose block.
+///
+/// Statistics are taken over every voxel, so non-finite values propagate into `mean_dose` and
+/// `mean_uncert`. Check [`DoseBlock::num_non_finite`] first if that matters.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DoseStats {
+    /// Total number of voxels.
+    pub num_voxels: usize,
+    /// Smallest voxel dose in `[Gy · cm2]`.
+    pub min_dose: f64,
+    /// Largest voxel dose in `[Gy · cm2]`.
+    pub max_dose: f64,
+    /// Mean voxel dose in `[Gy · cm2]`, unweighted by voxel volume.
+    pub mean_dose: f64,
+    /// Index of the maximum dose voxel into `doses`.
+    pub max_index: usize,
+    /// Centroid of the maximum dose voxel in `[cm]`.
+    pub max_centroid: [f64; 3],
+    /// Mean fractional uncertainty.
+    pub mean_uncert: f64,
+}
+
+/// Machine-readable summary of a dose block: grid, statistics and warnings.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DoseInfo {
+    /// Layout of the `3ddose` file, see [`DoseBlock::variant`].
+    pub variant: Option<DoseVariant>,
+    /// Number of voxels along *x*, *y* and *z*.
+    pub voxels: [usize; 3],
+    /// First and last node coordinates along *x*, *y* and *z* in `[cm]`.
+    pub bounds: [(f64, f64); 3],
+    /// Distinct voxel sizes along *x*, *y* and *z*, see [`DoseBlock::spacing_histogram`].
+    pub spacings: [Vec<(f64, usize)>; 3],
+    /// Dose and uncertainty statistics.
+    pub stats: DoseStats,
+    /// Uncertainties of the voxels with dose.
+    pub uncertainty: UncertaintyReport,
+    /// Number of voxels with a non-finite dose or uncertainty.
+    pub non_finite: usize,
+    /// Problems found by [`DoseBlock::warnings`].
+    pub warnings: Vec<String>,
+}
+
+impl DoseBlock {
+    /// Compute summary statistics over all voxels.
+    ///
+    /// The maximum location is the first voxel holding the maximum dose, in `doses` order.
+    /// Without any voxels the doses and uncertainty are `NaN`. Means are taken from compensated
+    /// sums, and under the `rayon` feature the voxels are reduced in parallel chunks.
+    pub fn stats(&self) -> DoseStats {
+        let num_voxels = self.num_voxels();
+        if self.doses.is_empty() {
+            return DoseStats {
+                num_voxels,
+                min_dose: f64::NAN,
+                max_dose: f64::NAN,
+                mean_dose: f64::NAN,
+                max_index: 0,
+                max_centroid: [f64::NAN; 3],
+                mean_uncert: f64::NAN,
+            };
+        }
+        let (min_dose, max_index) = self.dose_range(PARALLEL);
+        DoseStats {
+            num_voxels,
+            min_dose,
+            max_dose: self.doses[max_index],
+            mean_dose: stable_sum(&self.doses, PARALLEL) / num_voxels as f64,
+            max_index,
+            max_centroid: Centroids::new(self).voxel(self, max_index).centroid,
+            mean_uncert: stable_sum(&self.uncerts, PARALLEL) / num_voxels as f64,
+        }
+    }
+
+    /// Dose-weighted mean of the voxel centroids in `[cm]`, unweighted by voxel volume.
+    ///
+    /// `NaN` if the doses sum to zero.
+    pub fn dose_centroid(&self) -> [f64; 3] {
+        self.weighted_centroid(PARALLEL)
+    }
+
+    /// Smallest dose and index of the first maximum dose, see [`DoseBlock::stats`].
+    ///
+    /// A leading `NaN` dose counts as the maximum, and any other `NaN` never does.
+    fn dose_range(&self, parallel: bool) -> (f64, usize) {
+        let doses = &self.doses;
+        let chunks = map_ranges(doses.len(), parallel, |range| {
+            let mut min_dose = f64::INFINITY;
+            let mut max_index: Option<usize> = None;
+            for index in range {
+                min_dose = min_dose.min(doses[index]);
+                if !doses[index].is_nan() && max_index.is_none_or(|max| doses[index] > doses[max]) {
+                    max_index = Some(index);
+                }
+            }
+            (min_dose, max_index)
+        });
+        // chunks are in index order, so a later chunk only wins with a strictly larger dose
+        let mut min_dose = f64::INFINITY;
+        let mut max_index: Option<usize> = None;
+        for (chunk_min, chunk_max) in chunks {
+            min_dose = min_dose.min(chunk_min);
+            if let Some(index) = chunk_max {
+                if max_index.is_none_or(|max| doses[index] > doses[max]) {
+                    max_index = Some(index);
+                }
+            }
+        }
+        match max_index {
+            Some(index) if !doses[0].is_nan() => (min_dose, index),
+            _ => (min_dose, 0),
+        }
+    }
+
+    /// See [`DoseBlock::dose_centroid`].
+    fn weighted_centroid(&self, parallel: bool) -> [f64; 3] {
+        let centroids = Centroids::new(self);
+        let chunks = map_ranges(self.doses.len(), parallel, |range| {
+            let mut sums = [CompensatedSum::default(); 4];
+            for index in range {
+                let Voxel { dose, centroid: [x, y, z], .. } = centroids.voxel(self, index);
+                sums[0].add(dose);
+                sums[1].add(dose * x);
+                sums[2].add(dose * y);
+                sums[3].add(dose * z);
+            }
+            sums.map(|sum| sum.total())
+        });
+        let mut sums = [CompensatedSum::default(); 4];
+        for chunk in chunks {
+            for (sum, value) in sums.iter_mut().zip(&chunk) {
+                sum.add(*value);
+            }
+        }
+        let dose = sums[0].total();
+        [sums[1].total() / dose, sums[2].total() / dose, sums[3].total() / dose]
+    }
+
+    /// Doses at the `lo` and `hi` percentiles, e.g. `(1.0, 99.0)` for a display range that a
+    /// few hot voxels don't wash out.
+    ///
+    /// Percentiles interpolate linearly between the sorted finite doses, and are `NaN` if there
+    /// are none. Panics unless `0 <= lo <= hi <= 100`.
+    pub fn percentile_range(&self, lo: f64, hi: f64) -> (f64, f64) {
+        assert!(0.0 <= lo && lo <= hi && hi <= 100.0, "bad percentiles {} and {}", lo, hi);
+        let mut doses: Vec<f64> =
+            self.doses.iter().cloned().filter(|dose| dose.is_finite()).collect();
+        doses.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        let percentile = |pct: f64| {
+            if doses.is_empty() {
+                return f64::NAN;
+            }
+            let rank = pct / 100.0 * (doses.len() - 1) as f64;
+            let (below, frac) = (rank.floor() as usize, rank.fract());
+            match doses.get(below + 1) {
+                Some(above) if frac > 0.0 => doses[below] + frac * (above - doses[below]),
+                _ => doses[below],
+            }
+        };
+        (percentile(lo), percentile(hi))
+    }
+
+    /// Number of voxels whose dose or uncertainty is `NaN` or infinite.
+    pub fn num_non_finite(&self) -> usize {
+        self.doses
+            .iter()
+            .zip(&self.uncerts)
+            .filter(|(dose, uncert)| !dose.is_finite() || !uncert.is_finite())
+            .count()
+    }
+
+    /// First and last node coordinates along *x*, *y* and *z* in `[cm]`, `NaN` for an axis
+    /// without nodes.
+    pub fn bounds(&self) -> [(f64, f64); 3] {
+        let ends = |pts: &[f64]| match (pts.first(), pts.last()) {
+            (Some(&first), Some(&last)) => (first, last),
+            _ => (f64::NAN, f64::NAN),
+        };
+        [ends(&self.xs), ends(&self.ys), ends(&self.zs)]
+    }
+
+    /// Volume of each voxel in `[cm3]`, in the same order as `doses`.
+    pub fn voxel_volumes(&self) -> Vec<f64> {
+        let widths = |pts: &[f64]| pts.windows(2).map(|pair| pair[1] - pair[0]).collect::<Vec<_>>();
+        let (dxs, dys, dzs) = (widths(&self.xs), widths(&self.ys), widths(&self.zs));
+        let mut volumes = Vec::with_capacity(self.num_voxels());
+        for dz in &dzs {
+            for dy in &dys {
+                volumes.extend(dxs.iter().map(|dx| dx * dy * dz));
+            }
+        }
+        volumes
+    }
+
+    /// Sum of dose times voxel volume over the grid, in `[Gy cm2 cm3]` for `3ddose` dose per
+    /// fluence.
+    ///
+    /// Times the mass density this is the energy deposited, so it's comparable between grids
+    /// of different resolution where the mean dose isn't.
+    pub fn integral_dose(&self) -> f64 {
+        let mut sum = CompensatedSum::default();
+        for (dose, volume) in self.doses.iter().zip(self.voxel_volumes()) {
+            sum.add(dose * volume);
+        }
+        sum.total()
+    }
+
+    /// Distribution of voxel sizes along *x*, *y* and *z* in `[cm]`.
+    ///
+    /// Each axis has a list of `(spacing, count)` bins in increasing order of spacing. Spacings
+    /// within 0.1 % of each other share a bin, whose spacing is their mean. A uniform axis has a
+    /// single bin, while several bins usually mean deliberately fine and coarse regions, which
+    /// matter when interpolating.
+    pub fn spacing_histogram(&self) -> [Vec<(f64, usize)>; 3] {
+        let histogram = |pts: &[f64]| {
+            let mut spacings: Vec<f64> = pts.windows(2).map(|pair| pair[1] - pair[0]).collect();
+            spacings.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+            // (first spacing, sum, count) of each bin
+            let mut bins: Vec<(f64, f64, usize)> = Vec::new();
+            for spacing in spacings {
+                match bins.last_mut() {
+                    Some((first, sum, count))
+                        if (spacing - *first).abs() <= SPACING_TOLERANCE * first.abs() =>
+                    {
+                        *sum += spacing;
+                        *count += 1;
+                    }
+                    _ => bins.push((spacing, spacing, 1)),
+                }
+            }
+            bins.into_iter().map(|(_, sum, count)| (sum / count as f64, count)).collect()
+        };
+        [histogram(&self.xs), histogram(&self.ys), histogram(&self.zs)]
+    }
+
+    /// Voxel size along *x*, *y* and *z* in `[cm]` if every axis is uniformly spaced.
+    ///
+    /// Spacings within [`SPACING_TOLERANCE`], 0.1 %, of each other count as equal, as in
+    /// [`DoseBlock::spacing_histogram`].
+    pub fn uniform_spacing(&self) -> Option<[f64; 3]> {
+        self.uniform_spacing_within(SPACING_TOLERANCE)
+    }
+
+    /// Voxel size along *x*, *y* and *z* in `[cm]` if every axis is uniformly spaced, taking
+    /// spacings within a fraction `tol` of the smallest along their axis as equal.
+    ///
+    /// Each size is the mean spacing along its axis, and `None` means some axis has spacings
+    /// further apart, or that aren't finite, or no voxels.
+    pub fn uniform_spacing_within(&self, tol: f64) -> Option<[f64; 3]> {
+        let uniform = |pts| uniform_along(pts, tol);
+        Some([uniform(&self.xs)?, uniform(&self.ys)?, uniform(&self.zs)?])
+    }
+
+    /// Check the node coordinates along each axis are strictly increasing, failing with
+    /// [`DoseError::NonMonotonic`] at the first that isn't. A `NaN` coordinate is never in order.
+    pub fn check_monotonic(&self) -> Result<(), DoseError> {
+        let increasing = |pair: &[f64]| pair[0].partial_cmp(&pair[1]) == Some(Ordering::Less);
+        for axis in [Axis::X, Axis::Y, Axis::Z] {
+            if let Some(index) = self.nodes(axis).windows(2).position(|pair| !increasing(pair)) {
+                return Err(DoseError::NonMonotonic { axis, index: index + 1 });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check every dose and uncertainty is finite, failing with [`DoseError::NonFinite`] at
+    /// the first voxel that isn't, checking doses before uncertainties.
+    ///
+    /// DOSXYZnrc occasionally writes `NaN` or `Inf` after numerical trouble. Parsing accepts
+    /// them, so the data can still be inspected, and [`DoseBlock::num_non_finite`] counts them.
+    pub fn check_finite(&self) -> Result<(), DoseError> {
+        for (field, values) in [("dose value", &self.doses), ("uncertainty value", &self.uncerts)] {
+            if let Some(index) = values.iter().position(|value| !value.is_finite()) {
+                return Err(DoseError::NonFinite { field, index });
+            }
+        }
+        Ok(())
+    }
+
+    /// Describe anything suspicious about the data that doesn't stop it being converted.
+    ///
+    /// Checks for coordinates that aren't strictly increasing (degenerate or inverted voxels),
+    /// non-finite values, negative doses, and negative uncertainties.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if let Err(err) = self.check_monotonic() {
+            warnings.push(err.to_string());
+        }
+        let non_finite = self.num_non_finite();
+        if non_finite > 0 {
+            warnings.push(format!("{} voxels have a non-finite dose or uncertainty", non_finite));
+        }
+        let negative_doses = self.doses.iter().filter(|&&dose| dose < 0.0).count();
+        if negative_doses > 0 {
+            warnings.push(format!("{} voxels have a negative dose", negative_doses));
+        }
+        let negative_uncerts = self.uncerts.iter().filter(|&&uncert| uncert < 0.0).count();
+        if negative_uncerts > 0 {
+            warnings.push(format!("{} voxels have a negative uncertainty", negative_uncerts));
+        }
+        warnings
+    }
+
+    /// Collect the grid, statistics and warnings into one report.
+    pub fn info(&self) -> DoseInfo {
+        DoseInfo {
+            variant: self.variant,
+            voxels: [self.num_x(), self.num_y(), self.num_z()],
+            bounds: self.bounds(),
+            spacings: self.spacing_histogram(),
+            stats: self.stats(),
+            uncertainty: self.uncertainty_quality(),
+            non_finite: self.num_non_finite(),
+            warnings: self.warnings(),
+        }
+    }
+}
+
+impl fmt::Display for DoseStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "voxels:            {}", self.num_voxels)?;
+        writeln!(f, "min dose:          {:e} Gy·cm2", self.min_dose)?;
+        writeln!(f, "max dose:          {:e} Gy·cm2", self.max_dose)?;
+        writeln!(f, "mean dose:         {:e} Gy·cm2", self.mean_dose)?;
+        writeln!(
+            f,
+            "max dose voxel:    {} at ({}, {}, {}) cm",
+            self.max_index, self.max_centroid[0], self.max_centroid[1], self.max_centroid[2]
+        )?;
+        write!(f, "mean uncertainty:  {:.2} %", 100.0 * self.mean_uncert)
+    }
+}
+
+impl fmt::Display for DoseInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(variant) = self.variant {
+            writeln!(f, "layout:            {}", variant)?;
+        }
+        writeln!(f, "grid:              {} x {} x {}", self.voxels[0], self.voxels[1], self.voxels[2])?;
+        for (axis, (lo, hi)) in ["x", "y", "z"].iter().zip(&self.bounds) {
+            writeln!(f, "{} range:           [{}, {}] cm", axis, lo, hi)?;
+        }
+        for (axis, bins) in ["x", "y", "z"].iter().zip(&self.spacings) {
+            // spacings carry single precision noise, print them to the nearest 1e-6 cm
+            let round = |spacing: f64| (spacing * 1.0e6).round() / 1.0e6;
+            if bins.len() <= MAX_LISTED_SPACINGS {
+                let listed: Vec<String> =
+                    bins.iter().map(|&(spacing, count)| format!("{} cm ({})", round(spacing), count)).collect();
+                writeln!(f, "{} spacing:         {}", axis, listed.join(", "))?;
+            } else {
+                let (smallest, largest) = (bins[0].0, bins[bins.len() - 1].0);
+                writeln!(
+                    f,
+                    "{} spacing:         {} distinct, {} to {} cm",
+                    axis,
+                    bins.len(),
+                    round(smallest),
+                    round(largest)
+                )?;
+            }
+        }
+        writeln!(f, "{}", self.stats)?;
+        writeln!(f, "{}", self.uncertainty)?;
+        write!(f, "non-finite voxels: {}", self.non_finite)?;
+        for warning in &self.warnings {
+            write!(f, "\nwarning: {}", warning)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether reductions over voxels run in parallel, which they do under the `rayon` feature.
+pub(crate) const PARALLEL: bool = cfg!(feature = "rayon");
+
+/// Voxels per chunk in parallel reductions.
+///
+/// Fixed rather than split by thread, so results don't depend on how many threads there are.
+#[cfg(feature = "rayon")]
+const REDUCTION_CHUNK: usize = 1 << 16;
+
+/// Neumaier's compensated sum, which carries the rounding error of each addition along and adds
+/// it back at the end, so the error doesn't grow with the number of voxels as it does for a
+/// plain running sum.
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct CompensatedSum {
+    sum: f64,
+    compensation: f64,
+}
+
+impl CompensatedSum {
+    pub(crate) fn add(&mut self, value: f64) {
+        let sum = self.sum + value;
+        if self.sum.abs() >= value.abs() {
+            self.compensation += (self.sum - sum) + value;
+        } else {
+            self.compensation += (value - sum) + self.sum;
+        }
+        self.sum = sum;
+    }
+
+    pub(crate) fn total(&self) -> f64 {
+        // an infinite or NaN sum makes the compensation NaN, keep the sum as a plain sum would
+        if self.sum.is_finite() {
+            self.sum + self.compensation
+        } else {
+            self.sum
+        }
+    }
+}
+
+/// Compensated sum of `values`, in parallel chunks if `parallel` and the `rayon` feature is on.
+pub(crate) fn stable_sum(values: &[f64], parallel: bool) -> f64 {
+    let chunks = map_ranges(values.len(), parallel, |range| {
+        let mut sum = CompensatedSum::default();
+        values[range].iter().for_each(|&value| sum.add(value));
+        sum.total()
+    });
+    let mut sum = CompensatedSum::default();
+    chunks.into_iter().for_each(|value| sum.add(value));
+    sum.total()
+}
+
+/// Apply `reduce` to consecutive ranges of indices covering `0..len` and collect the results
+/// in order: a single range serially, or chunks of [`REDUCTION_CHUNK`] in parallel if
+/// `parallel` and the `rayon` feature is on.
+#[cfg_attr(not(feature = "rayon"), allow(unused_variables))]
+fn map_ranges<T, F>(len: usize, parallel: bool, reduce: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(std::ops::Range<usize>) -> T + Sync,
+{
+    #[cfg(feature = "rayon")]
+    {
+        if parallel {
+            use rayon::prelude::*;
+            return (0..len.div_ceil(REDUCTION_CHUNK))
+                .into_par_iter()
+                .map(|chunk| {
+                    let start = chunk * REDUCTION_CHUNK;
+                    reduce(start..len.min(start + REDUCTION_CHUNK))
+                })
+                .collect();
+        }
+    }
+    vec![reduce(0..len)]
+}
+
+/// Parse percentiles like `1,99` into a `(lo, hi)` pair for [`DoseBlock::percentile_range`].
+pub(crate) fn parse_percentiles(value: &str) -> Result<(f64, f64), String> {
+    let invalid = || format!("Could not parse {} as percentiles from 0 to 100, e.g. 1,99", value);
+    let mut parts = value.split(',').map(|part| part.trim().parse::<f64>());
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(lo)), Some(Ok(hi)), None) if 0.0 <= lo && lo <= hi && hi <= 100.0 => Ok((lo, hi)),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block() -> DoseBlock {
+        DoseBlock {
+            xs: vec![0.0, 2.0],
+            ys: vec![0.0, 2.0, 4.0],
+            zs: vec![0.0, 2.0, 4.0, 8.0],
+            doses: vec![10.0, 20.0, 30.0, 60.0, 50.0, 40.0],
+            uncerts: vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6],
+            variant: None,
+        }
+    }
+
+    #[test]
+    fn stats() {
+        let stats = block().stats();
+        assert_eq!(stats.num_voxels, 6);
+        assert_eq!(stats.min_dose, 10.0);
+        assert_eq!(stats.max_dose, 60.0);
+        assert_eq!(stats.mean_dose, 35.0);
+        assert_eq!(stats.max_index, 3);
+        assert_eq!(stats.max_centroid, [1.0, 3.0, 3.0]);
+        approx::assert_relative_eq!(stats.mean_uncert, 0.35);
+
+        // centroids at y = 1, 3 and z = 1, 3, 6 weighted by dose
+        let centroid = block().dose_centroid();
+        approx::assert_relative_eq!(centroid[0], 1.0);
+        approx::assert_relative_eq!(centroid[1], (90.0 + 3.0 * 120.0) / 210.0);
+        approx::assert_relative_eq!(centroid[2], (30.0 + 3.0 * 90.0 + 6.0 * 90.0) / 210.0);
+
+        // a plain running sum loses both ones to rounding
+        assert_eq!(stable_sum(&[1.0, 1e100, 1.0, -1e100], false), 2.0);
+        assert_eq!(stable_sum(&[1.0, f64::INFINITY, 1.0], false), f64::INFINITY);
+    }
+
+    #[test]
+    fn integral_dose() {
+        // 2 x 2 x 4 cm voxels then a 2 x 2 x 8 cm one in z
+        let data = block();
+        assert_eq!(data.voxel_volumes(), vec![8.0, 8.0, 8.0, 8.0, 16.0, 16.0]);
+        assert_eq!(data.integral_dose(), 8.0 * (10.0 + 20.0 + 30.0 + 60.0) + 16.0 * 90.0);
+
+        let mut uniform = data.clone();
+        uniform.zs = vec![0.0, 2.0, 4.0, 6.0];
+        assert!(uniform.voxel_volumes().iter().all(|&volume| volume == 8.0));
+        assert_eq!(uniform.integral_dose(), 8.0 * 210.0);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_stats_match_serial() {
+        // several chunks with a ragged last one, and doses over many decades
+        let (nx, ny, nz) = (70, 60, 50);
+        let nodes = |n: usize| (0..=n).map(|i| 0.1 * (i * i) as f64).collect::<Vec<f64>>();
+        let num_voxels = nx * ny * nz;
+        let dose = |i: usize| 10f64.powi((i % 13) as i32 - 6) * (1.0 + (i % 7) as f64);
+        let doses: Vec<f64> = (0..num_voxels).map(dose).collect();
+        let mut data = DoseBlock {
+            xs: nodes(nx),
+            ys: nodes(ny),
+            zs: nodes(nz),
+            doses,
+            uncerts: vec![0.01; num_voxels],
+            variant: None,
+        };
+        // the maximum repeats across chunks, and NaN is never the maximum past the first voxel
+        data.doses[100] = 1e9;
+        data.doses[3 * REDUCTION_CHUNK + 5] = 1e9;
+        data.doses[REDUCTION_CHUNK] = f64::NAN;
+        assert!(data.num_voxels().div_ceil(REDUCTION_CHUNK) > 3);
+
+        assert_eq!(data.dose_range(true), data.dose_range(false));
+        assert_eq!(data.dose_range(true).1, 100);
+        data.doses[REDUCTION_CHUNK] = 0.5;
+
+        let (parallel, serial) = (stable_sum(&data.doses, true), stable_sum(&data.doses, false));
+        approx::assert_relative_eq!(parallel, serial, max_relative = 1e-15);
+        let (parallel, serial) = (data.weighted_centroid(true), data.weighted_centroid(false));
+        for axis in 0..3 {
+            approx::assert_relative_eq!(parallel[axis], serial[axis], max_relative = 1e-15);
+        }
+        data.doses[0] = f64::NAN;
+        assert_eq!(data.dose_range(true).1, 0);
+        assert_eq!(data.dose_range(false).1, 0);
+    }
+
+    #[test]
+    fn percentile_range() {
+        let mut data = block();
+        assert_eq!(data.percentile_range(0.0, 100.0), (10.0, 60.0));
+        assert_eq!(data.percentile_range(50.0, 50.0), (35.0, 35.0));
+        // ranks 0.05 and 4.95 of 5
+        let (lo, hi) = data.percentile_range(1.0, 99.0);
+        approx::assert_relative_eq!(lo, 10.5);
+        approx::assert_relative_eq!(hi, 59.5);
+
+        // a single hot voxel doesn't stretch the range
+        data.doses[3] = 6000.0;
+        data.doses[0] = f64::NAN;
+        assert_eq!(data.percentile_range(0.0, 75.0), (20.0, 50.0));
+        data.doses = vec![f64::NAN; 6];
+        assert!(data.percentile_range(1.0, 99.0).0.is_nan());
+
+        assert_eq!(parse_percentiles("1, 99"), Ok((1.0, 99.0)));
+        assert!(parse_percentiles("99,1").is_err());
+        assert!(parse_percentiles("1,99,100").is_err());
+        assert!(parse_percentiles("1").is_err());
+    }
+
+    #[test]
+    fn spacing_histogram() {
+        let mut data = block();
+        data.zs = vec![0.0, 0.1, 0.2000001, 0.3, 0.8, 1.3];
+        let [xs, ys, zs] = data.spacing_histogram();
+        assert_eq!(xs, vec![(2.0, 1)]);
+        assert_eq!(ys, vec![(2.0, 2)]);
+        assert_eq!(zs.len(), 2);
+        approx::assert_relative_eq!(zs[0].0, 0.1);
+        assert_eq!(zs[0].1, 3);
+        assert_eq!(zs[1], (0.5, 2));
+
+        assert_eq!(data.uniform_spacing(), None);
+        assert_eq!(block().uniform_spacing(), None);
+        data.zs = vec![0.0, 2.0, 4.0000001];
+        assert_eq!(data.uniform_spacing().map(|[x, y, _]| [x, y]), Some([2.0, 2.0]));
+        // 1 % apart, only uniform with a looser tolerance
+        data.zs = vec![0.0, 1.0, 2.01];
+        assert_eq!(data.uniform_spacing(), None);
+        let spacing = data.uniform_spacing_within(0.02).unwrap();
+        approx::assert_relative_eq!(spacing[2], 1.005);
+        data.zs = vec![0.0, 1.0, f64::NAN];
+        assert_eq!(data.uniform_spacing_within(0.02), None);
+
+        data.zs = vec![0.0, 0.1, 0.2000001, 0.3, 0.8, 1.3];
+        let info = data.info().to_string();
+        assert!(info.contains("\nz spacing:         0.1 cm (3), 0.5 cm (2)\n"));
+        data.xs = vec![0.0, 1.0, 3.0, 6.0, 10.0, 15.0];
+        assert!(data.info().to_string().contains("\nx spacing:         5 distinct, 1 to 5 cm\n"));
+    }
+
+    #[test]
+    fn warnings() {
+        let mut data = block();
+        assert!(data.warnings().is_empty());
+        assert_eq!(data.info().bounds, [(0.0, 2.0), (0.0, 4.0), (0.0, 8.0)]);
+
+        data.zs.swap(1, 2);
+        data.doses[0] = f64::NAN;
+        data.doses[1] = -1.0;
+        assert_eq!(data.num_non_finite(), 1);
+        assert_eq!(
+            data.warnings(),
+            vec![
+                "z-coordinates are not strictly increasing at node 2",
+                "1 voxels have a non-finite dose or uncertainty",
+                "1 voxels have a negative dose",
+            ]
+        );
+    }
+}
@@ -0,0 +1,463 @@
+//! Single-pass conversion for files too large to hold in memory as a [`DoseBlock`].
+
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::str::FromStr;
+
+use crate::{
+    open_3ddose, write_msh2_nodes, DoseBlock, DoseError, FieldKind, Fmt, Quantity,
+    DEFAULT_BUFFER_SIZE,
+};
+
+/// Convert a `3ddose` file to a Gmsh `.msh` file (version 2.2) without reading the dose and
+/// uncertainty arrays into memory.
+///
+/// Only the node coordinates are kept. Dose and uncertainty values are copied into the
+/// `$ElementData` sections as they're read, so element data follows the voxel order of the
+/// `3ddose` file (*x* fastest, then *y*, then *z*), which is also the element numbering. That
+/// means nothing that needs the whole dose array is available here: the output is the same as
+/// [`DoseBlock::write_msh2`] with the default fields and options, and masking, normalization,
+/// extra fields and other orderings need [`DoseBlock::from_3d_dose`] instead. Gzip-compressed
+/// input is decompressed as it's read, as [`DoseBlock::from_3d_dose`] does.
+pub fn convert_3ddose_to_msh<P, Q>(input: P, output: Q) -> Result<(), DoseError>
+where
+    P: AsRef<std::path::Path>,
+    Q: AsRef<std::path::Path>,
+{
+    convert_3ddose_to_msh_buffered(input, output, DEFAULT_BUFFER_SIZE)
+}
+
+/// Convert a `3ddose` file to a Gmsh `.msh` file as [`convert_3ddose_to_msh`] does, reading
+/// and writing through buffers of `buffer_size` bytes each.
+///
+/// A zero `buffer_size` is a [`DoseError::InvalidParameter`] error.
+pub fn convert_3ddose_to_msh_buffered<P, Q>(
+    input: P,
+    output: Q,
+    buffer_size: usize,
+) -> Result<(), DoseError>
+where
+    P: AsRef<std::path::Path>,
+    Q: AsRef<std::path::Path>,
+{
+    let mut tokens = Tokens::new(open_3ddose(input, buffer_size)?);
+    // only create the output once the input looks like a 3ddose file
+    let grid = read_grid(&mut tokens)?;
+    let mut filestream = BufWriter::with_capacity(buffer_size, File::create(output)?);
+    write_streamed_msh2(&grid, &STREAMED_FIELDS, &mut filestream, |kind, _| {
+        tokens.parse::<f64>(token_field(kind))
+    })?;
+    filestream.flush()?;
+    Ok(())
+}
+
+/// Convert `3ddose` data from any buffered reader to Gmsh `.msh` (version 2.2) on any writer,
+/// as [`convert_3ddose_to_msh`] converts a file.
+///
+/// Only the node coordinates are held in memory, so e.g. a decompressed download can be
+/// converted straight to a socket or pipe however large the grid. Pass a buffered writer, as
+/// every value is written separately.
+pub fn convert_3ddose_reader_to_msh<R: BufRead, W: Write>(
+    input: R,
+    output: &mut W,
+) -> Result<(), DoseError> {
+    let mut tokens = Tokens::new(input);
+    let grid = read_grid(&mut tokens)?;
+    write_streamed_msh2(&grid, &STREAMED_FIELDS, output, |kind, _| {
+        tokens.parse::<f64>(token_field(kind))
+    })
+}
+
+/// Fields of a streamed conversion, in the order the `3ddose` file holds them.
+const STREAMED_FIELDS: [FieldKind; 2] = [FieldKind::Dose, FieldKind::Uncert];
+
+/// Name of the value being parsed for `kind`, one of the [`STREAMED_FIELDS`], for errors.
+fn token_field(kind: FieldKind) -> &'static str {
+    match kind {
+        FieldKind::Dose => "dose value",
+        _ => "uncertainty value",
+    }
+}
+
+/// Read the voxel counts and node coordinates, as a block without doses or uncertainties.
+pub(crate) fn read_grid<R: BufRead>(tokens: &mut Tokens<R>) -> Result<DoseBlock, DoseError> {
+    let num_x: usize = match tokens.next_token()? {
+        Some(token) => token.parse().map_err(|_| tokens.invalid("voxel number"))?,
+        None => return Err(DoseError::EmptyGrid),
+    };
+    let [num_x, num_y, num_z] = tokens.voxel_counts(num_x)?;
+    let grid = DoseBlock {
+        xs: tokens.parse_n(num_x + 1, "x-coordinate")?,
+        ys: tokens.parse_n(num_y + 1, "y-coordinate")?,
+        zs: tokens.parse_n(num_z + 1, "z-coordinate")?,
+        doses: Vec::new(),
+        uncerts: Vec::new(),
+        variant: None,
+    };
+    grid.check_monotonic()?;
+    Ok(grid)
+}
+
+/// Write the mesh of `grid`, then a `$ElementData` section for each of `kinds` in order, with
+/// the values one at a time as `value(kind, index)` gives them.
+pub(crate) fn write_streamed_msh2<W, T, V>(
+    grid: &DoseBlock,
+    kinds: &[FieldKind],
+    filestream: &mut W,
+    mut value: V,
+) -> Result<(), DoseError>
+where
+    W: Write,
+    T: std::fmt::Display,
+    V: FnMut(FieldKind, usize) -> Result<T, DoseError>,
+{
+    writeln!(filestream, "$MeshFormat\n2.2 0 8\n$EndMeshFormat")?;
+    write_msh2_nodes(filestream, &grid.xs, &grid.ys, &grid.zs)?;
+    grid.write_msh2_hex8(filestream, "0 0")?;
+
+    let num_voxels = grid.num_voxels();
+    for &kind in kinds {
+        let name = kind.label(Fmt::Msh2, Quantity::Dose);
+        // see `DoseBlock::write_msh2_fields_to` for the section layout
+        writeln!(filestream, "$ElementData\n1\n\"{}\"\n1\n0.0\n3\n0\n1\n{}", name, num_voxels)?;
+        for index in 0..num_voxels {
+            writeln!(filestream, "{} {}", index + 1, value(kind, index)?)?;
+        }
+        writeln!(filestream, "$EndElementData")?;
+    }
+    Ok(())
+}
+
+/// Where a token starts in its input, for pointing at bad values in large files.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TokenPosition {
+    /// Line number, counting from one.
+    pub line: usize,
+    /// Offset of the token's first byte from the start of the input, counting from zero.
+    pub byte: u64,
+}
+
+impl std::fmt::Display for TokenPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}, byte {}", self.line, self.byte)
+    }
+}
+
+/// Whitespace-separated tokens read straight from a buffered reader, without collecting lines.
+///
+/// `3ddose` files put each array on a single line, so reading by line would hold every dose
+/// value as text at once. The position of each token is tracked so parse errors can say
+/// where the bad value is.
+pub(crate) struct Tokens<R> {
+    reader: R,
+    token: Vec<u8>,
+    /// Line breaks read so far.
+    newlines: usize,
+    /// Bytes consumed from the reader so far.
+    consumed: u64,
+    /// Line the last token is on, from zero.
+    line: usize,
+    /// Offset of the last token's first byte.
+    start: u64,
+}
+
+impl<R: BufRead> Tokens<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Tokens { reader, token: Vec::new(), newlines: 0, consumed: 0, line: 0, start: 0 }
+    }
+
+    /// Line the last token read is on, counting from zero.
+    pub(crate) fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Where the last token read starts.
+    pub(crate) fn position(&self) -> TokenPosition {
+        TokenPosition { line: self.line + 1, byte: self.start }
+    }
+
+    /// The next token, or `None` at the end of the input.
+    pub(crate) fn next_token(&mut self) -> Result<Option<&str>, std::io::Error> {
+        self.token.clear();
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            let mut used = 0;
+            let mut ended = false;
+            for &byte in buf {
+                used += 1;
+                if !byte.is_ascii_whitespace() {
+                    if self.token.is_empty() {
+                        self.line = self.newlines;
+                        self.start = self.consumed + used as u64 - 1;
+                    }
+                    self.token.push(byte);
+                    continue;
+                }
+                if byte == b'\n' {
+                    self.newlines += 1;
+                }
+                if !self.token.is_empty() {
+                    ended = true;
+                    break;
+                }
+            }
+            self.reader.consume(used);
+            self.consumed += used as u64;
+            if ended {
+                break;
+            }
+        }
+        if self.token.is_empty() {
+            return Ok(None);
+        }
+        std::str::from_utf8(&self.token)
+            .map(Some)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+
+    /// Parse the next token, which must exist.
+    pub(crate) fn parse<T: FromStr>(&mut self, field: &'static str) -> Result<T, DoseError> {
+        match self.next_token()? {
+            Some(token) => {
+                let parsed = token.parse();
+                parsed.map_err(|_| self.invalid(field))
+            }
+            None => Err(DoseError::UnexpectedEof { field }),
+        }
+    }
+
+    /// An error for the last token read, which isn't a valid `field`.
+    pub(crate) fn invalid(&self, field: &'static str) -> DoseError {
+        let token = String::from_utf8_lossy(&self.token).into_owned();
+        DoseError::InvalidToken { field, token, at: self.position() }
+    }
+
+    /// Parse the *y* and *z* voxel counts following `num_x`, the first token of a block.
+    ///
+    /// Fails with [`DoseError::EmptyGrid`] if any count is zero, and with
+    /// [`DoseError::TooManyVoxels`] if the voxel or node counts wouldn't fit a `usize`.
+    pub(crate) fn voxel_counts(&mut self, num_x: usize) -> Result<[usize; 3], DoseError> {
+        let counts = [num_x, self.parse("voxel number")?, self.parse("voxel number")?];
+        let num_voxels = counts.iter().try_fold(1_usize, |product, &n| product.checked_mul(n));
+        let num_nodes = counts
+            .iter()
+            .try_fold(1_usize, |product, &n| product.checked_mul(n.checked_add(1)?));
+        match (num_voxels, num_nodes) {
+            (Some(0), _) => Err(DoseError::EmptyGrid),
+            (Some(_), Some(_)) => Ok(counts),
+            _ => Err(DoseError::TooManyVoxels { counts, at: self.position() }),
+        }
+    }
+
+    /// Parse the next `len` tokens.
+    pub(crate) fn parse_n<T: FromStr>(
+        &mut self,
+        len: usize,
+        field: &'static str,
+    ) -> Result<Vec<T>, DoseError> {
+        (0..len).map(|_| self.parse(field)).collect()
+    }
+
+    /// Parse the next `len` tokens as floats, with the same result and errors as
+    /// [`Tokens::parse_n`].
+    ///
+    /// Under the `rayon` feature with more than one thread, tokens are read a batch at a time,
+    /// then the batch is parsed in parallel, which speeds up reading the dose and uncertainty
+    /// arrays of large grids.
+    pub(crate) fn parse_floats(
+        &mut self,
+        len: usize,
+        field: &'static str,
+    ) -> Result<Vec<f64>, DoseError> {
+        #[cfg(not(feature = "rayon"))]
+        {
+            self.parse_n(len, field)
+        }
+
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+
+            // batching only pays for itself with threads to share the parsing
+            if rayon::current_num_threads() == 1 {
+                return self.parse_n(len, field);
+            }
+            const BATCH_TOKENS: usize = 1 << 16;
+            let mut values = Vec::new();
+            // tokens of a batch run together, each ending at its entry of `ends`
+            let mut text = String::new();
+            let mut ends = Vec::new();
+            let mut positions = Vec::new();
+            while values.len() < len {
+                text.clear();
+                ends.clear();
+                positions.clear();
+                let mut ended = false;
+                for _ in 0..BATCH_TOKENS.min(len - values.len()) {
+                    match self.next_token()? {
+                        Some(token) => text.push_str(token),
+                        None => {
+                            ended = true;
+                            break;
+                        }
+                    }
+                    ends.push(text.len());
+                    positions.push(self.position());
+                }
+                let token = |index: usize| {
+                    let start = if index == 0 { 0 } else { ends[index - 1] };
+                    &text[start..ends[index]]
+                };
+                let parsed: Vec<Option<f64>> = (0..ends.len())
+                    .into_par_iter()
+                    .with_min_len(1024)
+                    .map(|index| token(index).parse().ok())
+                    .collect();
+                // the first bad token wins over running out of tokens, as when parsing in turn
+                for (index, value) in parsed.into_iter().enumerate() {
+                    match value {
+                        Some(value) => values.push(value),
+                        None => {
+                            let token = token(index).to_string();
+                            let at = positions[index];
+                            return Err(DoseError::InvalidToken { field, token, at });
+                        }
+                    }
+                }
+                if ended {
+                    return Err(DoseError::UnexpectedEof { field });
+                }
+            }
+            Ok(values)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_in_memory_conversion() {
+        let mut input = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        input.push("props");
+        input.push("asym_2x3x4.3ddose");
+
+        let output = "tmp_stream.msh";
+        convert_3ddose_to_msh(&input, output).unwrap();
+        let streamed = std::fs::read(output).unwrap();
+        std::fs::remove_file(output).unwrap();
+
+        let mut expected = Vec::new();
+        DoseBlock::from_3d_dose(&input).unwrap().write_msh2_to(&mut expected).unwrap();
+        let mut piped = Vec::new();
+        let text = std::fs::read(&input).unwrap();
+        convert_3ddose_reader_to_msh(&text[..], &mut piped).unwrap();
+        assert_eq!(piped, streamed);
+        assert_eq!(String::from_utf8(streamed).unwrap(), String::from_utf8(expected).unwrap());
+        let empty = convert_3ddose_reader_to_msh(&b"\n"[..], &mut Vec::new());
+        assert!(matches!(empty, Err(DoseError::EmptyGrid)));
+    }
+
+    #[test]
+    fn parse_floats_matches_parse_n() {
+        // more than one batch of tokens when parsing in parallel, over several lines
+        let mut values: Vec<String> =
+            (0..70_000).map(|i| format!("{:e}", i as f64 * 0.37)).collect();
+        let lines = |values: &[String]| {
+            values.chunks(1000).map(|line| line.join(" ")).collect::<Vec<_>>().join("\n")
+        };
+        let text = lines(&values);
+        let parse_both = |text: &str, len| {
+            let parse_floats = || Tokens::new(text.as_bytes()).parse_floats(len, "dose value");
+            #[cfg(not(feature = "rayon"))]
+            let floats = parse_floats();
+            // in parallel even on a single core
+            #[cfg(feature = "rayon")]
+            let floats = rayon::ThreadPoolBuilder::new()
+                .num_threads(2)
+                .build()
+                .unwrap()
+                .install(parse_floats);
+            let each = Tokens::new(text.as_bytes()).parse_n::<f64>(len, "dose value");
+            (floats.map_err(|err| err.to_string()), each.map_err(|err| err.to_string()))
+        };
+
+        let (floats, each) = parse_both(&text, 70_000);
+        assert_eq!(floats.as_ref().unwrap().len(), 70_000);
+        assert_eq!(floats, each);
+        let (floats, each) = parse_both(&text, 70_001);
+        assert_eq!(floats, Err("the file ended before the last dose value".to_string()));
+        assert_eq!(floats, each);
+        values[68_000] = "2.5O".to_string();
+        let (floats, each) = parse_both(&lines(&values), 70_001);
+        let message = "could not parse \"2.5O\" as a dose value at line 69, byte ";
+        assert!(floats.as_ref().unwrap_err().starts_with(message));
+        assert_eq!(floats, each);
+    }
+
+    #[test]
+    fn bad_token_position() {
+        let mut input = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        input.push("props");
+        // asym_2x3x4.3ddose with a letter O in the third dose value
+        input.push("corrupt_2x3x4.3ddose");
+
+        let output = "tmp_corrupt.msh";
+        let err = convert_3ddose_to_msh(&input, output).unwrap_err();
+        std::fs::remove_file(output).unwrap();
+        assert_eq!(
+            err.to_string(),
+            "could not parse \"1.1000000O0E+01\" as a dose value at line 5, byte 264"
+        );
+        let multi = DoseBlock::from_3d_dose_multi(&input).unwrap_err();
+        assert_eq!(multi.to_string(), err.to_string());
+        let single = DoseBlock::from_3d_dose(&input).unwrap_err();
+        assert_eq!(single.to_string(), err.to_string());
+    }
+
+    #[test]
+    fn tokens() {
+        let mut tokens = Tokens::new(&b"  1 2.5\n\n-3e-2 x"[..]);
+        assert_eq!(tokens.parse::<usize>("voxel number").unwrap(), 1);
+        assert_eq!(tokens.parse::<f64>("dose value").unwrap(), 2.5);
+        assert_eq!(tokens.line(), 0);
+        assert_eq!(tokens.parse::<f64>("dose value").unwrap(), -0.03);
+        assert_eq!(tokens.line(), 2);
+        assert_eq!(tokens.position(), TokenPosition { line: 3, byte: 9 });
+        assert!(matches!(
+            tokens.parse::<f64>("dose value"),
+            Err(DoseError::InvalidToken { field: "dose value", token, at })
+                if token == "x" && at == TokenPosition { line: 3, byte: 15 }
+        ));
+        assert!(matches!(tokens.parse::<f64>("dose value"), Err(DoseError::UnexpectedEof { .. })));
+    }
+
+    #[test]
+    fn voxel_count_overflow() {
+        // 2^64 voxels, 2^64 - 1 voxels along x with one more node, and 2^64 - 2^33 + 1 voxels
+        // with 2^65 nodes
+        let headers = [
+            "4294967296 4294967296 2\n",
+            "18446744073709551615 1 1\n",
+            "4294967295 4294967295 1\n",
+        ];
+        for &header in &headers {
+            let too_many = |result: Result<(), DoseError>| match result {
+                Err(DoseError::TooManyVoxels { at, .. }) => assert_eq!(at.line, 1),
+                other => panic!("expected too many voxels for {:?}, got {:?}", header, other),
+            };
+            too_many(DoseBlock::from_reader(header.as_bytes()).map(drop));
+            too_many(DoseBlock::from_reader_f32(header.as_bytes()).map(drop));
+            too_many(convert_3ddose_reader_to_msh(header.as_bytes(), &mut Vec::new()));
+        }
+        let err = DoseBlock::from_reader(&b"4294967296 4294967296 2"[..]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "4294967296 x 4294967296 x 2 voxels at line 1, byte 22 are too many to address"
+        );
+    }
+}
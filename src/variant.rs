@@ -0,0 +1,41 @@
+//! The layouts `3ddose` files come in.
+
+use std::fmt;
+
+/// Layout of the `3ddose` file a dose block was read from.
+///
+/// Every layout holds the same six records: voxel counts, *x*, *y* and *z* node coordinates,
+/// doses and fractional uncertainties. They differ in how the records are split into lines.
+///
+/// The variant is informational only, for troubleshooting and `stats` output. It's recorded
+/// after a block is read and doesn't change how it's parsed: values are read as
+/// whitespace-separated tokens, which reads every layout the same way.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DoseVariant {
+    /// One line per record, as written by DOSXYZnrc.
+    Classic,
+    /// Records wrapped over several lines, or run together on fewer lines.
+    Wrapped,
+}
+
+impl DoseVariant {
+    /// Tell the layout from the line each of the six records ends on, counting from the line
+    /// the voxel counts start on.
+    pub(crate) fn from_record_lines(last_lines: [usize; 6]) -> DoseVariant {
+        if last_lines.iter().enumerate().all(|(record, &line)| line == record) {
+            DoseVariant::Classic
+        } else {
+            DoseVariant::Wrapped
+        }
+    }
+}
+
+impl fmt::Display for DoseVariant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DoseVariant::Classic => write!(f, "classic, one line per record"),
+            DoseVariant::Wrapped => write!(f, "wrapped, records split across lines"),
+        }
+    }
+}
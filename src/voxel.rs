@@ -0,0 +1,95 @@
+//! Walking the voxels of a dose block with their positions, for analyses that don't need a file.
+
+use crate::{centroids, DoseBlock};
+
+/// One voxel of a dose block, see [`DoseBlock::voxels`].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Voxel {
+    /// Index into `doses` and `uncerts`.
+    pub index: usize,
+    /// Voxel indices along *x*, *y* and *z*, see [`DoseBlock::voxel_index`].
+    pub ijk: [usize; 3],
+    /// Centroid in `[cm]`.
+    pub centroid: [f64; 3],
+    /// Dose in `[Gy · cm2]`.
+    pub dose: f64,
+    /// Fractional uncertainty.
+    pub uncert: f64,
+}
+
+/// Voxel centroids along *x*, *y* and *z*, computed once for looking up many voxels.
+pub(crate) struct Centroids([Vec<f64>; 3]);
+
+impl Centroids {
+    pub(crate) fn new(data: &DoseBlock) -> Self {
+        Centroids([centroids(&data.xs), centroids(&data.ys), centroids(&data.zs)])
+    }
+
+    /// The voxel of `data` at `index` into `doses`.
+    pub(crate) fn voxel(&self, data: &DoseBlock, index: usize) -> Voxel {
+        let (i, j, k) = data.voxel_ijk(index);
+        Voxel {
+            index,
+            ijk: [i, j, k],
+            centroid: [self.0[0][i], self.0[1][j], self.0[2][k]],
+            dose: data.doses[index],
+            uncert: data.uncerts[index],
+        }
+    }
+}
+
+impl DoseBlock {
+    /// Every voxel in `doses` order, *x* varying fastest, then *y*, then *z*.
+    ///
+    /// ```
+    /// # use dose2gmsh::DoseBlock;
+    /// # fn main() -> Result<(), dose2gmsh::DoseError> {
+    /// let data = DoseBlock::new(
+    ///     vec![0.0, 1.0, 2.0],
+    ///     vec![0.0, 1.0],
+    ///     vec![0.0, 1.0],
+    ///     vec![1.0, 3.0],
+    ///     vec![0.1, 0.02],
+    /// )?;
+    /// // dose-weighted mean x, 0.5 and 1.5 weighted 1 and 3
+    /// let (sum, weights) = data
+    ///     .voxels()
+    ///     .fold((0.0, 0.0), |(sum, weights), v| (sum + v.dose * v.centroid[0], weights + v.dose));
+    /// assert_eq!(sum / weights, 1.25);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn voxels(&self) -> impl ExactSizeIterator<Item = Voxel> + '_ {
+        let centroids = Centroids::new(self);
+        (0..self.num_voxels()).map(move |index| centroids.voxel(self, index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn voxels() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("props");
+        path.push("asym_2x3x4.3ddose");
+        let data = DoseBlock::from_3d_dose(&path).unwrap();
+
+        let voxels: Vec<Voxel> = data.voxels().collect();
+        assert_eq!(voxels.len(), 24);
+        for (index, voxel) in voxels.iter().enumerate() {
+            let [i, j, k] = voxel.ijk;
+            assert_eq!((voxel.index, data.voxel_index(i, j, k)), (index, index));
+            assert_eq!((voxel.dose, voxel.uncert), (data.doses[index], data.uncerts[index]));
+        }
+        // voxel (i, j, k) has dose 100k + 10j + i + 1
+        let last = voxels.last().unwrap();
+        assert_eq!((last.ijk, last.dose), ([1, 2, 3], 322.0));
+        let mut csv = Vec::new();
+        data.write_csv_to(&mut csv).unwrap();
+        let last_row = String::from_utf8(csv).unwrap().lines().last().unwrap().to_string();
+        let [x, y, z] = last.centroid;
+        assert!(last_row.starts_with(&format!("{},{},{},322,", x, y, z)));
+    }
+}
@@ -0,0 +1,127 @@
+//! Legacy VTK output for ParaView and other VTK-based viewers.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::{DoseBlock, Field, Fmt, DEFAULT_BUFFER_SIZE};
+
+/// Options for the legacy VTK writers.
+#[derive(Debug, Clone, Default)]
+pub struct VtkOptions {
+    /// Suggested dose display range, written as a two-component `dose_display_range` array of
+    /// field data, e.g. from [`DoseBlock::percentile_range`].
+    pub display_range: Option<(f64, f64)>,
+}
+
+impl DoseBlock {
+    /// Convert the `3ddose` data to a legacy VTK file, readable by ParaView and VisIt.
+    pub fn write_vtk<P: AsRef<std::path::Path>>(&self, output: P) -> Result<(), std::io::Error> {
+        self.write_vtk_fields(output, &self.default_fields(Fmt::Vtk), &VtkOptions::default())
+    }
+
+    /// Write the `3ddose` data in legacy VTK format to any writer.
+    pub fn write_vtk_to<W: Write>(&self, file: &mut W) -> Result<(), std::io::Error> {
+        self.write_vtk_fields_to(file, &self.default_fields(Fmt::Vtk), &VtkOptions::default())
+    }
+
+    /// Convert the grid and the given fields to a legacy VTK file.
+    pub fn write_vtk_fields<P: AsRef<std::path::Path>>(
+        &self,
+        output: P,
+        fields: &[Field],
+        options: &VtkOptions,
+    ) -> Result<(), std::io::Error> {
+        let mut file = BufWriter::with_capacity(DEFAULT_BUFFER_SIZE, File::create(output)?);
+        self.write_vtk_fields_to(&mut file, fields, options)?;
+        file.flush()
+    }
+
+    /// Write the grid and the given fields in legacy VTK format to any writer.
+    ///
+    /// The grid is an ASCII `RECTILINEAR_GRID` holding the node coordinates, so non-uniform
+    /// spacing is kept, and each field is a `SCALARS` array of cell data in `doses` order,
+    /// which is also VTK's cell order. VTK names can't contain spaces, so they're replaced by
+    /// underscores.
+    pub fn write_vtk_fields_to<W: Write>(
+        &self,
+        file: &mut W,
+        fields: &[Field],
+        options: &VtkOptions,
+    ) -> Result<(), std::io::Error> {
+        self.check_field_lengths(fields)?;
+
+        writeln!(file, "# vtk DataFile Version 3.0")?;
+        writeln!(file, "dose2gmsh dose grid")?;
+        writeln!(file, "ASCII")?;
+        writeln!(file, "DATASET RECTILINEAR_GRID")?;
+        if let Some((lo, hi)) = options.display_range {
+            // one array of one tuple with two components
+            writeln!(file, "FIELD FieldData 1\ndose_display_range 2 1 double\n{} {}", lo, hi)?;
+        }
+        writeln!(file, "DIMENSIONS {} {} {}", self.xs.len(), self.ys.len(), self.zs.len())?;
+        for (axis, pts) in [("X", &self.xs), ("Y", &self.ys), ("Z", &self.zs)].iter() {
+            writeln!(file, "{}_COORDINATES {} double", axis, pts.len())?;
+            write_values(file, pts)?;
+        }
+
+        writeln!(file, "CELL_DATA {}", self.num_voxels())?;
+        for field in fields {
+            writeln!(file, "SCALARS {} double 1", field.name.replace(' ', "_"))?;
+            writeln!(file, "LOOKUP_TABLE default")?;
+            write_values(file, field.values)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write space-separated values, a few to a line to keep lines short.
+pub(crate) fn write_values<W: Write>(file: &mut W, values: &[f64]) -> Result<(), std::io::Error> {
+    const PER_LINE: usize = 9;
+    for line in values.chunks(PER_LINE) {
+        let line: Vec<String> = line.iter().map(|value| value.to_string()).collect();
+        writeln!(file, "{}", line.join(" "))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_vtk() {
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 3.0],
+            ys: vec![0.0, 0.5],
+            zs: vec![-1.0, 0.0, 1.0],
+            doses: vec![1.0, 2.0, 3.0, 4.0],
+            uncerts: vec![0.1, 0.2, 0.3, 0.4],
+            variant: None,
+        };
+        let mut out = Vec::new();
+        data.write_vtk_to(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "# vtk DataFile Version 3.0\n\
+             dose2gmsh dose grid\n\
+             ASCII\n\
+             DATASET RECTILINEAR_GRID\n\
+             DIMENSIONS 3 2 3\n\
+             X_COORDINATES 3 double\n0 1 3\n\
+             Y_COORDINATES 2 double\n0 0.5\n\
+             Z_COORDINATES 3 double\n-1 0 1\n\
+             CELL_DATA 4\n\
+             SCALARS Dose_[Gy_cm2] double 1\nLOOKUP_TABLE default\n1 2 3 4\n\
+             SCALARS Uncertainty_fraction double 1\nLOOKUP_TABLE default\n0.1 0.2 0.3 0.4\n"
+        );
+
+        let options = VtkOptions { display_range: Some((1.5, 3.5)) };
+        let mut out = Vec::new();
+        data.write_vtk_fields_to(&mut out, &data.default_fields(Fmt::Vtk), &options).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains(
+            "DATASET RECTILINEAR_GRID\n\
+             FIELD FieldData 1\ndose_display_range 2 1 double\n1.5 3.5\n\
+             DIMENSIONS 3 2 3\n"
+        ));
+    }
+}
@@ -0,0 +1,249 @@
+//! VTK XML output, keeping the grid structure for ParaView to slice along voxel planes.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::vtk::write_values;
+use crate::{DoseBlock, DoseError, Field, Fmt, VtkOptions, DEFAULT_BUFFER_SIZE};
+
+impl DoseBlock {
+    /// Convert the `3ddose` data to a VTK XML rectilinear grid (`.vtr`) file.
+    pub fn write_vtr<P: AsRef<std::path::Path>>(&self, output: P) -> Result<(), std::io::Error> {
+        self.write_vtr_fields(output, &self.default_fields(Fmt::Vtr), &VtkOptions::default())
+    }
+
+    /// Write the `3ddose` data as a VTK XML rectilinear grid to any writer.
+    pub fn write_vtr_to<W: Write>(&self, file: &mut W) -> Result<(), std::io::Error> {
+        self.write_vtr_fields_to(file, &self.default_fields(Fmt::Vtr), &VtkOptions::default())
+    }
+
+    /// Convert the grid and the given fields to a VTK XML rectilinear grid (`.vtr`) file.
+    pub fn write_vtr_fields<P: AsRef<std::path::Path>>(
+        &self,
+        output: P,
+        fields: &[Field],
+        options: &VtkOptions,
+    ) -> Result<(), std::io::Error> {
+        let mut file = BufWriter::with_capacity(DEFAULT_BUFFER_SIZE, File::create(output)?);
+        self.write_vtr_fields_to(&mut file, fields, options)?;
+        file.flush()
+    }
+
+    /// Write the grid and the given fields as a VTK XML `RectilinearGrid` to any writer.
+    ///
+    /// The node coordinates along each axis are the `Coordinates` of a single piece, and each
+    /// field is an ASCII `Float64` array of `CellData` in `doses` order, the first marked as
+    /// the active scalars. Extents count nodes from 0, so a grid of `num_x` voxels along *x*
+    /// spans `0 num_x`. Unlike legacy VTK, names keep their spaces.
+    pub fn write_vtr_fields_to<W: Write>(
+        &self,
+        file: &mut W,
+        fields: &[Field],
+        options: &VtkOptions,
+    ) -> Result<(), std::io::Error> {
+        self.check_field_lengths(fields)?;
+
+        let extent = format!("0 {} 0 {} 0 {}", self.num_x(), self.num_y(), self.num_z());
+        writeln!(file, "<?xml version=\"1.0\"?>")?;
+        writeln!(
+            file,
+            "<VTKFile type=\"RectilinearGrid\" version=\"0.1\" byte_order=\"LittleEndian\">"
+        )?;
+        writeln!(file, "<RectilinearGrid WholeExtent=\"{}\">", extent)?;
+        write_display_range(file, options)?;
+        writeln!(file, "<Piece Extent=\"{}\">", extent)?;
+        write_cell_data(file, fields)?;
+        writeln!(file, "<Coordinates>")?;
+        for (axis, pts) in [("x", &self.xs), ("y", &self.ys), ("z", &self.zs)].iter() {
+            write_data_array(file, axis, pts)?;
+        }
+        writeln!(file, "</Coordinates>")?;
+        writeln!(file, "</Piece>\n</RectilinearGrid>\n</VTKFile>")?;
+        Ok(())
+    }
+
+    /// Convert the `3ddose` data to a VTK XML image data (`.vti`) file.
+    pub fn write_vti<P: AsRef<std::path::Path>>(&self, output: P) -> Result<(), std::io::Error> {
+        self.write_vti_fields(output, &self.default_fields(Fmt::Vti), &VtkOptions::default())
+    }
+
+    /// Write the `3ddose` data as VTK XML image data to any writer.
+    pub fn write_vti_to<W: Write>(&self, file: &mut W) -> Result<(), std::io::Error> {
+        self.write_vti_fields_to(file, &self.default_fields(Fmt::Vti), &VtkOptions::default())
+    }
+
+    /// Convert the grid and the given fields to a VTK XML image data (`.vti`) file.
+    pub fn write_vti_fields<P: AsRef<std::path::Path>>(
+        &self,
+        output: P,
+        fields: &[Field],
+        options: &VtkOptions,
+    ) -> Result<(), std::io::Error> {
+        let mut file = BufWriter::with_capacity(DEFAULT_BUFFER_SIZE, File::create(output)?);
+        self.write_vti_fields_to(&mut file, fields, options)?;
+        file.flush()
+    }
+
+    /// Write the grid and the given fields as VTK XML `ImageData` to any writer.
+    ///
+    /// Image data describes the grid by the first node as its `Origin` and the
+    /// [`DoseBlock::uniform_spacing`] as its `Spacing`, rather than listing every node, with
+    /// extents and fields as in [`DoseBlock::write_vtr_fields_to`]. Grids that aren't uniformly
+    /// spaced are an [`std::io::ErrorKind::InvalidInput`] error wrapping
+    /// [`DoseError::NonUniformGrid`], and should be written as a rectilinear grid instead.
+    pub fn write_vti_fields_to<W: Write>(
+        &self,
+        file: &mut W,
+        fields: &[Field],
+        options: &VtkOptions,
+    ) -> Result<(), std::io::Error> {
+        self.check_field_lengths(fields)?;
+        let [dx, dy, dz] = self.uniform_spacing().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, DoseError::NonUniformGrid)
+        })?;
+
+        let extent = format!("0 {} 0 {} 0 {}", self.num_x(), self.num_y(), self.num_z());
+        writeln!(file, "<?xml version=\"1.0\"?>")?;
+        writeln!(file, "<VTKFile type=\"ImageData\" version=\"0.1\" byte_order=\"LittleEndian\">")?;
+        writeln!(
+            file,
+            "<ImageData WholeExtent=\"{}\" Origin=\"{} {} {}\" Spacing=\"{} {} {}\">",
+            extent, self.xs[0], self.ys[0], self.zs[0], dx, dy, dz
+        )?;
+        write_display_range(file, options)?;
+        writeln!(file, "<Piece Extent=\"{}\">", extent)?;
+        write_cell_data(file, fields)?;
+        writeln!(file, "</Piece>\n</ImageData>\n</VTKFile>")?;
+        Ok(())
+    }
+}
+
+/// Write the display range, if any, as a two-component `dose_display_range` array of
+/// `FieldData`.
+fn write_display_range<W: Write>(file: &mut W, options: &VtkOptions) -> Result<(), std::io::Error> {
+    if let Some((lo, hi)) = options.display_range {
+        writeln!(file, "<FieldData>")?;
+        writeln!(
+            file,
+            "<DataArray type=\"Float64\" Name=\"dose_display_range\" NumberOfTuples=\"1\" \
+             NumberOfComponents=\"2\" format=\"ascii\">"
+        )?;
+        writeln!(file, "{} {}\n</DataArray>\n</FieldData>", lo, hi)?;
+    }
+    Ok(())
+}
+
+/// Write each field as a `CellData` array, the first as the active scalars.
+fn write_cell_data<W: Write>(file: &mut W, fields: &[Field]) -> Result<(), std::io::Error> {
+    match fields.first() {
+        Some(first) => writeln!(file, "<CellData Scalars=\"{}\">", escape(first.name))?,
+        None => writeln!(file, "<CellData>")?,
+    }
+    for field in fields {
+        write_data_array(file, field.name, field.values)?;
+    }
+    writeln!(file, "</CellData>")?;
+    Ok(())
+}
+
+/// Write an ASCII `Float64` data array of one component.
+fn write_data_array<W: Write>(
+    file: &mut W,
+    name: &str,
+    values: &[f64],
+) -> Result<(), std::io::Error> {
+    writeln!(file, "<DataArray type=\"Float64\" Name=\"{}\" format=\"ascii\">", escape(name))?;
+    write_values(file, values)?;
+    writeln!(file, "</DataArray>")?;
+    Ok(())
+}
+
+/// `name` with the characters XML attribute values can't hold replaced by entities.
+fn escape(name: &str) -> String {
+    name.replace('&', "&amp;").replace('<', "&lt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_vtr() {
+        // 2 x 1 x 2 voxels, uneven in x
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 3.0],
+            ys: vec![0.0, 0.5],
+            zs: vec![-1.0, 0.0, 1.0],
+            doses: vec![1.0, 2.0, 3.0, 4.0],
+            uncerts: vec![0.1, 0.2, 0.3, 0.4],
+            variant: None,
+        };
+        let mut out = Vec::new();
+        data.write_vtr_to(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "<?xml version=\"1.0\"?>\n\
+             <VTKFile type=\"RectilinearGrid\" version=\"0.1\" byte_order=\"LittleEndian\">\n\
+             <RectilinearGrid WholeExtent=\"0 2 0 1 0 2\">\n\
+             <Piece Extent=\"0 2 0 1 0 2\">\n\
+             <CellData Scalars=\"Dose [Gy cm2]\">\n\
+             <DataArray type=\"Float64\" Name=\"Dose [Gy cm2]\" format=\"ascii\">\n\
+             1 2 3 4\n</DataArray>\n\
+             <DataArray type=\"Float64\" Name=\"Uncertainty fraction\" format=\"ascii\">\n\
+             0.1 0.2 0.3 0.4\n</DataArray>\n\
+             </CellData>\n\
+             <Coordinates>\n\
+             <DataArray type=\"Float64\" Name=\"x\" format=\"ascii\">\n0 1 3\n</DataArray>\n\
+             <DataArray type=\"Float64\" Name=\"y\" format=\"ascii\">\n0 0.5\n</DataArray>\n\
+             <DataArray type=\"Float64\" Name=\"z\" format=\"ascii\">\n-1 0 1\n</DataArray>\n\
+             </Coordinates>\n\
+             </Piece>\n</RectilinearGrid>\n</VTKFile>\n"
+        );
+
+        let options = VtkOptions { display_range: Some((1.5, 3.5)) };
+        let fields = [Field { name: "a<b & \"c\"", values: &data.doses }];
+        let mut out = Vec::new();
+        data.write_vtr_fields_to(&mut out, &fields, &options).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains(
+            "<RectilinearGrid WholeExtent=\"0 2 0 1 0 2\">\n<FieldData>\n\
+             <DataArray type=\"Float64\" Name=\"dose_display_range\" NumberOfTuples=\"1\" \
+             NumberOfComponents=\"2\" format=\"ascii\">\n1.5 3.5\n</DataArray>\n</FieldData>\n"
+        ));
+        assert!(text.contains("<CellData Scalars=\"a&lt;b &amp; &quot;c&quot;\">\n"));
+    }
+
+    #[test]
+    fn write_vti() {
+        // 2 x 1 x 2 voxels of 0.5 x 2 x 1 cm
+        let mut data = DoseBlock {
+            xs: vec![-1.0, -0.5, 0.0],
+            ys: vec![0.0, 2.0],
+            zs: vec![3.0, 4.0, 5.0],
+            doses: vec![1.0, 2.0, 3.0, 4.0],
+            uncerts: vec![0.1, 0.2, 0.3, 0.4],
+            variant: None,
+        };
+        let mut out = Vec::new();
+        data.write_vti_to(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "<?xml version=\"1.0\"?>\n\
+             <VTKFile type=\"ImageData\" version=\"0.1\" byte_order=\"LittleEndian\">\n\
+             <ImageData WholeExtent=\"0 2 0 1 0 2\" Origin=\"-1 0 3\" Spacing=\"0.5 2 1\">\n\
+             <Piece Extent=\"0 2 0 1 0 2\">\n\
+             <CellData Scalars=\"Dose [Gy cm2]\">\n\
+             <DataArray type=\"Float64\" Name=\"Dose [Gy cm2]\" format=\"ascii\">\n\
+             1 2 3 4\n</DataArray>\n\
+             <DataArray type=\"Float64\" Name=\"Uncertainty fraction\" format=\"ascii\">\n\
+             0.1 0.2 0.3 0.4\n</DataArray>\n\
+             </CellData>\n\
+             </Piece>\n</ImageData>\n</VTKFile>\n"
+        );
+
+        data.xs[1] = -0.4;
+        let err = data.write_vti_to(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), DoseError::NonUniformGrid.to_string());
+    }
+}
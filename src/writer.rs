@@ -0,0 +1,320 @@
+//! Output writers behind a common trait, so formats can be chosen at runtime or added by users.
+
+use std::io::Write;
+
+use crate::{CsvOptions, DoseBlock, Field, Fmt, MshOptions, VtkOptions, DVH_BINS};
+
+/// A single-file output format for dose blocks.
+///
+/// Implement this to add a format of your own; [`Fmt::writer`] gives the built-in ones.
+pub trait DoseWriter {
+    /// Write the grid of `block` with the given fields, each holding one value per voxel in
+    /// `doses` order.
+    fn write_fields(
+        &self,
+        block: &DoseBlock,
+        fields: &[Field],
+        out: &mut dyn Write,
+    ) -> std::io::Result<()>;
+
+    /// Write `block` with its dose and uncertainty fields.
+    ///
+    /// Fields are labelled as in [`Fmt::Csv`] output unless the writer overrides this.
+    fn write(&self, block: &DoseBlock, out: &mut dyn Write) -> std::io::Result<()> {
+        self.write_fields(block, &block.default_fields(Fmt::Csv), out)
+    }
+}
+
+/// Gmsh `.msh` (version 2.2) output, see [`DoseBlock::write_msh2_fields_to`].
+#[derive(Debug, Clone, Default)]
+pub struct MshWriter {
+    pub options: MshOptions,
+}
+
+impl DoseWriter for MshWriter {
+    fn write_fields(
+        &self,
+        block: &DoseBlock,
+        fields: &[Field],
+        mut out: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        block.write_msh2_fields_to(&mut out, fields, &self.options)
+    }
+
+    fn write(&self, block: &DoseBlock, out: &mut dyn Write) -> std::io::Result<()> {
+        self.write_fields(block, &block.default_fields(Fmt::Msh2), out)
+    }
+}
+
+/// Gmsh `.msh` (version 4.1) output, see [`DoseBlock::write_msh4_fields_to`].
+#[derive(Debug, Clone, Default)]
+pub struct Msh4Writer {
+    pub options: MshOptions,
+}
+
+impl DoseWriter for Msh4Writer {
+    fn write_fields(
+        &self,
+        block: &DoseBlock,
+        fields: &[Field],
+        mut out: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        block.write_msh4_fields_to(&mut out, fields, &self.options)
+    }
+
+    fn write(&self, block: &DoseBlock, out: &mut dyn Write) -> std::io::Result<()> {
+        self.write_fields(block, &block.default_fields(Fmt::Msh4), out)
+    }
+}
+
+/// `csv` output, see [`DoseBlock::write_csv_fields_to`].
+#[derive(Debug, Clone, Default)]
+pub struct CsvWriter {
+    pub options: CsvOptions,
+}
+
+impl DoseWriter for CsvWriter {
+    fn write_fields(
+        &self,
+        block: &DoseBlock,
+        fields: &[Field],
+        mut out: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        block.write_csv_fields_to(&mut out, fields, &self.options)
+    }
+}
+
+/// Legacy VTK output, see [`DoseBlock::write_vtk_fields_to`].
+#[derive(Debug, Clone, Default)]
+pub struct VtkWriter {
+    pub options: VtkOptions,
+}
+
+impl DoseWriter for VtkWriter {
+    fn write_fields(
+        &self,
+        block: &DoseBlock,
+        fields: &[Field],
+        mut out: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        block.write_vtk_fields_to(&mut out, fields, &self.options)
+    }
+
+    fn write(&self, block: &DoseBlock, out: &mut dyn Write) -> std::io::Result<()> {
+        self.write_fields(block, &block.default_fields(Fmt::Vtk), out)
+    }
+}
+
+/// VTK XML rectilinear grid output, see [`DoseBlock::write_vtr_fields_to`].
+#[derive(Debug, Clone, Default)]
+pub struct VtrWriter {
+    pub options: VtkOptions,
+}
+
+impl DoseWriter for VtrWriter {
+    fn write_fields(
+        &self,
+        block: &DoseBlock,
+        fields: &[Field],
+        mut out: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        block.write_vtr_fields_to(&mut out, fields, &self.options)
+    }
+
+    fn write(&self, block: &DoseBlock, out: &mut dyn Write) -> std::io::Result<()> {
+        self.write_fields(block, &block.default_fields(Fmt::Vtr), out)
+    }
+}
+
+/// VTK XML image data output for uniform grids, see [`DoseBlock::write_vti_fields_to`].
+#[derive(Debug, Clone, Default)]
+pub struct VtiWriter {
+    pub options: VtkOptions,
+}
+
+impl DoseWriter for VtiWriter {
+    fn write_fields(
+        &self,
+        block: &DoseBlock,
+        fields: &[Field],
+        mut out: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        block.write_vti_fields_to(&mut out, fields, &self.options)
+    }
+
+    fn write(&self, block: &DoseBlock, out: &mut dyn Write) -> std::io::Result<()> {
+        self.write_fields(block, &block.default_fields(Fmt::Vti), out)
+    }
+}
+
+/// Cumulative dose-volume histogram output of the first field, see
+/// [`DoseBlock::write_dvh_fields_to`].
+#[derive(Debug, Clone)]
+pub struct DvhWriter {
+    pub bins: usize,
+}
+
+impl Default for DvhWriter {
+    fn default() -> Self {
+        DvhWriter { bins: DVH_BINS }
+    }
+}
+
+impl DoseWriter for DvhWriter {
+    fn write_fields(
+        &self,
+        block: &DoseBlock,
+        fields: &[Field],
+        mut out: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        match fields.first() {
+            Some(field) => block.write_dvh_fields_to(&mut out, field, self.bins),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Fmt {
+    /// Every output format, in the order `dose2gmsh formats` lists them.
+    pub const ALL: [Fmt; 11] = [
+        Fmt::Msh2, Fmt::Msh4, Fmt::Csv, Fmt::Vtk, Fmt::Vtr, Fmt::Vti, Fmt::Raw, Fmt::Mhd, Fmt::Npy,
+        Fmt::Dicom, Fmt::Dvh,
+    ];
+
+    /// The name selecting this format with `--format`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Fmt::Csv => "csv",
+            Fmt::Msh2 => "msh2",
+            Fmt::Msh4 => "msh4",
+            Fmt::Raw => "raw",
+            Fmt::Mhd => "mhd",
+            Fmt::Vtk => "vtk",
+            Fmt::Vtr => "vtr",
+            Fmt::Vti => "vti",
+            Fmt::Dicom => "dicom",
+            Fmt::Npy => "npy",
+            Fmt::Dvh => "dvh",
+        }
+    }
+
+    /// Extension given to output files, without the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Fmt::Msh2 | Fmt::Msh4 => "msh",
+            Fmt::Dicom => "dcm",
+            Fmt::Dvh => "csv",
+            format => format.name(),
+        }
+    }
+
+    /// The format written to files with this extension, given without the dot and in any
+    /// case, the inverse of [`Fmt::extension`].
+    ///
+    /// Formats sharing an extension go to the first of [`Fmt::ALL`], so `msh` is [`Fmt::Msh2`]
+    /// and `csv` is [`Fmt::Csv`].
+    pub fn from_extension(extension: &str) -> Option<Fmt> {
+        Fmt::ALL.iter().copied().find(|format| format.extension().eq_ignore_ascii_case(extension))
+    }
+
+    /// A one-line description of the format and what it's for.
+    pub fn description(self) -> &'static str {
+        match self {
+            Fmt::Csv => "voxel centroids and fields as text columns, for spreadsheets and plotting",
+            Fmt::Msh2 => "Gmsh mesh of hexahedra with element data, version 2.2",
+            Fmt::Msh4 => "Gmsh mesh of hexahedra in entity blocks, version 4.1 for newer tools",
+            Fmt::Raw => "binary float64 volumes with a text .hdr header",
+            Fmt::Mhd => "MetaImage for 3D Slicer, ImageJ and ITK, uniform grids only",
+            Fmt::Vtk => "legacy VTK rectilinear grid for ParaView",
+            Fmt::Vtr => "VTK XML rectilinear grid for ParaView, sliceable along voxel planes",
+            Fmt::Vti => "VTK XML image data, compact for uniform grids, otherwise written as vtr",
+            Fmt::Dicom => "DICOM RT Dose for treatment planning systems, evenly spaced in x and y",
+            Fmt::Npy => "NumPy arrays of dose, uncertainty and node coordinates for Python",
+            Fmt::Dvh => "cumulative dose-volume histogram as csv, weighting voxels by volume",
+        }
+    }
+
+    /// The writer for this format with the given options, each used only by its own format.
+    ///
+    /// `None` for [`Fmt::Raw`], [`Fmt::Mhd`] and [`Fmt::Npy`], which write several files, and
+    /// [`Fmt::Dicom`], which writes the dose alone, see [`DoseBlock::write_raw`],
+    /// [`DoseBlock::write_mhd`], [`DoseBlock::write_npy`] and [`DoseBlock::write_dicom_rtdose`].
+    pub fn writer(
+        self,
+        csv_options: CsvOptions,
+        msh_options: MshOptions,
+        vtk_options: VtkOptions,
+    ) -> Option<Box<dyn DoseWriter>> {
+        match self {
+            Fmt::Csv => Some(Box::new(CsvWriter { options: csv_options })),
+            Fmt::Msh2 => Some(Box::new(MshWriter { options: msh_options })),
+            Fmt::Msh4 => Some(Box::new(Msh4Writer { options: msh_options })),
+            Fmt::Vtk => Some(Box::new(VtkWriter { options: vtk_options })),
+            Fmt::Vtr => Some(Box::new(VtrWriter { options: vtk_options })),
+            Fmt::Vti => Some(Box::new(VtiWriter { options: vtk_options })),
+            Fmt::Dvh => Some(Box::new(DvhWriter::default())),
+            Fmt::Raw | Fmt::Mhd | Fmt::Npy | Fmt::Dicom => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writers_match_methods() {
+        let data = DoseBlock {
+            xs: vec![0.0, 1.0, 3.0],
+            ys: vec![0.0, 0.5],
+            zs: vec![-1.0, 0.0, 1.0],
+            doses: vec![1.0, 2.0, 3.0, 4.0],
+            uncerts: vec![0.1, 0.2, 0.3, 0.4],
+            variant: None,
+        };
+        for &format in &[Fmt::Csv, Fmt::Msh2, Fmt::Msh4, Fmt::Vtk, Fmt::Vtr, Fmt::Dvh] {
+            let mut expected = Vec::new();
+            match format {
+                Fmt::Csv => data.write_csv_to(&mut expected).unwrap(),
+                Fmt::Msh2 => data.write_msh2_to(&mut expected).unwrap(),
+                Fmt::Msh4 => data.write_msh4_to(&mut expected).unwrap(),
+                Fmt::Vtr => data.write_vtr_to(&mut expected).unwrap(),
+                Fmt::Dvh => {
+                    let dose = &data.default_fields(Fmt::Csv)[0];
+                    data.write_dvh_fields_to(&mut expected, dose, DVH_BINS).unwrap()
+                }
+                _ => data.write_vtk_to(&mut expected).unwrap(),
+            }
+            let mut out = Vec::new();
+            let writer = format.writer(Default::default(), Default::default(), Default::default()).unwrap();
+            writer.write(&data, &mut out).unwrap();
+            assert_eq!(out, expected, "{:?}", format);
+        }
+        assert!(Fmt::Raw.writer(Default::default(), Default::default(), Default::default()).is_none());
+        for &format in &Fmt::ALL {
+            assert!(format.name().parse::<Fmt>().is_ok(), "{:?}", format);
+        }
+        assert_eq!(Fmt::Msh2.extension(), "msh");
+        for &format in &Fmt::ALL {
+            let named = Fmt::from_extension(format.extension()).unwrap();
+            assert_eq!(named.extension(), format.extension(), "{:?}", format);
+        }
+        assert!(matches!(Fmt::from_extension("msh"), Some(Fmt::Msh2)));
+        assert!(matches!(Fmt::from_extension("VTR"), Some(Fmt::Vtr)));
+        assert!(Fmt::from_extension("msh.gz").is_none());
+
+        // a user-defined format
+        struct Total;
+        impl DoseWriter for Total {
+            fn write_fields(&self, _: &DoseBlock, fields: &[Field], out: &mut dyn Write) -> std::io::Result<()> {
+                for field in fields {
+                    writeln!(out, "{}: {}", field.name, field.values.iter().sum::<f64>())?;
+                }
+                Ok(())
+            }
+        }
+        let mut out = Vec::new();
+        Total.write(&data, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "Dose [Gy cm2]: 10\nUncertainty fraction: 1\n");
+    }
+}